@@ -0,0 +1,85 @@
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use cortexm3;
+use kernel::debug;
+use kernel::hil::led;
+use hotel;
+
+use PROCESSES;
+
+pub struct Writer;
+
+static mut WRITER: Writer = Writer {};
+
+// Set by the panic handler once printing over USB has been tried, so a
+// fault in the USB driver doesn't repeat on every line.
+static mut SKIP_USB: bool = false;
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        unsafe {
+            let uart = &hotel::uart::UART0;
+
+            static mut INITIALIZED: bool = false;
+            if !INITIALIZED {
+                INITIALIZED = true;
+                uart.config(115200);
+            }
+
+            uart.send_bytes_sync(s.as_bytes());
+            Ok(())
+        }
+    }
+}
+
+/// Panic handler. The panic goes to the UART console and is repeated on
+/// the USB shell interface, so it can be read without a serial adapter.
+#[cfg(not(test))]
+#[no_mangle]
+#[panic_implementation]
+pub unsafe extern "C" fn panic_fmt(pi: &PanicInfo) -> ! {
+    let writer = &mut WRITER;
+
+    debug::panic_begin(&cortexm3::support::nop);
+    debug::panic_banner(writer, pi);
+    hotel::panic::dump(writer);
+    debug::panic_process_info(&PROCESSES, writer);
+    hotel::memory::dump(writer, &PROCESSES);
+
+    if !SKIP_USB {
+        SKIP_USB = true;
+        let _ = UsbWriter.write_fmt(format_args!("\r\n\r\n{}\r\n", pi));
+        hotel::panic::dump(&mut UsbWriter);
+    }
+
+    // LED_3 blinks while the board is halted.
+    let mut led = led::LedHigh::new(&mut hotel::gpio::PORT0.pins[3]);
+    debug::panic_blink_forever(&mut [&mut led])
+}
+
+struct UsbWriter;
+
+impl Write for UsbWriter {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        unsafe { hotel::usb::USB_CONSOLE.write_sync(s.as_bytes()) };
+        Ok(())
+    }
+}
+
+
+#[macro_export]
+macro_rules! print {
+        ($($arg:tt)*) => (
+            {
+                use core::fmt::write;
+                let mut writer = $crate::io::Writer;
+                let _ = write(&mut writer, format_args!($($arg)*));
+            }
+        );
+}
+
+#[macro_export]
+macro_rules! println {
+        ($fmt:expr) => (print!(concat!($fmt, "\n")));
+            ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+}
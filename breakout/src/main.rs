@@ -0,0 +1,376 @@
+//! Board file for the hotel development breakout
+//!
+//! The breakout brings the chip's pads out to headers, with a UART console,
+//! four LEDs and two buttons. Unlike golf2 it is not a security key: the
+//! USB device offers only the shell interface, which carries a copy of
+//! any panic, and there is no personality, provisioning or U2F.
+//!
+//! | Pad    | Use                      |
+//! | ------ | :----------------------- |
+//! | DIOA0  | UART0 TX (console)       |
+//! | DIOA1  | UART0 RX (console)       |
+//! | DIOA7  | LED_0, GPIO0 pin 0       |
+//! | DIOA8  | LED_1, GPIO0 pin 1       |
+//! | DIOA9  | LED_2, GPIO0 pin 2       |
+//! | DIOA10 | LED_3, GPIO0 pin 3       |
+//! | DIOM0  | SW1, GPIO0 pin 4         |
+//! | DIOM1  | SW2, GPIO0 pin 5         |
+//! | DIOA12 | Bring-up strap, to GND   |
+
+#![no_std]
+#![no_main]
+#![feature(asm, const_fn, lang_items, compiler_builtins_lib)]
+#![feature(in_band_lifetimes)]
+#![feature(infer_outlives_requirements)]
+#![feature(panic_implementation)]
+#![feature(core_intrinsics)]
+
+extern crate capsules;
+extern crate hotel;
+#[macro_use(static_init, debug, create_capability)]
+extern crate kernel;
+extern crate cortexm3;
+
+#[macro_use]
+pub mod io;
+
+use capsules::console;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules::virtual_uart::{UartDevice, UartMux};
+
+use kernel::{Chip, Platform};
+use kernel::capabilities;
+use kernel::mpu::MPU;
+use kernel::hil;
+
+use hotel::usb::{Descriptor, Interface, StringDescriptor};
+
+// State for loading apps
+const NUM_PROCS: usize = 2;
+
+// how should the kernel respond when a process faults
+const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
+
+// The kernel loop must complete a pass this often or the watchdog resets
+// the chip
+const WATCHDOG_PERIOD_MS: u32 = 1000;
+
+#[link_section = ".app_memory"]
+static mut APP_MEMORY: [u8; 16384] = [0; 16384];
+
+static mut PROCESSES: [Option<&'static kernel::procs::ProcessType>; NUM_PROCS] = [None, None];
+
+/// Jumpers read at boot; set by pulling the pad low
+const STRAPS: [hotel::strap::Strap; 1] = [
+    // Bring-up: serve register peek and poke (with the `peek_poke` feature)
+    hotel::strap::Strap {
+        pad: hotel::pinmux::SelectablePin::Dioa12,
+        pull: hotel::pinmux::Pull::Up,
+    },
+];
+#[cfg(feature = "peek_poke")]
+const STRAP_BRING_UP: usize = 0;
+
+/// Dummy buffer that causes the linker to reserve enough space for the stack.
+#[no_mangle]
+#[link_section = ".stack_buffer"]
+pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
+
+pub struct Breakout {
+    console: &'static capsules::console::Console<'static, UartDevice<'static>>,
+    led: &'static capsules::led::LED<'static, hotel::gpio::GPIOPin>,
+    button: &'static capsules::button::Button<'static, hotel::gpio::GPIOPin>,
+    timer: &'static capsules::alarm::AlarmDriver<'static, VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>>,
+    ipc: kernel::ipc::IPC,
+}
+
+/// What this image is, for host tools (over USB)
+pub static BUILD_INFO: hotel::build_info::BuildInfo = hotel::build_info::BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_revision: env!("BUILD_GIT_REVISION"),
+    timestamp: env!("BUILD_TIMESTAMP"),
+};
+
+/// The breakout's USB interfaces
+static USB_INTERFACES: [Interface; 1] = [hotel::usb::SHELL_INTERFACE];
+
+/// Filled in from the device ID at boot
+static mut SERIAL_NUMBER: [u16; hotel::fuse::SERIAL_NUMBER_LEN] = [0; hotel::fuse::SERIAL_NUMBER_LEN];
+
+static mut STRINGS: [StringDescriptor; 8] = [
+    StringDescriptor {
+        b_length: 4,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0409], // English
+    },
+    StringDescriptor {
+        b_length: 24,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0047, 0x006f, 0x006f, 0x0067, 0x006c, 0x0065, 0x0020, 0x0049, 0x006e, 0x0063, 0x002e], // Google Inc.
+    },
+    StringDescriptor {
+        b_length: 18,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0062, 0x0072, 0x0065, 0x0061, 0x006b, 0x006f, 0x0075, 0x0074], // breakout
+    },
+    StringDescriptor {
+        b_length: 18,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0062, 0x0072, 0x0065, 0x0061, 0x006b, 0x006f, 0x0075, 0x0074], // breakout
+    },
+    StringDescriptor {
+        b_length: 12,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0053, 0x0068, 0x0065, 0x006C, 0x006C], // Shell
+    },
+    StringDescriptor {
+        b_length: 10,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[0x0042, 0x004C, 0x0041, 0x0048],  // BLAH
+    },
+    // No U2F interface
+    StringDescriptor {
+        b_length: 2,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[],
+    },
+    // Serial number, replaced at boot
+    StringDescriptor {
+        b_length: 2,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[],
+    },
+];
+
+#[no_mangle]
+pub unsafe fn reset_handler() {
+    hotel::memory::paint_stack();
+    hotel::init();
+
+    {
+        use hotel::pmu::*;
+        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeUs0Timer)).acquire();
+        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeLs0)).acquire();
+    }
+
+    hotel::timestamp::TIMESTAMP.start();
+    let start = hotel::timestamp::TIMESTAMP.now();
+
+    {
+        use hotel::pmu::*;
+        Clock::new(PeripheralClock::Bank0(PeripheralClock0::Gpio0)).acquire();
+        use hotel::pinmux::{self, Function, Input, PadConfig, Pull, SelectablePin};
+        let pulled_up = PadConfig {
+            pull: Pull::Up,
+            ..PadConfig::DEFAULT
+        };
+        hotel::strap::sample(&STRAPS);
+        pinmux::reset();
+
+        // LED_0 to LED_3
+        pinmux::connect_gpio(0, 0, SelectablePin::Dioa7);
+        pinmux::connect_gpio(0, 1, SelectablePin::Dioa8);
+        pinmux::connect_gpio(0, 2, SelectablePin::Dioa9);
+        pinmux::connect_gpio(0, 3, SelectablePin::Dioa10);
+
+        // SW1 and SW2
+        pinmux::connect_gpio(0, 4, SelectablePin::Diom0);
+        pinmux::configure_pad(SelectablePin::Diom0, pulled_up);
+        pinmux::connect_gpio(0, 5, SelectablePin::Diom1);
+        pinmux::configure_pad(SelectablePin::Diom1, pulled_up);
+
+        pinmux::connect_output(SelectablePin::Dioa0, Function::Uart0Tx);
+        hotel::io::set_console_pad(SelectablePin::Dioa0);
+        pinmux::configure_pad(SelectablePin::Dioa1, pulled_up);
+        pinmux::connect_input(Input::Uart0Rx, SelectablePin::Dioa1);
+    }
+
+    // Create capabilities that the board needs to call certain protected kernel
+    // functions.
+    let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
+    let main_cap = create_capability!(capabilities::MainLoopCapability);
+    let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+    let kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+
+    let uart_mux = static_init!(
+        UartMux<'static>,
+        UartMux::new(
+            &hotel::uart::UART0,
+            &mut capsules::virtual_uart::RX_BUF,
+            115200
+        )
+    );
+    hil::uart::UART::set_client(&hotel::uart::UART0, uart_mux);
+
+    // Create virtual device for console.
+    let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+    console_uart.setup();
+
+    let console = static_init!(
+        console::Console<UartDevice>,
+        console::Console::new(
+            console_uart,
+            115200,
+            &mut console::WRITE_BUF,
+            &mut console::READ_BUF,
+            kernel.create_grant(&grant_cap)
+        )
+    );
+    hil::uart::UART::set_client(console_uart, console);
+    console.initialize();
+
+    // Create virtual device for kernel debug.
+    let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
+    debugger_uart.setup();
+    let debugger = static_init!(
+        kernel::debug::DebugWriter,
+        kernel::debug::DebugWriter::new(
+            debugger_uart,
+            &mut kernel::debug::OUTPUT_BUF,
+            &mut kernel::debug::INTERNAL_BUF,
+        )
+    );
+    hil::uart::UART::set_client(debugger_uart, debugger);
+
+    let debug_wrapper = static_init!(
+        kernel::debug::DebugWriterWrapper,
+        kernel::debug::DebugWriterWrapper::new(debugger)
+    );
+    kernel::debug::set_debug_writer_wrapper(debug_wrapper);
+
+    // The LEDs are lit when driven high.
+    let led_pins = static_init!(
+        [(&'static hotel::gpio::GPIOPin, capsules::led::ActivationMode); 4],
+        [(&hotel::gpio::PORT0.pins[0], capsules::led::ActivationMode::ActiveHigh),
+         (&hotel::gpio::PORT0.pins[1], capsules::led::ActivationMode::ActiveHigh),
+         (&hotel::gpio::PORT0.pins[2], capsules::led::ActivationMode::ActiveHigh),
+         (&hotel::gpio::PORT0.pins[3], capsules::led::ActivationMode::ActiveHigh)]);
+    let led = static_init!(
+        capsules::led::LED<'static, hotel::gpio::GPIOPin>,
+        capsules::led::LED::new(led_pins));
+
+    // The buttons are pulled up and pressing one connects it to ground.
+    let button_pins = static_init!(
+        [(&'static hotel::gpio::GPIOPin, capsules::button::GpioMode); 2],
+        [(&hotel::gpio::PORT0.pins[4], capsules::button::GpioMode::LowWhenPressed),
+         (&hotel::gpio::PORT0.pins[5], capsules::button::GpioMode::LowWhenPressed)]);
+    let button = static_init!(
+        capsules::button::Button<'static, hotel::gpio::GPIOPin>,
+        capsules::button::Button::new(button_pins, kernel.create_grant(&grant_cap)));
+    for &(pin, _) in button_pins.iter() {
+        pin.set_client(button);
+    }
+
+    hotel::timeus::TIMEUS1.start();
+    let mux_alarm = static_init!(
+        MuxAlarm<'static, hotel::timeus::Timeus<'static>>,
+        MuxAlarm::new(&hotel::timeus::TIMEUS1));
+    hotel::timeus::TIMEUS1.set_client(mux_alarm);
+
+    let virtual_alarm_user = static_init!(
+        VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let timer = static_init!(
+        capsules::alarm::AlarmDriver<'static, VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>>,
+        capsules::alarm::AlarmDriver::new(
+            virtual_alarm_user, kernel.create_grant(&grant_cap)));
+    virtual_alarm_user.set_client(timer);
+
+    // Stop erasing and programming flash if the supply starts to fail.
+    hotel::volt::VOLT0.enable(2700);
+    hotel::volt::VOLT0.add_client(&hotel::flash::FLASH0);
+
+    BUILD_INFO.init();
+    hotel::memory::init();
+    hotel::pmu::init_clock_tree();
+    hotel::irq_count::init();
+    #[cfg(feature = "peek_poke")]
+    {
+        // Only with physical access to the board
+        hotel::peek_poke::init();
+        hotel::peek_poke::set_enabled(hotel::strap::straps().is_set(STRAP_BRING_UP));
+    }
+
+    let breakout = Breakout {
+        console: console,
+        led: led,
+        button: button,
+        timer: timer,
+        ipc: kernel::ipc::IPC::new(kernel, &grant_cap),
+    };
+
+    debug!("Tock 1.0 booting on the breakout. Initialization took {} us.",
+           hotel::timestamp::TIMESTAMP.now() - start);
+    debug!("Chip revision: {:?}", hotel::errata::revision());
+    debug!("Last reset: {:?}", hotel::pmu::reset_cause());
+    hotel::pmu::clear_reset_cause();
+    hotel::pinmux::clear_wakeup_status();
+
+    hotel::calendar::CALENDAR.init();
+
+    // Pressing SW1 wakes the chip from deep sleep.
+    hotel::pinmux::enable_wakeup(hotel::pinmux::SelectablePin::Diom0,
+                                 hotel::pinmux::WakeMode::FallingEdge);
+
+    let chip = static_init!(hotel::chip::Hotel, hotel::chip::Hotel::new());
+
+    chip.mpu().enable_mpu();
+
+    hotel::fuse::serial_number(&mut SERIAL_NUMBER);
+    STRINGS[hotel::usb::STRING_SERIAL as usize] = StringDescriptor::new(&SERIAL_NUMBER);
+
+    hotel::usb::USB_CONSOLE.init();
+    hotel::usb::select_phy(hotel::usb::PHY::A);
+    hotel::usb::USB0.init(&mut hotel::usb::OUT_DESCRIPTORS,
+                          &mut hotel::usb::OUT_BUFFERS,
+                          &mut hotel::usb::IN_DESCRIPTORS,
+                          &mut hotel::usb::IN_BUFFERS,
+                          &mut hotel::usb::CONFIGURATION_BUFFER,
+                          None,
+                          Some(0x18d1),
+                          Some(0x5027),
+                          &mut STRINGS,
+                          &USB_INTERFACES);
+    // There is no crystal, so keep the RC oscillator locked to the host.
+    hotel::xo::XO0.start_calibration();
+
+    // Everything the kernel needs is initialized and holds its clocks.
+    hotel::pmu::gate_unused_clocks();
+
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+    }
+    kernel::procs::load_processes(
+        kernel,
+        chip,
+        &_sapps as *const u8,
+        &mut APP_MEMORY,
+        &mut PROCESSES,
+        FAULT_RESPONSE,
+        &process_mgmt_cap,
+    );
+
+    let stack = hotel::memory::stack_usage();
+    debug!("Kernel stack: {} of {} bytes used during boot.", stack.used, stack.size);
+    hotel::watchdog::WATCHDOG0.start(WATCHDOG_PERIOD_MS);
+    debug!("Start main loop.");
+
+    kernel.kernel_loop(&breakout, chip, Some(&breakout.ipc), &main_cap);
+}
+
+impl Platform for Breakout {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+        where F: FnOnce(Option<&kernel::Driver>) -> R
+    {
+        match driver_num {
+            capsules::console::DRIVER_NUM => f(Some(self.console)),
+            capsules::led::DRIVER_NUM     => f(Some(self.led)),
+            capsules::button::DRIVER_NUM  => f(Some(self.button)),
+            capsules::alarm::DRIVER_NUM   => f(Some(self.timer)),
+            kernel::ipc::DRIVER_NUM       => f(Some(&self.ipc)),
+            _ =>  f(None),
+        }
+    }
+}
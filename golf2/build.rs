@@ -1,4 +1,40 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
     println!("cargo:rerun-if-changed=layout.ld");
     println!("cargo:rerun-if-changed=../kernel_layout.ld");
+
+    // Describe the build for `BUILD_INFO`.
+    let revision = Command::new("git")
+        .args(&["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|revision| revision.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=BUILD_GIT_REVISION={}", revision);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+
+    // Build in dcrypto's P-256 program (see `hotel::crypto::p256`) if one
+    // is given; without it the ECDSA engine reports ENOSUPPORT.
+    println!("cargo:rerun-if-env-changed=DCRYPTO_P256_PROGRAM");
+    let program = match env::var("DCRYPTO_P256_PROGRAM") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::read(&path).expect("can't read DCRYPTO_P256_PROGRAM")
+        }
+        Err(_) => Vec::new(),
+    };
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("dcrypto_p256.bin"), program).unwrap();
 }
@@ -0,0 +1,130 @@
+//! The flash region processes are loaded from
+//!
+//! Apps live between `_sapps` and `_eapps`, which the linker script places
+//! after the kernel image and before the personality region holding the
+//! device's identity and the U2F counter. `loadable_apps` walks the TBF
+//! headers in the region and counts the apps that are entirely inside it
+//! and clear of the protected regions, stopping at the first one that is
+//! not, so a bad header (e.g. from an update cut short) can't make the
+//! kernel treat kernel code or identity data as an app. Anything that
+//! writes flash on an app updater's behalf should check
+//! `APP_FLASH.contains` first.
+
+use core::ptr;
+
+/// Addresses `start..end` of a region of flash
+#[derive(Clone, Copy, Debug)]
+pub struct FlashRegion {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FlashRegion {
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        start < self.end && self.start < end
+    }
+
+    fn includes(&self, start: usize, end: usize) -> bool {
+        self.start <= start && start <= end && end <= self.end
+    }
+}
+
+pub struct AppFlash {
+    pub apps: FlashRegion,
+    /// Regions no app may overlap, and their names for reports
+    pub protected: [(&'static str, FlashRegion); 3],
+}
+
+impl AppFlash {
+    /// Whether `start..start + len` is inside the app region and clear of
+    /// every protected region.
+    pub fn contains(&self, start: usize, len: usize) -> bool {
+        let end = match start.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        self.apps.includes(start, end) &&
+            self.protected.iter().all(|&(_, region)| !region.overlaps(start, end))
+    }
+}
+
+extern "C" {
+    static _stext: u8;
+    static _etext: u8;
+    static _srelocate: u8;
+    static _erelocate: u8;
+    static _sapps: u8;
+    static _eapps: u8;
+    static _spersonality: u8;
+    static _epersonality: u8;
+    static _scounter: u8;
+    static _ecounter: u8;
+}
+
+fn address(symbol: &'static u8) -> usize {
+    symbol as *const u8 as usize
+}
+
+pub static mut APP_FLASH: AppFlash = AppFlash {
+    apps: FlashRegion { start: 0, end: 0 },
+    protected: [("kernel", FlashRegion { start: 0, end: 0 }),
+                ("personality", FlashRegion { start: 0, end: 0 }),
+                ("counter", FlashRegion { start: 0, end: 0 })],
+};
+
+/// Fills in `APP_FLASH` from the linker script's symbols.
+pub unsafe fn init() {
+    // The kernel image is its text followed by the initial values of its
+    // data.
+    let kernel_end = address(&_etext) + (address(&_erelocate) - address(&_srelocate));
+    APP_FLASH = AppFlash {
+        apps: FlashRegion { start: address(&_sapps), end: address(&_eapps) },
+        protected: [("kernel", FlashRegion { start: address(&_stext), end: kernel_end }),
+                    ("personality", FlashRegion { start: address(&_spersonality), end: address(&_epersonality) }),
+                    ("counter", FlashRegion { start: address(&_scounter), end: address(&_ecounter) })],
+    };
+}
+
+/// TBF header version that apps are built with
+const TBF_VERSION: u16 = 2;
+
+/// Length of the fixed part of a TBF header
+const TBF_BASE_HEADER_LEN: usize = 16;
+
+/// Number of apps at the start of `flash`'s app region, at most `max`,
+/// that can be loaded without reaching outside it.
+pub fn loadable_apps(flash: &AppFlash, max: usize) -> usize {
+    let region = flash.apps;
+    for &(name, protected) in flash.protected.iter() {
+        if protected.overlaps(region.start, region.end) {
+            debug!("App region {:#x}-{:#x} overlaps the {} region, not loading apps.",
+                   region.start, region.end, name);
+            return 0;
+        }
+    }
+
+    let mut count = 0;
+    let mut start = region.start;
+    while count < max && start % 4 == 0 && start + TBF_BASE_HEADER_LEN <= region.end {
+        // version: u16, header_size: u16, total_size: u32
+        let (first, total_size) = unsafe {
+            (ptr::read_volatile(start as *const u32) as usize,
+             ptr::read_volatile((start + 4) as *const u32) as usize)
+        };
+        let version = (first & 0xffff) as u16;
+        let header_size = first >> 16;
+        if version != TBF_VERSION {
+            // Erased flash or padding: the end of the apps.
+            break;
+        }
+        if header_size < TBF_BASE_HEADER_LEN || total_size < header_size ||
+            !flash.contains(start, total_size) {
+            debug!("App at {:#x} with size {:#x} leaves the app region, not loading it or later apps.",
+                   start, total_size);
+            break;
+        }
+        count += 1;
+        start += total_size;
+    }
+    count
+}
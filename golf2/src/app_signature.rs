@@ -0,0 +1,182 @@
+//! Checking the apps' signature before loading processes
+//!
+//! On a device whose personality holds a firmware key, the apps must be
+//! signed with it. The signature is in a footer at the end of the app
+//! region:
+//!
+//! | Offset | Size | Contents                                             |
+//! | ------ | ---- | :--------------------------------------------------- |
+//! | 0      | 4    | `FOOTER_MAGIC`, little-endian                        |
+//! | 4      | 4    | Length L of the signed apps, from the region's start |
+//! | 8      | 64   | P-256 signature (r, s) of the SHA-256 of the L bytes |
+//!
+//! `check` hashes the signed apps with the SHA engine and hands the
+//! signature to the ECDSA engine; the client then loads processes from the
+//! signed part of the region only, or from none of it if the signature is
+//! bad, in which case `signal_failure` lights the LED and renames the USB
+//! platform string so the host can tell why nothing runs. Devices without
+//! a firmware key load apps unchecked, and ones whose personality is
+//! invalid load none.
+//!
+//! ```ignore
+//! let app_signature = AppSignature::new(&hotel::crypto::p256::P256, sha, personality, buffer);
+//! match app_signature.check(app_flash::APP_FLASH.apps) {
+//!     Ok(app_signature::Check::Started) => {} // Load in signature_checked
+//!     Ok(app_signature::Check::Unchecked) => load_processes(...),
+//!     Err(_) => app_signature::signal_failure(led, ActivationMode::ActiveLow),
+//! }
+//! ```
+
+use app_flash::FlashRegion;
+use capsules::led::ActivationMode;
+use core::cell::Cell;
+use hotel::crypto::sha::ShaEngine;
+use hotel::hil::digest::{DigestEngine, DigestMode};
+use hotel::hil::ecdsa::{EcdsaClient, EcdsaP256, POINT_LEN, SCALAR_LEN};
+use hotel::personality::{Personality, State};
+use hotel::usb::StringDescriptor;
+use kernel::common::cells::TakeCell;
+use kernel::hil::gpio::Pin;
+use kernel::ReturnCode;
+
+/// "ASIG"
+pub const FOOTER_MAGIC: u32 = 0x47495341;
+pub const FOOTER_LEN: usize = 8 + POINT_LEN;
+
+/// Bad app signature
+static BAD_SIGNATURE: [u16; 17] = [0x0042, 0x0061, 0x0064, 0x0020, 0x0061, 0x0070, 0x0070, 0x0020, 0x0073,
+                                   0x0069, 0x0067, 0x006e, 0x0061, 0x0074, 0x0075, 0x0072, 0x0065];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// The client is told the result
+    Started,
+    /// The device doesn't check apps
+    Unchecked,
+}
+
+pub trait SignatureClient {
+    /// The signature has been checked: `signed` is the part of the app
+    /// region to load processes from, or None if the signature is bad.
+    fn signature_checked(&self, signed: Option<FlashRegion>);
+}
+
+pub struct AppSignature<'a, E: EcdsaP256 + 'a> {
+    ecdsa: &'a E,
+    sha: &'a ShaEngine,
+    personality: &'a Personality,
+    signature: TakeCell<'static, [u8]>,
+    // The apps the signature being checked covers
+    signed: Cell<Option<FlashRegion>>,
+    client: Cell<Option<&'static SignatureClient>>,
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Lights `led` and replaces the platform string with "Bad app signature".
+pub fn signal_failure(led: &Pin, mode: ActivationMode) {
+    led.make_output();
+    match mode {
+        ActivationMode::ActiveHigh => led.set(),
+        ActivationMode::ActiveLow => led.clear(),
+    }
+    unsafe {
+        hotel::usb::USB0.set_string(hotel::usb::STRING_PLATFORM, StringDescriptor::new(&BAD_SIGNATURE));
+    }
+}
+
+impl<'a, E: EcdsaP256 + 'a> AppSignature<'a, E> {
+    /// `personality` must have been initialized, and `signature` hold at
+    /// least `POINT_LEN` bytes.
+    pub fn new(ecdsa: &'a E, sha: &'a ShaEngine, personality: &'a Personality, signature: &'static mut [u8])
+               -> AppSignature<'a, E> {
+        AppSignature {
+            ecdsa: ecdsa,
+            sha: sha,
+            personality: personality,
+            signature: TakeCell::new(signature),
+            signed: Cell::new(None),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static SignatureClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Starts checking the signature of the apps in `region`. Fails with
+    /// FAIL if the apps can't be loaded without looking further: the
+    /// personality is invalid, or the footer is missing or malformed.
+    pub fn check(&self, region: FlashRegion) -> Result<Check, ReturnCode> {
+        if hotel::fault::check(|| self.personality.state() == State::Invalid) {
+            debug!("Personality invalid, not loading apps.");
+            return Err(ReturnCode::FAIL);
+        }
+        if !hotel::fault::check(|| self.personality.firmware_key().is_some()) {
+            return Ok(Check::Unchecked);
+        }
+        let key = match self.personality.firmware_key() {
+            Some(key) => key,
+            None => return Err(ReturnCode::FAIL),
+        };
+        if region.end < region.start + FOOTER_LEN {
+            return Err(ReturnCode::FAIL);
+        }
+        let footer_start = region.end - FOOTER_LEN;
+        let footer = unsafe { ::core::slice::from_raw_parts(footer_start as *const u8, FOOTER_LEN) };
+        let signed_len = read_u32(&footer[4..8]) as usize;
+        if read_u32(&footer[0..4]) != FOOTER_MAGIC || signed_len > footer_start - region.start {
+            debug!("No app signature footer at {:#x}, not loading apps.", footer_start);
+            return Err(ReturnCode::FAIL);
+        }
+
+        let apps = unsafe { ::core::slice::from_raw_parts(region.start as *const u8, signed_len) };
+        let mut digest = [0; SCALAR_LEN];
+        self.sha.initialize(DigestMode::Sha256)?;
+        let digested = self.sha.update(apps).is_ok() &&
+            self.sha.finalize(&mut digest).is_ok();
+        if !digested {
+            self.sha.cancel();
+            return Err(ReturnCode::FAIL);
+        }
+        let signature = match self.signature.take() {
+            Some(signature) => signature,
+            None => return Err(ReturnCode::EBUSY),
+        };
+        signature[..POINT_LEN].copy_from_slice(&footer[8..]);
+        let mut public_key = [0; POINT_LEN];
+        public_key.copy_from_slice(key);
+        match self.ecdsa.verify(&public_key, &digest, signature) {
+            Ok(()) => {
+                self.signed.set(Some(FlashRegion { start: region.start, end: region.start + signed_len }));
+                Ok(Check::Started)
+            }
+            Err((error, signature)) => {
+                self.signature.replace(signature);
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<'a, E: EcdsaP256 + 'a> EcdsaClient for AppSignature<'a, E> {
+    fn public_key_done(&self, _result: ReturnCode, point: &'static mut [u8]) {
+        self.signature.replace(point);
+    }
+
+    fn sign_done(&self, _result: ReturnCode, signature: &'static mut [u8]) {
+        self.signature.replace(signature);
+    }
+
+    fn verify_done(&self, result: ReturnCode, signature: &'static mut [u8]) {
+        self.signature.replace(signature);
+        let signed = self.signed.take();
+        let good = hotel::fault::check(|| result == ReturnCode::SUCCESS);
+        if !good {
+            debug!("App signature bad, not loading apps.");
+        }
+        self.client.get().map(|client| client.signature_checked(if good { signed } else { None }));
+    }
+}
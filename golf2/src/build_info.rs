@@ -0,0 +1,81 @@
+//! Syscall driver for the firmware's build information
+//!
+//! Lets a process check which kernel it is running on, in the format of
+//! `hotel::build_info::BuildInfo::serialize`.
+//!
+//! ### Allow
+//!   - 0: buffer the build information is copied into
+//!
+//! ### Command
+//!   - 0: check the driver is present
+//!   - 1: copy the build information into buffer 0, returning its length
+//!   - 2: return the build time, in seconds since the Unix epoch
+
+use hotel::build_info::BuildInfo;
+use kernel::{AppId, AppSlice, Driver, Grant, ReturnCode, Shared};
+
+pub const DRIVER_NUM: usize = 0x40007;
+
+pub struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App { buffer: None }
+    }
+}
+
+pub struct BuildInfoDriver {
+    info: &'static BuildInfo,
+    apps: Grant<App>,
+}
+
+impl BuildInfoDriver {
+    pub fn new(info: &'static BuildInfo, container: Grant<App>) -> BuildInfoDriver {
+        BuildInfoDriver {
+            info: info,
+            apps: container,
+        }
+    }
+
+    fn copy_info(&self, app_id: AppId) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                app.buffer.as_mut().map_or(ReturnCode::ENOMEM, |slice| {
+                    let len = self.info.serialize(slice.as_mut());
+                    ReturnCode::SuccessWithValue { value: len }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl Driver for BuildInfoDriver {
+    fn command(&self, command_num: usize, _: usize, _: usize, caller_id: AppId) -> ReturnCode {
+        match command_num {
+            0 /* Check if present */ => ReturnCode::SUCCESS,
+            1 /* Copy build information */ => self.copy_info(caller_id),
+            2 /* Build time */ => ReturnCode::SuccessWithValue { value: self.info.timestamp() as usize },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn allow(&self,
+             app_id: AppId,
+             minor_num: usize,
+             slice: Option<AppSlice<Shared, u8>>
+    ) -> ReturnCode {
+        match minor_num {
+            0 => {
+                self.apps
+                    .enter(app_id, |app, _| {
+                        app.buffer = slice;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -98,8 +98,10 @@ impl<'a> Driver for DcryptoDriver<'a> {
                     ReturnCode::EBUSY
                 } else {
                     self.app.map_or(ReturnCode::EBUSY, |app| {
-                        self.busy.set(true);
-                        self.run_program(app)
+                        // dcrypto refuses while the P-256 engine has it.
+                        let result = self.run_program(app);
+                        self.busy.set(result == ReturnCode::SUCCESS);
+                        result
                     })
                 }
             }
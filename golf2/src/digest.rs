@@ -44,10 +44,18 @@ impl<'a, E: DigestEngine> Driver for DigestDriver<'a, E> {
             0 => {
                 self.apps
                     .enter(caller_id, |_app_data, _| {
-                        if self.current_user.get().is_some() {
-                            return ReturnCode::EBUSY;
+                        match self.current_user.get() {
+                            Some(cur) if cur.idx() != caller_id.idx() => {
+                                return ReturnCode::EBUSY;
+                            }
+                            Some(_) => {
+                                // The caller is starting over, so drop the
+                                // digest it left unfinished.
+                                self.engine.cancel();
+                                self.current_user.set(None);
+                            }
+                            None => {}
                         }
-                        self.current_user.set(Some(caller_id));
                         
                         let digest_mode = match r2 {
                             0 => DigestMode::Sha1,
@@ -55,11 +63,18 @@ impl<'a, E: DigestEngine> Driver for DigestDriver<'a, E> {
                             _ => return ReturnCode::EINVAL,
                         };
 
+                        // The kernel's own users (e.g. the reboot request's
+                        // HMAC) take the engine too, so it may be busy even
+                        // though no app holds it.
                         match self.engine.initialize(digest_mode) {
-                            Ok(_t) => ReturnCode::SUCCESS,
+                            Ok(_t) => {
+                                self.current_user.set(Some(caller_id));
+                                ReturnCode::SUCCESS
+                            }
                             Err(DigestError::EngineNotSupported) => ReturnCode::ENOSUPPORT,
                             Err(DigestError::NotConfigured) => ReturnCode::FAIL,
-                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE
+                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE,
+                            Err(DigestError::Busy) => ReturnCode::EBUSY,
                         }
                     }).unwrap_or(ReturnCode::ENOMEM)
             },
@@ -90,7 +105,8 @@ impl<'a, E: DigestEngine> Driver for DigestDriver<'a, E> {
                             Ok(_t) => ReturnCode::SUCCESS,
                             Err(DigestError::EngineNotSupported) => ReturnCode::ENOSUPPORT,
                             Err(DigestError::NotConfigured) => ReturnCode::ERESERVE,
-                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE
+                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE,
+                            Err(DigestError::Busy) => ReturnCode::EBUSY,
                         }
                     })
                     .unwrap_or(ReturnCode::ENOMEM)
@@ -114,10 +130,14 @@ impl<'a, E: DigestEngine> Driver for DigestDriver<'a, E> {
                         };
                         
                         match self.engine.finalize(output_buffer.as_mut()) {
-                            Ok(_t) => ReturnCode::SUCCESS,
+                            Ok(_t) => {
+                                self.current_user.set(None);
+                                ReturnCode::SUCCESS
+                            }
                             Err(DigestError::EngineNotSupported) => ReturnCode::ENOSUPPORT,
                             Err(DigestError::NotConfigured) => ReturnCode::FAIL,
-                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE
+                            Err(DigestError::BufferTooSmall(_s)) => ReturnCode::ESIZE,
+                            Err(DigestError::Busy) => ReturnCode::EBUSY,
                         }
                     })
                     .unwrap_or(ReturnCode::ENOMEM)
@@ -0,0 +1,235 @@
+//! Syscall driver for raw HID reports
+//!
+//! Gives a process the reports of a `hil::hid` interface, so it can speak
+//! a custom protocol with a host tool. The process that subscribes to
+//! output reports owns the interface: it alone can send reports and set
+//! the feature report.
+//!
+//! ### Allow
+//!   - 0: buffer output reports from the host are copied into
+//!   - 1: buffer input reports are sent from
+//!   - 2: buffer for the feature report, in both directions
+//!
+//! ### Subscribe
+//!   - 0: an output report arrived; the argument is its length. Only one
+//!     process can be subscribed at a time.
+//!   - 1: the input report has been collected by the host
+//!   - 2: the host wrote the feature report
+//!
+//! ### Command
+//!   - 0: check the driver is present
+//!   - 1: send the first `arg1` bytes of buffer 1 as an input report
+//!   - 2: set the feature report the host reads to the first `arg1` bytes
+//!     of buffer 2
+//!   - 3: copy the feature report the host last wrote into buffer 2,
+//!     returning its length
+
+use core::cell::Cell;
+use core::cmp;
+use hotel::hil::hid::{HidClient, HidReports, REPORT_SIZE};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+pub const DRIVER_NUM: usize = 0x40006;
+
+pub struct App {
+    rx_buffer: Option<AppSlice<Shared, u8>>,
+    tx_buffer: Option<AppSlice<Shared, u8>>,
+    feature_buffer: Option<AppSlice<Shared, u8>>,
+    received_callback: Option<Callback>,
+    sent_callback: Option<Callback>,
+    feature_callback: Option<Callback>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            rx_buffer: None,
+            tx_buffer: None,
+            feature_buffer: None,
+            received_callback: None,
+            sent_callback: None,
+            feature_callback: None,
+        }
+    }
+}
+
+pub struct HidDriver<'a, H: HidReports + 'a> {
+    hid: &'a H,
+    apps: Grant<App>,
+    // The process that owns the interface
+    owner: Cell<Option<AppId>>,
+}
+
+impl<'a, H: HidReports + 'a> HidDriver<'a, H> {
+    pub fn new(hid: &'a H, container: Grant<App>) -> HidDriver<'a, H> {
+        HidDriver {
+            hid: hid,
+            apps: container,
+            owner: Cell::new(None),
+        }
+    }
+
+    fn is_owner(&self, app_id: AppId) -> bool {
+        self.owner.get().map_or(false, |owner| owner.idx() == app_id.idx())
+    }
+
+    /// Whether `app_id` may take the interface: nobody owns it, or the
+    /// owner has unsubscribed or died.
+    fn can_own(&self, app_id: AppId) -> bool {
+        match self.owner.get() {
+            None => true,
+            Some(owner) if owner.idx() == app_id.idx() => true,
+            Some(owner) => {
+                self.apps
+                    .enter(owner, |app, _| app.received_callback.is_none())
+                    .unwrap_or(true)
+            }
+        }
+    }
+
+    fn subscribe_reports(&self, callback: Option<Callback>, app_id: AppId) -> ReturnCode {
+        if !self.can_own(app_id) {
+            return ReturnCode::EBUSY;
+        }
+        let owning = callback.is_some();
+        self.apps
+            .enter(app_id, |app, _| {
+                app.received_callback = callback;
+                self.owner.set(if owning { Some(app_id) } else { None });
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn send_report(&self, app_id: AppId, len: usize) -> ReturnCode {
+        if !self.is_owner(app_id) {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(app_id, |app, _| {
+                app.tx_buffer.as_ref().map_or(ReturnCode::ENOMEM, |slice| {
+                    if len > slice.len() || len > REPORT_SIZE {
+                        return ReturnCode::ESIZE;
+                    }
+                    self.hid.send_report(&slice.as_ref()[..len])
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn set_feature_report(&self, app_id: AppId, len: usize) -> ReturnCode {
+        if !self.is_owner(app_id) {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(app_id, |app, _| {
+                app.feature_buffer.as_ref().map_or(ReturnCode::ENOMEM, |slice| {
+                    if len > slice.len() || len > REPORT_SIZE {
+                        return ReturnCode::ESIZE;
+                    }
+                    self.hid.set_feature_report(&slice.as_ref()[..len]);
+                    ReturnCode::SUCCESS
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn get_feature_report(&self, app_id: AppId) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                app.feature_buffer.as_mut().map_or(ReturnCode::ENOMEM, |slice| {
+                    let len = self.hid.feature_report(slice.as_mut());
+                    ReturnCode::SuccessWithValue { value: len }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, H: HidReports + 'a> HidClient for HidDriver<'a, H> {
+    fn report_received(&self, report: &[u8]) {
+        self.owner.get().map(|owner| {
+            let _ = self.apps.enter(owner, |app, _| {
+                let copied = app.rx_buffer.as_mut().map_or(0, |slice| {
+                    let count = cmp::min(report.len(), slice.len());
+                    slice.as_mut()[..count].copy_from_slice(&report[..count]);
+                    count
+                });
+                app.received_callback.map(|mut cb| cb.schedule(copied, 0, 0));
+            });
+        });
+    }
+
+    fn report_sent(&self) {
+        self.owner.get().map(|owner| {
+            let _ = self.apps.enter(owner, |app, _| {
+                app.sent_callback.map(|mut cb| cb.schedule(0, 0, 0));
+            });
+        });
+    }
+
+    fn feature_report_written(&self) {
+        self.owner.get().map(|owner| {
+            let _ = self.apps.enter(owner, |app, _| {
+                app.feature_callback.map(|mut cb| cb.schedule(0, 0, 0));
+            });
+        });
+    }
+}
+
+impl<'a, H: HidReports + 'a> Driver for HidDriver<'a, H> {
+    fn subscribe(&self,
+                 subscribe_num: usize,
+                 callback: Option<Callback>,
+                 app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.subscribe_reports(callback, app_id),
+            1 | 2 => {
+                self.apps
+                    .enter(app_id, |app, _| {
+                        if subscribe_num == 1 {
+                            app.sent_callback = callback;
+                        } else {
+                            app.feature_callback = callback;
+                        }
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, _: usize, caller_id: AppId) -> ReturnCode {
+        match command_num {
+            0 /* Check if present */ => ReturnCode::SUCCESS,
+            1 /* Send report */ => self.send_report(caller_id, arg1),
+            2 /* Set feature report */ => self.set_feature_report(caller_id, arg1),
+            3 /* Get feature report */ => self.get_feature_report(caller_id),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn allow(&self,
+             app_id: AppId,
+             minor_num: usize,
+             slice: Option<AppSlice<Shared, u8>>
+    ) -> ReturnCode {
+        match minor_num {
+            0...2 => {
+                self.apps
+                    .enter(app_id, |app, _| {
+                        match minor_num {
+                            0 => app.rx_buffer = slice,
+                            1 => app.tx_buffer = slice,
+                            _ => app.feature_buffer = slice,
+                        }
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
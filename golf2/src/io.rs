@@ -2,18 +2,29 @@ use core::fmt::Write;
 use core::panic::PanicInfo;
 use cortexm3;
 use kernel::debug;
-use kernel::hil::led;
 use hotel;
 
+use morse;
 use PROCESSES;
 
 pub struct Writer;
 
 static mut WRITER: Writer = Writer {};
 
+// Set by the panic handler when the fault is in the UART or USB driver, so
+// printing doesn't re-enter the driver that failed.
+static mut SKIP_UART: bool = false;
+static mut SKIP_USB: bool = false;
+
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
         unsafe {
+            // With `swo_debug`, output goes to SWO (or semihosting), which
+            // works before the consoles do.
+            if cfg!(feature = "swo_debug") {
+                return hotel::itm::ITM_WRITER.write_str(s);
+            }
+
             let uart = &hotel::uart::UART0;
 
             static mut INITIALIZED: bool = false;
@@ -27,7 +38,15 @@ impl Write for Writer {
                 uart.config(115200);
             }
 
-            uart.send_bytes_sync(s.as_bytes());
+            if !SKIP_UART {
+                uart.send_bytes_sync(s.as_bytes());
+            }
+            #[cfg(feature = "usb_console")]
+            {
+                if !SKIP_USB {
+                    hotel::usb::USB_CONSOLE.write_sync(s.as_bytes());
+                }
+            }
             Ok(())
         }
     }
@@ -38,9 +57,29 @@ impl Write for Writer {
 #[no_mangle]
 #[panic_implementation]
 pub unsafe extern "C" fn panic_fmt(pi: &PanicInfo) -> ! {
-    let led = &mut led::LedLow::new(&mut hotel::gpio::PORT0.pins[0]);
     let writer = &mut WRITER;
-    debug::panic(&mut [led], writer, pi, &cortexm3::support::nop, &PROCESSES)
+    let letter = morse::fault_letter(pi);
+    SKIP_UART = letter == 'S';
+    SKIP_USB = letter == 'U';
+
+    // With `panic_reset` the board restarts (and re-enumerates) instead
+    // of halting, after printing what it can.
+    if cfg!(feature = "panic_reset") {
+        let _ = writer.write_fmt(format_args!("\r\n\r\n{}\r\n", pi));
+        hotel::panic::dump(writer);
+        hotel::memory::dump(writer, &PROCESSES);
+        hotel::panic::reset();
+    }
+
+    debug::panic_begin(&cortexm3::support::nop);
+    debug::panic_banner(writer, pi);
+    hotel::panic::dump(writer);
+    debug::panic_process_info(&PROCESSES, writer);
+    hotel::memory::dump(writer, &PROCESSES);
+
+    // Nothing above may have reached anyone, so also blink the fault on
+    // LED_0 (active low).
+    morse::blink_forever(&hotel::gpio::PORT0.pins[0], true, letter)
 }
 
 
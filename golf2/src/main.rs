@@ -14,13 +14,22 @@ extern crate cortexm3;
 
 #[macro_use]
 pub mod io;
+pub mod morse;
 
 pub mod digest;
 pub mod aes;
+pub mod app_flash;
+pub mod app_signature;
+pub mod build_info;
 pub mod dcrypto;
-pub mod dcrypto_test;
+pub mod hid;
+#[cfg(feature = "selftest")]
+pub mod selftest;
+pub mod temperature;
+pub mod u2f;
 
 use capsules::console;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_uart::{UartDevice, UartMux};
 
 use kernel::{Chip, Platform};
@@ -28,8 +37,7 @@ use kernel::capabilities;
 use kernel::mpu::MPU;
 use kernel::hil;
 
-use hotel::crypto::dcrypto::Dcrypto;
-use hotel::usb::{Descriptor, StringDescriptor};
+use hotel::usb::{Descriptor, Interface, StringDescriptor};
 
 //use kernel::hil::rng::RNG;
 
@@ -39,6 +47,10 @@ const NUM_PROCS: usize = 2;
 // how should the kernel respond when a process faults
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// The kernel loop must complete a pass this often or the watchdog resets
+// the chip
+const WATCHDOG_PERIOD_MS: u32 = 1000;
+
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 16384] = [0; 16384];
 
@@ -52,15 +64,70 @@ pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
 pub struct Golf {
     console: &'static capsules::console::Console<'static, UartDevice<'static>>,
     gpio: &'static capsules::gpio::GPIO<'static, hotel::gpio::GPIOPin>,
-    timer: &'static capsules::alarm::AlarmDriver<'static, hotel::timels::Timels<'static>>,
+    led: &'static capsules::led::LED<'static, hotel::gpio::GPIOPin>,
+    button: &'static capsules::button::Button<'static, hotel::gpio::GPIOPin>,
+    timer: &'static capsules::alarm::AlarmDriver<'static, VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>>,
     ipc: kernel::ipc::IPC,
     digest: &'static digest::DigestDriver<'static, hotel::crypto::sha::ShaEngine>,
     aes: &'static aes::AesDriver<'static>,
     //rng: &'static capsules::rng::SimpleRng<'static, hotel::trng::Trng<'static>>,
     dcrypto: &'static dcrypto::DcryptoDriver<'static>,
+    #[cfg(not(feature = "kernel_u2f"))]
+    u2f: &'static u2f::U2fDriver<'static, hotel::usb::U2fHid>,
+    hid: &'static hid::HidDriver<'static, hotel::usb::RawHid>,
+    build_info: &'static build_info::BuildInfoDriver,
+    temperature: &'static temperature::TemperatureAlerts<'static, VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>>,
 }
 
-static mut STRINGS: [StringDescriptor; 7] = [
+/// What this image is, for host tools (over USB) and apps
+pub static BUILD_INFO: hotel::build_info::BuildInfo = hotel::build_info::BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_revision: env!("BUILD_GIT_REVISION"),
+    timestamp: env!("BUILD_TIMESTAMP"),
+};
+
+/// U2F requests are received into the first buffer and responses sent
+/// from the second.
+static mut U2F_BUFFER: [u8; 1024] = [0; 1024];
+#[cfg(not(feature = "kernel_u2f"))]
+static mut U2F_RESPONSE_BUFFER: [u8; 1024] = [0; 1024];
+
+/// Where the in-kernel authenticator's keys and signatures are computed
+/// (`POINT_LEN` bytes)
+#[cfg(feature = "kernel_u2f")]
+static mut U2F_SCRATCH_BUFFER: [u8; 64] = [0; 64];
+
+/// The apps' signature (`POINT_LEN` bytes), copied from their footer for
+/// the ECDSA engine
+static mut APP_SIGNATURE_BUFFER: [u8; 64] = [0; 64];
+
+/// dcrypto's P-256 program (see `hotel::crypto::p256`), empty if the build
+/// doesn't have one
+static DCRYPTO_P256_PROGRAM: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dcrypto_p256.bin"));
+
+/// Jumpers read at boot; set by pulling the pad low
+const STRAPS: [hotel::strap::Strap; 1] = [
+    // Recovery: boot the kernel without loading any apps
+    hotel::strap::Strap {
+        pad: hotel::pinmux::SelectablePin::Dioa12,
+        pull: hotel::pinmux::Pull::Up,
+    },
+];
+const STRAP_RECOVERY: usize = 0;
+
+// Whether a host must answer a challenge with the key ladder's HMAC to
+// reboot the device into recovery mode (see `hotel::reboot`)
+const REBOOT_AUTHENTICATED: bool = true;
+
+/// The security key's USB interfaces
+static USB_INTERFACES: [Interface; 3] = [hotel::usb::U2F_INTERFACE,
+                                          hotel::usb::SHELL_INTERFACE,
+                                          hotel::usb::RAW_HID_INTERFACE];
+
+/// Filled in from the device ID at boot
+static mut SERIAL_NUMBER: [u16; hotel::fuse::SERIAL_NUMBER_LEN] = [0; hotel::fuse::SERIAL_NUMBER_LEN];
+
+static mut STRINGS: [StringDescriptor; 8] = [
     StringDescriptor {
         b_length: 4,
         b_descriptor_type: Descriptor::String as u8,
@@ -81,16 +148,13 @@ static mut STRINGS: [StringDescriptor; 7] = [
         b_descriptor_type: Descriptor::String as u8,
         b_string: &[0x0070, 0x0072, 0x006F, 0x0074, 0x006F, 0x0032, 0x005F, 0x0076, 0x0031, 0x002E, 0x0031, 0x002E, 0x0038, 0x0037, 0x0031, 0x0033, 0x002D, 0x0030, 0x0031, 0x0033, 0x0032, 0x0031, 0x0037, 0x0064, 0x0039, 0x0031], // proto2-...
     },
-    // Why does this need 3 l (0x6C)? Linux seems to be truncating last one.
-    // Verified GetDescriptor for the String is returning complete information.
-    // -pal
     StringDescriptor {
         b_length: 12,
         b_descriptor_type: Descriptor::String as u8,
-        b_string: &[0x0053, 0x0068, 0x0065, 0x006C, 0x006C, 0x006C], // Shell
+        b_string: &[0x0053, 0x0068, 0x0065, 0x006C, 0x006C], // Shell
     },
     StringDescriptor {
-        b_length: 8,
+        b_length: 10,
         b_descriptor_type: Descriptor::String as u8,
         b_string: &[0x0042, 0x004C, 0x0041, 0x0048],  // BLAH
     },
@@ -99,58 +163,150 @@ static mut STRINGS: [StringDescriptor; 7] = [
         b_descriptor_type: Descriptor::String as u8,
         b_string: &[0x0048, 0x0061, 0x0076, 0x0065, 0x006E, 0x0020, 0x0055, 0x0032, 0x0046], // Haven U2F
     },
+    // Serial number, replaced at boot
+    StringDescriptor {
+        b_length: 2,
+        b_descriptor_type: Descriptor::String as u8,
+        b_string: &[],
+    },
 ];
 
+/// Panics unless a step of board setup succeeded: the board is no use
+/// without its pins and monitors.
+fn expect_success(result: kernel::ReturnCode, what: &str) {
+    if result != kernel::ReturnCode::SUCCESS {
+        panic!("{} failed: {:?}", what, result);
+    }
+}
+
+/// Loads processes from the apps at the start of `region`.
+unsafe fn load_apps(kernel: &'static kernel::Kernel,
+                    chip: &'static hotel::chip::Hotel,
+                    region: app_flash::FlashRegion) {
+    let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
+    let flash = app_flash::AppFlash {
+        apps: region,
+        protected: app_flash::APP_FLASH.protected,
+    };
+    let app_count = app_flash::loadable_apps(&flash, NUM_PROCS);
+    kernel::procs::load_processes(
+        kernel,
+        chip,
+        region.start as *const u8,
+        &mut APP_MEMORY,
+        &mut PROCESSES[..app_count],
+        FAULT_RESPONSE,
+        &process_mgmt_cap,
+    );
+}
+
+/// Loads the apps once their signature has been checked. The ECDSA engine
+/// has one client, so the check borrows it and gives it back to
+/// `ecdsa_client` (the in-kernel authenticator, if there is one) when done.
+struct AppLoader {
+    kernel: &'static kernel::Kernel,
+    chip: &'static hotel::chip::Hotel,
+    ecdsa_client: Option<&'static hotel::hil::ecdsa::EcdsaClient>,
+}
+
+impl AppLoader {
+    fn release_engine(&self) {
+        if let Some(client) = self.ecdsa_client {
+            unsafe { hotel::hil::ecdsa::EcdsaP256::set_client(&hotel::crypto::p256::P256, client) };
+        }
+    }
+}
+
+impl app_signature::SignatureClient for AppLoader {
+    fn signature_checked(&self, signed: Option<app_flash::FlashRegion>) {
+        self.release_engine();
+        match signed {
+            Some(region) => unsafe { load_apps(self.kernel, self.chip, region) },
+            None => unsafe {
+                app_signature::signal_failure(&hotel::gpio::PORT0.pins[0],
+                                              capsules::led::ActivationMode::ActiveLow)
+            },
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe fn reset_handler() {
+    hotel::memory::paint_stack();
     hotel::init();
+    if cfg!(feature = "swo_debug") {
+        hotel::itm::init();
+    }
+    if cfg!(feature = "irq_timing") {
+        hotel::irq_timing::init();
+    }
 
-    let timerhs = {
+    {
         use hotel::pmu::*;
-        use hotel::timeus::Timeus;
-        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeUs0Timer)).enable();
-        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeLs0)).enable();
-        let timer = Timeus::new(0);
-        timer
-    };
+        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeUs0Timer)).acquire();
+        Clock::new(PeripheralClock::Bank1(PeripheralClock1::TimeLs0)).acquire();
+    }
 
-    timerhs.start();
-    let start = timerhs.now();
+    hotel::timestamp::TIMESTAMP.start();
+    hotel::profile::enable();
+    if cfg!(feature = "power_trace") {
+        hotel::power_trace::init();
+    }
+    let start = hotel::timestamp::TIMESTAMP.now();
 
     {
         use hotel::pmu::*;
-        Clock::new(PeripheralClock::Bank0(PeripheralClock0::Gpio0)).enable();
-        let pinmux = &mut *hotel::pinmux::PINMUX;
+        Clock::new(PeripheralClock::Bank0(PeripheralClock0::Gpio0)).acquire();
+        use hotel::pinmux::{self, Function, Input, PadConfig, Pull, SelectablePin};
+        let pulled_up = PadConfig {
+            pull: Pull::Up,
+            ..PadConfig::DEFAULT
+        };
+        hotel::strap::sample(&STRAPS);
+        pinmux::reset();
+
         // LED_0
-        pinmux.dioa11.select.set(hotel::pinmux::Function::Gpio0Gpio0);
+        expect_success(pinmux::connect_gpio(0, 0, SelectablePin::Dioa11), "LED_0 pinmux");
 
         // SW1
-        pinmux.gpio0_gpio1.select.set(hotel::pinmux::SelectablePin::Diom2);
-        pinmux.diom2.select.set(hotel::pinmux::Function::Gpio0Gpio1);
-        pinmux.diom2.control.set(1 << 2 | 1 << 4);
-
-        pinmux.diob1.select.set(hotel::pinmux::Function::Uart0Tx);
-        pinmux.diob6.control.set(1 << 2 | 1 << 4);
-        pinmux.uart0_rx.select.set(hotel::pinmux::SelectablePin::Diob6);
+        expect_success(pinmux::connect_gpio(0, 1, SelectablePin::Diom2), "SW1 pinmux");
+        pinmux::configure_pad(SelectablePin::Diom2, pulled_up);
+
+        expect_success(pinmux::connect_output(SelectablePin::Diob1, Function::Uart0Tx),
+                       "UART0 TX pinmux");
+        hotel::io::set_console_pad(SelectablePin::Diob1);
+        pinmux::configure_pad(SelectablePin::Diob6, pulled_up);
+        expect_success(pinmux::connect_input(Input::Uart0Rx, SelectablePin::Diob6),
+                       "UART0 RX pinmux");
     }
 
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
-    let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
     let main_cap = create_capability!(capabilities::MainLoopCapability);
     let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
     let kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
+    // The console and kernel debug output go over UART0 unless the board
+    // is built with the `usb_console` feature, in which case they use the
+    // USB shell interface.
+    #[cfg(not(feature = "usb_console"))]
+    let console_device = &hotel::uart::UART0;
+    #[cfg(feature = "usb_console")]
+    let console_device = {
+        hotel::usb::USB_CONSOLE.init();
+        &hotel::usb::USB_CONSOLE
+    };
+
     let uart_mux = static_init!(
         UartMux<'static>,
         UartMux::new(
-            &hotel::uart::UART0,
+            console_device,
             &mut capsules::virtual_uart::RX_BUF,
             115200
         )
     );
-    hil::uart::UART::set_client(&hotel::uart::UART0, uart_mux);
+    hil::uart::UART::set_client(console_device, uart_mux);
     
     // Create virtual device for console.
     let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
@@ -196,15 +352,53 @@ pub unsafe fn reset_handler() {
     let gpio = static_init!(
         capsules::gpio::GPIO<'static, hotel::gpio::GPIOPin>,
         capsules::gpio::GPIO::new(gpio_pins));
-    for pin in gpio_pins.iter() {
-        pin.set_client(gpio)
+    // Apps (blink, gpio_test) drive and watch pin 0; pin 1's interrupts
+    // belong to the button below.
+    gpio_pins[0].set_client(gpio);
+
+    // LED_0 is lit when driven low.
+    let led_pins = static_init!(
+        [(&'static hotel::gpio::GPIOPin, capsules::led::ActivationMode); 1],
+        [(&hotel::gpio::PORT0.pins[0], capsules::led::ActivationMode::ActiveLow)]);
+    let led = static_init!(
+        capsules::led::LED<'static, hotel::gpio::GPIOPin>,
+        capsules::led::LED::new(led_pins));
+
+    // SW1 is the user-presence button; it is pulled up and pressing it
+    // connects it to ground.
+    let button_pins = static_init!(
+        [(&'static hotel::gpio::GPIOPin, capsules::button::GpioMode); 1],
+        [(&hotel::gpio::PORT0.pins[1], capsules::button::GpioMode::LowWhenPressed)]);
+    let button = static_init!(
+        capsules::button::Button<'static, hotel::gpio::GPIOPin>,
+        capsules::button::Button::new(button_pins, kernel.create_grant(&grant_cap)));
+    for &(pin, _) in button_pins.iter() {
+        pin.set_client(button);
     }
 
+    hotel::timeus::TIMEUS1.start();
+    let mux_alarm = static_init!(
+        MuxAlarm<'static, hotel::timeus::Timeus<'static>>,
+        MuxAlarm::new(&hotel::timeus::TIMEUS1));
+    hotel::timeus::TIMEUS1.set_client(mux_alarm);
+
+    // Application timers and periodic kernel duties run from the low-speed
+    // timer, which keeps counting and can wake the chip while it is in deep
+    // sleep.
+    let wake_mux_alarm = static_init!(
+        MuxAlarm<'static, hotel::timels::Timels<'static>>,
+        MuxAlarm::new(&hotel::timels::TIMELS0));
+    hotel::timels::TIMELS0.set_client(wake_mux_alarm);
+    hotel::timels::TIMELS0.enable_wakeup();
+
+    let virtual_alarm_user = static_init!(
+        VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>,
+        VirtualMuxAlarm::new(wake_mux_alarm));
     let timer = static_init!(
-        capsules::alarm::AlarmDriver<'static, hotel::timels::Timels<'static>>,
+        capsules::alarm::AlarmDriver<'static, VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>>,
         capsules::alarm::AlarmDriver::new(
-            &hotel::timels::TIMELS0, kernel.create_grant(&grant_cap)));
-    hotel::timels::TIMELS0.set_client(timer);
+            virtual_alarm_user, kernel.create_grant(&grant_cap)));
+    virtual_alarm_user.set_client(timer);
 
     let digest = static_init!(
         digest::DigestDriver<'static, hotel::crypto::sha::ShaEngine>,
@@ -221,8 +415,134 @@ pub unsafe fn reset_handler() {
     let dcrypto = static_init!(
         dcrypto::DcryptoDriver<'static>,
         dcrypto::DcryptoDriver::new(&mut hotel::crypto::dcrypto::DCRYPTO));
-    
-    hotel::crypto::dcrypto::DCRYPTO.set_client(dcrypto);
+
+    // The P-256 engine shares dcrypto with the apps' driver.
+    expect_success(hotel::crypto::p256::P256.init(&hotel::crypto::dcrypto::DCRYPTO,
+                                                  dcrypto,
+                                                  &hotel::crypto::sha::KEYMGR0_SHA,
+                                                  DCRYPTO_P256_PROGRAM),
+                   "P-256 program");
+
+    #[cfg(not(feature = "kernel_u2f"))]
+    let u2f = static_init!(
+        u2f::U2fDriver<'static, hotel::usb::U2fHid>,
+        u2f::U2fDriver::new(&hotel::usb::U2F_HID,
+                            &mut U2F_BUFFER,
+                            &mut U2F_RESPONSE_BUFFER,
+                            kernel.create_grant(&grant_cap)));
+    #[cfg(not(feature = "kernel_u2f"))]
+    {
+        hotel::hil::u2f::U2fTransport::set_client(&hotel::usb::U2F_HID, u2f);
+        u2f.start();
+    }
+
+    let hid = static_init!(
+        hid::HidDriver<'static, hotel::usb::RawHid>,
+        hid::HidDriver::new(&hotel::usb::RAW_HID, kernel.create_grant(&grant_cap)));
+    hotel::hil::hid::HidReports::set_client(&hotel::usb::RAW_HID, hid);
+
+    let temperature_alarm = static_init!(
+        VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>,
+        VirtualMuxAlarm::new(wake_mux_alarm));
+    let temperature = static_init!(
+        temperature::TemperatureAlerts<'static, VirtualMuxAlarm<'static, hotel::timels::Timels<'static>>>,
+        temperature::TemperatureAlerts::new(&hotel::temp::TEMP0,
+                                            temperature_alarm,
+                                            kernel.create_grant(&grant_cap)));
+    temperature_alarm.set_client(temperature);
+    hil::sensors::TemperatureDriver::set_client(&hotel::temp::TEMP0, temperature);
+
+    // Wipe dcrypto memories if the supply starts to fail.
+    expect_success(hotel::volt::VOLT0.enable(2700), "voltage monitor");
+    expect_success(hotel::volt::VOLT0.add_client(&hotel::crypto::dcrypto::DCRYPTO),
+                   "dcrypto brownout client");
+    expect_success(hotel::volt::VOLT0.add_client(&hotel::crypto::p256::P256), "P-256 brownout client");
+    expect_success(hotel::volt::VOLT0.add_client(&hotel::flash::FLASH0), "flash brownout client");
+    let recovery_alarm = static_init!(
+        VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let recovery = static_init!(
+        hotel::volt::RecoveryMonitor<'static, VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>>,
+        hotel::volt::RecoveryMonitor::new(&hotel::volt::VOLT0, recovery_alarm));
+    recovery_alarm.set_client(recovery);
+    expect_success(hotel::volt::VOLT0.add_client(recovery), "brownout recovery client");
+
+    // Let an update tool reboot the device into recovery mode over USB.
+    hotel::trng::TRNG0.init();
+    let reboot_alarm = static_init!(
+        VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let reboot = static_init!(
+        hotel::reboot::Reboot<'static, VirtualMuxAlarm<'static, hotel::timeus::Timeus<'static>>>,
+        hotel::reboot::Reboot::new(reboot_alarm,
+                                   &hotel::trng::TRNG0,
+                                   &hotel::crypto::sha::KEYMGR0_SHA,
+                                   REBOOT_AUTHENTICATED));
+    reboot_alarm.set_client(reboot);
+    reboot.init();
+
+    BUILD_INFO.init();
+    hotel::memory::init();
+    hotel::pmu::init_clock_tree();
+    hotel::irq_count::init();
+    #[cfg(feature = "peek_poke")]
+    {
+        // Only with physical access to the board
+        hotel::peek_poke::init();
+        hotel::peek_poke::set_enabled(hotel::strap::straps().is_set(STRAP_RECOVERY));
+    }
+
+    // Let a factory tool write the attestation key and certificate once.
+    app_flash::init();
+    let personality_region = app_flash::APP_FLASH.protected[1].1;
+    let personality = hotel::personality::PERSONALITY.init(
+        personality_region.start,
+        personality_region.end - personality_region.start,
+        &hotel::crypto::sha::KEYMGR0_SHA);
+    let provisioning = static_init!(
+        hotel::provision::Provisioning<'static>,
+        hotel::provision::Provisioning::new(&hotel::personality::PERSONALITY,
+                                            &hotel::flash::FLASH0,
+                                            &hotel::crypto::sha::KEYMGR0_SHA));
+    provisioning.init();
+
+    // With `kernel_u2f` the kernel answers U2F requests itself, signing
+    // with the ECDSA engine; otherwise they are passed to an app.
+    #[cfg(feature = "kernel_u2f")]
+    let ecdsa_client: Option<&'static hotel::hil::ecdsa::EcdsaClient> = {
+        let counter_region = app_flash::APP_FLASH.protected[2].1;
+        expect_success(hotel::counter::COUNTER.init(&hotel::flash::FLASH0,
+                                                    counter_region.start,
+                                                    counter_region.end - counter_region.start),
+                       "U2F counter");
+        // SW1 shows the user is present.
+        hil::gpio::Pin::make_input(&hotel::gpio::PORT0.pins[1]);
+        let authenticator = static_init!(
+            hotel::authenticator::Authenticator<'static,
+                                                hotel::usb::U2fHid,
+                                                hotel::crypto::p256::P256Engine,
+                                                hotel::gpio::GPIOPin>,
+            hotel::authenticator::Authenticator::new(&hotel::usb::U2F_HID,
+                                                     &hotel::crypto::p256::P256,
+                                                     &hotel::crypto::sha::KEYMGR0_SHA,
+                                                     &hotel::trng::TRNG0,
+                                                     &hotel::counter::COUNTER,
+                                                     &hotel::personality::PERSONALITY,
+                                                     &hotel::gpio::PORT0.pins[1],
+                                                     true,
+                                                     &mut U2F_BUFFER,
+                                                     &mut U2F_SCRATCH_BUFFER));
+        hotel::hil::u2f::U2fTransport::set_client(&hotel::usb::U2F_HID, authenticator);
+        hotel::hil::ecdsa::EcdsaP256::set_client(&hotel::crypto::p256::P256, authenticator);
+        authenticator.start();
+        Some(authenticator)
+    };
+    #[cfg(not(feature = "kernel_u2f"))]
+    let ecdsa_client = None;
+
+    let build_info = static_init!(
+        build_info::BuildInfoDriver,
+        build_info::BuildInfoDriver::new(&BUILD_INFO, kernel.create_grant(&grant_cap)));
         
     /*    hotel::trng::TRNG0.init();
     let rng = static_init!(
@@ -234,84 +554,129 @@ pub unsafe fn reset_handler() {
     let golf2 = Golf {
         console: console,
         gpio: gpio,
+        led: led,
+        button: button,
         timer: timer,
         ipc: kernel::ipc::IPC::new(kernel, &grant_cap),
         digest: digest,
         aes: aes,
-        dcrypto: dcrypto
+        dcrypto: dcrypto,
+        #[cfg(not(feature = "kernel_u2f"))]
+        u2f: u2f,
+        hid: hid,
+        build_info: build_info,
+        temperature: temperature,
 //        rng: rng,
     };
 
     // ** GLOBALSEC **
-    // TODO(alevy): refactor out
+    // The CPU can reach everything; the crypto coprocessor and the USB
+    // controller only need RAM for their DMA.
     {
-        use core::intrinsics::volatile_store as vs;
-
-        vs(0x40090000 as *mut u32, !0);
-        vs(0x40090004 as *mut u32, !0);
-        vs(0x40090008 as *mut u32, !0);
-        vs(0x4009000c as *mut u32, !0);
-
-        // GLOBALSEC_DDMA0-DDMA3
-        vs(0x40090080 as *mut u32, !0);
-        vs(0x40090084 as *mut u32, !0);
-        vs(0x40090088 as *mut u32, !0);
-        vs(0x4009008c as *mut u32, !0);
-        
-        // GLOBALSEC_DUSB_REGION0-DUSB_REGION3
-        vs(0x400900c0 as *mut u32, !0);
-        vs(0x400900c4 as *mut u32, !0);
-        vs(0x400900c8 as *mut u32, !0);
-        vs(0x400900cc as *mut u32, !0);
+        use hotel::globalsec::{self, Master, Region};
+        globalsec::init();
+        globalsec::configure_region(Master::Cpu, 0, Region::everything());
+        globalsec::configure_region(Master::Dcrypto, 0, Region::read_write(0x10000, 0x10000));
+        globalsec::configure_region(Master::Usb, 0, Region::read_write(0x10000, 0x10000));
+        globalsec::lock(Master::Dcrypto);
+        globalsec::lock(Master::Usb);
     }
 
     let mut _ctr = 0;
-    let end = timerhs.now();
+    let end = hotel::timestamp::TIMESTAMP.now();
+
+    println!("Tock 1.0 booting. Initialization took {} us.",
+             end - start);
+    debug!("Chip revision: {:?}", hotel::errata::revision());
+    println!("Last reset: {:?}", hotel::pmu::reset_cause());
+    hotel::pmu::clear_reset_cause();
+    if let Some(pad) = hotel::pinmux::woken_by() {
+        debug!("Woken from deep sleep by {:?}", pad);
+    }
+    hotel::pinmux::clear_wakeup_status();
+    debug!("Straps: {:#x}", hotel::strap::straps().bits());
+    debug!("Personality: {:?}", personality);
 
-    println!("Tock 1.0 booting. Initialization took {} tics.",
-             end.wrapping_sub(start));
+    hotel::calendar::CALENDAR.init();
+
+    // Pressing SW1 wakes the chip from deep sleep.
+    hotel::pinmux::enable_wakeup(hotel::pinmux::SelectablePin::Diom2,
+                                 hotel::pinmux::WakeMode::FallingEdge);
 
     let chip = static_init!(hotel::chip::Hotel, hotel::chip::Hotel::new());
 
     chip.mpu().enable_mpu();
 
     for _i in 0..1_000_000 {
-        _ctr += timerhs.now();
+        _ctr += hotel::timestamp::TIMESTAMP.now();
     }
 
     println!("Tock 1.0 booting. About to initialize USB.");
-    
+
+    hotel::fuse::serial_number(&mut SERIAL_NUMBER);
+    STRINGS[hotel::usb::STRING_SERIAL as usize] = StringDescriptor::new(&SERIAL_NUMBER);
+
+    hotel::usb::U2F_HID.init();
+    hotel::usb::RAW_HID.init();
+    hotel::usb::select_phy(hotel::usb::PHY::A);
     hotel::usb::USB0.init(&mut hotel::usb::OUT_DESCRIPTORS,
                           &mut hotel::usb::OUT_BUFFERS,
                           &mut hotel::usb::IN_DESCRIPTORS,
                           &mut hotel::usb::IN_BUFFERS,
                           &mut hotel::usb::CONFIGURATION_BUFFER,
-                          hotel::usb::PHY::A,
                           None,
                           Some(0x18d1),
                           Some(0x5026),
-                          &mut STRINGS);
-
-
-
-
-    
-// dcrypto_test::run_dcrypto();
-//    rng_test::run_rng();
-
-    extern "C" {
-        /// Beginning of the ROM region containing app images.
-        static _sapps: u8;
+                          &mut STRINGS,
+                          &USB_INTERFACES);
+    // There is no crystal, so keep the RC oscillator locked to the host.
+    hotel::xo::XO0.start_calibration();
+
+    // Everything the kernel needs is initialized and holds its clocks.
+    hotel::pmu::gate_unused_clocks();
+
+    #[cfg(feature = "selftest")]
+    selftest::run(aes, mux_alarm);
+
+    // Always take the request, so it doesn't outlive the next boot.
+    let reboot_requested = hotel::pmu::take_bootloader_request();
+    if hotel::strap::straps().is_set(STRAP_RECOVERY) {
+        debug!("Recovery strap set, not loading apps.");
+    } else if reboot_requested {
+        debug!("Recovery requested over USB, not loading apps.");
+    } else {
+        // Devices with a firmware key only run apps signed with it.
+        let app_loader = static_init!(
+            AppLoader,
+            AppLoader {
+                kernel: kernel,
+                chip: chip,
+                ecdsa_client: ecdsa_client,
+            });
+        let app_signature = static_init!(
+            app_signature::AppSignature<'static, hotel::crypto::p256::P256Engine>,
+            app_signature::AppSignature::new(&hotel::crypto::p256::P256,
+                                             &hotel::crypto::sha::KEYMGR0_SHA,
+                                             &hotel::personality::PERSONALITY,
+                                             &mut APP_SIGNATURE_BUFFER));
+        app_signature.set_client(app_loader);
+        hotel::hil::ecdsa::EcdsaP256::set_client(&hotel::crypto::p256::P256, app_signature);
+        match app_signature.check(app_flash::APP_FLASH.apps) {
+            Ok(app_signature::Check::Started) => {} // Loaded in signature_checked
+            Ok(app_signature::Check::Unchecked) => {
+                app_loader.release_engine();
+                load_apps(kernel, chip, app_flash::APP_FLASH.apps)
+            }
+            Err(_) => {
+                app_loader.release_engine();
+                app_signature::signal_failure(&hotel::gpio::PORT0.pins[0],
+                                              capsules::led::ActivationMode::ActiveLow)
+            }
+        }
     }
-    kernel::procs::load_processes(
-        kernel,
-        chip,
-        &_sapps as *const u8,
-        &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
-        &process_mgmt_cap,
-    );
+    let stack = hotel::memory::stack_usage();
+    debug!("Kernel stack: {} of {} bytes used during boot.", stack.used, stack.size);
+    hotel::watchdog::WATCHDOG0.start(WATCHDOG_PERIOD_MS);
     debug!("Start main loop.");
     debug!(" ");
 
@@ -325,12 +690,19 @@ impl Platform for Golf {
         match driver_num {
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::gpio::DRIVER_NUM    => f(Some(self.gpio)),
+            capsules::led::DRIVER_NUM     => f(Some(self.led)),
+            capsules::button::DRIVER_NUM  => f(Some(self.button)),
             digest::DRIVER_NUM            => f(Some(self.digest)),
             capsules::alarm::DRIVER_NUM   => f(Some(self.timer)),
             aes::DRIVER_NUM               => f(Some(self.aes)),
 //            capsules::rng::DRIVER_NUM   => f(Some(self.rng)),
             kernel::ipc::DRIVER_NUM       => f(Some(&self.ipc)),
             dcrypto::DRIVER_NUM           => f(Some(self.dcrypto)),
+            #[cfg(not(feature = "kernel_u2f"))]
+            u2f::DRIVER_NUM               => f(Some(self.u2f)),
+            hid::DRIVER_NUM               => f(Some(self.hid)),
+            build_info::DRIVER_NUM        => f(Some(self.build_info)),
+            temperature::DRIVER_NUM       => f(Some(self.temperature)),
             _ =>  f(None),
         }
     }
@@ -0,0 +1,80 @@
+//! Panic indicator in Morse code
+//!
+//! With no console attached a panic is otherwise just a generic blinking
+//! LED. The panic handler instead blinks a letter saying roughly what
+//! failed, worked out from where the panic was raised:
+//!
+//! | Letter | Morse  | Fault                                        |
+//! | ------ | :----- | :------------------------------------------- |
+//! | U      | ..-    | USB driver                                   |
+//! | S      | ...    | UART (serial) driver                         |
+//! | C      | -.-.   | Crypto drivers                               |
+//! | P      | .--.   | A process faulted                            |
+//! | H      | ....   | Hard fault in the kernel                     |
+//! | K      | -.-    | Anything else in the kernel                  |
+//!
+//! Only the GPIO port registers are touched and time is kept by spinning,
+//! so the indicator works whichever subsystem failed.
+
+use core::panic::PanicInfo;
+use cortexm3;
+use kernel::hil::gpio::Pin;
+use hotel::gpio::GPIOPin;
+
+/// Spins of a dot, about 150ms at 24Mhz.
+const DOT_SPINS: u32 = 900_000;
+
+/// The letter for the fault behind `panic_info`.
+pub fn fault_letter(panic_info: &PanicInfo) -> char {
+    let file = panic_info.location().map_or("", |location| location.file());
+    if file.contains("hotel/src/usb") {
+        'U'
+    } else if file.contains("hotel/src/uart") {
+        'S'
+    } else if file.contains("hotel/src/crypto") || file.contains("golf2/src/dcrypto") {
+        'C'
+    } else if file.contains("kernel/src/process") {
+        // Processes only panic the kernel under `FaultResponse::Panic`.
+        'P'
+    } else if file.contains("arch/cortex-m") {
+        'H'
+    } else {
+        'K'
+    }
+}
+
+/// Dots (false) and dashes (true) for the letters `fault_letter` returns.
+fn code(letter: char) -> &'static [bool] {
+    match letter {
+        'U' => &[false, false, true],
+        'S' => &[false, false, false],
+        'C' => &[true, false, true, false],
+        'P' => &[false, true, true, false],
+        'H' => &[false, false, false, false],
+        _ => &[true, false, true],
+    }
+}
+
+fn spin(dots: u32) {
+    for _ in 0..dots * DOT_SPINS {
+        cortexm3::support::nop();
+    }
+}
+
+/// Drives `pin` as an output and blinks `letter` on it forever, with a
+/// pause between repetitions. `active_low` is for LEDs lit by a low pin.
+pub fn blink_forever(pin: &GPIOPin, active_low: bool, letter: char) -> ! {
+    let set = |on: bool| if on != active_low { pin.set() } else { pin.clear() };
+    pin.make_output();
+    set(false);
+    loop {
+        for &dash in code(letter).iter() {
+            set(true);
+            spin(if dash { 3 } else { 1 });
+            set(false);
+            spin(1);
+        }
+        // Word gap, less the gap after the last symbol
+        spin(6);
+    }
+}
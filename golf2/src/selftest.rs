@@ -0,0 +1,70 @@
+//! Hardware self-tests at boot
+//!
+//! Built with the `selftest` feature, golf2 registers the chip's self-tests
+//! with `hotel::test_registry` and runs them once the kernel is set up,
+//! printing the results on the console. The host can run them again over
+//! USB (see `hotel::test_runner`). The tests take over the TRNG, AES and
+//! DCRYPTO clients when they start; when a run finishes, AES is handed back
+//! to its syscall driver and DCRYPTO to the P-256 engine, which passes the
+//! apps' completions on to theirs. The USB test fails unless a host is
+//! attached.
+
+use aes::AesDriver;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use hotel::crypto::aes::KEYMGR0_AES;
+use hotel::crypto::dcrypto::{Dcrypto, DCRYPTO};
+use hotel::crypto::p256::P256;
+use hotel::crypto::sha::{ShaEngine, KEYMGR0_SHA};
+use hotel::test_aes::TestAes;
+use hotel::test_dcrypto::TestDcrypto;
+use hotel::test_registry::{RegistryClient, TESTS};
+use hotel::test_rng::TestRng;
+use hotel::test_runner::TEST_RUNNER;
+use hotel::test_sha::TestSha;
+use hotel::test_usb::TestUsb;
+use hotel::timeus::Timeus;
+use hotel::trng;
+
+pub struct BoardTests {
+    aes_driver: &'static AesDriver<'static>,
+}
+
+impl RegistryClient for BoardTests {
+    fn tests_complete(&self, _passed: usize, _failed: usize) {
+        unsafe {
+            KEYMGR0_AES.set_client(self.aes_driver);
+            DCRYPTO.set_client(&P256);
+        }
+    }
+}
+
+pub unsafe fn run(aes_driver: &'static AesDriver<'static>,
+                  mux_alarm: &'static MuxAlarm<'static, Timeus<'static>>) {
+    let rng_test = static_init!(TestRng<'static>, TestRng::new(&trng::TRNG0));
+    TESTS.register(rng_test);
+
+    let aes_test = static_init!(TestAes<'static>, TestAes::new(&KEYMGR0_AES));
+    TESTS.register(aes_test);
+
+    let sha_test = static_init!(TestSha<'static, ShaEngine>, TestSha::new(&KEYMGR0_SHA));
+    TESTS.register(sha_test);
+
+    let dcrypto_test = static_init!(TestDcrypto<'static>, TestDcrypto::new(&DCRYPTO));
+    TESTS.register(dcrypto_test);
+
+    let usb_alarm = static_init!(
+        VirtualMuxAlarm<'static, Timeus<'static>>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let usb_test = static_init!(
+        TestUsb<'static, VirtualMuxAlarm<'static, Timeus<'static>>>,
+        TestUsb::new(usb_alarm));
+    usb_alarm.set_client(usb_test);
+    TESTS.register(usb_test);
+
+    let board_tests = static_init!(BoardTests, BoardTests {
+        aes_driver: aes_driver,
+    });
+    TESTS.set_client(board_tests);
+    TESTS.run();
+    TEST_RUNNER.init();
+}
@@ -0,0 +1,191 @@
+//! Syscall driver for the temperature sensor, with threshold alerts
+//!
+//! Serves the standard temperature interface of `capsules::temperature`,
+//! so apps written against it work unchanged, and adds alerts so a
+//! monitoring process can react to thermal anomalies (e.g. by rate-limiting
+//! crypto operations). While any process has set limits, the driver takes a
+//! reading every `ALERT_PERIOD_MS` and calls that process's alert callback
+//! when the temperature leaves its limits, once per excursion: the alert is
+//! re-armed when a reading is back within them. Every process waiting for
+//! a reading gets the same one.
+//!
+//! Temperatures are in hundredths of a degree Celsius, as `i32`s.
+//!
+//! ### Subscribe
+//!   - 0: a reading requested with command 1 is ready; the argument is the
+//!     temperature
+//!   - 1: alert; the arguments are the temperature and 1 if it is above the
+//!     high limit, 0 if it is below the low one
+//!
+//! ### Command
+//!   - 0: check the driver is present
+//!   - 1: start a reading
+//!   - 2: set the low (`arg1`) and high (`arg2`) limits and start alerts
+//!   - 3: stop alerts
+
+use capsules;
+use core::cell::Cell;
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+pub const DRIVER_NUM: usize = capsules::temperature::DRIVER_NUM;
+
+/// Time between the readings taken to check limits
+pub const ALERT_PERIOD_MS: u32 = 1000;
+
+pub struct App {
+    reading_callback: Option<Callback>,
+    alert_callback: Option<Callback>,
+    // Waiting for the result of command 1
+    reading: bool,
+    // Low and high limits, if alerts are on
+    limits: Option<(i32, i32)>,
+    // Whether the last reading outside the limits has been reported
+    alerted: bool,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            reading_callback: None,
+            alert_callback: None,
+            reading: false,
+            limits: None,
+            alerted: false,
+        }
+    }
+}
+
+pub struct TemperatureAlerts<'a, A: Alarm + 'a> {
+    sensor: &'a TemperatureDriver,
+    alarm: &'a A,
+    apps: Grant<App>,
+    busy: Cell<bool>,
+}
+
+impl<'a, A: Alarm + 'a> TemperatureAlerts<'a, A> {
+    pub fn new(sensor: &'a TemperatureDriver, alarm: &'a A, container: Grant<App>)
+               -> TemperatureAlerts<'a, A> {
+        TemperatureAlerts {
+            sensor: sensor,
+            alarm: alarm,
+            apps: container,
+            busy: Cell::new(false),
+        }
+    }
+
+    fn start_reading(&self) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::SUCCESS;
+        }
+        let result = self.sensor.read_temperature();
+        if result == ReturnCode::SUCCESS {
+            self.busy.set(true);
+        }
+        result
+    }
+
+    fn read(&self, app_id: AppId) -> ReturnCode {
+        let result = self.apps
+            .enter(app_id, |app, _| {
+                app.reading = true;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.start_reading()
+    }
+
+    fn set_limits(&self, app_id: AppId, limits: Option<(i32, i32)>) -> ReturnCode {
+        if let Some((low, high)) = limits {
+            if low > high {
+                return ReturnCode::EINVAL;
+            }
+        }
+        let result = self.apps
+            .enter(app_id, |app, _| {
+                app.limits = limits;
+                app.alerted = false;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS && limits.is_some() && !self.alarm.is_armed() {
+            self.schedule_check();
+        }
+        result
+    }
+
+    fn schedule_check(&self) {
+        let delay = ALERT_PERIOD_MS * <A::Frequency>::frequency() / 1000;
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(delay));
+    }
+
+    fn any_limits(&self) -> bool {
+        let any = Cell::new(false);
+        self.apps.each(|app| if app.limits.is_some() {
+            any.set(true);
+        });
+        any.get()
+    }
+}
+
+impl<'a, A: Alarm + 'a> TemperatureClient for TemperatureAlerts<'a, A> {
+    fn callback(&self, value: usize, _: usize, _: usize) {
+        self.busy.set(false);
+        let temperature = value as i32;
+        self.apps.each(|app| {
+            if app.reading {
+                app.reading = false;
+                app.reading_callback.map(|mut callback| callback.schedule(value, 0, 0));
+            }
+            if let Some((low, high)) = app.limits {
+                if temperature < low || temperature > high {
+                    if !app.alerted {
+                        app.alerted = true;
+                        let above = (temperature > high) as usize;
+                        app.alert_callback.map(|mut callback| callback.schedule(value, above, 0));
+                    }
+                } else {
+                    app.alerted = false;
+                }
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for TemperatureAlerts<'a, A> {
+    fn fired(&self) {
+        if self.any_limits() {
+            self.start_reading();
+            self.schedule_check();
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> Driver for TemperatureAlerts<'a, A> {
+    fn subscribe(&self, subscribe_num: usize, callback: Option<Callback>, app_id: AppId) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.reading_callback = callback,
+                    1 => app.alert_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, caller_id: AppId) -> ReturnCode {
+        match command_num {
+            0 /* Check if present */ => ReturnCode::SUCCESS,
+            1 /* Read the temperature */ => self.read(caller_id),
+            2 /* Set limits */ => self.set_limits(caller_id, Some((arg1 as i32, arg2 as i32))),
+            3 /* Stop alerts */ => self.set_limits(caller_id, None),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
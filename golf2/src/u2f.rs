@@ -0,0 +1,402 @@
+//! Syscall driver for the U2F transport
+//!
+//! Lets processes act as the authenticator behind a `hil::u2f` transport.
+//! Any number of processes can subscribe to requests. Each U2F channel is
+//! bound to one of them, recorded in its grant: the first request on a
+//! channel binds it to the next subscribed process in turn, and the rest
+//! go to the same process, so e.g. an authenticator app and a diagnostics
+//! app can each serve the hosts talking to them. A process receives each
+//! request (an APDU) in the buffer it allowed and answers it with the
+//! response command before it is given the next; the response goes back
+//! on the request's channel. Processes with responses waiting take turns
+//! to send them. A request whose process unsubscribes or dies before
+//! answering is cancelled with the transport, so it doesn't keep one of
+//! the transport's `MAX_REQUESTS` places; a death is noticed the next time
+//! any process subscribes or responds.
+//!
+//! ### Allow
+//!   - 0: buffer requests are copied into
+//!   - 1: buffer responses are sent from
+//!
+//! ### Subscribe
+//!   - 0: a request arrived; arguments are its length and U2FHID command.
+//!     Unsubscribing gives up the process's channels.
+//!   - 1: the response has been sent
+//!
+//! ### Command
+//!   - 0: check the driver is present
+//!   - 1: send the first `arg1` bytes of the response buffer as the
+//!     response to the current request
+
+use core::cell::Cell;
+use core::cmp;
+use hotel::hil::u2f::{U2fClient, U2fTransport, COMMAND_MSG, MAX_REQUESTS};
+use kernel::common::cells::TakeCell;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+pub const DRIVER_NUM: usize = 0x40005;
+
+/// Channels bound to a process at once; binding another gives up the one
+/// bound longest ago.
+pub const CHANNELS_PER_APP: usize = 4;
+
+pub struct App {
+    rx_buffer: Option<AppSlice<Shared, u8>>,
+    tx_buffer: Option<AppSlice<Shared, u8>>,
+    request_callback: Option<Callback>,
+    response_callback: Option<Callback>,
+    // The channels bound to the process, oldest first; 0 is never a channel
+    channels: [u32; CHANNELS_PER_APP],
+    // The channel of the request the process is answering
+    request: Option<u32>,
+    // The length of its response, until the transport takes it
+    response: Option<usize>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            rx_buffer: None,
+            tx_buffer: None,
+            request_callback: None,
+            response_callback: None,
+            channels: [0; CHANNELS_PER_APP],
+            request: None,
+            response: None,
+        }
+    }
+}
+
+impl App {
+    fn bind(&mut self, channel: u32) {
+        for i in 1..CHANNELS_PER_APP {
+            self.channels[i - 1] = self.channels[i];
+        }
+        self.channels[CHANNELS_PER_APP - 1] = channel;
+    }
+}
+
+pub struct U2fDriver<'a, T: U2fTransport + 'a> {
+    transport: &'a T,
+    apps: Grant<App>,
+    // Held while a request waits for its process to answer the one before
+    rx_buffer: TakeCell<'static, [u8]>,
+    // Taken while a response is being sent
+    tx_buffer: TakeCell<'static, [u8]>,
+    // The request waiting for its process: channel, command and length
+    held: Cell<Option<(u32, u8, usize)>>,
+    // A channel no process could take, to answer with an empty response
+    unanswered: Cell<Option<u32>>,
+    // The process whose response is being sent
+    sending: Cell<Option<AppId>>,
+    // The processes answering requests, and the requests' channels
+    delivered: [Cell<Option<(AppId, u32)>>; MAX_REQUESTS],
+    // The processes the last new channel and the last response went to
+    last_bound: Cell<usize>,
+    last_sent: Cell<usize>,
+}
+
+impl<'a, T: U2fTransport + 'a> U2fDriver<'a, T> {
+    pub fn new(transport: &'a T,
+               rx_buffer: &'static mut [u8],
+               tx_buffer: &'static mut [u8],
+               container: Grant<App>)
+               -> U2fDriver<'a, T> {
+        U2fDriver {
+            transport: transport,
+            apps: container,
+            rx_buffer: TakeCell::new(rx_buffer),
+            tx_buffer: TakeCell::new(tx_buffer),
+            held: Cell::new(None),
+            unanswered: Cell::new(None),
+            sending: Cell::new(None),
+            delivered: [Cell::new(None), Cell::new(None), Cell::new(None), Cell::new(None)],
+            last_bound: Cell::new(0),
+            last_sent: Cell::new(0),
+        }
+    }
+
+    /// Hands the receive buffer to the transport so requests can be
+    /// received.
+    pub fn start(&self) -> ReturnCode {
+        self.rx_buffer.take().map_or(ReturnCode::EALREADY, |buffer| self.transport.receive(buffer))
+    }
+
+    /// The first process after the one with index `last` for which
+    /// `wanted` holds, going round.
+    fn next_app<F: Fn(&App) -> bool>(&self, last: usize, wanted: F) -> Option<AppId> {
+        let first = Cell::new(None);
+        let next = Cell::new(None);
+        self.apps.each(|app| if wanted(app) {
+            let app_id = app.appid();
+            if first.get().is_none() {
+                first.set(Some(app_id));
+            }
+            if app_id.idx() > last && next.get().is_none() {
+                next.set(Some(app_id));
+            }
+        });
+        next.get().or(first.get())
+    }
+
+    /// The subscribed process `channel` is bound to, binding it to the next
+    /// one in turn if it isn't bound yet.
+    fn owner(&self, channel: u32) -> Option<AppId> {
+        let owner = Cell::new(None);
+        self.apps.each(|app| if app.request_callback.is_some() && app.channels.contains(&channel) {
+            owner.set(Some(app.appid()));
+        });
+        if owner.get().is_some() {
+            return owner.get();
+        }
+        self.next_app(self.last_bound.get(), |app| app.request_callback.is_some()).map(|app_id| {
+            self.last_bound.set(app_id.idx());
+            let _ = self.apps.enter(app_id, |app, _| app.bind(channel));
+            app_id
+        })
+    }
+
+    fn subscribe_requests(&self, callback: Option<Callback>, app_id: AppId) -> ReturnCode {
+        let result = self.apps
+            .enter(app_id, |app, _| {
+                if callback.is_none() {
+                    app.channels = [0; CHANNELS_PER_APP];
+                    app.request = None;
+                    app.response = None;
+                }
+                app.request_callback = callback;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        // A process restarting lets go of the channels and requests of the
+        // one before.
+        self.cancel_abandoned();
+        self.release_held();
+        result
+    }
+
+    fn set_delivered(&self, app_id: AppId, channel: u32) {
+        self.delivered
+            .iter()
+            .find(|entry| entry.get().is_none())
+            .map(|free| free.set(Some((app_id, channel))));
+    }
+
+    fn clear_delivered(&self, channel: u32) {
+        self.delivered
+            .iter()
+            .find(|entry| entry.get().map(|(_, delivered)| delivered) == Some(channel))
+            .map(|entry| entry.set(None));
+    }
+
+    /// Cancels the delivered requests whose processes are no longer
+    /// answering them: they died, or unsubscribed and gave them up.
+    fn cancel_abandoned(&self) {
+        for entry in self.delivered.iter() {
+            if let Some((app_id, channel)) = entry.get() {
+                let answering = self.apps
+                    .enter(app_id, |app, _| app.request == Some(channel))
+                    .unwrap_or(false);
+                if !answering {
+                    entry.set(None);
+                    self.transport.cancel(channel);
+                }
+            }
+        }
+    }
+
+    fn respond(&self, app_id: AppId, len: usize) -> ReturnCode {
+        let capacity = self.tx_buffer.map_or(len, |buffer| buffer.len());
+        let queued = self.apps
+            .enter(app_id, |app, _| {
+                if app.request.is_none() || app.response.is_some() {
+                    return ReturnCode::EINVAL;
+                }
+                let allowed = match app.tx_buffer {
+                    Some(ref slice) => slice.len(),
+                    None => return ReturnCode::ENOMEM,
+                };
+                if len > allowed || len > capacity {
+                    return ReturnCode::ESIZE;
+                }
+                app.response = Some(len);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        self.cancel_abandoned();
+        if queued == ReturnCode::SUCCESS {
+            self.send_next();
+        }
+        queued
+    }
+
+    /// Sends the next response waiting, if the transmit buffer is free:
+    /// the empty one for an unanswered channel first, then those of the
+    /// processes in turn.
+    fn send_next(&self) {
+        let buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        if let Some(channel) = self.unanswered.take() {
+            if let Err((_, buffer)) = self.transport.respond(channel, COMMAND_MSG, buffer, 0) {
+                self.tx_buffer.replace(buffer);
+                self.send_next();
+            }
+            return;
+        }
+        let app_id = match self.next_app(self.last_sent.get(), |app| app.response.is_some()) {
+            Some(app_id) => app_id,
+            None => {
+                self.tx_buffer.replace(buffer);
+                return;
+            }
+        };
+        self.last_sent.set(app_id.idx());
+        let response = self.apps
+            .enter(app_id, |app, _| {
+                let channel = app.request.take();
+                let len = app.response.take().unwrap_or(0);
+                let copied = app.tx_buffer.as_ref().map_or(false, |slice| {
+                    if len > slice.len() || len > buffer.len() {
+                        return false;
+                    }
+                    buffer[..len].copy_from_slice(&slice.as_ref()[..len]);
+                    true
+                });
+                channel.map(|channel| (channel, if copied { len } else { 0 }))
+            })
+            .unwrap_or(None);
+        match response {
+            Some((channel, len)) => {
+                self.clear_delivered(channel);
+                match self.transport.respond(channel, COMMAND_MSG, buffer, len) {
+                    Ok(()) => self.sending.set(Some(app_id)),
+                    Err((_, buffer)) => {
+                        self.tx_buffer.replace(buffer);
+                        self.transport.cancel(channel);
+                    }
+                }
+            }
+            None => {
+                self.tx_buffer.replace(buffer);
+            }
+        }
+        // The process can take the request held for it now.
+        self.release_held();
+    }
+
+    /// Tries again to deliver the request held for a busy process, which
+    /// may have answered or gone away since.
+    fn release_held(&self) {
+        self.held.take().map(|(channel, command, len)| {
+            self.rx_buffer.take().map(|buffer| self.request_received(channel, command, buffer, len));
+        });
+    }
+}
+
+impl<'a, T: U2fTransport + 'a> U2fClient for U2fDriver<'a, T> {
+    fn request_received(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize) {
+        // Some(true) if delivered, Some(false) if the process is busy
+        let delivered = self.owner(channel).and_then(|owner| {
+            self.apps
+                .enter(owner, |app, _| {
+                    if app.request.is_some() {
+                        return false;
+                    }
+                    let copied = app.rx_buffer.as_mut().map_or(0, |slice| {
+                        let count = cmp::min(len, slice.len());
+                        slice.as_mut()[..count].copy_from_slice(&buffer[..count]);
+                        count
+                    });
+                    app.request = Some(channel);
+                    app.request_callback.map(|mut cb| cb.schedule(copied, command as usize, 0));
+                    true
+                })
+                .ok()
+                .map(|delivered| {
+                    if delivered {
+                        self.set_delivered(owner, channel);
+                    }
+                    delivered
+                })
+        });
+        match delivered {
+            Some(true) => {
+                self.transport.receive(buffer);
+            }
+            Some(false) => {
+                self.held.set(Some((channel, command, len)));
+                self.rx_buffer.replace(buffer);
+            }
+            None => {
+                // Nobody to answer; send an empty response so the channel
+                // isn't left waiting.
+                self.unanswered.set(Some(channel));
+                self.transport.receive(buffer);
+                self.send_next();
+            }
+        }
+    }
+
+    fn response_sent(&self, buffer: &'static mut [u8]) {
+        self.tx_buffer.replace(buffer);
+        self.sending.take().map(|owner| {
+            let _ = self.apps.enter(owner, |app, _| {
+                app.response_callback.map(|mut cb| cb.schedule(0, 0, 0));
+            });
+        });
+        self.send_next();
+    }
+}
+
+impl<'a, T: U2fTransport + 'a> Driver for U2fDriver<'a, T> {
+    fn subscribe(&self,
+                 subscribe_num: usize,
+                 callback: Option<Callback>,
+                 app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.subscribe_requests(callback, app_id),
+            1 => {
+                self.apps
+                    .enter(app_id, |app, _| {
+                        app.response_callback = callback;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, _: usize, caller_id: AppId) -> ReturnCode {
+        match command_num {
+            0 /* Check if present */ => ReturnCode::SUCCESS,
+            1 /* Send response */ => self.respond(caller_id, arg1),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn allow(&self,
+             app_id: AppId,
+             minor_num: usize,
+             slice: Option<AppSlice<Shared, u8>>
+    ) -> ReturnCode {
+        match minor_num {
+            0 | 1 => {
+                self.apps
+                    .enter(app_id, |app, _| {
+                        if minor_num == 0 {
+                            app.rx_buffer = slice;
+                        } else {
+                            app.tx_buffer = slice;
+                        }
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
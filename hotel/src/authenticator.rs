@@ -0,0 +1,531 @@
+//! U2F authenticator in the kernel
+//!
+//! `Authenticator` answers U2F requests from a `hil::u2f` transport
+//! itself, for boards that don't want the authenticator in a process: it
+//! is the transport's client in place of the U2F syscall driver. It
+//! implements `U2F_REGISTER`, `U2F_AUTHENTICATE` and `U2F_VERSION` of the
+//! FIDO U2F raw message format; other instructions are refused with
+//! `SW_INS_NOT_SUPPORTED`.
+//!
+//! Keys are never stored. A key handle is a random nonce followed by the
+//! HMAC of the application parameter and the nonce, keyed with the key
+//! ladder's output, and the private key for it is another HMAC of the same
+//! (see `derive`). So only this device can use its key handles, and only
+//! for the application they were registered for.
+//!
+//! The user is present while the user-presence button is held, and
+//! registering or an authentication that enforces presence is refused with
+//! `SW_CONDITIONS_NOT_SATISFIED` otherwise, which hosts retry. Requests
+//! that arrive while an app is using the SHA engine, or while the ECDSA
+//! engine is busy, get the same status so they are retried too. The
+//! authentication counter is `counter::COUNTER`, and registrations are
+//! signed with the attestation key and certificate from the personality,
+//! so the device must have been provisioned.
+//!
+//! golf2 uses it, with `crypto::p256::P256` as the engine, when built with
+//! the `kernel_u2f` feature.
+
+use core::cell::Cell;
+use core::ptr;
+use counter::Counter;
+use crypto::sha::ShaEngine;
+use fault;
+use hil::digest::{DigestEngine, DigestError, DigestMode};
+use hil::ecdsa::{EcdsaClient, EcdsaP256, POINT_LEN, SCALAR_LEN};
+use hil::u2f::{U2fClient, U2fTransport, COMMAND_MSG};
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use kernel::hil::gpio;
+use personality::Personality;
+use trace;
+use trng::Trng;
+
+const U2F_REGISTER: u8 = 0x01;
+const U2F_AUTHENTICATE: u8 = 0x02;
+const U2F_VERSION: u8 = 0x03;
+
+/// `U2F_AUTHENTICATE` P1 values
+const AUTH_ENFORCE: u8 = 0x03;
+const AUTH_CHECK_ONLY: u8 = 0x07;
+const AUTH_DONT_ENFORCE: u8 = 0x08;
+
+const SW_NO_ERROR: u16 = 0x9000;
+const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+const SW_WRONG_DATA: u16 = 0x6a80;
+const SW_WRONG_LENGTH: u16 = 0x6700;
+const SW_CLA_NOT_SUPPORTED: u16 = 0x6e00;
+const SW_INS_NOT_SUPPORTED: u16 = 0x6d00;
+/// No precise diagnosis, e.g. the device isn't provisioned
+const SW_UNKNOWN: u16 = 0x6f00;
+
+const VERSION: &'static [u8] = b"U2F_V2";
+
+/// Length of the challenge and application parameters
+const PARAMETER_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+const KEY_HANDLE_LEN: usize = NONCE_LEN + 32;
+
+/// Longest DER-encoded signature
+const MAX_SIGNATURE_LEN: usize = 72;
+
+/// Register response: reserved byte, public key, key handle length and
+/// key handle, then the certificate and signature
+const REGISTER_RESERVED: u8 = 0x05;
+const REGISTER_HEADER_LEN: usize = 1 + 1 + POINT_LEN + 1 + KEY_HANDLE_LEN;
+/// Authenticate response: user presence and counter, then the signature
+const AUTHENTICATE_HEADER_LEN: usize = 1 + 4;
+
+/// Uncompressed point
+const POINT_TAG: u8 = 0x04;
+
+const LABEL_PRIVATE_KEY: &'static [u8] = b"u2f private key";
+const LABEL_KEY_HANDLE: &'static [u8] = b"u2f key handle";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Idle,
+    /// Computing the new key's public key
+    PublicKey,
+    /// Signing a registration; the signature goes after the certificate
+    SignRegistration(usize),
+    SignAuthentication,
+}
+
+pub struct Authenticator<'a, T: U2fTransport + 'a, E: EcdsaP256 + 'a, P: gpio::Pin + 'a> {
+    transport: &'a T,
+    ecdsa: &'a E,
+    sha: &'a ShaEngine,
+    trng: &'a Trng<'a>,
+    counter: &'a Counter,
+    personality: &'a Personality,
+    presence: &'a P,
+    pressed_when_low: bool,
+
+    // The buffer requests arrive in and responses go out of
+    buffer: TakeCell<'static, [u8]>,
+    // Where the engine puts points and signatures
+    scratch: TakeCell<'static, [u8]>,
+
+    // The request being answered
+    channel: Cell<u32>,
+    operation: Cell<Operation>,
+    challenge: Cell<[u8; PARAMETER_LEN]>,
+    application: Cell<[u8; PARAMETER_LEN]>,
+    key_handle: Cell<[u8; KEY_HANDLE_LEN]>,
+    // The user presence and counter an authentication signs
+    authentication: Cell<[u8; AUTHENTICATE_HEADER_LEN]>,
+}
+
+/// The status for a digest the SHA engine refused to start: the host
+/// retries if it is in use.
+fn digest_status(error: DigestError) -> u16 {
+    match error {
+        DigestError::Busy => SW_CONDITIONS_NOT_SATISFIED,
+        _ => SW_UNKNOWN,
+    }
+}
+
+/// The status for an operation the ECDSA engine refused to start.
+fn ecdsa_status(error: ReturnCode) -> u16 {
+    match error {
+        ReturnCode::EBUSY => SW_CONDITIONS_NOT_SATISFIED,
+        _ => SW_UNKNOWN,
+    }
+}
+
+fn wipe(key: &mut [u8; SCALAR_LEN]) {
+    unsafe { ptr::write_volatile(key, [0; SCALAR_LEN]) };
+}
+
+/// Writes `value` as a DER INTEGER, returning its length.
+fn encode_integer(value: &[u8], out: &mut [u8]) -> usize {
+    let start = value.iter().position(|&byte| byte != 0).unwrap_or(value.len() - 1);
+    let value = &value[start..];
+    // A set top bit would make the integer negative.
+    let pad = (value[0] & 0x80 != 0) as usize;
+    out[0] = 0x02;
+    out[1] = (pad + value.len()) as u8;
+    out[2] = 0;
+    out[2 + pad..2 + pad + value.len()].copy_from_slice(value);
+    2 + pad + value.len()
+}
+
+/// Writes the raw signature `r || s` as a DER SEQUENCE of two INTEGERs,
+/// returning its length.
+fn encode_signature(raw: &[u8], out: &mut [u8]) -> usize {
+    let r_len = encode_integer(&raw[..SCALAR_LEN], &mut out[2..]);
+    let s_len = encode_integer(&raw[SCALAR_LEN..POINT_LEN], &mut out[2 + r_len..]);
+    out[0] = 0x30;
+    out[1] = (r_len + s_len) as u8;
+    2 + r_len + s_len
+}
+
+/// Start and length of the request data of an APDU of `len` bytes, in
+/// extended or short encoding.
+fn request_data(apdu: &[u8], len: usize) -> Option<(usize, usize)> {
+    let (start, data_len) = if len <= 5 {
+        (len, 0)
+    } else if apdu[4] == 0 && len >= 7 {
+        (7, (apdu[5] as usize) << 8 | apdu[6] as usize)
+    } else {
+        (5, apdu[4] as usize)
+    };
+    if start + data_len > len {
+        return None;
+    }
+    Some((start, data_len))
+}
+
+impl<'a, T: U2fTransport + 'a, E: EcdsaP256 + 'a, P: gpio::Pin + 'a> Authenticator<'a, T, E, P> {
+    /// `presence` must be configured as an input, and reads low while
+    /// pressed if `pressed_when_low`. `trng` must have been started, and
+    /// `scratch` hold at least `POINT_LEN` bytes.
+    pub fn new(transport: &'a T,
+               ecdsa: &'a E,
+               sha: &'a ShaEngine,
+               trng: &'a Trng<'a>,
+               counter: &'a Counter,
+               personality: &'a Personality,
+               presence: &'a P,
+               pressed_when_low: bool,
+               buffer: &'static mut [u8],
+               scratch: &'static mut [u8])
+               -> Authenticator<'a, T, E, P> {
+        Authenticator {
+            transport: transport,
+            ecdsa: ecdsa,
+            sha: sha,
+            trng: trng,
+            counter: counter,
+            personality: personality,
+            presence: presence,
+            pressed_when_low: pressed_when_low,
+            buffer: TakeCell::new(buffer),
+            scratch: TakeCell::new(scratch),
+            channel: Cell::new(0),
+            operation: Cell::new(Operation::Idle),
+            challenge: Cell::new([0; PARAMETER_LEN]),
+            application: Cell::new([0; PARAMETER_LEN]),
+            key_handle: Cell::new([0; KEY_HANDLE_LEN]),
+            authentication: Cell::new([0; AUTHENTICATE_HEADER_LEN]),
+        }
+    }
+
+    /// Hands the buffer to the transport so requests can be received.
+    pub fn start(&self) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EALREADY, |buffer| self.transport.receive(buffer))
+    }
+
+    fn user_present(&self) -> bool {
+        self.presence.read() != self.pressed_when_low
+    }
+
+    /// HMAC of `label`, the application parameter and `nonce`, keyed with
+    /// the key ladder's output.
+    fn derive(&self, label: &[u8], application: &[u8], nonce: &[u8], out: &mut [u8; 32]) -> Result<(), u16> {
+        self.sha.initialize_hidden_key_hmac().map_err(digest_status)?;
+        self.finish_digest(&[label, application, nonce], out)
+    }
+
+    /// SHA-256 of `parts` in order.
+    fn digest(&self, parts: &[&[u8]], out: &mut [u8; SCALAR_LEN]) -> Result<(), u16> {
+        self.sha.initialize(DigestMode::Sha256).map_err(digest_status)?;
+        self.finish_digest(parts, out)
+    }
+
+    /// Adds `parts` to the digest just started and finishes it into `out`,
+    /// leaving the SHA engine free either way.
+    fn finish_digest(&self, parts: &[&[u8]], out: &mut [u8]) -> Result<(), u16> {
+        let computed = parts.iter().all(|part| self.sha.update(part).is_ok()) &&
+            self.sha.finalize(out).is_ok();
+        if !computed {
+            self.sha.cancel();
+            return Err(SW_UNKNOWN);
+        }
+        Ok(())
+    }
+
+    /// Sends the first `len` bytes of `buffer` followed by `status`.
+    fn respond(&self, buffer: &'static mut [u8], len: usize, status: u16) {
+        self.operation.set(Operation::Idle);
+        let len = if len + 2 > buffer.len() { 0 } else { len };
+        buffer[len] = (status >> 8) as u8;
+        buffer[len + 1] = status as u8;
+        if let Err((_, buffer)) = self.transport.respond(self.channel.get(), COMMAND_MSG, buffer, len + 2) {
+            self.transport.receive(buffer);
+        }
+    }
+
+    /// Starts answering the request in `buffer`. Returns the length of the
+    /// response if it is already in the buffer, or None if it will be once
+    /// the engine is done.
+    fn dispatch(&self, buffer: &mut [u8], len: usize) -> Result<Option<usize>, u16> {
+        if len < 4 {
+            return Err(SW_WRONG_LENGTH);
+        }
+        if buffer[0] != 0 {
+            return Err(SW_CLA_NOT_SUPPORTED);
+        }
+        let instruction = buffer[1];
+        let p1 = buffer[2];
+        if instruction == U2F_VERSION {
+            buffer[..VERSION.len()].copy_from_slice(VERSION);
+            return Ok(Some(VERSION.len()));
+        }
+        let (start, data_len) = match request_data(buffer, len) {
+            Some(data) => data,
+            None => return Err(SW_WRONG_LENGTH),
+        };
+        let data = &buffer[start..start + data_len];
+        let result = match instruction {
+            U2F_REGISTER => self.register(data, buffer.len()),
+            U2F_AUTHENTICATE => self.authenticate(p1, data),
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        };
+        result.map(|_| None)
+    }
+
+    fn register(&self, data: &[u8], capacity: usize) -> Result<(), u16> {
+        if data.len() != 2 * PARAMETER_LEN {
+            return Err(SW_WRONG_LENGTH);
+        }
+        let certificate_len = match (self.personality.attestation_key(),
+                                     self.personality.attestation_certificate()) {
+            (Some(key), Some(certificate)) if key.len() == SCALAR_LEN => certificate.len(),
+            _ => return Err(SW_UNKNOWN),
+        };
+        if REGISTER_HEADER_LEN + certificate_len + MAX_SIGNATURE_LEN + 2 > capacity {
+            return Err(SW_UNKNOWN);
+        }
+        if !fault::check(|| self.user_present()) {
+            return Err(SW_CONDITIONS_NOT_SATISFIED);
+        }
+
+        let mut challenge = [0; PARAMETER_LEN];
+        let mut application = [0; PARAMETER_LEN];
+        challenge.copy_from_slice(&data[..PARAMETER_LEN]);
+        application.copy_from_slice(&data[PARAMETER_LEN..]);
+        let mut key_handle = [0; KEY_HANDLE_LEN];
+        for word in key_handle[..NONCE_LEN].chunks_mut(4) {
+            // The host retries if the TRNG can't keep up.
+            let random = match self.trng.try_read() {
+                Some(random) => random,
+                None => return Err(SW_CONDITIONS_NOT_SATISFIED),
+            };
+            for (i, byte) in word.iter_mut().enumerate() {
+                *byte = (random >> (i * 8)) as u8;
+            }
+        }
+        let mut tag = [0; 32];
+        let mut private_key = [0; SCALAR_LEN];
+        let derived = self.derive(LABEL_KEY_HANDLE, &application, &key_handle[..NONCE_LEN], &mut tag)
+            .and_then(|_| self.derive(LABEL_PRIVATE_KEY, &application, &key_handle[..NONCE_LEN], &mut private_key));
+        key_handle[NONCE_LEN..].copy_from_slice(&tag);
+        self.challenge.set(challenge);
+        self.application.set(application);
+        self.key_handle.set(key_handle);
+
+        let result = derived.and_then(|_| match self.scratch.take() {
+            Some(scratch) => {
+                self.ecdsa.public_key(&private_key, scratch).map_err(|(error, scratch)| {
+                    trace::record("u2f public key failed", usize::from(error) as u32);
+                    self.scratch.replace(scratch);
+                    ecdsa_status(error)
+                })
+            }
+            None => Err(SW_UNKNOWN),
+        });
+        wipe(&mut private_key);
+        if result.is_ok() {
+            self.operation.set(Operation::PublicKey);
+        }
+        result
+    }
+
+    fn authenticate(&self, p1: u8, data: &[u8]) -> Result<(), u16> {
+        if data.len() < 2 * PARAMETER_LEN + 1 {
+            return Err(SW_WRONG_LENGTH);
+        }
+        let handle = &data[2 * PARAMETER_LEN + 1..];
+        if data[2 * PARAMETER_LEN] as usize != KEY_HANDLE_LEN || handle.len() != KEY_HANDLE_LEN {
+            return Err(SW_WRONG_DATA);
+        }
+        let challenge = &data[..PARAMETER_LEN];
+        let application = &data[PARAMETER_LEN..2 * PARAMETER_LEN];
+        let nonce = &handle[..NONCE_LEN];
+
+        // A key handle this device didn't make for this application is
+        // wrong data. Compare every byte, so the time taken doesn't say
+        // where the first difference is.
+        let mut tag = [0; 32];
+        self.derive(LABEL_KEY_HANDLE, application, nonce, &mut tag)?;
+        let matches = || tag.iter().zip(handle[NONCE_LEN..].iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+        if !fault::check(matches) {
+            return Err(SW_WRONG_DATA);
+        }
+        let present = fault::check(|| self.user_present());
+        match p1 {
+            // The handle is ours, which is reported like this.
+            AUTH_CHECK_ONLY => return Err(SW_CONDITIONS_NOT_SATISFIED),
+            AUTH_ENFORCE if !present => return Err(SW_CONDITIONS_NOT_SATISFIED),
+            AUTH_ENFORCE | AUTH_DONT_ENFORCE => {}
+            _ => return Err(SW_WRONG_DATA),
+        }
+
+        let count = match self.counter.increment() {
+            Ok(count) => count,
+            Err(error) => {
+                trace::record("u2f counter failed", usize::from(error) as u32);
+                return Err(SW_UNKNOWN);
+            }
+        };
+        let mut header = [0; AUTHENTICATE_HEADER_LEN];
+        header[0] = present as u8;
+        for i in 0..4 {
+            header[1 + i] = (count >> (24 - 8 * i)) as u8;
+        }
+        let mut digest = [0; SCALAR_LEN];
+        let mut private_key = [0; SCALAR_LEN];
+        let ready = self.digest(&[application, &header, challenge], &mut digest)
+            .and_then(|_| self.derive(LABEL_PRIVATE_KEY, application, nonce, &mut private_key));
+        self.authentication.set(header);
+
+        let result = ready.and_then(|_| match self.scratch.take() {
+            Some(scratch) => {
+                self.ecdsa.sign(&private_key, &digest, scratch).map_err(|(error, scratch)| {
+                    trace::record("u2f sign failed", usize::from(error) as u32);
+                    self.scratch.replace(scratch);
+                    ecdsa_status(error)
+                })
+            }
+            None => Err(SW_UNKNOWN),
+        });
+        wipe(&mut private_key);
+        if result.is_ok() {
+            self.operation.set(Operation::SignAuthentication);
+        }
+        result
+    }
+
+    /// Writes the registration up to the signature, and starts signing it
+    /// with the attestation key.
+    fn sign_registration(&self, buffer: &mut [u8], point: &'static mut [u8]) -> Result<(), u16> {
+        let (key, certificate) = match (self.personality.attestation_key(),
+                                        self.personality.attestation_certificate()) {
+            (Some(key), Some(certificate)) => (key, certificate),
+            _ => {
+                self.scratch.replace(point);
+                return Err(SW_UNKNOWN);
+            }
+        };
+        let key_handle = self.key_handle.get();
+        buffer[0] = REGISTER_RESERVED;
+        buffer[1] = POINT_TAG;
+        buffer[2..2 + POINT_LEN].copy_from_slice(&point[..POINT_LEN]);
+        buffer[2 + POINT_LEN] = KEY_HANDLE_LEN as u8;
+        buffer[3 + POINT_LEN..REGISTER_HEADER_LEN].copy_from_slice(&key_handle);
+        let signature_offset = REGISTER_HEADER_LEN + certificate.len();
+        buffer[REGISTER_HEADER_LEN..signature_offset].copy_from_slice(certificate);
+
+        let mut digest = [0; SCALAR_LEN];
+        let digested = self.digest(&[&[0],
+                                     &self.application.get(),
+                                     &self.challenge.get(),
+                                     &key_handle,
+                                     &buffer[1..2 + POINT_LEN]],
+                                   &mut digest);
+        if let Err(status) = digested {
+            self.scratch.replace(point);
+            return Err(status);
+        }
+        let mut private_key = [0; SCALAR_LEN];
+        private_key.copy_from_slice(key);
+        let result = self.ecdsa.sign(&private_key, &digest, point).map_err(|(error, point)| {
+            trace::record("u2f attestation failed", usize::from(error) as u32);
+            self.scratch.replace(point);
+            ecdsa_status(error)
+        });
+        wipe(&mut private_key);
+        if result.is_ok() {
+            self.operation.set(Operation::SignRegistration(signature_offset));
+        }
+        result
+    }
+}
+
+impl<'a, T: U2fTransport + 'a, E: EcdsaP256 + 'a, P: gpio::Pin + 'a> U2fClient for Authenticator<'a, T, E, P> {
+    fn request_received(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize) {
+        self.channel.set(channel);
+        let result = if command == COMMAND_MSG {
+            self.dispatch(buffer, len)
+        } else {
+            Err(SW_INS_NOT_SUPPORTED)
+        };
+        match result {
+            Ok(Some(response_len)) => self.respond(buffer, response_len, SW_NO_ERROR),
+            // Wait for the engine.
+            Ok(None) => {
+                self.buffer.replace(buffer);
+            }
+            Err(status) => self.respond(buffer, 0, status),
+        }
+    }
+
+    fn response_sent(&self, buffer: &'static mut [u8]) {
+        self.transport.receive(buffer);
+    }
+}
+
+impl<'a, T: U2fTransport + 'a, E: EcdsaP256 + 'a, P: gpio::Pin + 'a> EcdsaClient for Authenticator<'a, T, E, P> {
+    fn public_key_done(&self, result: ReturnCode, point: &'static mut [u8]) {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.scratch.replace(point);
+                return;
+            }
+        };
+        if result != ReturnCode::SUCCESS || self.operation.get() != Operation::PublicKey {
+            self.scratch.replace(point);
+            self.respond(buffer, 0, SW_UNKNOWN);
+            return;
+        }
+        match self.sign_registration(buffer, point) {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+            }
+            Err(status) => self.respond(buffer, 0, status),
+        }
+    }
+
+    fn sign_done(&self, result: ReturnCode, signature: &'static mut [u8]) {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.scratch.replace(signature);
+                return;
+            }
+        };
+        let offset = match self.operation.get() {
+            Operation::SignRegistration(offset) => offset,
+            Operation::SignAuthentication => AUTHENTICATE_HEADER_LEN,
+            _ => 0,
+        };
+        if result != ReturnCode::SUCCESS || offset == 0 {
+            self.scratch.replace(signature);
+            self.respond(buffer, 0, SW_UNKNOWN);
+            return;
+        }
+        if self.operation.get() == Operation::SignAuthentication {
+            buffer[..AUTHENTICATE_HEADER_LEN].copy_from_slice(&self.authentication.get());
+        }
+        let len = encode_signature(signature, &mut buffer[offset..]);
+        self.scratch.replace(signature);
+        self.respond(buffer, offset + len, SW_NO_ERROR);
+    }
+
+    fn verify_done(&self, _result: ReturnCode, signature: &'static mut [u8]) {
+        // The authenticator never verifies.
+        self.scratch.replace(signature);
+    }
+}
@@ -0,0 +1,80 @@
+//! Firmware version and build information
+//!
+//! The board describes the image it is part of with a `BuildInfo` static,
+//! filled in at compile time, and registers it to answer
+//! `REQUEST_BUILD_INFO` vendor requests so host tooling can check exactly
+//! what is running (e.g. before provisioning secrets); a syscall driver
+//! can hand apps the same bytes. `serialize` lays the information out as:
+//!
+//! | Offset | Size | Contents                                      |
+//! | ------ | ---- | :-------------------------------------------- |
+//! | 0      | 4    | `BUILD_INFO_MAGIC`, little-endian             |
+//! | 4      | 4    | Build time, seconds since the Unix epoch (LE) |
+//! | 8      | 1    | Length V of the version                       |
+//! | 9      | V    | Version, e.g. "0.1.0"                         |
+//! | 9+V    | 1    | Length G of the git revision                  |
+//! | 10+V   | G    | Git revision, with "-dirty" for local changes |
+//!
+//! Each string is cut to `MAX_STRING_LEN` bytes.
+
+use core::cmp;
+use kernel::ReturnCode;
+use usb::{VendorHandler, VendorRequest, USB0};
+
+pub const REQUEST_BUILD_INFO: u8 = 0x15;
+
+/// "BILD"
+pub const BUILD_INFO_MAGIC: u32 = 0x444c4942;
+
+/// Longest version or git revision reported
+pub const MAX_STRING_LEN: usize = 24;
+
+/// Longest serialized `BuildInfo`
+pub const MAX_BUILD_INFO_LEN: usize = 10 + 2 * MAX_STRING_LEN;
+
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_revision: &'static str,
+    /// Seconds since the Unix epoch, in decimal
+    pub timestamp: &'static str,
+}
+
+impl BuildInfo {
+    /// Answers `REQUEST_BUILD_INFO` with this information.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe { USB0.add_vendor_handler(REQUEST_BUILD_INFO, self) }
+    }
+
+    /// Build time in seconds since the Unix epoch, or 0 if unknown.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp.parse().unwrap_or(0)
+    }
+
+    /// Writes the information into `buffer` in the format above, cut to
+    /// its length. Returns how many bytes were written.
+    pub fn serialize(&self, buffer: &mut [u8]) -> usize {
+        let mut bytes = [0; MAX_BUILD_INFO_LEN];
+        let mut len = 0;
+        for &word in [BUILD_INFO_MAGIC, self.timestamp()].iter() {
+            for i in 0..4 {
+                bytes[len + i] = (word >> (i * 8)) as u8;
+            }
+            len += 4;
+        }
+        for string in [self.version, self.git_revision].iter() {
+            let string_len = cmp::min(string.len(), MAX_STRING_LEN);
+            bytes[len] = string_len as u8;
+            bytes[len + 1..len + 1 + string_len].copy_from_slice(&string.as_bytes()[..string_len]);
+            len += 1 + string_len;
+        }
+        let len = cmp::min(len, buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+        len
+    }
+}
+
+impl VendorHandler for BuildInfo {
+    fn vendor_request(&self, _request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        Ok(self.serialize(data))
+    }
+}
@@ -0,0 +1,206 @@
+//! Wall-clock time on the always-on timer
+//!
+//! `CALENDAR` keeps the time of day on TIMELS1, which runs from the
+//! always-on 256Khz oscillator and so keeps counting through deep sleep.
+//! The 32-bit count wraps every ~16 seconds, so the calendar takes TIMELS1
+//! over as its alarm and folds the elapsed ticks into a 64-bit microsecond
+//! count every `FOLD_TICKS`.
+//!
+//! The oscillator is only accurate to a percent or two. While USB is
+//! connected the calendar measures it against the host's Start of Frame
+//! (as `xo` does for the high-speed clock) and converts ticks using the
+//! measured frequency, which brings drift down to tens of ppm. It stops
+//! asking for frames once two windows agree to within `SETTLED_HZ`, until
+//! the measurement is restarted.
+//!
+//! The time is unknown until set with `hil::wallclock::WallClock`. On each
+//! fold the current second is saved in the PMU's long-life scratch
+//! registers, so after any reset other than a power on (including the
+//! reset that ends deep sleep) the time resumes from the saved value,
+//! reported with `TimeQuality::Restored` since the time spent in reset or
+//! asleep isn't known.
+
+use core::cell::Cell;
+use core::cmp;
+use hil::wallclock::{TimeQuality, WallClock};
+use kernel::hil::time::{self, Alarm};
+use pmu::{self, ScratchWord};
+use timels::TIMELS1;
+
+/// Nominal TIMELS frequency
+const NOMINAL_HZ: u32 = 256000;
+
+/// Measured frequencies further than this from nominal are discarded.
+const MAX_DEVIATION_HZ: u32 = NOMINAL_HZ / 20;
+
+/// Ticks between folds (4 seconds), well inside the 32-bit wrap.
+const FOLD_TICKS: u32 = 4 * NOMINAL_HZ;
+
+/// Frames per drift measurement window (~4 seconds).
+const WINDOW_FRAMES: u32 = 4096;
+
+/// A measurement this close to the last one ends the measurement (~20ppm).
+const SETTLED_HZ: u32 = 5;
+
+/// Frame numbers are 11 bits on a full-speed bus.
+const FRAME_MASK: u32 = 0x7ff;
+
+/// Marks the saved time in `ScratchWord::Scratch2` as valid
+const SAVED_MAGIC: u32 = 0xca1e_0001;
+
+pub static mut CALENDAR: Calendar = Calendar::new();
+
+pub struct Calendar {
+    // Microseconds since the epoch at `last_ticks`
+    epoch_us: Cell<u64>,
+    last_ticks: Cell<u32>,
+    quality: Cell<Option<TimeQuality>>,
+    frequency_hz: Cell<u32>,
+    // Tick count at the start of the current drift measurement window
+    window_start: Cell<Option<u32>>,
+    last_frame: Cell<u32>,
+    frames: Cell<u32>,
+    settled: Cell<bool>,
+}
+
+impl Calendar {
+    const fn new() -> Calendar {
+        Calendar {
+            epoch_us: Cell::new(0),
+            last_ticks: Cell::new(0),
+            quality: Cell::new(None),
+            frequency_hz: Cell::new(NOMINAL_HZ),
+            window_start: Cell::new(None),
+            last_frame: Cell::new(0),
+            frames: Cell::new(0),
+            settled: Cell::new(false),
+        }
+    }
+
+    /// Takes over TIMELS1 and restores the time saved before the last
+    /// reset, if any.
+    pub fn init(&'static self) {
+        unsafe {
+            TIMELS1.set_client(self);
+        }
+        if pmu::scratch(ScratchWord::Scratch2) == SAVED_MAGIC {
+            self.epoch_us.set(pmu::scratch(ScratchWord::Scratch1) as u64 * 1_000_000);
+            self.quality.set(Some(TimeQuality::Restored));
+        }
+        self.last_ticks.set(self.ticks());
+        self.schedule_fold();
+    }
+
+    fn ticks(&self) -> u32 {
+        unsafe { Alarm::now(&TIMELS1) }
+    }
+
+    fn schedule_fold(&self) {
+        let next = self.last_ticks.get().wrapping_add(FOLD_TICKS);
+        unsafe { TIMELS1.set_alarm(next) };
+    }
+
+    /// Converts the ticks elapsed since the last fold into `epoch_us`,
+    /// carrying any fraction of a microsecond over to the next fold.
+    fn fold(&self) {
+        let frequency = self.frequency_hz.get() as u64;
+        let elapsed = self.ticks().wrapping_sub(self.last_ticks.get()) as u64;
+        let us = elapsed * 1_000_000 / frequency;
+        let consumed = (us * frequency / 1_000_000) as u32;
+        self.epoch_us.set(self.epoch_us.get() + us);
+        self.last_ticks.set(self.last_ticks.get().wrapping_add(consumed));
+    }
+
+    fn now_us(&self) -> u64 {
+        let elapsed = self.ticks().wrapping_sub(self.last_ticks.get()) as u64;
+        self.epoch_us.get() + elapsed * 1_000_000 / self.frequency_hz.get() as u64
+    }
+
+    /// Saves the current second for the next boot.
+    pub fn save(&self) {
+        if self.quality.get().is_none() {
+            return;
+        }
+        pmu::set_scratch(ScratchWord::Scratch1, (self.now_us() / 1_000_000) as u32);
+        pmu::set_scratch(ScratchWord::Scratch2, SAVED_MAGIC);
+    }
+
+    /// The measured TIMELS frequency in Hz.
+    pub fn frequency_hz(&self) -> u32 {
+        self.frequency_hz.get()
+    }
+
+    /// Discards the current drift measurement and measures again, e.g.
+    /// because the bus was reset or suspended and frames were missed.
+    pub fn restart_drift_measurement(&self) {
+        self.window_start.set(None);
+        self.frames.set(0);
+        self.settled.set(false);
+    }
+
+    /// Called by the USB driver on every Start of Frame with the frame
+    /// number from the device status register. Returns whether the drift
+    /// measurement still needs frames.
+    pub fn handle_sof(&self, frame: u32) -> bool {
+        if self.settled.get() {
+            return false;
+        }
+        let frame = frame & FRAME_MASK;
+        let now = self.ticks();
+        match self.window_start.get() {
+            None => {
+                self.window_start.set(Some(now));
+                self.last_frame.set(frame);
+                self.frames.set(0);
+            }
+            Some(start) => {
+                let elapsed = frame.wrapping_sub(self.last_frame.get()) & FRAME_MASK;
+                self.last_frame.set(frame);
+                self.frames.set(self.frames.get() + elapsed);
+                if self.frames.get() >= WINDOW_FRAMES {
+                    // Ticks per second, with frames a millisecond apart
+                    let measured = (now.wrapping_sub(start) as u64 * 1000 /
+                                    self.frames.get() as u64) as u32;
+                    if measured > NOMINAL_HZ - MAX_DEVIATION_HZ &&
+                        measured < NOMINAL_HZ + MAX_DEVIATION_HZ {
+                        // Ticks so far were at the old rate
+                        self.fold();
+                        let previous = self.frequency_hz.get();
+                        let difference = cmp::max(measured, previous) - cmp::min(measured, previous);
+                        self.settled.set(difference <= SETTLED_HZ);
+                        let smoothed = (self.frequency_hz.get() * 3 + measured) / 4;
+                        self.frequency_hz.set(smoothed);
+                    }
+                    self.window_start.set(Some(now));
+                    self.frames.set(0);
+                }
+            }
+        }
+        !self.settled.get()
+    }
+}
+
+impl time::Client for Calendar {
+    fn fired(&self) {
+        self.fold();
+        self.save();
+        self.schedule_fold();
+    }
+}
+
+impl WallClock for Calendar {
+    fn now_ms(&self) -> Option<u64> {
+        self.quality.get().map(|_| self.now_us() / 1000)
+    }
+
+    fn quality(&self) -> Option<TimeQuality> {
+        self.quality.get()
+    }
+
+    fn set_time_ms(&self, ms: u64) {
+        self.fold();
+        self.epoch_us.set(ms * 1000);
+        self.quality.set(Some(TimeQuality::Set));
+        self.save();
+    }
+}
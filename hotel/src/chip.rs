@@ -1,11 +1,28 @@
 use cortexm3;
 use crypto;
+use deferred_call::{self, Task};
 use gpio;
+use i2c;
+use irq_count;
+use irq_timing;
 use kernel::Chip;
+use kernel::hil::time::Time;
+use memory;
+use pmu::{self, WakeSource};
+use power_trace::{self, PowerState};
+use pwm;
+use rbox;
+use spi;
+use sps;
+use temp;
 use timels;
+use timestamp;
+use timeus;
 use trng;
 use uart;
 use usb;
+use volt;
+use watchdog::{Component, WATCHDOG0};
 
 pub struct Hotel {
     mpu: cortexm3::mpu::MPU,
@@ -29,31 +46,61 @@ impl Chip for Hotel {
     type SysTick = cortexm3::systick::SysTick;
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { cortexm3::nvic::next_pending().is_some() }
+        unsafe { cortexm3::nvic::next_pending().is_some() || deferred_call::has_tasks() }
     }
 
     fn service_pending_interrupts(&self) {
         unsafe {
+            // The kernel calls this once per pass of its main loop.
+            WATCHDOG0.feed();
+            memory::scan_stack();
+
             while let Some(nvic_num) = cortexm3::nvic::next_pending() {
+                WATCHDOG0.set_component(Component::Interrupt(nvic_num));
+                irq_count::fired(nvic_num);
+                let started = irq_timing::handler_start();
                 match nvic_num {
                     1 | 3 | 6 | 7 | 8 | 9 | 10 | 11 => crypto::dcrypto::DCRYPTO.handle_error_interrupt(nvic_num),
                     4 => crypto::dcrypto::DCRYPTO.handle_done_interrupt(),
                     5 => crypto::dcrypto::DCRYPTO.handle_receive_interrupt(),
                     
+                    99 => i2c::I2C0.handle_interrupt(),
+                    100 => i2c::I2C1.handle_interrupt(),
+                    101...103 => i2c::I2CS0.handle_interrupt(),
+
                     104...109 => crypto::aes::KEYMGR0_AES.handle_interrupt(nvic_num),
 
-                    110 => (), // KEYMGR0_DSHA_INT, currently polled
-                    111 => (), // KEYMGR0_SHA_WFIFO_FULL
+                    110 => irq_count::spurious(nvic_num), // KEYMGR0_DSHA_INT, currently polled
+                    111 => irq_count::spurious(nvic_num), // KEYMGR0_SHA_WFIFO_FULL
+
+                    115...124 => rbox::RBOX0.handle_interrupt(),
+
+                    138 => spi::SPI0.handle_interrupt(),
+                    139 => spi::SPI1.handle_interrupt(),
+                    140...144 => sps::SPS0.handle_interrupt(),
+
+                    149 => temp::TEMP0.handle_interrupt(),
 
                     159 => timels::TIMELS0.handle_interrupt(),
                     160 => timels::TIMELS1.handle_interrupt(),
 
+                    161 => timeus::TIMEUS0.handle_interrupt(),
+                    162 => timeus::TIMEUS1.handle_interrupt(),
+                    163 => pwm::PWM0.handle_off_interrupt(),
+                    164 => pwm::PWM1.handle_off_interrupt(),
+                    165 => timestamp::TIMESTAMP.handle_wrap_interrupt(),
+                    167 => pwm::PWM0.handle_on_interrupt(),
+                    168 => pwm::PWM1.handle_on_interrupt(),
+
                     169 => trng::TRNG0.handle_interrupt(),
 
+                    171...173 => uart::UART0.handle_error_interrupt(),
                     174 => uart::UART0.handle_rx_interrupt(),
                     177 => uart::UART0.handle_tx_interrupt(),
+                    178...180 => uart::UART1.handle_error_interrupt(),
                     181 => uart::UART1.handle_rx_interrupt(),
                     184 => uart::UART1.handle_tx_interrupt(),
+                    185...187 => uart::UART2.handle_error_interrupt(),
                     188 => uart::UART2.handle_rx_interrupt(),
                     191 => uart::UART2.handle_tx_interrupt(),
 
@@ -61,23 +108,39 @@ impl Chip for Hotel {
                         usb::USB0.handle_interrupt()
                     },
 
+                    194 => volt::VOLT0.handle_interrupt(),
+
                     pin @ 65...80 => {
                         gpio::PORT0.pins[(pin - 65) as usize].handle_interrupt();
                     }
                     81 => {
                         // GPIO Combined interrupt... why does this remain asserted?
+                        irq_count::spurious(nvic_num);
                     }
                     pin @ 82...97 => {
                         gpio::PORT1.pins[(pin - 82) as usize].handle_interrupt();
                     }
                     98 => {
                         // GPIO Combined interrupt... why does this remain asserted?
+                        irq_count::spurious(nvic_num);
                     }
                     _ => panic!("Unexected ISR {}", nvic_num),
                 }
+                irq_timing::handler_end(nvic_num, started);
                 cortexm3::nvic::Nvic::new(nvic_num).clear_pending();
                 cortexm3::nvic::Nvic::new(nvic_num).enable();
             }
+
+            // Run whatever the handlers above deferred, before returning to
+            // the kernel.
+            while let Some(task) = deferred_call::next_pending() {
+                WATCHDOG0.set_component(Component::Deferred(task));
+                match task {
+                    Task::Usb => usb::USB0.handle_deferred_call(),
+                    Task::UsbConsole => usb::USB_CONSOLE.handle_deferred_call(),
+                }
+            }
+            WATCHDOG0.set_component(Component::Kernel);
         }
     }
 
@@ -93,13 +156,39 @@ impl Chip for Hotel {
         &self.userspace_kernel_boundary
     }
     
+    /// Waits for an interrupt in the shallowest state that is safe:
+    /// napping, with the PMU gating the clocks of drivers that have no work
+    /// in flight, or a plain `wfi` if every clock it could gate is busy.
+    /// While the USB bus is suspended and every gateable clock is idle, it
+    /// sleeps deep instead, until a wake source (USB resume among them)
+    /// fires. The microsecond timers stop in deep sleep, so it doesn't go
+    /// there while one of them has an alarm armed.
     fn sleep(&self) {
+        let state = if !pmu::prepare_nap() {
+            PowerState::Idle
+        } else if !unsafe { usb::USB0.is_suspended() } {
+            PowerState::Nap
+        } else if !pmu::gateable_clocks_idle() ||
+                  unsafe { timeus::TIMEUS0.is_armed() || timeus::TIMEUS1.is_armed() } {
+            PowerState::Suspend
+        } else {
+            PowerState::DeepSleep
+        };
         unsafe {
+            if state == PowerState::DeepSleep {
+                pmu::enable_wake_source(WakeSource::UsbSuspend);
+                cortexm3::scb::set_sleepdeep();
+            } else {
                 cortexm3::scb::unset_sleepdeep();
-        }
-        
-        unsafe {
+            }
+            WATCHDOG0.set_component(Component::Sleep);
+            power_trace::transition(state);
             cortexm3::support::wfi();
+            power_trace::transition(PowerState::Run);
+            WATCHDOG0.set_component(Component::Kernel);
+            if state == PowerState::DeepSleep {
+                cortexm3::scb::unset_sleepdeep();
+            }
         }
     }
 
@@ -0,0 +1,127 @@
+//! A monotonic counter in flash
+//!
+//! The counter survives resets and never goes backwards, as U2F's
+//! signature counter has to. It lives in two pages of internal flash. Each
+//! page starts with a base value; every increment programs the next word
+//! of the active page to zero, so the page's value is its base plus the
+//! number of zeroed words. When the active page is full, the other page is
+//! erased and started with the current value as its base, and takes over:
+//! the active page is the one with the larger base (an erased page has
+//! none), so losing power at any point leaves the value where it was or
+//! one past it.
+//!
+//! ```ignore
+//! hotel::counter::COUNTER.init(&hotel::flash::FLASH0, start, 2 * hotel::flash::PAGE_SIZE);
+//! let value = hotel::counter::COUNTER.increment();
+//! ```
+
+use core::cell::Cell;
+use flash::{Flash, PAGE_SIZE};
+use kernel::ReturnCode;
+
+const WORDS_PER_PAGE: usize = PAGE_SIZE / 4;
+
+const ERASED: u32 = !0;
+
+pub static mut COUNTER: Counter = Counter::new();
+
+pub struct Counter {
+    flash: Cell<Option<&'static Flash>>,
+    start: Cell<usize>,
+}
+
+fn read_word(address: usize) -> u32 {
+    unsafe { *(address as *const u32) }
+}
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter {
+            flash: Cell::new(None),
+            start: Cell::new(0),
+        }
+    }
+
+    /// Keeps the counter in the two pages from `start`, which must be
+    /// page-aligned; `len` must be at least two pages.
+    pub fn init(&self, flash: &'static Flash, start: usize, len: usize) -> ReturnCode {
+        if start % PAGE_SIZE != 0 || len < 2 * PAGE_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        self.flash.set(Some(flash));
+        self.start.set(start);
+        ReturnCode::SUCCESS
+    }
+
+    fn page(&self, index: usize) -> usize {
+        self.start.get() + index * PAGE_SIZE
+    }
+
+    /// The page's base, if it has one, and how many increments it holds.
+    fn read_page(&self, index: usize) -> Option<(u32, usize)> {
+        let page = self.page(index);
+        let base = read_word(page);
+        if base == ERASED {
+            return None;
+        }
+        let used = (1..WORDS_PER_PAGE).take_while(|&i| read_word(page + 4 * i) != ERASED).count();
+        Some((base, used))
+    }
+
+    /// The active page, its base and increments.
+    fn active(&self) -> Option<(usize, u32, usize)> {
+        match (self.read_page(0), self.read_page(1)) {
+            (Some((base0, used0)), Some((base1, _))) if base0 >= base1 => Some((0, base0, used0)),
+            (_, Some((base1, used1))) => Some((1, base1, used1)),
+            (Some((base0, used0)), None) => Some((0, base0, used0)),
+            (None, None) => None,
+        }
+    }
+
+    /// The current value; zero before the first increment.
+    pub fn value(&self) -> u32 {
+        self.active().map_or(0, |(_, base, used)| base.wrapping_add(used as u32))
+    }
+
+    /// Adds one to the counter and returns the new value. Returns ENOMEM
+    /// once the counter has reached its limit, which it then stays at.
+    pub fn increment(&self) -> Result<u32, ReturnCode> {
+        let flash = match self.flash.get() {
+            Some(flash) => flash,
+            None => return Err(ReturnCode::EOFF),
+        };
+        let (index, base, used) = match self.active() {
+            Some(active) => active,
+            None => {
+                // Never used: start page 0 at zero.
+                let result = flash.program(self.page(0), &[0]);
+                if result != ReturnCode::SUCCESS {
+                    return Err(result);
+                }
+                (0, 0, 0)
+            }
+        };
+        let value = base.wrapping_add(used as u32);
+        if value >= ERASED - 1 {
+            return Err(ReturnCode::ENOMEM);
+        }
+        if used + 1 < WORDS_PER_PAGE {
+            let result = flash.program(self.page(index) + 4 * (used + 1), &[0]);
+            return match result {
+                ReturnCode::SUCCESS => Ok(value + 1),
+                error => Err(error),
+            };
+        }
+        // The active page is full: move to the other one, starting it at
+        // the new value.
+        let other = self.page(1 - index);
+        let result = flash.erase(other);
+        if result != ReturnCode::SUCCESS {
+            return Err(result);
+        }
+        match flash.program(other, &[value + 1]) {
+            ReturnCode::SUCCESS => Ok(value + 1),
+            error => Err(error),
+        }
+    }
+}
@@ -42,7 +42,9 @@ use kernel::common::cells::TakeCell;
 use kernel::common::cells::VolatileCell;
 use kernel::ReturnCode;
 
-use pmu::{Clock, PeripheralClock, PeripheralClock0, reset_dcrypto};
+use pmu::{Clock, PeripheralClock, PeripheralClock0, PeripheralReset};
+use profile::{self, Region};
+use volt::BrownoutClient;
 
 
 
@@ -53,6 +55,9 @@ const DCRYPTO_BASE: *mut Registers = DCRYPTO_BASE_ADDR as *mut Registers;
 
 pub static mut DCRYPTO: DcryptoEngine<'static> = unsafe {DcryptoEngine::new(DCRYPTO_BASE) };
 
+/// Cycles from calling a program to its completion interrupt
+pub static mut PROGRAM: Region = Region::new("dcrypto program");
+
 
 const DROM_OFFSET: u32 = 0x2000;
 const DROM_SIZE: usize = 1024;
@@ -233,6 +238,7 @@ pub struct DcryptoEngine<'a> {
     registers: *mut Registers,
     client: Cell<Option<&'a DcryptoClient<'a>>>,
     state: Cell<State>,
+    call_cycles: Cell<u32>,
     drom: TakeCell<'static, [u32; DROM_SIZE]>,
     dmem: TakeCell<'static, [u32; DMEM_SIZE]>,
     imem: TakeCell<'static, [u32; IMEM_SIZE]>
@@ -244,6 +250,7 @@ impl<'a> DcryptoEngine<'a> {
             registers: registers,
             client: Cell::new(None),
             state: Cell::new(State::Uninitialized),
+            call_cycles: Cell::new(0),
             drom: TakeCell::empty(),
             dmem: TakeCell::empty(),
             imem: TakeCell::empty(),
@@ -256,80 +263,85 @@ impl<'a> DcryptoEngine<'a> {
             self.dmem = TakeCell::new(mem::transmute(DCRYPTO_BASE_ADDR + DMEM_OFFSET));
             self.imem = TakeCell::new(mem::transmute(DCRYPTO_BASE_ADDR + IMEM_OFFSET));
         }
-                
-        let registers: &mut Registers = unsafe {mem::transmute(self.registers)};
-        
+
         // Note: this is a re-implementation of the C code for
         // the Cr52 dcrypto runtime -pal
         if self.state.get() != State::Uninitialized {
             ReturnCode::EALREADY
         } else {
             // Enable PMU and reset it
-            unsafe {Clock::new(PeripheralClock::Bank0(PeripheralClock0::Crypto0)).enable();}
-            reset_dcrypto();
-
-            // Turn off random no-ops
-            let mut stall = registers.rand_stall.get();
-            stall = stall & RAND_STALL_EN_MASK;
-            registers.rand_stall.set(stall);
-
-            // Configure random no-op percentage to 6%
-            stall = stall & RAND_STALL_FREQ_MASK;
-            stall = stall | RAND_STALL_FREQ_6;
-            registers.rand_stall.set(stall);
-
-            // Turn on random no-ops
-            stall = stall | RAND_STALL_EN;
-            registers.rand_stall.set(stall);
-
-            // Initialize dmem
-            self.dmem.map(|mem| {
-                for i in 0..DMEM_SIZE {
-                    mem[i] = 0xdddddddd;
-                }
-            });
-            // Initialize imem
-            self.imem.map(|mem| {
-                for i in 0..IMEM_SIZE {
-                    mem[i] = 0xdddddddd;
-                }
-            });
-
-            // Clear then enable all interrupts: the Cr52 implementation
-            // does this but also handles interrupts differently, so we
-            // selectively enable below. Left here for reference.
-            // registers.int_state.set(0xffffffff);
-            // registers.int_enable.set(0xffffffff);
-
-            // Clear all interrupts then enable done interrupt
-            // Note: implementation currently does not handle start
-            // interrupt due to NVIC re-ordering.
-            registers.int_state.set(0xffffffff);
-            let interrupts =
-                InterruptFlag::CommandDone as u32 |
-                InterruptFlag::DMemPointersOverflow as u32 |
-                InterruptFlag::DrfPointersOverflow as u32 |
-                InterruptFlag::LoopStackOverflow as u32 |
-                InterruptFlag::LoopStackUnderflow as u32 |
-                InterruptFlag::OperandOutofRange as u32 |
-                InterruptFlag::PCStackOverflow as u32 |
-                InterruptFlag::ProgramFault as u32 |
-                InterruptFlag::Trap as u32;
-                
-                
-            registers.int_enable.set(interrupts);
-            //InterruptFlag::CommandDone as u32);
-            //registers.int_enable.set(InterruptFlag::CommandDone as u32);
-            
-            // Reset
-            registers.control.set(1);
-            registers.control.set(0);
-
+            unsafe {Clock::new(PeripheralClock::Bank0(PeripheralClock0::Crypto0)).acquire();}
+            self.reset_engine();
             self.state.set(State::Halt);
             ReturnCode::SUCCESS
         }
     }
 
+    /// Pulses the engine's reset line and brings it back to the
+    /// configuration `initialize` leaves it in. Memories are
+    /// overwritten, so this also wipes any secrets they held.
+    fn reset_engine(&self) {
+        let registers: &mut Registers = unsafe {mem::transmute(self.registers)};
+        unsafe {PeripheralReset::new(PeripheralClock::Bank0(PeripheralClock0::Crypto0)).pulse();}
+
+        // Turn off random no-ops
+        let mut stall = registers.rand_stall.get();
+        stall = stall & RAND_STALL_EN_MASK;
+        registers.rand_stall.set(stall);
+
+        // Configure random no-op percentage to 6%
+        stall = stall & RAND_STALL_FREQ_MASK;
+        stall = stall | RAND_STALL_FREQ_6;
+        registers.rand_stall.set(stall);
+
+        // Turn on random no-ops
+        stall = stall | RAND_STALL_EN;
+        registers.rand_stall.set(stall);
+
+        // Initialize dmem
+        self.dmem.map(|mem| {
+            for i in 0..DMEM_SIZE {
+                mem[i] = 0xdddddddd;
+            }
+        });
+        // Initialize imem
+        self.imem.map(|mem| {
+            for i in 0..IMEM_SIZE {
+                mem[i] = 0xdddddddd;
+            }
+        });
+
+        // Clear then enable all interrupts: the Cr52 implementation
+        // does this but also handles interrupts differently, so we
+        // selectively enable below. Left here for reference.
+        // registers.int_state.set(0xffffffff);
+        // registers.int_enable.set(0xffffffff);
+
+        // Clear all interrupts then enable done interrupt
+        // Note: implementation currently does not handle start
+        // interrupt due to NVIC re-ordering.
+        registers.int_state.set(0xffffffff);
+        let interrupts =
+            InterruptFlag::CommandDone as u32 |
+            InterruptFlag::DMemPointersOverflow as u32 |
+            InterruptFlag::DrfPointersOverflow as u32 |
+            InterruptFlag::LoopStackOverflow as u32 |
+            InterruptFlag::LoopStackUnderflow as u32 |
+            InterruptFlag::OperandOutofRange as u32 |
+            InterruptFlag::PCStackOverflow as u32 |
+            InterruptFlag::ProgramFault as u32 |
+            InterruptFlag::Trap as u32;
+            
+            
+        registers.int_enable.set(interrupts);
+        //InterruptFlag::CommandDone as u32);
+        //registers.int_enable.set(InterruptFlag::CommandDone as u32);
+        
+        // Reset
+        registers.control.set(1);
+        registers.control.set(0);
+    }
+
     pub fn handle_error_interrupt(&self, nvic: u32) {
         let registers: &mut Registers = unsafe {mem::transmute(self.registers)};
         let cause = match nvic {
@@ -361,6 +373,7 @@ impl<'a> DcryptoEngine<'a> {
         };
         
         registers.int_state.set(flag as u32);
+        unsafe { PROGRAM.record(profile::cycles().wrapping_sub(self.call_cycles.get())) };
         let prior_state = self.state.get();
         self.state.set(State::Break);
         if prior_state == State::Running || prior_state == State::Break {
@@ -393,6 +406,7 @@ impl<'a> DcryptoEngine<'a> {
             let registers: &mut Registers = unsafe {mem::transmute(self.registers)};
             // Clear interrupt
             registers.int_state.set(InterruptFlag::CommandDone as u32);
+            unsafe { PROGRAM.record(profile::cycles().wrapping_sub(self.call_cycles.get())) };
 
             self.state.set(State::Halt);
             self.client.get().map(|client| {
@@ -406,6 +420,16 @@ impl<'a> DcryptoEngine<'a> {
     }
 }
 
+impl<'a> BrownoutClient for DcryptoEngine<'a> {
+    /// Wipe the engine's memories before power is lost.
+    fn brownout_warning(&self) {
+        if self.state.get() != State::Uninitialized {
+            self.reset_engine();
+            self.state.set(State::Halt);
+        }
+    }
+}
+
 impl<'a> Dcrypto<'a> for DcryptoEngine<'a> {
     fn set_client(&self, client: &'a DcryptoClient<'a>) {
         self.client.set(Some(client));
@@ -422,7 +446,7 @@ impl<'a> Dcrypto<'a> for DcryptoEngine<'a> {
         self.dmem.map(|mem| {
             for i in 0..length {
                 let index = (i * 4) as usize;
-                let word = mem[i as usize];
+                let word = mem[(offset + i) as usize];
                 data[index]     = (word       & 0xff) as u8;
                 data[index + 1] = (word >> 8  & 0xff) as u8;
                 data[index + 2] = (word >> 16 & 0xff) as u8;
@@ -468,7 +492,7 @@ impl<'a> Dcrypto<'a> for DcryptoEngine<'a> {
         self.imem.map(|mem| {
             for i in 0..length {
                 let index = (i * 4) as usize;
-                let word = mem[i as usize];
+                let word = mem[(offset + i) as usize];
                 instructions[index]     = (word       & 0xff) as u8;
                 instructions[index + 1] = (word >> 8  & 0xff) as u8;
                 instructions[index + 2] = (word >> 16 & 0xff) as u8;
@@ -548,6 +572,7 @@ impl<'a> Dcrypto<'a> for DcryptoEngine<'a> {
         
         registers.host_cmd.set(instruction);
         if is_call {
+            self.call_cycles.set(profile::cycles());
             self.state.set(State::Running);
         }
         ReturnCode::SUCCESS
@@ -558,7 +583,15 @@ impl<'a> Dcrypto<'a> for DcryptoEngine<'a> {
     }
 
     fn reset(&self) -> ReturnCode {
-        ReturnCode::FAIL
+        if self.state.get() == State::Uninitialized {
+            return ReturnCode::EOFF;
+        }
+        self.reset_engine();
+        self.state.set(State::Halt);
+        self.client.get().map(|client| {
+            client.reset_complete(ReturnCode::SUCCESS);
+        });
+        ReturnCode::SUCCESS
     }
 
     fn wipe_secrets(&self) -> ReturnCode {
@@ -2,5 +2,6 @@ pub mod keymgr;
 pub mod sha;
 pub mod aes;
 pub mod dcrypto;
+pub mod p256;
 
 const KEYMGR0_BASE_ADDRESS: usize = 0x40570000;
@@ -0,0 +1,389 @@
+//! ECDSA over P-256 on dcrypto
+//!
+//! `P256Engine` implements `hil::ecdsa::EcdsaP256` by running a P-256
+//! program on dcrypto. The kernel doesn't carry the program: the board
+//! passes its image to `init` (golf2 builds in the file named by
+//! `DCRYPTO_P256_PROGRAM`), and without one every operation fails with
+//! ENOSUPPORT.
+//!
+//! An image starts with a header of little-endian words, `PROGRAM_MAGIC`
+//! followed by the IMEM addresses of the public key, sign and verify
+//! routines, and the instructions after it are loaded at IMEM address 0.
+//! The routines find their inputs and leave their outputs in DMEM, one
+//! 256-bit value at each of the word offsets below, least significant word
+//! first. They check that scalars are in range and points are on the
+//! curve, and write `RESULT_OK` to the word at `RESULT` if the operation
+//! succeeded.
+//!
+//! | Routine    | Inputs        | Outputs |
+//! | ---------- | ------------- | ------- |
+//! | public key | D             | X, Y    |
+//! | sign       | D, K, E       | R, S    |
+//! | verify     | X, Y, E, R, S |         |
+//!
+//! The nonce K of a signature is the HMAC of the private key and the
+//! digest, keyed with the key ladder's output: it is secret and never
+//! repeats for different messages, without needing the TRNG. Secrets are
+//! only in DMEM while an operation runs: the engine resets dcrypto, which
+//! overwrites its memories, before reporting the result, and on a
+//! brownout warning.
+//!
+//! dcrypto has one client. The engine is that client and passes
+//! completions it didn't start on to the apps' driver, given to `init`; it
+//! refuses to start an operation with EBUSY while an app's program runs.
+
+use core::cell::Cell;
+use core::ptr;
+use crypto::dcrypto::{Dcrypto, DcryptoClient, DcryptoEngine, ProgramFault, State};
+use crypto::sha::ShaEngine;
+use hil::digest::DigestEngine;
+use hil::ecdsa::{EcdsaClient, EcdsaP256, POINT_LEN, SCALAR_LEN};
+use kernel::common::cells::TakeCell;
+use kernel::ReturnCode;
+use volt::BrownoutClient;
+
+/// First word of a program image
+pub const PROGRAM_MAGIC: u32 = 0x36353270;
+/// Magic and routine addresses
+const HEADER_LEN: usize = 16;
+
+// DMEM word offsets of the routines' inputs and outputs
+/// Private key
+const D: u32 = 0;
+/// Nonce
+const K: u32 = 8;
+/// Digest
+const E: u32 = 16;
+/// Public key
+const X: u32 = 24;
+const Y: u32 = 32;
+/// Signature
+const R: u32 = 40;
+const S: u32 = 48;
+const RESULT: u32 = 56;
+
+const RESULT_OK: u32 = 1;
+
+/// Words in a 256-bit value
+const VALUE_WORDS: u32 = (SCALAR_LEN / 4) as u32;
+
+/// The group order, big-endian
+const ORDER: [u8; SCALAR_LEN] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+/// The operation in progress. Each one's value is the index of the header
+/// word holding its routine's address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Idle,
+    PublicKey = 1,
+    Sign = 2,
+    Verify = 3,
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Whether the big-endian `bytes` are a valid scalar: not zero and less
+/// than the group order. Doesn't branch on their value.
+fn is_scalar(bytes: &[u8]) -> bool {
+    let mut borrow = 0;
+    let mut nonzero = 0;
+    for i in (0..SCALAR_LEN).rev() {
+        let difference = bytes[i] as i32 - ORDER[i] as i32 - borrow;
+        borrow = (difference >> 8) & 1;
+        nonzero |= bytes[i];
+    }
+    borrow == 1 && nonzero != 0
+}
+
+/// Writes each of `values`, big-endian, to DMEM at its offset.
+fn write_values(dcrypto: &DcryptoEngine, values: &[(u32, &[u8])]) -> ReturnCode {
+    for &(offset, value) in values.iter() {
+        let result = write_value(dcrypto, offset, value);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+    }
+    ReturnCode::SUCCESS
+}
+
+/// Writes the big-endian `value` to DMEM at `offset`.
+fn write_value(dcrypto: &DcryptoEngine, offset: u32, value: &[u8]) -> ReturnCode {
+    let mut words = [0; SCALAR_LEN];
+    for (i, byte) in value[..SCALAR_LEN].iter().rev().enumerate() {
+        words[i] = *byte;
+    }
+    let result = dcrypto.write_data(&words, offset, VALUE_WORDS);
+    unsafe { ptr::write_volatile(&mut words, [0; SCALAR_LEN]) };
+    result
+}
+
+/// Reads the value at DMEM `offset` into `value`, big-endian.
+fn read_value(dcrypto: &DcryptoEngine, offset: u32, value: &mut [u8]) -> ReturnCode {
+    let mut words = [0; SCALAR_LEN];
+    let result = dcrypto.read_data(&mut words, offset, VALUE_WORDS);
+    for (i, byte) in words.iter().rev().enumerate() {
+        value[i] = *byte;
+    }
+    result
+}
+
+pub struct P256Engine {
+    dcrypto: Cell<Option<&'static DcryptoEngine<'static>>>,
+    dcrypto_client: Cell<Option<&'static DcryptoClient<'static>>>,
+    sha: Cell<Option<&'static ShaEngine>>,
+    program: Cell<&'static [u8]>,
+    client: Cell<Option<&'static EcdsaClient>>,
+    operation: Cell<Operation>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+pub static mut P256: P256Engine = P256Engine::new();
+
+impl P256Engine {
+    const fn new() -> P256Engine {
+        P256Engine {
+            dcrypto: Cell::new(None),
+            dcrypto_client: Cell::new(None),
+            sha: Cell::new(None),
+            program: Cell::new(&[]),
+            client: Cell::new(None),
+            operation: Cell::new(Operation::Idle),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    /// Makes the engine `dcrypto`'s client, passing completions of the
+    /// programs `dcrypto_client` runs on to it. `sha` makes the nonces of
+    /// signatures. Returns EINVAL, and leaves the engine unsupported, if
+    /// `program` isn't an empty or valid image.
+    pub fn init(&'static self,
+                dcrypto: &'static DcryptoEngine<'static>,
+                dcrypto_client: &'static DcryptoClient<'static>,
+                sha: &'static ShaEngine,
+                program: &'static [u8])
+                -> ReturnCode {
+        self.dcrypto.set(Some(dcrypto));
+        self.dcrypto_client.set(Some(dcrypto_client));
+        self.sha.set(Some(sha));
+        dcrypto.set_client(self);
+        if program.is_empty() {
+            return ReturnCode::SUCCESS;
+        }
+        if program.len() < HEADER_LEN || program.len() % 4 != 0 || read_u32(program) != PROGRAM_MAGIC {
+            return ReturnCode::EINVAL;
+        }
+        self.program.set(program);
+        ReturnCode::SUCCESS
+    }
+
+    /// The nonce for signing `digest` with `private_key`: the first HMAC,
+    /// of them and a counter, that is a valid scalar.
+    fn nonce(&self, private_key: &[u8; SCALAR_LEN], digest: &[u8; SCALAR_LEN], nonce: &mut [u8; SCALAR_LEN])
+             -> ReturnCode {
+        let sha = match self.sha.get() {
+            Some(sha) => sha,
+            None => return ReturnCode::EOFF,
+        };
+        for counter in 0..4u8 {
+            if let Err(error) = sha.initialize_hidden_key_hmac() {
+                return ReturnCode::from(error);
+            }
+            let computed = sha.update(private_key).is_ok() &&
+                sha.update(digest).is_ok() &&
+                sha.update(&[counter]).is_ok() &&
+                sha.finalize(nonce).is_ok();
+            if !computed {
+                sha.cancel();
+                return ReturnCode::FAIL;
+            }
+            if is_scalar(nonce) {
+                return ReturnCode::SUCCESS;
+            }
+        }
+        ReturnCode::FAIL
+    }
+
+    /// Loads the program, has `load` write the routine's inputs and calls
+    /// the routine for `operation`, keeping `buffer` for the result.
+    fn begin<F>(&self, operation: Operation, buffer: &'static mut [u8], load: F)
+                -> Result<(), (ReturnCode, &'static mut [u8])>
+        where F: FnOnce(&DcryptoEngine) -> ReturnCode
+    {
+        let program = self.program.get();
+        let dcrypto = match self.dcrypto.get() {
+            Some(dcrypto) if !program.is_empty() => dcrypto,
+            _ => return Err((ReturnCode::ENOSUPPORT, buffer)),
+        };
+        if self.operation.get() != Operation::Idle || dcrypto.state() != State::Halt {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if buffer.len() < POINT_LEN {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        let instructions = &program[HEADER_LEN..];
+        let entry = read_u32(&program[4 * operation as usize..]);
+        self.operation.set(operation);
+        let mut result = dcrypto.write_instructions(instructions, 0, (instructions.len() / 4) as u32);
+        if result == ReturnCode::SUCCESS {
+            result = load(dcrypto);
+        }
+        if result == ReturnCode::SUCCESS {
+            result = dcrypto.call_imem(entry);
+        }
+        if result != ReturnCode::SUCCESS {
+            dcrypto.reset();
+            self.operation.set(Operation::Idle);
+            return Err((result, buffer));
+        }
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+
+    /// Reads what the routine for `operation` left in DMEM into `buffer`.
+    fn read_result(&self, dcrypto: &DcryptoEngine, operation: Operation, buffer: &mut [u8]) -> ReturnCode {
+        let mut word = [0; 4];
+        let result = dcrypto.read_data(&mut word, RESULT, 1);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        if read_u32(&word) != RESULT_OK {
+            return ReturnCode::FAIL;
+        }
+        let (first, second) = match operation {
+            Operation::PublicKey => (X, Y),
+            Operation::Sign => (R, S),
+            _ => return ReturnCode::SUCCESS,
+        };
+        let result = read_value(dcrypto, first, &mut buffer[..SCALAR_LEN]);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        read_value(dcrypto, second, &mut buffer[SCALAR_LEN..POINT_LEN])
+    }
+
+    /// Wipes dcrypto and reports the operation in progress to the client.
+    fn finish(&self, dcrypto: &DcryptoEngine, result: ReturnCode) {
+        let operation = self.operation.get();
+        dcrypto.reset();
+        self.operation.set(Operation::Idle);
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        if let Some(client) = self.client.get() {
+            match operation {
+                Operation::PublicKey => client.public_key_done(result, buffer),
+                Operation::Sign => client.sign_done(result, buffer),
+                _ => client.verify_done(result, buffer),
+            }
+        }
+    }
+}
+
+impl EcdsaP256 for P256Engine {
+    fn set_client(&self, client: &'static EcdsaClient) {
+        self.client.set(Some(client));
+    }
+
+    fn public_key(&self, private_key: &[u8; SCALAR_LEN], point: &'static mut [u8])
+                  -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !is_scalar(private_key) {
+            return Err((ReturnCode::EINVAL, point));
+        }
+        self.begin(Operation::PublicKey, point, |dcrypto| write_value(dcrypto, D, private_key))
+    }
+
+    fn sign(&self,
+            private_key: &[u8; SCALAR_LEN],
+            digest: &[u8; SCALAR_LEN],
+            signature: &'static mut [u8])
+            -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !is_scalar(private_key) {
+            return Err((ReturnCode::EINVAL, signature));
+        }
+        if self.program.get().is_empty() {
+            return Err((ReturnCode::ENOSUPPORT, signature));
+        }
+        let mut nonce = [0; SCALAR_LEN];
+        let result = match self.nonce(private_key, digest, &mut nonce) {
+            ReturnCode::SUCCESS => {
+                self.begin(Operation::Sign, signature, |dcrypto| {
+                    write_values(dcrypto, &[(D, &private_key[..]), (K, &nonce[..]), (E, &digest[..])])
+                })
+            }
+            error => Err((error, signature)),
+        };
+        unsafe { ptr::write_volatile(&mut nonce, [0; SCALAR_LEN]) };
+        result
+    }
+
+    fn verify(&self,
+              public_key: &[u8; POINT_LEN],
+              digest: &[u8; SCALAR_LEN],
+              signature: &'static mut [u8])
+              -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if signature.len() < POINT_LEN {
+            return Err((ReturnCode::ESIZE, signature));
+        }
+        let mut rs = [0; POINT_LEN];
+        rs.copy_from_slice(&signature[..POINT_LEN]);
+        self.begin(Operation::Verify, signature, |dcrypto| {
+            write_values(dcrypto,
+                         &[(X, &public_key[..SCALAR_LEN]),
+                           (Y, &public_key[SCALAR_LEN..]),
+                           (E, &digest[..]),
+                           (R, &rs[..SCALAR_LEN]),
+                           (S, &rs[SCALAR_LEN..])])
+        })
+    }
+}
+
+impl DcryptoClient<'static> for P256Engine {
+    fn execution_complete(&self, error: ReturnCode, fault: ProgramFault) {
+        let operation = self.operation.get();
+        let dcrypto = match self.dcrypto.get() {
+            Some(dcrypto) if operation != Operation::Idle => dcrypto,
+            _ => {
+                self.dcrypto_client.get().map(|client| client.execution_complete(error, fault));
+                return;
+            }
+        };
+        let result = self.buffer
+            .map(|buffer| if error == ReturnCode::SUCCESS {
+                self.read_result(dcrypto, operation, buffer)
+            } else {
+                error
+            })
+            .unwrap_or(ReturnCode::FAIL);
+        self.finish(dcrypto, result);
+    }
+
+    fn reset_complete(&self, error: ReturnCode) {
+        // The engine's own resets wipe it after an operation.
+        if self.operation.get() == Operation::Idle {
+            self.dcrypto_client.get().map(|client| client.reset_complete(error));
+        }
+    }
+
+    fn secret_wipe_complete(&self, error: ReturnCode) {
+        self.dcrypto_client.get().map(|client| client.secret_wipe_complete(error));
+    }
+}
+
+impl BrownoutClient for P256Engine {
+    /// Ends the operation in progress, wiping dcrypto, since it can't be
+    /// trusted to complete.
+    fn brownout_warning(&self) {
+        if self.operation.get() == Operation::Idle {
+            return;
+        }
+        if let Some(dcrypto) = self.dcrypto.get() {
+            self.finish(dcrypto, ReturnCode::EOFF);
+        }
+    }
+}
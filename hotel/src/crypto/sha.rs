@@ -37,6 +37,27 @@ impl ShaEngine {
             current_mode: Cell::new(None),
         }
     }
+
+    /// Initializes the engine for HMAC-SHA256 keyed with the key ladder's
+    /// output, which software never sees. `update` and `finalize` then work
+    /// as for a plain SHA256 digest. Fails with `DigestError::Busy` while
+    /// another digest is in progress, as `initialize` does.
+    pub fn initialize_hidden_key_hmac(&self) -> Result<(), DigestError> {
+        let ref regs = unsafe { &*self.regs }.sha;
+        if self.current_mode.get().is_some() {
+            return Err(DigestError::Busy);
+        }
+        self.current_mode.set(Some(DigestMode::Sha256));
+
+        regs.trig.set(ShaTrigMask::Stop as u32);
+        regs.use_hidden_key.set(1);
+        regs.cfg_en.set(ShaCfgEnMask::Livestream as u32 |
+                        ShaCfgEnMask::IntEnDone as u32 |
+                        ShaCfgEnMask::Hmac as u32);
+        regs.trig.set(ShaTrigMask::Go as u32);
+
+        Ok(())
+    }
 }
 
 pub static mut KEYMGR0_SHA: ShaEngine = unsafe { ShaEngine::new(KEYMGR0_REGS) };
@@ -50,9 +71,13 @@ impl DigestEngine for ShaEngine {
             DigestMode::Sha1 |
             DigestMode::Sha256 => (),
         };
+        if self.current_mode.get().is_some() {
+            return Err(DigestError::Busy);
+        }
         self.current_mode.set(Some(mode));
 
         regs.trig.set(ShaTrigMask::Stop as u32);
+        regs.use_hidden_key.set(0);
 
         let mut flags = ShaCfgEnMask::Livestream as u32 | ShaCfgEnMask::IntEnDone as u32;
         match mode {
@@ -108,7 +133,17 @@ impl DigestEngine for ShaEngine {
         }
 
         regs.itop.set(0);
+        self.current_mode.set(None);
 
         Ok(expected_output_size)
     }
+
+    fn cancel(&self) {
+        let ref regs = unsafe { &*self.regs }.sha;
+        if self.current_mode.get().is_some() {
+            regs.trig.set(ShaTrigMask::Stop as u32);
+            regs.itop.set(0);
+            self.current_mode.set(None);
+        }
+    }
 }
@@ -0,0 +1,66 @@
+//! Deferred calls for chip peripherals
+//!
+//! A driver sets its `DeferredCall` to have work run later from the chip's
+//! `service_pending_interrupts`, in the kernel's main loop, rather than in
+//! the middle of the code that found it: e.g. to call a client back after
+//! the call that started an operation has returned, or to keep an interrupt
+//! handler down to acknowledging the hardware. A deferred call that is set
+//! again before it runs still runs once.
+//!
+//! Each user has a `Task`, which `chip::Hotel` maps to the driver's
+//! handler:
+//!
+//! ```ignore
+//! static DEFERRED_CALL: DeferredCall = DeferredCall::new(Task::Usb);
+//!
+//! DEFERRED_CALL.set();
+//! ```
+
+use cortexm3::support;
+
+/// Work that can be deferred, one per user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    /// USB data endpoint events (`usb::USB::handle_deferred_call`)
+    Usb = 0,
+    /// Refused USB console writes (`usb::UsbConsole::handle_deferred_call`)
+    UsbConsole = 1,
+}
+
+const TASKS: [Task; 2] = [Task::Usb, Task::UsbConsole];
+
+// Bit N is set if `TASKS[N]` is pending
+static mut PENDING: u32 = 0;
+
+pub struct DeferredCall(Task);
+
+impl DeferredCall {
+    pub const fn new(task: Task) -> DeferredCall {
+        DeferredCall(task)
+    }
+
+    /// Schedules the task to run.
+    pub fn set(&self) {
+        let bit = 1 << self.0 as u32;
+        unsafe { support::atomic(|| PENDING |= bit) }
+    }
+}
+
+/// Whether any task is waiting to run.
+pub fn has_tasks() -> bool {
+    unsafe { PENDING != 0 }
+}
+
+/// Takes the next pending task, which the caller must run.
+pub fn next_pending() -> Option<Task> {
+    unsafe {
+        support::atomic(|| {
+            if PENDING == 0 {
+                return None;
+            }
+            let index = PENDING.trailing_zeros() as usize;
+            PENDING &= !(1 << index);
+            TASKS.get(index).cloned()
+        })
+    }
+}
@@ -0,0 +1,65 @@
+//! Silicon revision errata
+//!
+//! The chip revision is read from the fuses once at boot (`init`, called
+//! from `hotel::init`). Each known erratum is listed in `ERRATA` with the
+//! first revision that fixes it, and drivers check `applies` where their
+//! behavior has to differ, so one kernel binary runs on every stepping.
+//! Parts with blank fuses are treated as the earliest silicon, since
+//! unnecessary workarounds are cheaper than missing ones.
+//!
+//! To add a workaround, add a variant to `Erratum` and an entry to
+//! `ERRATA`, and test `errata::applies(Erratum::...)` in the driver.
+
+use core::fmt::Write;
+use fuse::{self, ChipRevision};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Erratum {
+    /// The USB controller has half the FIFO RAM, so IN endpoint FIFOs can
+    /// only hold one packet.
+    UsbSmallFifoRam,
+    /// The TRNG's analog source takes longer to start, so the digital
+    /// side must wait longer before timing out.
+    TrngSlowStartup,
+}
+
+struct Entry {
+    erratum: Erratum,
+    /// The first revision without the erratum
+    fixed_in: u8,
+}
+
+const ERRATA: [Entry; 2] = [
+    Entry { erratum: Erratum::UsbSmallFifoRam, fixed_in: 0x10 },
+    Entry { erratum: Erratum::TrngSlowStartup, fixed_in: 0x20 },
+];
+
+static mut REVISION: ChipRevision = ChipRevision { revision: 0, variant: 0 };
+
+/// Reads the chip revision. Must be called before any driver is
+/// initialized.
+pub unsafe fn init() {
+    REVISION = fuse::chip_revision();
+}
+
+pub fn revision() -> ChipRevision {
+    unsafe { REVISION }
+}
+
+/// Whether the chip this is running on has `erratum`.
+pub fn applies(erratum: Erratum) -> bool {
+    let revision = revision().revision;
+    ERRATA.iter().any(|entry| entry.erratum == erratum && revision < entry.fixed_in)
+}
+
+/// Prints the revision and the errata it has.
+pub fn dump(writer: &mut Write) {
+    let revision = revision();
+    let _ = writer.write_fmt(format_args!("Chip revision {:#04x} variant {:#04x}\r\n",
+                                          revision.revision, revision.variant));
+    for entry in ERRATA.iter() {
+        if applies(entry.erratum) {
+            let _ = writer.write_fmt(format_args!("  erratum {:?}\r\n", entry.erratum));
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Countermeasures against fault injection
+//!
+//! A glitch on the supply or clock at the right moment can make the CPU
+//! skip an instruction or take the wrong side of a branch, e.g. accept a
+//! bad signature. `random_delay` spins for a TRNG-derived number of
+//! cycles, so an attacker can't time a glitch from a trigger outside the
+//! chip, and `check` evaluates a security-critical condition twice with
+//! random delays around each evaluation: if the two disagree, one was
+//! glitched, and `detected` resets the chip and records the fault so the
+//! next boot reports `ResetCause::FaultDetected`.
+//!
+//! The TRNG should have been started; until then the delays come from the
+//! timestamp counter, which is much easier to predict. Conditions should
+//! read what they test from memory, flash or registers each time they are
+//! called, rather than from a local the compiler could evaluate once.
+//!
+//! ```ignore
+//! if !hotel::fault::check(|| digests_match(&expected, stored)) {
+//!     return Err(ReturnCode::FAIL);
+//! }
+//! ```
+
+use panic;
+use pmu;
+use timestamp::TIMESTAMP;
+use trace;
+use trng::TRNG0;
+
+/// Longest delay, in iterations of a loop of a few cycles
+pub const MAX_DELAY: u32 = 0xff;
+
+/// Spins for a random number of iterations up to `MAX_DELAY`.
+#[inline(never)]
+pub fn random_delay() {
+    let random = unsafe { TRNG0.try_read() }.unwrap_or_else(|| unsafe { TIMESTAMP.now() as u32 });
+    for _ in 0..random & MAX_DELAY {
+        // Also a compiler barrier: memory is re-read after it.
+        unsafe { asm!("nop" ::: "memory" : "volatile") };
+    }
+}
+
+/// Evaluates `condition` twice, with random delays before, between and
+/// after, and returns its value. Resets the chip if the two evaluations
+/// disagree.
+#[inline(never)]
+pub fn check<F: Fn() -> bool>(condition: F) -> bool {
+    random_delay();
+    let first = condition();
+    random_delay();
+    let second = condition();
+    random_delay();
+    if first != second {
+        detected();
+    }
+    first
+}
+
+/// Handles a detected fault: records it and resets the chip.
+pub fn detected() -> ! {
+    trace::record("fault detected", panic::link_register());
+    pmu::record_fault();
+    unsafe { panic::reset() }
+}
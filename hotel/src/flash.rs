@@ -0,0 +1,264 @@
+//! Internal flash controller (FLASH0)
+//!
+//! The internal flash is mapped at `FLASH_BASE` as two banks of
+//! `BANK_SIZE` bytes and reads like memory. Erasing, a `PAGE_SIZE` page at
+//! a time to all ones, and programming, which can only clear bits, go
+//! through the controller. Both are synchronous: the CPU spins until the
+//! controller is done, which takes tens of milliseconds for an erase. The
+//! kernel runs from the first bank, so only the second one should be
+//! changed while it runs.
+//!
+//! `protect` makes the controller refuse to erase or program a range until
+//! the next reset, so data that must stay fixed (such as the device's
+//! personality) can't be changed by a bug later in the boot.
+//!
+//! An erase or program cut short by a brownout can leave flash neither old
+//! nor new, so `Flash` is a `BrownoutClient`: operations are synchronous,
+//! so none is in progress when the warning is delivered, and after it the
+//! driver refuses new ones with EOFF until the supply is back above the
+//! threshold.
+//!
+//! The controller's clock (`PeripheralClock0::Flash0`) is not gateable, so
+//! the driver doesn't acquire it.
+//!
+//! ```ignore
+//! hotel::flash::FLASH0.erase(PAGE_ADDRESS);
+//! hotel::flash::FLASH0.program(PAGE_ADDRESS, &words);
+//! hotel::flash::FLASH0.protect(PAGE_ADDRESS, hotel::flash::PAGE_SIZE);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use trace;
+use volt::{BrownoutClient, VOLT0};
+
+#[repr(C)]
+struct Registers {
+    /// Must be written with `PE_ENABLE_KEY` before each operation; it is
+    /// cleared when the operation starts
+    pe_enable: VolatileCell<u32>,
+
+    /// Bit 0 starts the operation in `opcode` and reads as 1 until it
+    /// completes
+    control: VolatileCell<u32>,
+
+    /// `OP_ERASE` or `OP_PROGRAM`
+    opcode: VolatileCell<u32>,
+
+    /// | bits  | Description                                      |
+    /// | ----- | :----------------------------------------------- |
+    /// | 0-16  | Offset of the operation from `FLASH_BASE`, words |
+    /// | 17-21 | Number of words to program, minus one            |
+    transaction: VolatileCell<u32>,
+
+    /// Nonzero if the last operation failed (e.g. it was protected);
+    /// write to clear
+    error: VolatileCell<u32>,
+
+    /// Word offsets from `FLASH_BASE` of the start and end of each
+    /// protected range
+    protect_start: [VolatileCell<u32>; NUM_PROTECTED],
+    protect_end: [VolatileCell<u32>; NUM_PROTECTED],
+
+    /// Bit n enables range n and locks its bounds until reset (write-once
+    /// per bit)
+    protect_lock: VolatileCell<u32>,
+
+    _reserved: [u32; 22],
+
+    /// Words to program
+    write_data: [VolatileCell<u32>; MAX_PROGRAM_WORDS],
+}
+
+const FLASH0_REGS: *const Registers = 0x40720000 as *const Registers;
+
+pub const FLASH_BASE: usize = 0x40000;
+pub const BANK_SIZE: usize = 0x40000;
+pub const FLASH_SIZE: usize = 2 * BANK_SIZE;
+
+/// Erase granularity, in bytes
+pub const PAGE_SIZE: usize = 0x800;
+
+/// Most words that one program operation writes
+pub const MAX_PROGRAM_WORDS: usize = 32;
+
+/// Ranges that can be protected at once
+pub const NUM_PROTECTED: usize = 2;
+
+const PE_ENABLE_KEY: u32 = 0xb11924e1;
+const OP_ERASE: u32 = 0x31415927;
+const OP_PROGRAM: u32 = 0x27182818;
+
+const CONTROL_START: u32 = 1 << 0;
+const TRANSACTION_SIZE_SHIFT: u32 = 17;
+
+pub static mut FLASH0: Flash = unsafe { Flash::new(FLASH0_REGS) };
+
+pub struct Flash {
+    regs: *const Registers,
+    parked: Cell<bool>,
+}
+
+impl Flash {
+    const unsafe fn new(regs: *const Registers) -> Flash {
+        Flash {
+            regs: regs,
+            parked: Cell::new(false),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    /// Whether `address..address + len` is all in flash.
+    fn in_flash(address: usize, len: usize) -> bool {
+        address >= FLASH_BASE && len <= FLASH_SIZE && address - FLASH_BASE <= FLASH_SIZE - len
+    }
+
+    /// Whether any part of `address..address + len` is protected.
+    pub fn is_protected(&self, address: usize, len: usize) -> bool {
+        let regs = self.registers();
+        let locked = regs.protect_lock.get();
+        let start = address.saturating_sub(FLASH_BASE) / 4;
+        let end = start + (len + 3) / 4;
+        (0..NUM_PROTECTED).any(|i| {
+            locked & 1 << i != 0 &&
+                start < regs.protect_end[i].get() as usize &&
+                (regs.protect_start[i].get() as usize) < end
+        })
+    }
+
+    /// Refuses to erase or program `address..address + len` until reset.
+    /// The range must be word-aligned. Returns ENOMEM if `NUM_PROTECTED`
+    /// ranges already are.
+    pub fn protect(&self, address: usize, len: usize) -> ReturnCode {
+        if !Flash::in_flash(address, len) || address % 4 != 0 || len % 4 != 0 {
+            return ReturnCode::EINVAL;
+        }
+        let regs = self.registers();
+        let locked = regs.protect_lock.get();
+        let index = match (0..NUM_PROTECTED).find(|&i| locked & 1 << i == 0) {
+            Some(index) => index,
+            None => return ReturnCode::ENOMEM,
+        };
+        let start = (address - FLASH_BASE) / 4;
+        regs.protect_start[index].set(start as u32);
+        regs.protect_end[index].set((start + len / 4) as u32);
+        regs.protect_lock.set(1 << index);
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether a brownout warning came in and the supply is still low.
+    fn parked(&self) -> bool {
+        if self.parked.get() && unsafe { VOLT0.is_low() } {
+            return true;
+        }
+        self.parked.set(false);
+        false
+    }
+
+    /// Runs an operation on the words from `address` and waits for it.
+    /// Returns EOFF while parked by a brownout warning.
+    fn execute(&self, opcode: u32, address: usize, words: usize) -> ReturnCode {
+        if self.parked() {
+            return ReturnCode::EOFF;
+        }
+        let regs = self.registers();
+        let offset = ((address - FLASH_BASE) / 4) as u32;
+        regs.error.set(0);
+        regs.opcode.set(opcode);
+        regs.transaction.set(offset | ((words as u32 - 1) << TRANSACTION_SIZE_SHIFT));
+        regs.pe_enable.set(PE_ENABLE_KEY);
+        regs.control.set(CONTROL_START);
+        while regs.control.get() & CONTROL_START != 0 {}
+        match regs.error.get() {
+            0 => ReturnCode::SUCCESS,
+            error => {
+                trace::record("flash error", error);
+                regs.error.set(0);
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    /// Erases the page at `address`, which must be page-aligned. Returns
+    /// ERESERVE if the page is protected.
+    pub fn erase(&self, address: usize) -> ReturnCode {
+        if !Flash::in_flash(address, PAGE_SIZE) || address % PAGE_SIZE != 0 {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_protected(address, PAGE_SIZE) {
+            return ReturnCode::ERESERVE;
+        }
+        self.execute(OP_ERASE, address, 1)
+    }
+
+    /// Programs `words` from `address`, which must be word-aligned. Bits
+    /// that are already clear stay clear. Returns ERESERVE if any of the
+    /// words are protected.
+    pub fn program(&self, address: usize, words: &[u32]) -> ReturnCode {
+        let len = words.len() * 4;
+        if !Flash::in_flash(address, len) || address % 4 != 0 {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_protected(address, len) {
+            return ReturnCode::ERESERVE;
+        }
+        let regs = self.registers();
+        let mut address = address;
+        for chunk in words.chunks(MAX_PROGRAM_WORDS) {
+            for (i, &word) in chunk.iter().enumerate() {
+                regs.write_data[i].set(word);
+            }
+            let result = self.execute(OP_PROGRAM, address, chunk.len());
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+            address += chunk.len() * 4;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Programs `bytes` from `address`, which must be word-aligned, padding
+    /// the last word with ones (which leave flash unchanged).
+    pub fn program_bytes(&self, address: usize, bytes: &[u8]) -> ReturnCode {
+        let mut words = [!0u32; MAX_PROGRAM_WORDS];
+        let mut address = address;
+        for chunk in bytes.chunks(MAX_PROGRAM_WORDS * 4) {
+            let count = (chunk.len() + 3) / 4;
+            for (i, word) in words[..count].iter_mut().enumerate() {
+                let mut value = !0u32;
+                let end = cmp::min(chunk.len(), 4 * i + 4);
+                for (j, &byte) in chunk[4 * i..end].iter().enumerate() {
+                    value &= !(0xff << (8 * j)) | (byte as u32) << (8 * j);
+                }
+                *word = value;
+            }
+            let result = self.program(address, &words[..count]);
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+            address += count * 4;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether every byte of `address..address + len` is erased.
+    pub fn is_erased(&self, address: usize, len: usize) -> bool {
+        if !Flash::in_flash(address, len) {
+            return false;
+        }
+        let bytes = unsafe { ::core::slice::from_raw_parts(address as *const u8, len) };
+        bytes.iter().all(|&byte| byte == 0xff)
+    }
+}
+
+impl BrownoutClient for Flash {
+    fn brownout_warning(&self) {
+        trace::record("flash parked", 0);
+        self.parked.set(true);
+    }
+}
@@ -0,0 +1,127 @@
+//! Fuse block
+//!
+//! The fuses are programmed at manufacturing test and read back as words
+//! in a read-only window. They identify the silicon: a 64-bit unique
+//! device ID, the chip revision and where the die came from (lot, wafer
+//! and position on the wafer), and hold per-chip calibration such as the
+//! temperature sensor's. Blank fuses read as zero.
+
+use kernel::common::cells::VolatileCell;
+
+/// Fuse words, as mapped at FUSE0 + 0x100
+///
+/// | word | Description                                                   |
+/// | ---- | :------------------------------------------------------------ |
+/// | 0    | Device ID, low word                                           |
+/// | 1    | Device ID, high word                                          |
+/// | 2    | Bits 0-7 revision, 8-15 variant                               |
+/// | 3    | Bits 0-23 lot number, 24-31 wafer number                      |
+/// | 4    | Bits 0-7 die x position, 8-15 die y position                  |
+/// | 5    | Bit 0 provisioning closed                                     |
+/// | 8    | Bits 0-11 temperature sensor reading at 25°C, 16-27 slope     |
+#[repr(C)]
+struct Registers {
+    words: [VolatileCell<u32>; NUM_WORDS],
+}
+
+const NUM_WORDS: usize = 64;
+
+const WORD_DEVICE_ID_LOW: usize = 0;
+const WORD_DEVICE_ID_HIGH: usize = 1;
+const WORD_REVISION: usize = 2;
+const WORD_LOT: usize = 3;
+const WORD_DIE_POSITION: usize = 4;
+const WORD_FLAGS: usize = 5;
+const WORD_TEMPERATURE_CALIBRATION: usize = 8;
+
+const FLAG_PROVISIONING_CLOSED: u32 = 1 << 0;
+
+const FUSE0_BASE: *const Registers = (0x40450000 + 0x100) as *const Registers;
+
+/// UTF-16 characters in the string written by `serial_number`
+pub const SERIAL_NUMBER_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChipRevision {
+    pub revision: u8,
+    pub variant: u8,
+}
+
+/// Where the die was made
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LotInfo {
+    pub lot: u32,
+    pub wafer: u8,
+    pub x: u8,
+    pub y: u8,
+}
+
+/// Temperature sensor calibration
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TemperatureCalibration {
+    /// Raw reading at 25°C
+    pub raw_25c: u16,
+    /// In 1/256ths of a hundredth of a degree per LSB
+    pub slope: u16,
+}
+
+fn word(index: usize) -> u32 {
+    unsafe { (&*FUSE0_BASE).words[index].get() }
+}
+
+/// The chip's unique 64-bit ID, or None if the fuses are blank.
+pub fn device_id() -> Option<u64> {
+    let id = (word(WORD_DEVICE_ID_HIGH) as u64) << 32 | word(WORD_DEVICE_ID_LOW) as u64;
+    if id == 0 { None } else { Some(id) }
+}
+
+pub fn chip_revision() -> ChipRevision {
+    let w = word(WORD_REVISION);
+    ChipRevision {
+        revision: w as u8,
+        variant: (w >> 8) as u8,
+    }
+}
+
+pub fn lot_info() -> LotInfo {
+    let lot = word(WORD_LOT);
+    let position = word(WORD_DIE_POSITION);
+    LotInfo {
+        lot: lot & 0xffffff,
+        wafer: (lot >> 24) as u8,
+        x: position as u8,
+        y: (position >> 8) as u8,
+    }
+}
+
+/// Whether the provisioning-closed fuse has been blown. The factory blows
+/// it once the device is provisioned; like any fuse it can't be cleared,
+/// so unlike the personality page it survives a flash erase.
+pub fn provisioning_closed() -> bool {
+    word(WORD_FLAGS) & FLAG_PROVISIONING_CLOSED != 0
+}
+
+/// The temperature sensor calibration, or None if it wasn't fused.
+pub fn temperature_calibration() -> Option<TemperatureCalibration> {
+    let w = word(WORD_TEMPERATURE_CALIBRATION);
+    let raw_25c = (w & 0xfff) as u16;
+    let slope = ((w >> 16) & 0xfff) as u16;
+    if raw_25c == 0 || slope == 0 {
+        None
+    } else {
+        Some(TemperatureCalibration {
+            raw_25c: raw_25c,
+            slope: slope,
+        })
+    }
+}
+
+/// Writes the device ID as 16 upper-case hex digits in UTF-16, for use as
+/// a USB serial number string. Blank fuses give all zeros.
+pub fn serial_number(buffer: &mut [u16; SERIAL_NUMBER_LEN]) {
+    let id = device_id().unwrap_or(0);
+    for (i, c) in buffer.iter_mut().enumerate() {
+        let nibble = (id >> ((SERIAL_NUMBER_LEN - 1 - i) * 4)) as u16 & 0xf;
+        *c = if nibble < 10 { 0x30 + nibble } else { 0x41 + nibble - 10 };
+    }
+}
@@ -0,0 +1,261 @@
+//! Bus security regions (GLOBALSEC)
+//!
+//! GLOBALSEC checks every access made by the chip's bus masters (the CPU's
+//! data accesses, the crypto coprocessor's DMA and the USB controller's DMA)
+//! against four address windows per master. Accesses outside the enabled
+//! windows, or of a kind a window doesn't allow, are refused and recorded;
+//! out of reset no window is enabled, so nothing gets through. Boards open
+//! the windows each master needs at boot and lock them, after which they
+//! can't be changed until reset, so a compromised or misprogrammed
+//! peripheral can't reach the kernel's memory.
+//!
+//! ```ignore
+//! use hotel::globalsec::{self, Master, Region};
+//! globalsec::init();
+//! globalsec::configure_region(Master::Cpu, 0, Region::everything());
+//! globalsec::configure_region(Master::Usb, 0, Region::read_write(0x10000, 0x10000));
+//! globalsec::lock(Master::Usb);
+//! ```
+
+use core::fmt::Write;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use pmu::{Clock, PeripheralClock, PeripheralClock0};
+
+/// The windows of one bus master
+#[repr(C)]
+struct MasterRegisters {
+    /// Window control
+    ///
+    /// | bits | Description                                           |
+    /// | ---- | :---------------------------------------------------- |
+    /// | 0    | Enable                                                |
+    /// | 1    | Allow reads                                           |
+    /// | 2    | Allow writes                                          |
+    /// | 3    | Cover the whole address space, ignoring base and size |
+    window_control: [VolatileCell<u32>; NUM_REGIONS],
+
+    /// Window start addresses, 256-byte aligned
+    window_base: [VolatileCell<u32>; NUM_REGIONS],
+
+    /// Window sizes in bytes, multiples of 256
+    window_size: [VolatileCell<u32>; NUM_REGIONS],
+
+    /// Bit 0 locks the windows until reset (write-once)
+    control: VolatileCell<u32>,
+
+    /// Address of the last refused access
+    violation_address: VolatileCell<u32>,
+
+    /// Bit 0 is set when an access has been refused; write 1 to clear
+    violation_status: VolatileCell<u32>,
+}
+
+#[repr(C)]
+struct Registers {
+    /// The CPU's data accesses
+    cpu: MasterRegisters,
+    _reserved0: [u32; 17],
+    /// The crypto coprocessor's DMA (DDMA)
+    dcrypto: MasterRegisters,
+    _reserved1: [u32; 1],
+    /// The USB controller's DMA (DUSB)
+    usb: MasterRegisters,
+}
+
+const CONTROL_LOCK: u32 = 1 << 0;
+
+const WINDOW_CONTROL_ENABLE: u32 = 1 << 0;
+const WINDOW_CONTROL_READ: u32 = 1 << 1;
+const WINDOW_CONTROL_WRITE: u32 = 1 << 2;
+const WINDOW_CONTROL_ALL: u32 = 1 << 3;
+
+/// Windows per master
+pub const NUM_REGIONS: usize = 4;
+const NUM_MASTERS: usize = 3;
+
+/// Window bases and sizes are multiples of this
+pub const REGION_ALIGN: u32 = 256;
+
+const GLOBALSEC_BASE: *const Registers = 0x40090000 as *const Registers;
+
+static CLOCK: Clock = unsafe { Clock::new(PeripheralClock::Bank0(PeripheralClock0::GlobalSec)) };
+
+/// Bus masters whose accesses are checked
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Master {
+    Cpu,
+    Dcrypto,
+    Usb,
+}
+
+pub const MASTERS: [Master; NUM_MASTERS] = [Master::Cpu, Master::Dcrypto, Master::Usb];
+
+/// A window; a size of zero means the whole address space
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Region {
+    pub const fn everything() -> Region {
+        Region {
+            base: 0,
+            size: 0,
+            read: true,
+            write: true,
+        }
+    }
+
+    pub const fn read_only(base: u32, size: u32) -> Region {
+        Region {
+            base: base,
+            size: size,
+            read: true,
+            write: false,
+        }
+    }
+
+    pub const fn read_write(base: u32, size: u32) -> Region {
+        Region {
+            base: base,
+            size: size,
+            read: true,
+            write: true,
+        }
+    }
+}
+
+/// The policy applied to a master, as read back from the hardware
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Policy {
+    pub regions: [Option<Region>; NUM_REGIONS],
+    pub locked: bool,
+}
+
+fn master_registers(master: Master) -> &'static MasterRegisters {
+    let regs = unsafe { &*GLOBALSEC_BASE };
+    match master {
+        Master::Cpu => &regs.cpu,
+        Master::Dcrypto => &regs.dcrypto,
+        Master::Usb => &regs.usb,
+    }
+}
+
+/// Turns on the block's clock. Must be called before anything else.
+pub fn init() {
+    CLOCK.acquire();
+}
+
+/// Sets window `index` of `master`. Returns EINVAL if the index is out
+/// of range or the window is not `REGION_ALIGN`-aligned, and EALREADY if
+/// the master has been locked.
+pub fn configure_region(master: Master, index: usize, region: Region) -> ReturnCode {
+    let regs = master_registers(master);
+    if index >= NUM_REGIONS || region.base % REGION_ALIGN != 0 || region.size % REGION_ALIGN != 0 {
+        return ReturnCode::EINVAL;
+    }
+    if regs.control.get() & CONTROL_LOCK != 0 {
+        return ReturnCode::EALREADY;
+    }
+    regs.window_control[index].set(0);
+    regs.window_base[index].set(region.base);
+    regs.window_size[index].set(region.size);
+    let mut control = WINDOW_CONTROL_ENABLE;
+    if region.size == 0 {
+        control |= WINDOW_CONTROL_ALL;
+    }
+    if region.read {
+        control |= WINDOW_CONTROL_READ;
+    }
+    if region.write {
+        control |= WINDOW_CONTROL_WRITE;
+    }
+    regs.window_control[index].set(control);
+    ReturnCode::SUCCESS
+}
+
+/// Disables window `index` of `master`, with the same errors as
+/// `configure_region`.
+pub fn disable_region(master: Master, index: usize) -> ReturnCode {
+    let regs = master_registers(master);
+    if index >= NUM_REGIONS {
+        return ReturnCode::EINVAL;
+    }
+    if regs.control.get() & CONTROL_LOCK != 0 {
+        return ReturnCode::EALREADY;
+    }
+    regs.window_control[index].set(0);
+    ReturnCode::SUCCESS
+}
+
+/// Locks `master`'s windows until reset.
+pub fn lock(master: Master) {
+    master_registers(master).control.set(CONTROL_LOCK);
+}
+
+pub fn policy(master: Master) -> Policy {
+    let regs = master_registers(master);
+    let mut regions = [None; NUM_REGIONS];
+    for (index, region) in regions.iter_mut().enumerate() {
+        let control = regs.window_control[index].get();
+        if control & WINDOW_CONTROL_ENABLE == 0 {
+            continue;
+        }
+        let (base, size) = if control & WINDOW_CONTROL_ALL != 0 {
+            (0, 0)
+        } else {
+            (regs.window_base[index].get(), regs.window_size[index].get())
+        };
+        *region = Some(Region {
+            base: base,
+            size: size,
+            read: control & WINDOW_CONTROL_READ != 0,
+            write: control & WINDOW_CONTROL_WRITE != 0,
+        });
+    }
+    Policy {
+        regions: regions,
+        locked: regs.control.get() & CONTROL_LOCK != 0,
+    }
+}
+
+/// The address of the last access by `master` that was refused, if any
+/// since the last `clear_violation`.
+pub fn last_violation(master: Master) -> Option<u32> {
+    let regs = master_registers(master);
+    if regs.violation_status.get() & 1 != 0 {
+        Some(regs.violation_address.get())
+    } else {
+        None
+    }
+}
+
+pub fn clear_violation(master: Master) {
+    master_registers(master).violation_status.set(1);
+}
+
+/// Prints every master's policy and last violation.
+pub fn dump(writer: &mut Write) {
+    for &master in MASTERS.iter() {
+        let policy = policy(master);
+        let _ = writer.write_fmt(format_args!("GLOBALSEC {:?}: locked {}\r\n",
+                                              master, policy.locked));
+        for (index, region) in policy.regions.iter().enumerate() {
+            region.map(|region| {
+                let _ = writer.write_fmt(format_args!("  {}: {:#010x}+{:#x} {}{}\r\n",
+                                                      index,
+                                                      region.base,
+                                                      region.size,
+                                                      if region.read { "r" } else { "-" },
+                                                      if region.write { "w" } else { "-" }));
+            });
+        }
+        last_violation(master).map(|address| {
+            let _ = writer.write_fmt(format_args!("  refused access at {:#010x}\r\n", address));
+        });
+    }
+}
@@ -1,8 +1,20 @@
+//! General purpose I/O
+//!
+//! Each of the two ports has 16 pins, which are connected to pads through
+//! the pinmux (`pinmux::Function::Gpio0Gpio0` etc. for outputs, and the
+//! `gpio0_gpio0`... selectors for inputs). Every pin implements
+//! `hil::gpio::Pin` and has its own NVIC line and client; rising and
+//! falling edge interrupts are done in hardware, and either-edge interrupts
+//! by re-arming the opposite edge after each one. Pull-ups and pull-downs
+//! are pad properties kept by the pinmux; `hil::gpio::PinCtl` forwards to
+//! it for pins connected with `pinmux::connect_gpio`.
+
 use self::Pin::*;
 use core::cell::Cell;
 use core::mem::transmute;
 use kernel::common::cells::VolatileCell;
 use kernel::hil;
+use pinmux::{self, Pull, SelectablePin};
 
 pub struct PortRegisters {
     pub data_in: VolatileCell<u32>,
@@ -91,6 +103,8 @@ pub struct GPIOPin {
     client_data: Cell<usize>,
     change: Cell<bool>,
     client: Cell<Option<&'static hil::gpio::Client>>,
+    // The pad the pin is connected to, set by `pinmux::connect_gpio`
+    pad: Cell<Option<SelectablePin>>,
 }
 
 impl GPIOPin {
@@ -101,6 +115,7 @@ impl GPIOPin {
             change: Cell::new(false),
             client_data: Cell::new(0),
             client: Cell::new(None),
+            pad: Cell::new(None),
         }
     }
 
@@ -110,14 +125,12 @@ impl GPIOPin {
         let port: &mut PortRegisters = unsafe { transmute(self.port) };
         port.interrupt_status.set(mask);
 
-        // If our InterruptMode was `Change`, we need to flip the direction of
-        // the interrupt polarity.
+        // If our InterruptMode was `Change`, arm the opposite edge to the
+        // pin's current level. Reading the level (rather than flipping the
+        // polarity) keeps us in step if the pin bounced back before we got
+        // here.
         if self.change.get() {
-            if port.interrupt_pol_set.get() & mask != 0 {
-                port.interrupt_pol_clear.set(mask);
-            } else {
-                port.interrupt_pol_set.set(mask);
-            }
+            self.arm_next_edge();
         }
 
         self.client.get().map(|client| {
@@ -128,6 +141,29 @@ impl GPIOPin {
     pub fn set_client(&self, client: &'static hil::gpio::Client) {
         self.client.set(Some(client));
     }
+
+    /// Records which pad the pin is connected to, so that pulls can be
+    /// set through `hil::gpio::PinCtl`.
+    pub fn set_pad(&self, pad: SelectablePin) {
+        self.pad.set(Some(pad));
+    }
+
+    /// Sets the interrupt polarity to catch the next change of the pin:
+    /// falling if it is high, rising if it is low.
+    fn arm_next_edge(&self) {
+        let port: &mut PortRegisters = unsafe { transmute(self.port) };
+        let mask = 1 << (self.pin as u32);
+        if self.read_input() {
+            port.interrupt_pol_clear.set(mask);
+        } else {
+            port.interrupt_pol_set.set(mask);
+        }
+    }
+
+    fn read_input(&self) -> bool {
+        let port: &mut PortRegisters = unsafe { transmute(self.port) };
+        port.data_in.get() & (1 << (self.pin as u32)) != 0
+    }
 }
 
 impl hil::gpio::Pin for GPIOPin {
@@ -137,7 +173,9 @@ impl hil::gpio::Pin for GPIOPin {
     }
 
     fn make_input(&self) {
-        // Noop, input is always enabled on this chip
+        // Input is always enabled on this chip, so only stop driving the pin
+        let port: &mut PortRegisters = unsafe { transmute(self.port) };
+        port.output_disable.set(1 << (self.pin as u32));
     }
 
     fn disable(&self) {
@@ -164,8 +202,7 @@ impl hil::gpio::Pin for GPIOPin {
     }
 
     fn read(&self) -> bool {
-        let port: &mut PortRegisters = unsafe { transmute(self.port) };
-        port.data_in.get() & (1 << (self.pin as u32)) != 0
+        self.read_input()
     }
 
     // `InterruptMode::Change` is not implemented in hardware, so we simulate it
@@ -189,14 +226,12 @@ impl hil::gpio::Pin for GPIOPin {
                 self.change.set(true);
                 // Set the interrupt polarity based on whatever the current
                 // state of the pin is.
-                if self.read() {
-                    port.interrupt_pol_clear.set(mask);
-                } else {
-                    port.interrupt_pol_set.set(mask);
-                }
+                self.arm_next_edge();
             }
         }
+        // Edge triggered; drop any edge latched before now
         port.interrupt_type_set.set(mask);
+        port.interrupt_status.set(mask);
         port.interrupt_enable.set(mask);
     }
 
@@ -204,19 +239,20 @@ impl hil::gpio::Pin for GPIOPin {
         let port: &mut PortRegisters = unsafe { transmute(self.port) };
         let mask = 1 << (self.pin as u32);
         port.interrupt_disable.set(mask);
+        port.interrupt_status.set(mask);
+        self.change.set(false);
     }
 }
 
 impl hil::gpio::PinCtl for GPIOPin {
-    // InputMode equivilent is set in the Pinmux.
+    // Pulls are a property of the pad, so this has no effect on a pin that
+    // was not connected with `pinmux::connect_gpio`.
     fn set_input_mode(&self, mode: hil::gpio::InputMode) {
-        match mode {
-            hil::gpio::InputMode::PullUp => {
-            }
-            hil::gpio::InputMode::PullDown => {
-            }
-            hil::gpio::InputMode::PullNone => {
-            }
-        }
+        let pull = match mode {
+            hil::gpio::InputMode::PullUp => Pull::Up,
+            hil::gpio::InputMode::PullDown => Pull::Down,
+            hil::gpio::InputMode::PullNone => Pull::None,
+        };
+        self.pad.get().map(|pad| pinmux::set_pull(pad, pull));
     }
 }
@@ -1,3 +1,4 @@
+use kernel::ReturnCode;
 use super::common::SyscallError;
 
 #[derive(Copy, Clone)]
@@ -24,6 +25,8 @@ pub enum DigestError {
     NotConfigured,
     /// The supplied output buffer is too small. Parameter is the required buffer size.
     BufferTooSmall(usize),
+    /// `initialize` was called while another digest is in progress.
+    Busy,
 }
 
 impl From<DigestError> for SyscallError {
@@ -32,10 +35,25 @@ impl From<DigestError> for SyscallError {
             DigestError::EngineNotSupported => SyscallError::NotImplemented,
             DigestError::NotConfigured => SyscallError::InvalidState,
             DigestError::BufferTooSmall(_) => SyscallError::OutOfRange,
+            DigestError::Busy => SyscallError::ResourceBusy,
         }
     }
 }
 
+impl From<DigestError> for ReturnCode {
+    fn from(e: DigestError) -> Self {
+        match e {
+            DigestError::EngineNotSupported => ReturnCode::ENOSUPPORT,
+            DigestError::NotConfigured => ReturnCode::FAIL,
+            DigestError::BufferTooSmall(_) => ReturnCode::ESIZE,
+            DigestError::Busy => ReturnCode::EBUSY,
+        }
+    }
+}
+
+/// An engine computes one digest at a time: from `initialize` until
+/// `finalize` succeeds or `cancel` is called, it is in use and `initialize`
+/// fails with `DigestError::Busy`.
 pub trait DigestEngine {
     /// Initializes the digest engine for the given mode.
     fn initialize(&self, mode: DigestMode) -> Result<(), DigestError>;
@@ -47,4 +65,7 @@ pub trait DigestEngine {
     /// Finalizes the digest, and stores it in the `output` buffer. Returns the number of bytes
     /// stored.
     fn finalize(&self, output: &mut [u8]) -> Result<usize, DigestError>;
+
+    /// Abandons the digest in progress, if any, leaving the engine free.
+    fn cancel(&self);
 }
@@ -0,0 +1,54 @@
+//! Interface for ECDSA over the NIST P-256 curve
+//!
+//! Private keys are 32-byte big-endian scalars. Points are the 64 bytes of
+//! their big-endian x and y coordinates, and signatures the 64 bytes of
+//! big-endian r and s. An engine copies the private key before a call
+//! returns, so the caller can wipe it straight away, and reports the
+//! result to its client later. A call that can't start gives the buffer
+//! straight back with the error.
+
+use kernel::ReturnCode;
+
+/// Length of a private key and of a digest to sign
+pub const SCALAR_LEN: usize = 32;
+/// Length of a public key or signature
+pub const POINT_LEN: usize = 64;
+
+pub trait EcdsaP256 {
+    fn set_client(&self, client: &'static EcdsaClient);
+
+    /// Computes the public key of `private_key` into the first `POINT_LEN`
+    /// bytes of `point`. Fails with EINVAL if the key is not a valid scalar
+    /// (zero, or not less than the curve order) and EBUSY if an operation
+    /// is in progress.
+    fn public_key(&self, private_key: &[u8; SCALAR_LEN], point: &'static mut [u8])
+                  -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Signs `digest` with `private_key` into the first `POINT_LEN` bytes
+    /// of `signature`.
+    fn sign(&self,
+            private_key: &[u8; SCALAR_LEN],
+            digest: &[u8; SCALAR_LEN],
+            signature: &'static mut [u8])
+            -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Checks that the first `POINT_LEN` bytes of `signature` are a
+    /// signature of `digest` by the owner of `public_key`.
+    fn verify(&self,
+              public_key: &[u8; POINT_LEN],
+              digest: &[u8; SCALAR_LEN],
+              signature: &'static mut [u8])
+              -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait EcdsaClient {
+    /// `public_key` finished; `point` is the buffer passed to it.
+    fn public_key_done(&self, result: ReturnCode, point: &'static mut [u8]);
+
+    /// `sign` finished; `signature` is the buffer passed to it.
+    fn sign_done(&self, result: ReturnCode, signature: &'static mut [u8]);
+
+    /// `verify` finished: `result` is SUCCESS if the signature is good and
+    /// FAIL if it isn't. `signature` is the buffer passed to it.
+    fn verify_done(&self, result: ReturnCode, signature: &'static mut [u8]);
+}
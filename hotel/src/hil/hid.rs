@@ -0,0 +1,41 @@
+//! Interface for raw access to a HID interface's reports
+//!
+//! Reports are passed through unchanged, so the protocol spoken over them
+//! is up to the client. Input reports go to the host over the interrupt
+//! endpoint and output reports come back the same way; the feature report
+//! is read and written by the host over the control endpoint, so the
+//! client publishes the value the host will read and is told when the host
+//! writes a new one.
+
+use kernel::ReturnCode;
+
+/// Bytes in every input, output and feature report
+pub const REPORT_SIZE: usize = 64;
+
+pub trait HidReports {
+    fn set_client(&self, client: &'static HidClient);
+
+    /// Sends an input report (at most `REPORT_SIZE` bytes; shorter ones
+    /// are padded with zeros). Returns EBUSY if the previous report hasn't
+    /// been collected and EOFF if the host hasn't configured the device.
+    fn send_report(&self, report: &[u8]) -> ReturnCode;
+
+    /// Sets the feature report returned to the host's GET_REPORT
+    /// requests.
+    fn set_feature_report(&self, report: &[u8]);
+
+    /// Copies the feature report last written by the host into `report`,
+    /// returning its length (0 if the host hasn't written one).
+    fn feature_report(&self, report: &mut [u8]) -> usize;
+}
+
+pub trait HidClient {
+    /// The host sent an output report, valid for the duration of the call.
+    fn report_received(&self, report: &[u8]);
+
+    /// The report passed to `send_report` was collected by the host.
+    fn report_sent(&self);
+
+    /// The host wrote the feature report.
+    fn feature_report_written(&self);
+}
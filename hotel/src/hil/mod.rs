@@ -1,4 +1,8 @@
 pub mod common;
 pub mod digest;
+pub mod ecdsa;
+pub mod hid;
 pub mod aes;
 pub mod rng;
+pub mod u2f;
+pub mod wallclock;
@@ -0,0 +1,50 @@
+//! Interface for a U2F authenticator transport
+//!
+//! A transport (such as U2FHID over USB) delivers U2F requests from a host
+//! on a logical channel and sends back one response per request. It keeps
+//! its own framing and channel management to itself: clients only see
+//! complete messages. A transport delivers one request at a time, the next
+//! only once the client has given the buffer back with `receive`, and up
+//! to `MAX_REQUESTS` delivered requests can be waiting for responses, each
+//! on a different channel; it tells other hosts it is busy until then.
+
+use kernel::ReturnCode;
+
+/// U2FHID message command: an ISO 7816 APDU carrying a U2F request or
+/// response
+pub const COMMAND_MSG: u8 = 0x83;
+
+/// Requests that can be waiting for responses at once
+pub const MAX_REQUESTS: usize = 4;
+
+pub trait U2fTransport {
+    fn set_client(&self, client: &'static U2fClient);
+
+    /// Gives the transport a buffer to receive the next request into. No
+    /// requests are delivered until a buffer has been provided.
+    fn receive(&self, buffer: &'static mut [u8]) -> ReturnCode;
+
+    /// Sends the first `len` bytes of `buffer` as the response to the
+    /// request delivered on `channel`, after any response being sent.
+    /// Fails with EINVAL if no request is waiting for a response on
+    /// `channel`, and EBUSY if another response is already queued; the
+    /// buffer is given back.
+    fn respond(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize)
+               -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Gives up on the request delivered on `channel`, e.g. because the
+    /// process answering it died, freeing its place for another; the host
+    /// gets no response. Fails with EINVAL if no request is waiting on
+    /// `channel` and EALREADY if its response is already being sent.
+    fn cancel(&self, channel: u32) -> ReturnCode;
+}
+
+pub trait U2fClient {
+    /// A request of `len` bytes arrived on `channel`. `buffer` is the one
+    /// passed to `receive`.
+    fn request_received(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize);
+
+    /// The response passed to `respond` has been sent, or the host went
+    /// away before it could be.
+    fn response_sent(&self, buffer: &'static mut [u8]);
+}
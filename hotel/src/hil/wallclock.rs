@@ -0,0 +1,20 @@
+/// How far the current time can be trusted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimeQuality {
+    /// Set by a trusted source since the last power on.
+    Set,
+    /// Carried over a reset or deep sleep from a time saved beforehand, so
+    /// it only bounds the real time from below.
+    Restored,
+}
+
+pub trait WallClock {
+    /// Milliseconds since the Unix epoch, or None if the time is unknown.
+    fn now_ms(&self) -> Option<u64>;
+
+    /// How the current time was obtained, or None if it is unknown.
+    fn quality(&self) -> Option<TimeQuality>;
+
+    /// Sets the time, in milliseconds since the Unix epoch.
+    fn set_time_ms(&self, ms: u64);
+}
@@ -0,0 +1,628 @@
+//! I2C controllers
+//!
+//! I2C0 and I2C1 are bus masters implementing `hil::i2c::I2CMaster`. The
+//! controller works a byte at a time: each command shifts one byte in or
+//! out, optionally preceded by a start (or repeated start) condition and
+//! followed by a stop, and raises an interrupt when it is done, so the
+//! driver steps through a transfer from the interrupt handler. The
+//! controller waits for SCL to be released before clocking the next bit,
+//! so targets that stretch the clock are tolerated without a timeout.
+//!
+//! I2CS0 is a separate target (slave) controller implementing
+//! `hil::i2c::I2CSlave`, so an application processor can address the chip
+//! over I2C. It stretches the clock whenever it has no buffer to receive
+//! into or nothing queued to send, which gives the client time to respond
+//! to `write_expected` and `read_expected`.
+//!
+//! SCL and SDA are routed through the pinmux (`Function::I2C0Scl` etc. and
+//! `Input::I2c0Scl` etc.) and need open-drain pads with pull-ups.
+//!
+//! ```ignore
+//! hotel::i2c::I2C0.set_client(client);
+//! hotel::i2c::I2C0.set_speed(hotel::i2c::Speed::Fast400k);
+//! hil::i2c::I2CMaster::enable(&hotel::i2c::I2C0);
+//! hil::i2c::I2CMaster::write_read(&hotel::i2c::I2C0, 0x50, buffer, 1, 16);
+//!
+//! hotel::i2c::I2CS0.set_client(client);
+//! hil::i2c::I2CSlave::set_address(&hotel::i2c::I2CS0, 0x30);
+//! hil::i2c::I2CSlave::enable(&hotel::i2c::I2CS0);
+//! hil::i2c::I2CSlave::write_receive(&hotel::i2c::I2CS0, rx_buffer, 32);
+//! hil::i2c::I2CSlave::listen(&hotel::i2c::I2CS0);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{TakeCell, VolatileCell};
+use kernel::hil;
+use pmu::{Clock, PeripheralClock, PeripheralClock0};
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Enable                                               |
+    /// | 1    | Wait while a target holds SCL low (clock stretching) |
+    control: VolatileCell<u32>,
+
+    /// SCL timing in peripheral clock cycles
+    ///
+    /// | bits  | Description                                         |
+    /// | ----- | :-------------------------------------------------- |
+    /// | 0-15  | SCL low period                                      |
+    /// | 16-31 | SCL high period                                     |
+    timing: VolatileCell<u32>,
+
+    /// Writing starts a byte command
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0-7  | Byte to send                                         |
+    /// | 8    | Generate a (repeated) start condition first          |
+    /// | 9    | Generate a stop condition afterwards                 |
+    /// | 10   | Receive a byte instead of sending one                |
+    /// | 11   | Don't acknowledge the received byte                  |
+    /// | 12   | Only generate a stop condition                       |
+    command: VolatileCell<u32>,
+
+    /// The byte received by the last receive command
+    rx_data: VolatileCell<u32>,
+
+    /// Status
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Command in progress                                  |
+    /// | 1    | The last byte sent was not acknowledged              |
+    /// | 2    | Arbitration lost                                     |
+    /// | 3    | SCL is held low by a target                          |
+    status: VolatileCell<u32>,
+
+    /// Interrupt enables, with the same layout as `interrupt_state`
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Command done                                         |
+    /// | 1    | NAK received                                         |
+    /// | 2    | Arbitration lost                                     |
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Pending interrupts; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+}
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_STRETCH: u32 = 1 << 1;
+
+const COMMAND_START: u32 = 1 << 8;
+const COMMAND_STOP: u32 = 1 << 9;
+const COMMAND_READ: u32 = 1 << 10;
+const COMMAND_NACK: u32 = 1 << 11;
+const COMMAND_STOP_ONLY: u32 = 1 << 12;
+
+const STATUS_NAK: u32 = 1 << 1;
+const STATUS_ARBITRATION_LOST: u32 = 1 << 2;
+
+const INTERRUPT_DONE: u32 = 1 << 0;
+const INTERRUPT_NAK: u32 = 1 << 1;
+const INTERRUPT_ARBITRATION_LOST: u32 = 1 << 2;
+const INTERRUPT_ALL: u32 = 0x7;
+
+#[repr(C)]
+struct SlaveRegisters {
+    _version: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Enable                                               |
+    /// | 1    | Respond to (acknowledge) the address                 |
+    /// | 2    | Reset RX FIFO (self-clearing)                        |
+    /// | 3    | Reset TX FIFO (self-clearing)                        |
+    control: VolatileCell<u32>,
+
+    /// 7-bit address to respond to
+    address: VolatileCell<u32>,
+
+    /// Status
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Addressed: between address match and stop            |
+    /// | 1    | The master is reading                                |
+    /// | 2    | SCL is being stretched                               |
+    status: VolatileCell<u32>,
+
+    /// Interrupt enables, with the same layout as `interrupt_state`
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Address matched                                      |
+    /// | 1    | RX FIFO not empty                                    |
+    /// | 2    | TX FIFO empty while the master is reading            |
+    /// | 3    | Stop condition                                       |
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Pending interrupts; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+
+    /// Reading pops a byte from the RX FIFO
+    rx_data: VolatileCell<u32>,
+    /// Bytes in the RX FIFO
+    rx_level: VolatileCell<u32>,
+
+    /// Writing pushes a byte into the TX FIFO
+    tx_data: VolatileCell<u32>,
+    /// Bytes in the TX FIFO
+    tx_level: VolatileCell<u32>,
+}
+
+const SLAVE_CONTROL_ENABLE: u32 = 1 << 0;
+const SLAVE_CONTROL_ACK_ADDRESS: u32 = 1 << 1;
+const SLAVE_CONTROL_RESET_RX: u32 = 1 << 2;
+const SLAVE_CONTROL_RESET_TX: u32 = 1 << 3;
+
+const SLAVE_STATUS_ADDRESSED: u32 = 1 << 0;
+const SLAVE_STATUS_READ: u32 = 1 << 1;
+
+const SLAVE_INTERRUPT_ADDRESS: u32 = 1 << 0;
+const SLAVE_INTERRUPT_RX: u32 = 1 << 1;
+const SLAVE_INTERRUPT_TX_EMPTY: u32 = 1 << 2;
+const SLAVE_INTERRUPT_STOP: u32 = 1 << 3;
+const SLAVE_INTERRUPT_ALL: u32 = 0xf;
+
+/// Depth of the target's TX FIFO
+const SLAVE_FIFO_SIZE: u32 = 16;
+
+/// Sent once the queued read data runs out
+const SLAVE_IDLE_BYTE: u8 = 0xff;
+
+const PCLK_HZ: u32 = 24_000_000;
+
+const I2C0_BASE: *const Registers = 0x40580000 as *const Registers;
+const I2C1_BASE: *const Registers = 0x40590000 as *const Registers;
+const I2CS0_BASE: *const SlaveRegisters = 0x40650000 as *const SlaveRegisters;
+
+pub static mut I2C0: I2cMaster = unsafe { I2cMaster::new(I2C0_BASE, PeripheralClock0::I2C0) };
+pub static mut I2C1: I2cMaster = unsafe { I2cMaster::new(I2C1_BASE, PeripheralClock0::I2C1) };
+pub static mut I2CS0: I2cSlave = unsafe { I2cSlave::new(I2CS0_BASE) };
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    Standard100k,
+    Fast400k,
+}
+
+impl Speed {
+    fn hz(self) -> u32 {
+        match self {
+            Speed::Standard100k => 100_000,
+            Speed::Fast400k => 400_000,
+        }
+    }
+}
+
+/// The part of a transfer the controller is working on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    /// Sending the address byte, with the read bit if `reading`
+    Address,
+    Writing,
+    Reading,
+}
+
+pub struct I2cMaster {
+    regs: *const Registers,
+    clock: Clock,
+    client: Cell<Option<&'static hil::i2c::I2CHwMasterClient>>,
+    speed: Cell<Speed>,
+    state: Cell<State>,
+    buffer: TakeCell<'static, [u8]>,
+    address: Cell<u8>,
+    write_len: Cell<usize>,
+    read_len: Cell<usize>,
+    // The current address phase is for the read part of the transfer
+    reading: Cell<bool>,
+    // Index in `buffer` of the byte being sent or received
+    index: Cell<usize>,
+}
+
+impl I2cMaster {
+    const unsafe fn new(regs: *const Registers, clock: PeripheralClock0) -> I2cMaster {
+        I2cMaster {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank0(clock)),
+            client: Cell::new(None),
+            speed: Cell::new(Speed::Standard100k),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            write_len: Cell::new(0),
+            read_len: Cell::new(0),
+            reading: Cell::new(false),
+            index: Cell::new(0),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    pub fn set_client(&self, client: &'static hil::i2c::I2CHwMasterClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Sets the SCL frequency, taking effect immediately if the controller
+    /// is enabled.
+    pub fn set_speed(&self, speed: Speed) {
+        self.speed.set(speed);
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE != 0 {
+            self.set_timing();
+        }
+    }
+
+    fn set_timing(&self) {
+        // Equal high and low periods
+        let half_period = PCLK_HZ / self.speed.get().hz() / 2;
+        self.registers().timing.set(half_period << 16 | half_period);
+    }
+
+    fn command(&self, command: u32) {
+        self.registers().command.set(command);
+    }
+
+    /// Sends the address byte for the write or read part of the
+    /// transfer, with a (repeated) start condition.
+    fn send_address(&self, reading: bool) {
+        self.state.set(State::Address);
+        self.reading.set(reading);
+        let address = (self.address.get() as u32) << 1 | if reading { 1 } else { 0 };
+        self.command(COMMAND_START | address);
+    }
+
+    /// Sends the byte at `index`, with a stop condition after the last
+    /// byte of a write-only transfer.
+    fn send_byte(&self) {
+        self.state.set(State::Writing);
+        let index = self.index.get();
+        let mut command = self.buffer.map_or(0, |buffer| buffer[index] as u32);
+        if index + 1 == self.write_len.get() && self.read_len.get() == 0 {
+            command |= COMMAND_STOP;
+        }
+        self.command(command);
+    }
+
+    /// Receives the byte at `index`, not acknowledging the last one and
+    /// following it with a stop condition.
+    fn receive_byte(&self) {
+        self.state.set(State::Reading);
+        let mut command = COMMAND_READ;
+        if self.index.get() + 1 == self.read_len.get() {
+            command |= COMMAND_NACK | COMMAND_STOP;
+        }
+        self.command(command);
+    }
+
+    /// Starts the data phase after the address was acknowledged.
+    fn start_data(&self) {
+        self.index.set(0);
+        if self.reading.get() {
+            self.receive_byte();
+        } else {
+            self.send_byte();
+        }
+    }
+
+    fn complete(&self, error: hil::i2c::Error) {
+        self.state.set(State::Idle);
+        self.registers().interrupt_enable.set(0);
+        self.buffer.take().map(|buffer| {
+            self.client.get().map(move |client| client.command_complete(buffer, error));
+        });
+    }
+
+    /// Ends a transfer early on a NAK, releasing the bus.
+    fn abort(&self, error: hil::i2c::Error) {
+        self.command(COMMAND_STOP_ONLY);
+        self.complete(error);
+    }
+
+    fn start(&self, address: u8, buffer: &'static mut [u8], write_len: usize, read_len: usize) {
+        let regs = self.registers();
+        if self.state.get() != State::Idle || write_len > buffer.len() || read_len > buffer.len() ||
+            write_len + read_len == 0 {
+            self.client.get().map(move |client| {
+                client.command_complete(buffer, hil::i2c::Error::DataNak);
+            });
+            return;
+        }
+        self.buffer.replace(buffer);
+        self.address.set(address);
+        self.write_len.set(write_len);
+        self.read_len.set(read_len);
+        regs.interrupt_state.set(INTERRUPT_ALL);
+        regs.interrupt_enable.set(INTERRUPT_ALL);
+        self.send_address(write_len == 0);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        let state = regs.interrupt_state.get();
+        regs.interrupt_state.set(state);
+        let status = regs.status.get();
+
+        if state & INTERRUPT_ARBITRATION_LOST != 0 || status & STATUS_ARBITRATION_LOST != 0 {
+            // The bus now belongs to the other master, so no stop
+            self.complete(hil::i2c::Error::ArbitrationLost);
+            return;
+        }
+        if state & INTERRUPT_NAK != 0 || status & STATUS_NAK != 0 {
+            let error = match self.state.get() {
+                State::Address => hil::i2c::Error::AddressNak,
+                _ => hil::i2c::Error::DataNak,
+            };
+            self.abort(error);
+            return;
+        }
+        if state & INTERRUPT_DONE == 0 {
+            return;
+        }
+
+        match self.state.get() {
+            State::Idle => {}
+            State::Address => self.start_data(),
+            State::Writing => {
+                let index = self.index.get() + 1;
+                self.index.set(index);
+                if index < self.write_len.get() {
+                    self.send_byte();
+                } else if self.read_len.get() > 0 {
+                    self.send_address(true);
+                } else {
+                    self.complete(hil::i2c::Error::CommandComplete);
+                }
+            }
+            State::Reading => {
+                let index = self.index.get();
+                let byte = regs.rx_data.get() as u8;
+                self.buffer.map(|buffer| buffer[index] = byte);
+                self.index.set(index + 1);
+                if index + 1 < self.read_len.get() {
+                    self.receive_byte();
+                } else {
+                    self.complete(hil::i2c::Error::CommandComplete);
+                }
+            }
+        }
+    }
+}
+
+impl hil::i2c::I2CMaster for I2cMaster {
+    fn enable(&self) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE == 0 {
+            self.clock.acquire();
+        }
+        self.set_timing();
+        regs.control.set(CONTROL_ENABLE | CONTROL_STRETCH);
+    }
+
+    fn disable(&self) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE != 0 {
+            regs.interrupt_enable.set(0);
+            regs.control.set(0);
+            self.clock.release();
+        }
+    }
+
+    /// Writes `write_len` bytes of `data` to `addr`, then reads `read_len`
+    /// bytes back into the start of `data` after a repeated start. Invalid
+    /// lengths, or a transfer already in progress, complete at once with
+    /// `DataNak`.
+    fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
+        self.start(addr, data, write_len as usize, read_len as usize);
+    }
+
+    fn write(&self, addr: u8, data: &'static mut [u8], len: u8) {
+        self.start(addr, data, len as usize, 0);
+    }
+
+    fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
+        self.start(addr, buffer, 0, len as usize);
+    }
+}
+
+pub struct I2cSlave {
+    regs: *const SlaveRegisters,
+    clock: Clock,
+    client: Cell<Option<&'static hil::i2c::I2CHwSlaveClient>>,
+    // Receives the bytes of master writes
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_limit: Cell<usize>,
+    rx_len: Cell<usize>,
+    // Sent in response to master reads
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_limit: Cell<usize>,
+    // Bytes of `tx_buffer` pushed into the TX FIFO
+    tx_queued: Cell<usize>,
+    // The current transaction is a master read
+    reading: Cell<bool>,
+}
+
+impl I2cSlave {
+    const unsafe fn new(regs: *const SlaveRegisters) -> I2cSlave {
+        I2cSlave {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank0(PeripheralClock0::I2CS0)),
+            client: Cell::new(None),
+            rx_buffer: TakeCell::empty(),
+            rx_limit: Cell::new(0),
+            rx_len: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_limit: Cell::new(0),
+            tx_queued: Cell::new(0),
+            reading: Cell::new(false),
+        }
+    }
+
+    fn registers(&self) -> &SlaveRegisters {
+        unsafe { &*self.regs }
+    }
+
+    pub fn set_client(&self, client: &'static hil::i2c::I2CHwSlaveClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Moves received bytes into the receive buffer, dropping any beyond
+    /// its end. Leaves them in the FIFO (stretching the clock once it is
+    /// full) while there is no buffer.
+    fn drain_rx(&self) {
+        let regs = self.registers();
+        if self.rx_buffer.is_none() {
+            return;
+        }
+        while regs.rx_level.get() > 0 {
+            let b = regs.rx_data.get() as u8;
+            let len = self.rx_len.get();
+            if len < self.rx_limit.get() {
+                self.rx_buffer.map(|buffer| buffer[len] = b);
+                self.rx_len.set(len + 1);
+            }
+        }
+    }
+
+    /// Tops up the TX FIFO from the read data, then with the idle byte.
+    fn fill_tx(&self) {
+        let regs = self.registers();
+        let limit = self.tx_limit.get();
+        let mut queued = self.tx_queued.get();
+        self.tx_buffer.map(|buffer| {
+            while regs.tx_level.get() < SLAVE_FIFO_SIZE {
+                let b = if queued < limit { buffer[queued] } else { SLAVE_IDLE_BYTE };
+                regs.tx_data.set(b as u32);
+                queued += 1;
+            }
+        });
+        self.tx_queued.set(queued);
+    }
+
+    /// Ends the transaction at a stop condition, returning the buffer it
+    /// used.
+    fn end_transaction(&self) {
+        let regs = self.registers();
+        if self.reading.get() {
+            // Bytes still in the FIFO were never clocked out
+            let unsent = regs.tx_level.get() as usize;
+            regs.control.set(regs.control.get() | SLAVE_CONTROL_RESET_TX);
+            regs.interrupt_enable.set(regs.interrupt_enable.get() & !SLAVE_INTERRUPT_TX_EMPTY);
+            let sent = cmp::min(self.tx_queued.get().saturating_sub(unsent), self.tx_limit.get());
+            self.tx_buffer.take().map(|buffer| {
+                self.client.get().map(move |client| {
+                    client.command_complete(buffer, sent as u8, hil::i2c::SlaveTransmissionType::Read);
+                });
+            });
+        } else {
+            self.drain_rx();
+            let len = self.rx_len.get();
+            self.rx_buffer.take().map(|buffer| {
+                self.client.get().map(move |client| {
+                    client.command_complete(buffer, len as u8, hil::i2c::SlaveTransmissionType::Write);
+                });
+            });
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        let state = regs.interrupt_state.get() & regs.interrupt_enable.get();
+        regs.interrupt_state.set(state);
+
+        if state & SLAVE_INTERRUPT_ADDRESS != 0 {
+            let reading = regs.status.get() & SLAVE_STATUS_READ != 0;
+            self.reading.set(reading);
+            if reading {
+                if self.tx_buffer.is_some() {
+                    self.fill_tx();
+                    regs.interrupt_enable.set(regs.interrupt_enable.get() | SLAVE_INTERRUPT_TX_EMPTY);
+                } else {
+                    self.client.get().map(|client| client.read_expected());
+                }
+            } else {
+                self.rx_len.set(0);
+                if self.rx_buffer.is_none() {
+                    self.client.get().map(|client| client.write_expected());
+                }
+            }
+        }
+        if state & SLAVE_INTERRUPT_RX != 0 {
+            self.drain_rx();
+        }
+        if state & SLAVE_INTERRUPT_TX_EMPTY != 0 {
+            self.fill_tx();
+        }
+        if state & SLAVE_INTERRUPT_STOP != 0 {
+            self.end_transaction();
+        }
+    }
+}
+
+impl hil::i2c::I2CSlave for I2cSlave {
+    fn enable(&self) {
+        let regs = self.registers();
+        if regs.control.get() & SLAVE_CONTROL_ENABLE == 0 {
+            self.clock.acquire();
+        }
+        regs.control.set(SLAVE_CONTROL_ENABLE | SLAVE_CONTROL_RESET_RX | SLAVE_CONTROL_RESET_TX);
+    }
+
+    fn disable(&self) {
+        let regs = self.registers();
+        if regs.control.get() & SLAVE_CONTROL_ENABLE != 0 {
+            regs.interrupt_enable.set(0);
+            regs.control.set(0);
+            self.clock.release();
+        }
+    }
+
+    fn set_address(&self, addr: u8) {
+        self.registers().address.set((addr & 0x7f) as u32);
+    }
+
+    /// Supplies the buffer for the next master write, of which at most
+    /// `max_len` bytes are kept. If the master is already writing (after
+    /// `write_expected`), the stretched clock is released.
+    fn write_receive(&self, data: &'static mut [u8], max_len: u8) {
+        self.rx_limit.set(cmp::min(max_len as usize, data.len()));
+        self.rx_buffer.replace(data);
+        self.drain_rx();
+    }
+
+    /// Queues `max_len` bytes of `data` for the next master read. If the
+    /// master is already reading (after `read_expected`), they are sent
+    /// at once.
+    fn read_send(&self, data: &'static mut [u8], max_len: u8) {
+        let regs = self.registers();
+        self.tx_limit.set(cmp::min(max_len as usize, data.len()));
+        self.tx_queued.set(0);
+        self.tx_buffer.replace(data);
+        if self.reading.get() && regs.status.get() & SLAVE_STATUS_ADDRESSED != 0 {
+            self.fill_tx();
+            regs.interrupt_enable.set(regs.interrupt_enable.get() | SLAVE_INTERRUPT_TX_EMPTY);
+        }
+    }
+
+    /// Starts acknowledging the address.
+    fn listen(&self) {
+        let regs = self.registers();
+        regs.interrupt_state.set(SLAVE_INTERRUPT_ALL);
+        regs.interrupt_enable.set(SLAVE_INTERRUPT_ADDRESS | SLAVE_INTERRUPT_RX | SLAVE_INTERRUPT_STOP);
+        regs.control.set(regs.control.get() | SLAVE_CONTROL_ACK_ADDRESS);
+    }
+}
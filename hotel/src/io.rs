@@ -5,6 +5,16 @@ use uart;
 
 pub struct Writer;
 
+// The pad UART0 TX is driven on, set by the board
+static mut CONSOLE_PAD: pinmux::SelectablePin = pinmux::SelectablePin::Dioa0;
+
+/// Sets the pad `Writer` drives UART0 TX on, before it is first used.
+pub fn set_console_pad(pad: pinmux::SelectablePin) {
+    unsafe {
+        CONSOLE_PAD = pad;
+    }
+}
+
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
         unsafe {
@@ -14,9 +24,8 @@ impl Write for Writer {
             if !INITIALIZED {
                 INITIALIZED = true;
 
-                let pinmux = &mut *pinmux::PINMUX;
-                // Drive DIOA0 from TX
-                pinmux.dioa0.select.set(pinmux::Function::Uart0Tx);
+                // The board may have connected the pad already.
+                let _ = pinmux::connect_output(CONSOLE_PAD, pinmux::Function::Uart0Tx);
 
                 uart.config(115200);
             }
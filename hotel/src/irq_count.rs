@@ -0,0 +1,155 @@
+//! Interrupt counts per NVIC line
+//!
+//! The chip counts, for every NVIC line it services, how many times the
+//! line fired and how many of those were spurious: lines the chip has no
+//! handler for, such as the combined GPIO lines whose pins also have lines
+//! of their own. An interrupt storm (e.g. an unmasked SOF) shows up as one
+//! line's count racing ahead of the others. Unlike `irq_timing` the counts
+//! are always kept, since they cost a table lookup per interrupt.
+//!
+//! The first `MAX_LINES` distinct lines to fire are counted; any others
+//! are ignored. `dump` prints the counts and the panic dump includes them.
+//! A `REQUEST_IRQ_COUNTS` vendor request returns up to `ENTRIES_PER_REQUEST`
+//! lines, starting from the one whose index (in the order lines first
+//! fired) is in wValue:
+//!
+//! | Offset | Size | Contents                                    |
+//! | ------ | ---- | :------------------------------------------ |
+//! | 8n     | 2    | NVIC line of the n-th entry (LE)            |
+//! | 8n+2   | 2    | Times it was spurious, saturating (LE)      |
+//! | 8n+4   | 4    | Times it fired (LE)                         |
+
+use core::cmp;
+use core::fmt::Write;
+use kernel::ReturnCode;
+use usb::{VendorHandler, VendorRequest, MAX_VENDOR_DATA, USB0};
+
+pub const REQUEST_IRQ_COUNTS: u8 = 0x1b;
+
+/// Most NVIC lines that are counted
+pub const MAX_LINES: usize = 32;
+
+/// Bytes per line in a `REQUEST_IRQ_COUNTS` response
+const ENTRY_LEN: usize = 8;
+
+/// Lines in one `REQUEST_IRQ_COUNTS` response
+pub const ENTRIES_PER_REQUEST: usize = MAX_VENDOR_DATA / ENTRY_LEN;
+
+/// Number of NVIC lines
+const NUM_IRQS: usize = 255;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IrqCount {
+    pub line: u32,
+    pub fired: u32,
+    pub spurious: u32,
+}
+
+impl IrqCount {
+    /// Times the line fired and had a handler
+    pub fn handled(&self) -> u32 {
+        self.fired - self.spurious
+    }
+}
+
+const UNUSED: IrqCount = IrqCount {
+    line: 0,
+    fired: 0,
+    spurious: 0,
+};
+
+// Index in `LINES` of each line's count plus one, or 0 if it has none yet
+static mut INDEX: [u8; NUM_IRQS] = [0; NUM_IRQS];
+
+static mut LINES: [IrqCount; MAX_LINES] = [UNUSED; MAX_LINES];
+static mut NUM_LINES: usize = 0;
+
+fn count(line: u32) -> Option<&'static mut IrqCount> {
+    unsafe {
+        let index = match INDEX.get_mut(line as usize) {
+            Some(index) => index,
+            None => return None,
+        };
+        if *index == 0 {
+            if NUM_LINES == MAX_LINES {
+                return None;
+            }
+            LINES[NUM_LINES] = IrqCount { line: line, ..UNUSED };
+            NUM_LINES += 1;
+            *index = NUM_LINES as u8;
+        }
+        Some(&mut LINES[*index as usize - 1])
+    }
+}
+
+/// Answers `REQUEST_IRQ_COUNTS`.
+pub fn init() -> ReturnCode {
+    unsafe { USB0.add_vendor_handler(REQUEST_IRQ_COUNTS, &IRQ_COUNTS) }
+}
+
+/// Records that `line` fired. The chip calls this before dispatching it.
+pub fn fired(line: u32) {
+    count(line).map(|count| count.fired = count.fired.wrapping_add(1));
+}
+
+/// Records that `line`, which has just been counted as fired, had no
+/// handler.
+pub fn spurious(line: u32) {
+    count(line).map(|count| count.spurious = count.spurious.wrapping_add(1));
+}
+
+/// Counts of every line that fired since boot or the last `reset`, in the
+/// order they first fired.
+pub fn counts() -> &'static [IrqCount] {
+    unsafe { &LINES[..NUM_LINES] }
+}
+
+/// Forgets all counts.
+pub fn reset() {
+    unsafe {
+        for count in LINES[..NUM_LINES].iter() {
+            INDEX[count.line as usize] = 0;
+        }
+        NUM_LINES = 0;
+    }
+}
+
+/// Prints a line per NVIC line that fired.
+pub fn dump(writer: &mut Write) {
+    let _ = writer.write_fmt(format_args!("\r\nIRQ      fired   handled  spurious\r\n"));
+    for count in counts() {
+        let _ = writer.write_fmt(format_args!("{:>3} {:>10} {:>9} {:>9}\r\n",
+                                              count.line,
+                                              count.fired,
+                                              count.handled(),
+                                              count.spurious));
+    }
+}
+
+/// Serves `REQUEST_IRQ_COUNTS`
+pub struct IrqCounts;
+
+pub static IRQ_COUNTS: IrqCounts = IrqCounts;
+
+impl VendorHandler for IrqCounts {
+    fn vendor_request(&self, request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        let counts = counts();
+        let first = cmp::min(request.value as usize, counts.len());
+        let mut len = 0;
+        for (count, entry) in counts[first..].iter().zip(data.chunks_mut(ENTRY_LEN)) {
+            if entry.len() < ENTRY_LEN {
+                break;
+            }
+            let spurious = cmp::min(count.spurious, 0xffff);
+            entry[0] = count.line as u8;
+            entry[1] = (count.line >> 8) as u8;
+            entry[2] = spurious as u8;
+            entry[3] = (spurious >> 8) as u8;
+            for i in 0..4 {
+                entry[4 + i] = (count.fired >> (i * 8)) as u8;
+            }
+            len += ENTRY_LEN;
+        }
+        Ok(len)
+    }
+}
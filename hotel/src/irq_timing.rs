@@ -0,0 +1,182 @@
+//! Interrupt latency and handler duration, per NVIC line
+//!
+//! Built with the `irq_timing` feature, the vector table points every
+//! interrupt at `isr`, which stamps its entry with the DWT cycle counter
+//! before handing over to the usual `generic_isr`. The chip's
+//! `service_pending_interrupts` then records, for each line it services,
+//! the latency from entry to the handler starting and how long the handler
+//! ran. `timing` and `dump` report the count, maximum and mean of both for
+//! every line seen since boot or the last `reset`; without the feature
+//! nothing is recorded.
+//!
+//! The first `MAX_LINES` distinct lines to fire are tracked; any others are
+//! ignored. Times are in cycles of the 24MHz core clock.
+
+use cortexm3::generic_isr;
+use profile;
+
+/// Most NVIC lines that are tracked
+pub const MAX_LINES: usize = 32;
+
+/// Core clock cycles per microsecond
+pub const CYCLES_PER_US: u32 = 24;
+
+/// Number of entries of the vector table's `IRQS`
+const NUM_IRQS: usize = 255;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IrqTiming {
+    pub line: u32,
+    pub count: u32,
+    pub max_latency: u32,
+    pub total_latency: u64,
+    pub max_duration: u32,
+    pub total_duration: u64,
+}
+
+impl IrqTiming {
+    pub fn mean_latency(&self) -> u32 {
+        mean(self.total_latency, self.count)
+    }
+
+    pub fn mean_duration(&self) -> u32 {
+        mean(self.total_duration, self.count)
+    }
+}
+
+fn mean(total: u64, count: u32) -> u32 {
+    match count {
+        0 => 0,
+        count => (total / count as u64) as u32,
+    }
+}
+
+const UNUSED: IrqTiming = IrqTiming {
+    line: 0,
+    count: 0,
+    max_latency: 0,
+    total_latency: 0,
+    max_duration: 0,
+    total_duration: 0,
+};
+
+// Cycle count when each line's interrupt was taken. The low bit is always
+// set, so 0 means the entry wasn't stamped (e.g. the line was set pending
+// by software).
+static mut ENTRY: [u32; NUM_IRQS] = [0; NUM_IRQS];
+
+static mut LINES: [IrqTiming; MAX_LINES] = [UNUSED; MAX_LINES];
+static mut NUM_LINES: usize = 0;
+
+/// Starts the cycle counter; without it every time reads as zero.
+pub fn init() {
+    profile::enable();
+}
+
+/// Interrupt entry point used instead of `generic_isr` with the
+/// `irq_timing` feature.
+#[naked]
+pub unsafe extern "C" fn isr() {
+    // r1 and lr (the EXC_RETURN value `generic_isr` looks at) are saved
+    // around the call, which keeps the stack 8-byte aligned.
+    asm!("push {r1, lr}
+          blx r2
+          pop {r1, lr}
+          bx r1"
+         :
+         : "{r1}"(generic_isr as usize), "{r2}"(stamp_entry as usize)
+         :
+         : "volatile");
+}
+
+unsafe extern "C" fn stamp_entry() {
+    let ipsr: u32;
+    asm!("mrs $0, ipsr" : "=r"(ipsr) ::: "volatile");
+    // IPSR[8:0] holds the exception number, which is the NVIC line plus 16.
+    let line = ((ipsr & 0x1ff) as usize).wrapping_sub(16);
+    if line < NUM_IRQS {
+        ENTRY[line] = profile::cycles() | 1;
+    }
+}
+
+/// What `handler_end` needs when the handler for a line is started.
+pub fn handler_start() -> u32 {
+    if cfg!(feature = "irq_timing") {
+        profile::cycles()
+    } else {
+        0
+    }
+}
+
+/// Records a handler for `line` that started at `started` and has just
+/// returned.
+pub fn handler_end(line: u32, started: u32) {
+    if !cfg!(feature = "irq_timing") {
+        return;
+    }
+    let duration = profile::cycles().wrapping_sub(started);
+    unsafe {
+        let entry = match ENTRY.get_mut(line as usize) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if *entry == 0 {
+            return;
+        }
+        let latency = started.wrapping_sub(*entry);
+        *entry = 0;
+
+        let index = match LINES[..NUM_LINES].iter().position(|timing| timing.line == line) {
+            Some(index) => index,
+            None if NUM_LINES < MAX_LINES => {
+                LINES[NUM_LINES] = IrqTiming { line: line, ..UNUSED };
+                NUM_LINES += 1;
+                NUM_LINES - 1
+            }
+            None => return,
+        };
+        let timing = &mut LINES[index];
+        timing.count = timing.count.wrapping_add(1);
+        timing.total_latency = timing.total_latency.wrapping_add(latency as u64);
+        timing.total_duration = timing.total_duration.wrapping_add(duration as u64);
+        if latency > timing.max_latency {
+            timing.max_latency = latency;
+        }
+        if duration > timing.max_duration {
+            timing.max_duration = duration;
+        }
+    }
+}
+
+/// Timing of `line`, if it has been serviced since the last `reset`.
+pub fn timing(line: u32) -> Option<IrqTiming> {
+    unsafe { LINES[..NUM_LINES].iter().find(|timing| timing.line == line).cloned() }
+}
+
+/// Timings of every line serviced since the last `reset`, in the order
+/// they first fired.
+pub fn timings() -> &'static [IrqTiming] {
+    unsafe { &LINES[..NUM_LINES] }
+}
+
+/// Forgets all recorded timings.
+pub fn reset() {
+    unsafe {
+        NUM_LINES = 0;
+    }
+}
+
+/// Prints a line of statistics per interrupt on the console, in
+/// microseconds.
+pub fn dump() {
+    println!("IRQ   count  latency max/mean  handler max/mean (us)\r");
+    for timing in timings() {
+        println!("{:>3} {:>7} {:>9}/{:<6} {:>9}/{:<6}\r",
+                 timing.line,
+                 timing.count,
+                 timing.max_latency / CYCLES_PER_US,
+                 timing.mean_latency() / CYCLES_PER_US,
+                 timing.max_duration / CYCLES_PER_US,
+                 timing.mean_duration() / CYCLES_PER_US);
+    }
+}
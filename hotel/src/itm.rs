@@ -0,0 +1,161 @@
+//! Debug output over ITM/SWO or semihosting
+//!
+//! `ITM_WRITER` is a `core::fmt::Write` that needs nothing from the chip's
+//! peripherals, so it works before the UART or USB consoles are up and
+//! while debugging their drivers. Text goes to ITM stimulus port 0 (and out
+//! of the SWO pin as NRZ at `SWO_BAUD`) once `init` has run; failing that,
+//! it goes to the debugger over semihosting if one is attached, and is
+//! dropped otherwise. Semihosting halts the core for every call, so it is
+//! much slower than SWO.
+
+use core::fmt;
+use kernel::common::cells::VolatileCell;
+
+/// Core clock feeding the TPIU
+const TRACE_CLOCK_HZ: u32 = 24_000_000;
+
+/// SWO bit rate
+pub const SWO_BAUD: u32 = 2_000_000;
+
+#[repr(C)]
+struct ItmRegisters {
+    /// Stimulus ports 0-31
+    stimulus: [VolatileCell<u32>; 32],
+    _reserved0: [u32; 864],
+    /// Trace Enable Register: one bit per stimulus port
+    trace_enable: VolatileCell<u32>,
+    _reserved1: [u32; 15],
+    /// Trace Privilege Register
+    trace_privilege: VolatileCell<u32>,
+    _reserved2: [u32; 15],
+    /// Trace Control Register
+    ///
+    /// | bits  | Description                      |
+    /// | ----- | :------------------------------- |
+    /// | 0     | ITMENA: enable the ITM           |
+    /// | 3     | DWTENA: forward DWT packets      |
+    /// | 22:16 | TraceBusID                       |
+    trace_control: VolatileCell<u32>,
+    _reserved3: [u32; 75],
+    /// Lock Access Register
+    lock_access: VolatileCell<u32>,
+}
+
+#[repr(C)]
+struct TpiuRegisters {
+    _reserved0: [u32; 4],
+    /// Asynchronous Clock Prescaler Register: SWO clock = trace clock /
+    /// (prescaler + 1)
+    prescaler: VolatileCell<u32>,
+    _reserved1: [u32; 55],
+    /// Selected Pin Protocol Register: 1 Manchester, 2 NRZ
+    pin_protocol: VolatileCell<u32>,
+    _reserved2: [u32; 132],
+    /// Formatter and Flush Control Register
+    formatter_control: VolatileCell<u32>,
+}
+
+const ITM_BASE: *const ItmRegisters = 0xE0000000 as *const ItmRegisters;
+const TPIU_BASE: *const TpiuRegisters = 0xE0040000 as *const TpiuRegisters;
+
+/// Debug Halting Control and Status Register
+const DHCSR: *const VolatileCell<u32> = 0xE000EDF0 as *const VolatileCell<u32>;
+
+/// Debug Exception and Monitor Control Register
+const DEMCR: *const VolatileCell<u32> = 0xE000EDFC as *const VolatileCell<u32>;
+
+/// DHCSR: a debugger is attached
+const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+
+/// DEMCR: enables the DWT and ITM units
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+/// LAR: key that unlocks the ITM registers
+const LAR_UNLOCK: u32 = 0xC5ACCE55;
+
+/// TCR: ITM enabled, trace bus ID 1
+const TCR_ITMENA: u32 = 1 << 0;
+const TCR_TRACE_BUS_ID: u32 = 1 << 16;
+
+/// SPPR: asynchronous NRZ (UART-like) encoding
+const SPPR_NRZ: u32 = 2;
+
+/// FFCR: continuous formatting off, so ITM packets go straight out
+const FFCR_TRIGIN: u32 = 1 << 8;
+
+/// Semihosting operation writing a NUL-terminated string
+const SYS_WRITE0: u32 = 0x04;
+
+pub static mut ITM_WRITER: ItmWriter = ItmWriter;
+
+/// Turns on the ITM and TPIU so stimulus port 0 comes out of SWO.
+pub fn init() {
+    unsafe {
+        let demcr = &*DEMCR;
+        demcr.set(demcr.get() | DEMCR_TRCENA);
+
+        let tpiu = &*TPIU_BASE;
+        tpiu.pin_protocol.set(SPPR_NRZ);
+        tpiu.prescaler.set(TRACE_CLOCK_HZ / SWO_BAUD - 1);
+        tpiu.formatter_control.set(FFCR_TRIGIN);
+
+        let itm = &*ITM_BASE;
+        itm.lock_access.set(LAR_UNLOCK);
+        itm.trace_control.set(TCR_ITMENA | TCR_TRACE_BUS_ID);
+        itm.trace_privilege.set(0);
+        itm.trace_enable.set(1);
+    }
+}
+
+/// Whether stimulus port 0 is enabled, by `init` or by the debugger.
+fn itm_enabled() -> bool {
+    unsafe {
+        let itm = &*ITM_BASE;
+        (&*DEMCR).get() & DEMCR_TRCENA != 0 && itm.trace_control.get() & TCR_ITMENA != 0 &&
+            itm.trace_enable.get() & 1 != 0
+    }
+}
+
+fn debugger_attached() -> bool {
+    unsafe { (&*DHCSR).get() & DHCSR_C_DEBUGEN != 0 }
+}
+
+fn itm_write(bytes: &[u8]) {
+    let port = unsafe { &(&*ITM_BASE).stimulus[0] };
+    for &byte in bytes {
+        // The port reads 1 when its FIFO has room.
+        while port.get() & 1 == 0 {}
+        // A byte-sized write sends a one-byte packet.
+        unsafe { (&*(port as *const VolatileCell<u32> as *const VolatileCell<u8>)).set(byte) };
+    }
+}
+
+fn semihosting_write(bytes: &[u8]) {
+    // SYS_WRITE0 wants a NUL-terminated string, so send it in pieces.
+    let mut buffer = [0u8; 33];
+    for chunk in bytes.chunks(buffer.len() - 1) {
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        buffer[chunk.len()] = 0;
+        let _result: u32;
+        unsafe {
+            asm!("bkpt 0xab"
+                 : "={r0}"(_result)
+                 : "{r0}"(SYS_WRITE0), "{r1}"(buffer.as_ptr())
+                 : "memory"
+                 : "volatile");
+        }
+    }
+}
+
+pub struct ItmWriter;
+
+impl fmt::Write for ItmWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if itm_enabled() {
+            itm_write(s.as_bytes());
+        } else if debugger_attached() {
+            semihosting_write(s.as_bytes());
+        }
+        Ok(())
+    }
+}
@@ -10,22 +10,65 @@ extern crate kernel;
 #[macro_use]
 pub mod io;
 
+pub mod authenticator;
+pub mod build_info;
+pub mod calendar;
 pub mod chip;
+pub mod counter;
 pub mod crypto;
+pub mod deferred_call;
+pub mod errata;
+pub mod fault;
+pub mod flash;
+pub mod fuse;
+pub mod globalsec;
 pub mod gpio;
 pub mod hil;
+pub mod i2c;
+pub mod irq_count;
+pub mod irq_timing;
+pub mod itm;
+pub mod memory;
+pub mod panic;
+#[cfg(feature = "peek_poke")]
+pub mod peek_poke;
+pub mod personality;
 pub mod pinmux;
 pub mod pmu;
+pub mod power_trace;
+pub mod profile;
+pub mod provision;
+pub mod pwm;
+pub mod rbox;
+pub mod reboot;
+pub mod receiver;
+pub mod spi;
+pub mod spi_flash;
+pub mod sps;
+pub mod strap;
+pub mod temp;
 pub mod timels;
+pub mod timestamp;
 pub mod timeus;
+pub mod tpm;
+pub mod trace;
 pub mod trng;
 pub mod uart;
 pub mod usb;
+pub mod volt;
+pub mod watchdog;
+pub mod xo;
 
-pub mod test_rng;
+pub mod test_registry;
+pub mod test_aes;
 pub mod test_dcrypto;
+pub mod test_flash;
+pub mod test_rng;
+pub mod test_runner;
+pub mod test_sha;
+pub mod test_usb;
 
-use cortexm3::{generic_isr, hard_fault_handler, svc_handler, systick_handler};
+use cortexm3::{generic_isr, svc_handler, systick_handler};
 
 unsafe extern "C" fn unhandled_interrupt() {
     let mut interrupt_number: u32;
@@ -67,7 +110,7 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     _estack,
     reset_handler,
     unhandled_interrupt, // NMI
-    hard_fault_handler,  // Hard Fault
+    panic::hard_fault_entry, // Hard Fault
     unhandled_interrupt, // MemManage
     unhandled_interrupt, // BusFault
     unhandled_interrupt, // UsageFault
@@ -82,9 +125,26 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     systick_handler,     // SysTick
 ];
 
+#[cfg(not(feature = "irq_timing"))]
+const ISR: unsafe extern "C" fn() = generic_isr;
+#[cfg(feature = "irq_timing")]
+const ISR: unsafe extern "C" fn() = irq_timing::isr;
+
+/// The 255 interrupt vectors: `ISR` for every line but the watchdog's
+#[repr(C)]
+pub struct Irqs {
+    before_watchdog: [unsafe extern "C" fn(); watchdog::WATCHDOG0_IRQ],
+    watchdog: unsafe extern "C" fn(),
+    after_watchdog: [unsafe extern "C" fn(); 254 - watchdog::WATCHDOG0_IRQ],
+}
+
 #[link_section = ".vectors"]
 #[no_mangle] // Ensures that the symbol is kept until the final binary
-pub static IRQS: [unsafe extern "C" fn(); 255] = [generic_isr; 255];
+pub static IRQS: Irqs = Irqs {
+    before_watchdog: [ISR; watchdog::WATCHDOG0_IRQ],
+    watchdog: watchdog::bark_handler,
+    after_watchdog: [ISR; 254 - watchdog::WATCHDOG0_IRQ],
+};
 
 pub unsafe fn init() {
     // Relocate data segment.
@@ -114,4 +174,6 @@ pub unsafe fn init() {
     cortexm3::nvic::disable_all();
     cortexm3::nvic::clear_all_pending();
     cortexm3::nvic::enable_all();
+
+    errata::init();
 }
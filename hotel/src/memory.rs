@@ -0,0 +1,274 @@
+//! Kernel stack and buffer usage
+//!
+//! So the kernel stack and the static buffers drivers are handed can be
+//! sized from data rather than guesses, this module tracks how much of each
+//! has been needed since boot.
+//!
+//! The kernel stack grows down from `_estack` to `_sstack`; interrupt
+//! handlers run on it too. `paint_stack` fills the unused part with
+//! `STACK_PATTERN` at boot, and the deepest word that no longer holds the
+//! pattern is the stack's high-water mark. The chip calls `scan_stack` once
+//! per pass of the kernel's main loop: it only looks just below the last
+//! mark, so it is cheap, and records a trace event the first time less than
+//! `LOW_STACK_BYTES` are left. `stack_usage` scans the whole stack.
+//!
+//! A `BufferUsage` records the most of a buffer a driver has needed, which
+//! can be more than the buffer holds when something didn't fit. Drivers
+//! `register` theirs so they are reported. `dump` prints both, and how each
+//! process is using its memory and grants, on the console. A
+//! `REQUEST_MEMORY_USAGE` vendor request returns the numbers:
+//!
+//! | Offset | Size | Contents                                  |
+//! | ------ | ---- | :---------------------------------------- |
+//! | 0      | 4    | Size of the kernel stack in bytes (LE)    |
+//! | 4      | 4    | Deepest kernel stack use in bytes (LE)    |
+//! | 8+8n   | 4    | Capacity of the n-th registered buffer    |
+//! | 12+8n  | 4    | Most of the n-th registered buffer needed |
+//!
+//! ```ignore
+//! static mut U2F_MESSAGE: BufferUsage = BufferUsage::new("u2f message");
+//! U2F_MESSAGE.register(buffer.len());
+//! U2F_MESSAGE.record(len);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::Write;
+use core::ptr;
+use kernel::ReturnCode;
+use kernel::procs::ProcessType;
+use trace;
+use usb::{VendorHandler, VendorRequest, MAX_VENDOR_DATA, USB0};
+
+pub const REQUEST_MEMORY_USAGE: u8 = 0x16;
+
+/// What the unused kernel stack is filled with
+pub const STACK_PATTERN: u32 = 0x57ac57ac;
+
+/// Stack left below which `scan_stack` records a trace event
+pub const LOW_STACK_BYTES: usize = 512;
+
+/// Most buffers that can be registered: as many as fit in one vendor
+/// response
+pub const MAX_BUFFERS: usize = (MAX_VENDOR_DATA - 8) / 8;
+
+/// Words in a row that still hold the pattern below the mark, after which
+/// `scan_stack` stops looking. A frame can leave a few words it reserved
+/// unwritten.
+const SCAN_GAP_WORDS: usize = 16;
+
+extern "C" {
+    static _sstack: u32;
+    static _estack: u32;
+}
+
+// Lowest stack address known to have been written, or 0 before the first
+// scan. It is in BSS, which `hotel::init` clears after the stack is
+// painted.
+static mut STACK_MARK: usize = 0;
+static mut STACK_LOW_RECORDED: bool = false;
+
+static mut BUFFERS: [Option<&'static BufferUsage>; MAX_BUFFERS] = [None; MAX_BUFFERS];
+
+fn stack_bounds() -> (usize, usize) {
+    unsafe { (&_sstack as *const u32 as usize, &_estack as *const u32 as usize) }
+}
+
+#[inline(always)]
+fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe {
+        asm!("mov $0, sp" : "=r"(sp) ::: "volatile");
+    }
+    sp
+}
+
+fn is_painted(address: usize) -> bool {
+    unsafe { ptr::read_volatile(address as *const u32) == STACK_PATTERN }
+}
+
+/// Fills the kernel stack below the stack pointer with `STACK_PATTERN`.
+/// Must be called first thing at boot, before `hotel::init` enables
+/// interrupts, which would use the stack being painted.
+#[inline(never)]
+pub unsafe fn paint_stack() {
+    let (start, _) = stack_bounds();
+    // Nothing lives below the stack pointer: the ABI has no red zone.
+    let end = stack_pointer() & !3;
+    let mut address = start;
+    while address < end {
+        ptr::write_volatile(address as *mut u32, STACK_PATTERN);
+        address += 4;
+    }
+}
+
+/// Answers `REQUEST_MEMORY_USAGE`.
+pub fn init() -> ReturnCode {
+    unsafe { USB0.add_vendor_handler(REQUEST_MEMORY_USAGE, &MEMORY_USAGE) }
+}
+
+/// Moves the stack's high-water mark down past any words written below it
+/// since the last scan.
+pub fn scan_stack() {
+    let (start, end) = stack_bounds();
+    unsafe {
+        if STACK_MARK == 0 {
+            STACK_MARK = stack_pointer() & !3;
+        }
+        let mut mark = STACK_MARK;
+        let mut address = mark;
+        while address > start && address + SCAN_GAP_WORDS * 4 > mark {
+            address -= 4;
+            if !is_painted(address) {
+                mark = address;
+            }
+        }
+        STACK_MARK = mark;
+        if mark - start < LOW_STACK_BYTES && !STACK_LOW_RECORDED {
+            STACK_LOW_RECORDED = true;
+            trace::record("kernel stack low", (end - mark) as u32);
+        }
+    }
+}
+
+/// Size of the kernel stack and the most of it used since boot, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct StackUsage {
+    pub size: usize,
+    pub used: usize,
+}
+
+/// Scans the whole kernel stack for its high-water mark. If the stack was
+/// never painted, it reads as entirely used.
+pub fn stack_usage() -> StackUsage {
+    let (start, end) = stack_bounds();
+    let mut address = start;
+    while address < end && is_painted(address) {
+        address += 4;
+    }
+    unsafe {
+        if STACK_MARK == 0 || address < STACK_MARK {
+            STACK_MARK = address;
+        }
+    }
+    StackUsage {
+        size: end - start,
+        used: end - address,
+    }
+}
+
+/// The most of a buffer that has been needed
+pub struct BufferUsage {
+    name: &'static str,
+    capacity: Cell<usize>,
+    peak: Cell<usize>,
+}
+
+impl BufferUsage {
+    pub const fn new(name: &'static str) -> BufferUsage {
+        BufferUsage {
+            name: name,
+            capacity: Cell::new(0),
+            peak: Cell::new(0),
+        }
+    }
+
+    /// Adds the buffer, which is `capacity` bytes long, to the ones
+    /// reported. Registering it again only updates the capacity.
+    pub fn register(&'static self, capacity: usize) -> ReturnCode {
+        self.capacity.set(capacity);
+        unsafe {
+            for slot in BUFFERS.iter_mut() {
+                match *slot {
+                    Some(usage) if usage as *const BufferUsage == self as *const BufferUsage => {
+                        return ReturnCode::SUCCESS;
+                    }
+                    Some(_) => {}
+                    None => {
+                        *slot = Some(self);
+                        return ReturnCode::SUCCESS;
+                    }
+                }
+            }
+        }
+        ReturnCode::ENOMEM
+    }
+
+    /// Notes that `len` bytes of the buffer were needed, which may be more
+    /// than it holds.
+    pub fn record(&self, len: usize) {
+        if len > self.peak.get() {
+            self.peak.set(len);
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.get()
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.get()
+    }
+}
+
+/// Registered buffers, in the order they were registered
+pub fn buffers() -> &'static [Option<&'static BufferUsage>] {
+    unsafe { &BUFFERS }
+}
+
+/// Prints the kernel stack's and every registered buffer's usage, and how
+/// much of each process's memory its stack and heap and the kernel's grants
+/// take up.
+pub fn dump(writer: &mut Write, processes: &[Option<&'static ProcessType>]) {
+    let stack = stack_usage();
+    let _ = writer.write_fmt(format_args!("\r\nKernel stack: {} of {} bytes used\r\n",
+                                          stack.used, stack.size));
+    for usage in buffers().iter().filter_map(|usage| *usage) {
+        let _ = writer.write_fmt(format_args!("{:<20} {:>5} of {:>5} bytes{}\r\n",
+                                              usage.name(),
+                                              usage.peak(),
+                                              usage.capacity(),
+                                              if usage.peak() > usage.capacity() { " (too small)" } else { "" }));
+    }
+    for process in processes.iter().filter_map(|process| *process) {
+        let start = process.mem_start() as usize;
+        let end = process.mem_end() as usize;
+        let app_break = process.app_memory_break() as usize;
+        let kernel_break = process.kernel_memory_break() as usize;
+        let _ = writer.write_fmt(format_args!("{:<20} {:>5} bytes: app {:>5} grants {:>5} free {:>5}\r\n",
+                                              process.get_process_name(),
+                                              end - start,
+                                              app_break - start,
+                                              end - kernel_break,
+                                              kernel_break.saturating_sub(app_break)));
+    }
+}
+
+/// Serves `REQUEST_MEMORY_USAGE`
+pub struct MemoryUsage;
+
+pub static MEMORY_USAGE: MemoryUsage = MemoryUsage;
+
+impl VendorHandler for MemoryUsage {
+    fn vendor_request(&self, _request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        let stack = stack_usage();
+        let mut words = [0; 2 + 2 * MAX_BUFFERS];
+        words[0] = stack.size as u32;
+        words[1] = stack.used as u32;
+        let mut count = 2;
+        for usage in buffers().iter().filter_map(|usage| *usage) {
+            words[count] = usage.capacity() as u32;
+            words[count + 1] = usage.peak() as u32;
+            count += 2;
+        }
+        let len = cmp::min(count * 4, data.len());
+        for (i, byte) in data[..len].iter_mut().enumerate() {
+            *byte = (words[i / 4] >> ((i % 4) * 8)) as u8;
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,101 @@
+//! Chip state reporting for board panic handlers
+//!
+//! A panic in a driver (USB in particular) otherwise gives the host nothing
+//! to go on. Board panic handlers call `dump` before or instead of
+//! `kernel::debug::panic` to print the USB driver state, the interrupt
+//! counts and the recent event trace, and may then `reset` the chip rather
+//! than halt.
+//!
+//! A `panic!` reports its own file and line, but a hard fault in the kernel
+//! panics from inside `cortexm3`'s handler, far from the faulting code. The
+//! chip's hard fault vector is therefore `hard_fault_entry`, which records
+//! the PC and LR the fault stacked before handing over, and `dump` prints
+//! them when the panic is raised while handling the fault.
+
+use core::fmt::Write;
+use cortexm3;
+use irq_count;
+use kernel::common::cells::VolatileCell;
+use trace;
+use usb;
+
+/// Application Interrupt and Reset Control Register
+const AIRCR: *const VolatileCell<u32> = 0xE000ED0C as *const VolatileCell<u32>;
+
+/// AIRCR: write key and system reset request
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// The link register of the caller, i.e., the return address into the
+/// function that called the one this is inlined into.
+#[inline(always)]
+pub fn link_register() -> u32 {
+    let lr: u32;
+    unsafe {
+        asm!("mov $0, lr" : "=r"(lr) ::: "volatile");
+    }
+    lr
+}
+
+/// Exception number of the hard fault in IPSR
+const HARD_FAULT_EXCEPTION: u32 = 3;
+
+// PC and LR stacked by the last hard fault
+static mut FAULT_FRAME: [u32; 2] = [0; 2];
+
+/// The hard fault vector: records the PC and LR stacked by the fault, then
+/// continues to `cortexm3::hard_fault_handler` with the registers and stack
+/// as the fault left them.
+#[naked]
+pub unsafe extern "C" fn hard_fault_entry() {
+    // The frame is on the stack the faulting code was using (EXC_RETURN
+    // bit 2): r0-r3, r12, lr, pc, xpsr. Only registers the exception
+    // stacked are touched.
+    asm!("
+    tst lr, #4
+    ite eq
+    mrseq r0, msp
+    mrsne r0, psp
+    ldr r1, [r0, #20]
+    ldr r2, [r0, #24]
+    str r2, [r3]
+    str r1, [r3, #4]
+    bx r12"
+    :
+    : "{r3}"(&mut FAULT_FRAME as *mut [u32; 2]),
+      "{r12}"(cortexm3::hard_fault_handler as unsafe extern "C" fn())
+    : "r0", "r1", "r2", "memory"
+    : "volatile");
+}
+
+/// The PC and LR stacked by the hard fault being handled, if the panic was
+/// raised while handling one.
+pub fn fault_frame() -> Option<(u32, u32)> {
+    let ipsr: u32;
+    unsafe {
+        asm!("mrs $0, ipsr" : "=r"(ipsr) ::: "volatile");
+    }
+    if ipsr & 0x1ff == HARD_FAULT_EXCEPTION {
+        unsafe { Some((FAULT_FRAME[0], FAULT_FRAME[1])) }
+    } else {
+        None
+    }
+}
+
+/// Prints where a hard fault happened (if the panic came from one), the
+/// USB driver state, the interrupt counts and the event trace.
+pub unsafe fn dump(writer: &mut Write) {
+    if let Some((pc, lr)) = fault_frame() {
+        let _ = writer.write_fmt(format_args!("\r\nHard fault at pc {:#010x} lr {:#010x}\r\n",
+                                              pc, lr));
+    }
+    usb::USB0.dump_state(writer);
+    irq_count::dump(writer);
+    trace::dump(writer);
+}
+
+/// Resets the whole chip.
+pub unsafe fn reset() -> ! {
+    (&*AIRCR).set(AIRCR_VECTKEY | AIRCR_SYSRESETREQ);
+    loop {}
+}
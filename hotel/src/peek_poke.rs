@@ -0,0 +1,235 @@
+//! Register peek and poke over USB, for bring-up
+//!
+//! Built with the `peek_poke` feature, this module serves vendor requests
+//! that let a hardware engineer read and write peripheral registers and
+//! look at the USB DMA descriptors from a host, without a JTAG probe.
+//! Without the feature the module doesn't exist, so production images
+//! can't contain it. Even when built in, requests are refused until the
+//! board calls `set_enabled(true)`, e.g. because a bring-up strap is set.
+//!
+//! Only the register blocks in `RANGES` can be reached, and those marked
+//! read-only (such as the USB controller the requests arrive through, the
+//! PMU's clock and reset words, or the PMU scratch words the reset cause
+//! is kept in) can't be written. Accesses are whole aligned words;
+//! wIndex:wValue is the address, and the data is little-endian words:
+//!
+//! | bRequest           | Direction | Data                                    |
+//! | ------------------ | :-------- | :-------------------------------------- |
+//! | `REQUEST_PEEK`     | IN        | wLength / 4 words read from the address |
+//! | `REQUEST_POKE`     | OUT       | Words written to the address            |
+//! | `REQUEST_DMA_DUMP` | IN        | USB DMA descriptors, from the wValue-th |
+//!
+//! `REQUEST_DMA_DUMP` returns up to `ENTRIES_PER_REQUEST` descriptors in
+//! the order of `USB::each_dma_descriptor`; a borrowed one reads as all
+//! ones:
+//!
+//! | Offset | Size | Contents                         |
+//! | ------ | ---- | :------------------------------- |
+//! | 8n     | 4    | Status quadlet of the n-th (LE)  |
+//! | 8n+4   | 4    | Buffer address of the n-th (LE)  |
+
+use core::cmp;
+use core::ptr;
+use kernel::ReturnCode;
+use trace;
+use usb::{VendorHandler, VendorRequest, MAX_VENDOR_DATA, USB0};
+
+pub const REQUEST_PEEK: u8 = 0x1c;
+pub const REQUEST_POKE: u8 = 0x1d;
+pub const REQUEST_DMA_DUMP: u8 = 0x1e;
+
+/// Bytes per descriptor in a `REQUEST_DMA_DUMP` response
+const ENTRY_LEN: usize = 8;
+
+/// Descriptors in one `REQUEST_DMA_DUMP` response
+pub const ENTRIES_PER_REQUEST: usize = MAX_VENDOR_DATA / ENTRY_LEN;
+
+/// A block of registers that can be reached
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    pub name: &'static str,
+    pub start: usize,
+    pub len: usize,
+    pub writable: bool,
+}
+
+const fn span(name: &'static str, start: usize, len: usize, writable: bool) -> Range {
+    Range {
+        name: name,
+        start: start,
+        len: len,
+        writable: writable,
+    }
+}
+
+const fn block(name: &'static str, start: usize, writable: bool) -> Range {
+    span(name, start, 0x1000, writable)
+}
+
+const PMU: usize = 0x40000000;
+
+/// The PMU's chip reset words, `reset` through `global_reset`
+const PMU_RESET_LEN: usize = 0x14;
+
+/// The PMU's clock words, `memory_clk_set` through `_clock0`: turning a
+/// clock off from here would pull it from under its driver's reference
+/// count
+const PMU_CLOCKS: usize = 0x4000005c;
+const PMU_CLOCKS_LEN: usize = 0x34;
+
+/// The PMU's peripheral reset words, `reset0_write_enable` through `reset1`
+const PMU_PERIPHERAL_RESETS: usize = 0x40000090;
+const PMU_PERIPHERAL_RESETS_LEN: usize = 0x10;
+
+/// The PMU's `long_life_scratch` words, which carry the reset cause and the
+/// saved time across resets
+const PMU_SCRATCH: usize = 0x400000a0;
+const PMU_SCRATCH_LEN: usize = 12;
+
+/// The register blocks that can be reached: each peripheral's first 4KB.
+/// Key management, crypto, fuses and the TRNG are left out entirely.
+pub static RANGES: [Range; 28] = [
+    span("pmu reset", PMU, PMU_RESET_LEN, false),
+    span("pmu", PMU + PMU_RESET_LEN, PMU_CLOCKS - PMU - PMU_RESET_LEN, true),
+    span("pmu clocks", PMU_CLOCKS, PMU_CLOCKS_LEN, false),
+    span("pmu resets", PMU_PERIPHERAL_RESETS, PMU_PERIPHERAL_RESETS_LEN, false),
+    span("pmu scratch", PMU_SCRATCH, PMU_SCRATCH_LEN, false),
+    span("pmu", PMU_SCRATCH + PMU_SCRATCH_LEN, PMU + 0x1000 - PMU_SCRATCH - PMU_SCRATCH_LEN, true),
+    block("pinmux", 0x40060000, true),
+    block("globalsec", 0x40090000, false),
+    block("gpio0", 0x40200000, true),
+    block("gpio1", 0x40210000, true),
+    block("usb", 0x40300000, false),
+    block("xo", 0x40460000, true),
+    block("watchdog", 0x40500000, false),
+    block("sps", 0x40520000, true),
+    block("timels", 0x40540000, true),
+    block("rbox", 0x40550000, true),
+    block("volt", 0x40560000, true),
+    block("i2c0", 0x40580000, true),
+    block("i2c1", 0x40590000, true),
+    block("uart0", 0x40600000, true),
+    block("uart1", 0x40610000, true),
+    block("uart2", 0x40620000, true),
+    block("i2cs0", 0x40650000, true),
+    block("timeus", 0x40670000, true),
+    block("temp", 0x40690000, true),
+    block("spi0", 0x40700000, true),
+    block("spi1", 0x40710000, true),
+    block("flash", 0x40720000, false),
+];
+
+static mut ENABLED: bool = false;
+
+/// Answers `REQUEST_PEEK`, `REQUEST_POKE` and `REQUEST_DMA_DUMP`.
+pub fn init() -> ReturnCode {
+    unsafe {
+        for &request in [REQUEST_PEEK, REQUEST_POKE, REQUEST_DMA_DUMP].iter() {
+            let result = USB0.add_vendor_handler(request, &PEEK_POKE);
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+        }
+    }
+    ReturnCode::SUCCESS
+}
+
+/// Lets requests through (`true`) or refuses them all.
+pub fn set_enabled(enabled: bool) {
+    unsafe {
+        ENABLED = enabled;
+    }
+}
+
+/// Whether requests are let through.
+pub fn enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// The range `len` bytes at `address` lie entirely within, if any.
+pub fn range(address: usize, len: usize) -> Option<&'static Range> {
+    RANGES.iter().find(|range| {
+        address >= range.start && len <= range.len && address - range.start <= range.len - len
+    })
+}
+
+/// Checks an access of `len` bytes at `address`: aligned whole words
+/// within one range, which must be writable if `write`.
+fn check(address: usize, len: usize, write: bool) -> Result<(), ReturnCode> {
+    if address % 4 != 0 || len % 4 != 0 {
+        return Err(ReturnCode::EINVAL);
+    }
+    match range(address, len) {
+        Some(range) if range.writable || !write => Ok(()),
+        Some(_) => Err(ReturnCode::ERESERVE),
+        None => Err(ReturnCode::EINVAL),
+    }
+}
+
+fn peek(address: usize, data: &mut [u8]) -> Result<usize, ReturnCode> {
+    let len = data.len() & !3;
+    if let Err(error) = check(address, len, false) {
+        return Err(error);
+    }
+    for (i, bytes) in data[..len].chunks_mut(4).enumerate() {
+        let word = unsafe { ptr::read_volatile((address + 4 * i) as *const u32) };
+        for (j, byte) in bytes.iter_mut().enumerate() {
+            *byte = (word >> (j * 8)) as u8;
+        }
+    }
+    Ok(len)
+}
+
+fn poke(address: usize, data: &[u8]) -> Result<usize, ReturnCode> {
+    if let Err(error) = check(address, data.len(), true) {
+        return Err(error);
+    }
+    trace::record("peek_poke write", address as u32);
+    for (i, bytes) in data.chunks(4).enumerate() {
+        let word = bytes.iter().enumerate().fold(0, |word, (j, &byte)| word | (byte as u32) << (j * 8));
+        unsafe { ptr::write_volatile((address + 4 * i) as *mut u32, word) };
+    }
+    Ok(0)
+}
+
+fn dma_dump(first: usize, data: &mut [u8]) -> usize {
+    let mut index = 0;
+    let mut len = 0;
+    unsafe {
+        USB0.each_dma_descriptor(|desc| {
+            if index >= first && len + ENTRY_LEN <= data.len() {
+                let (flags, addr) = desc.map_or((!0, !0), |desc| (desc.flags.to_u32(), desc.addr as u32));
+                for i in 0..4 {
+                    data[len + i] = (flags >> (i * 8)) as u8;
+                    data[len + 4 + i] = (addr >> (i * 8)) as u8;
+                }
+                len += ENTRY_LEN;
+            }
+            index += 1;
+        });
+    }
+    len
+}
+
+/// Serves the peek and poke requests
+pub struct PeekPoke;
+
+pub static PEEK_POKE: PeekPoke = PeekPoke;
+
+impl VendorHandler for PeekPoke {
+    fn vendor_request(&self, request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        if !enabled() {
+            return Err(ReturnCode::EOFF);
+        }
+        let address = (request.index as usize) << 16 | request.value as usize;
+        match request.request {
+            REQUEST_PEEK => {
+                let len = cmp::min(request.length as usize, data.len());
+                peek(address, &mut data[..len])
+            }
+            REQUEST_POKE => poke(address, data),
+            REQUEST_DMA_DUMP => Ok(dma_dump(request.value as usize, data)),
+            _ => Err(ReturnCode::ENOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! The device's personality: its attestation key and certificate, and the
+//! key firmware is signed with
+//!
+//! The personality lives in its own page of flash, outside any image, and
+//! is written once per device (see `provision`). The page holds:
+//!
+//! | Offset | Size | Contents                                          |
+//! | ------ | ---- | :------------------------------------------------ |
+//! | 0      | 4    | `PERSONALITY_MAGIC`, little-endian                |
+//! | 4      | 2    | Length K of the attestation key (LE)              |
+//! | 6      | 2    | Length C of the attestation certificate (LE)      |
+//! | 8      | 2    | Length F of the firmware key (LE): 0 or 64        |
+//! | 10     | 2    | Reserved, 0xffff                                  |
+//! | 12     | 32   | HMAC-SHA256 of bytes 0-11 and 44-(44+K+C+F)       |
+//! | 44     | K    | Attestation key                                   |
+//! | 44+K   | C    | Attestation certificate                           |
+//! | 44+K+C | F    | Public key apps are signed with (see `hil::ecdsa`)|
+//!
+//! The HMAC is keyed with the key ladder's output, which is unique to the
+//! device, so a personality copied from another device or changed in
+//! flash is rejected. The header is written last: while its first word is
+//! erased the device is unprovisioned.
+
+use core::cell::Cell;
+use core::slice;
+use crypto::sha::ShaEngine;
+use fault;
+use hil::digest::DigestEngine;
+use kernel::ReturnCode;
+
+/// "PRSN"
+pub const PERSONALITY_MAGIC: u32 = 0x4e535250;
+
+pub const HEADER_LEN: usize = 44;
+pub const MAC_LEN: usize = 32;
+/// Length of the magic and lengths the MAC covers, which the MAC follows
+pub const FIELDS_LEN: usize = 12;
+const MAC_OFFSET: usize = FIELDS_LEN;
+
+/// Longest attestation key accepted
+pub const MAX_KEY_LEN: usize = 64;
+
+/// Length of the firmware key, a P-256 point, if there is one
+pub const FIRMWARE_KEY_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Nothing has been committed
+    Unprovisioned = 0,
+    Provisioned = 1,
+    /// Something other than a personality for this device is in the page
+    Invalid = 2,
+}
+
+pub static mut PERSONALITY: Personality = Personality::new();
+
+pub struct Personality {
+    start: Cell<usize>,
+    len: Cell<usize>,
+    state: Cell<State>,
+    key_len: Cell<usize>,
+    certificate_len: Cell<usize>,
+    firmware_key_len: Cell<usize>,
+}
+
+fn read_u16(bytes: &[u8]) -> usize {
+    bytes[0] as usize | (bytes[1] as usize) << 8
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Computes the MAC of a personality whose header starts with `fields`
+/// (magic and lengths) and whose key and certificate are `body`. Fails
+/// with EBUSY if the SHA engine is in use.
+pub fn compute_mac(sha: &ShaEngine, fields: &[u8], body: &[u8], mac: &mut [u8; MAC_LEN]) -> Result<(), ReturnCode> {
+    sha.initialize_hidden_key_hmac()?;
+    let computed = sha.update(fields).is_ok() &&
+        sha.update(body).is_ok() &&
+        sha.finalize(mac).is_ok();
+    if !computed {
+        sha.cancel();
+        return Err(ReturnCode::FAIL);
+    }
+    Ok(())
+}
+
+impl Personality {
+    const fn new() -> Personality {
+        Personality {
+            start: Cell::new(0),
+            len: Cell::new(0),
+            state: Cell::new(State::Invalid),
+            key_len: Cell::new(0),
+            certificate_len: Cell::new(0),
+            firmware_key_len: Cell::new(0),
+        }
+    }
+
+    /// Reads the personality in the `len` bytes of flash at `start` and
+    /// checks its MAC. Boards call this before anything else can be using
+    /// the SHA engine.
+    pub fn init(&self, start: usize, len: usize, sha: &ShaEngine) -> State {
+        self.start.set(start);
+        self.len.set(len);
+        self.verify(sha).unwrap_or(State::Invalid)
+    }
+
+    /// Start, and length in bytes, of the page the personality is in
+    pub fn region(&self) -> (usize, usize) {
+        (self.start.get(), self.len.get())
+    }
+
+    /// The page the personality is in
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.start.get() as *const u8, self.len.get()) }
+    }
+
+    /// Re-reads the page, e.g. after provisioning wrote it. Fails with
+    /// EBUSY, leaving the state as it was, if the SHA engine is in use.
+    pub fn verify(&self, sha: &ShaEngine) -> Result<State, ReturnCode> {
+        let state = self.check(sha)?;
+        self.state.set(state);
+        Ok(state)
+    }
+
+    fn check(&self, sha: &ShaEngine) -> Result<State, ReturnCode> {
+        let bytes = self.bytes();
+        if bytes.len() < HEADER_LEN {
+            return Ok(State::Invalid);
+        }
+        if read_u32(&bytes[0..4]) == !0 {
+            return Ok(State::Unprovisioned);
+        }
+        if read_u32(&bytes[0..4]) != PERSONALITY_MAGIC {
+            return Ok(State::Invalid);
+        }
+        let key_len = read_u16(&bytes[4..6]);
+        let certificate_len = read_u16(&bytes[6..8]);
+        let firmware_key_len = read_u16(&bytes[8..10]);
+        let body_len = key_len + certificate_len + firmware_key_len;
+        if key_len > MAX_KEY_LEN || (firmware_key_len != 0 && firmware_key_len != FIRMWARE_KEY_LEN) ||
+            body_len > bytes.len() - HEADER_LEN {
+            return Ok(State::Invalid);
+        }
+        let mut mac = [0; MAC_LEN];
+        let body = &bytes[HEADER_LEN..HEADER_LEN + body_len];
+        match compute_mac(sha, &bytes[..MAC_OFFSET], body, &mut mac) {
+            Ok(()) => {}
+            Err(ReturnCode::EBUSY) => return Err(ReturnCode::EBUSY),
+            Err(_) => return Ok(State::Invalid),
+        }
+        let stored = &bytes[MAC_OFFSET..MAC_OFFSET + MAC_LEN];
+        let matches = || mac.iter().zip(stored.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+        if !fault::check(matches) {
+            return Ok(State::Invalid);
+        }
+        self.key_len.set(key_len);
+        self.certificate_len.set(certificate_len);
+        self.firmware_key_len.set(firmware_key_len);
+        Ok(State::Provisioned)
+    }
+
+    pub fn state(&self) -> State {
+        self.state.get()
+    }
+
+    /// Most bytes of keys and certificate together that the page holds
+    pub fn capacity(&self) -> usize {
+        self.len.get().saturating_sub(HEADER_LEN)
+    }
+
+    pub fn attestation_key(&self) -> Option<&'static [u8]> {
+        match self.state.get() {
+            State::Provisioned => Some(&self.bytes()[HEADER_LEN..HEADER_LEN + self.key_len.get()]),
+            _ => None,
+        }
+    }
+
+    pub fn attestation_certificate(&self) -> Option<&'static [u8]> {
+        match self.state.get() {
+            State::Provisioned => {
+                let start = HEADER_LEN + self.key_len.get();
+                Some(&self.bytes()[start..start + self.certificate_len.get()])
+            }
+            _ => None,
+        }
+    }
+
+    /// The public key apps must be signed with, if the device checks them
+    pub fn firmware_key(&self) -> Option<&'static [u8]> {
+        match (self.state.get(), self.firmware_key_len.get()) {
+            (State::Provisioned, FIRMWARE_KEY_LEN) => {
+                let start = HEADER_LEN + self.key_len.get() + self.certificate_len.get();
+                Some(&self.bytes()[start..start + FIRMWARE_KEY_LEN])
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,7 +1,56 @@
+//! Pin multiplexer
+//!
+//! Every pad (DIOM0-4, DIOA0-14, DIOB0-7) has a `select` register that picks
+//! which peripheral output `Function` drives it, and every peripheral input
+//! has a `select` register that picks which pad (`SelectablePin`) it
+//! listens to. The registers are exposed directly for code that must work
+//! before anything is set up (e.g. panic output), but boards should route
+//! signals through `connect_output` and `connect_input`, which track which
+//! pads are in use and refuse conflicting routings:
+//!
+//! ```ignore
+//! pinmux::reset();
+//! pinmux::connect_output(SelectablePin::Diob1, Function::Uart0Tx);
+//! pinmux::connect_input(Input::Uart0Rx, SelectablePin::Diob6);
+//! pinmux::connect_gpio(0, 0, SelectablePin::Dioa11);
+//! ```
+//!
+//! The electrical properties of a pad (input enable, pulls, drive strength
+//! and open drain) are set with `configure_pad`:
+//!
+//! ```ignore
+//! pinmux::configure_pad(SelectablePin::Diom2, PadConfig {
+//!     pull: Pull::Up,
+//!     ..PadConfig::DEFAULT
+//! });
+//! ```
+//!
+//! Pads can also be armed with `enable_wakeup` to bring the chip out of
+//! deep sleep on a level or edge; after such a wakeup `woken_by` reports
+//! which pad it was.
+//!
+//! A pad can be driven by only one output, and a pad driven by an output
+//! cannot also be routed to a peripheral input (and vice versa). Several
+//! inputs may listen to the same pad.
+
+use core::mem::transmute;
+use kernel::ReturnCode;
 use kernel::common::cells::VolatileCell;
+use gpio;
+use pmu::{self, WakeSource};
 
 pub struct Pin {
     pub select: VolatileCell<Function>,
+
+    /// Electrical configuration of the pad
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0-1  | Drive strength                                       |
+    /// | 2    | Input enable                                         |
+    /// | 3    | Pull-down enable                                     |
+    /// | 4    | Pull-up enable                                       |
+    /// | 6    | Open drain: only drive low, float instead of high    |
     pub control: VolatileCell<u32>,
 }
 
@@ -146,11 +195,22 @@ pub struct Registers {
     pub xo0_testbus5: Peripheral,
     pub xo0_testbus6: Peripheral,
     pub xo0_testbus7: Peripheral,
+
+    /// Pads that may wake the chip from deep sleep. Bits in this and the
+    /// following `exit_*` registers are indexed by `SelectablePin`.
+    pub exit_enable: VolatileCell<u32>,
+    /// Set for edge-triggered wakeup, clear for level-triggered
+    pub exit_edge: VolatileCell<u32>,
+    /// Set to wake on a low level or falling edge, clear for high/rising
+    pub exit_invert: VolatileCell<u32>,
+    /// Pads whose wakeup condition occurred; write 1 to clear
+    pub exit_status: VolatileCell<u32>,
 }
 
 pub const PINMUX: *mut Registers = 0x40060000 as *mut Registers;
 
 #[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SelectablePin {
     Vio1 = 1,
     Vio0 = 2,
@@ -185,6 +245,7 @@ pub enum SelectablePin {
 }
 
 #[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Function {
     Default = 0,
     Gpio0Gpio0 = 1,
@@ -287,3 +348,382 @@ pub enum Function {
     Xo0testbus6 = 98,
     Xo0Testbus7 = 99,
 }
+
+impl Function {
+    /// The output function for pin `pin` (0-15) of GPIO port `port` (0-1).
+    pub fn gpio(port: usize, pin: usize) -> Option<Function> {
+        if port > 1 || pin > 15 {
+            return None;
+        }
+        // Gpio0Gpio0..Gpio1Gpio15 are numbered consecutively from 1
+        Some(unsafe { transmute(Function::Gpio0Gpio0 as u32 + (port * 16 + pin) as u32) })
+    }
+}
+
+/// Peripheral inputs that can be routed to a pad
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    /// Pin `n` (0-15) of GPIO port 0
+    Gpio0(usize),
+    /// Pin `n` (0-15) of GPIO port 1
+    Gpio1(usize),
+    I2c0Scl,
+    I2c0Sda,
+    I2c1Scl,
+    I2c1Sda,
+    I2cs0Scl,
+    I2cs0Sda,
+    Spi1Clk,
+    Spi1Csb,
+    Spi1Miso,
+    Spi1Mosi,
+    Uart0Cts,
+    Uart0Rx,
+    Uart1Cts,
+    Uart1Rx,
+    Uart2Cts,
+    Uart2Rx,
+}
+
+const CONTROL_DRIVE_MASK: u32 = 0b11;
+const CONTROL_INPUT_ENABLE: u32 = 1 << 2;
+const CONTROL_PULL_DOWN: u32 = 1 << 3;
+const CONTROL_PULL_UP: u32 = 1 << 4;
+const CONTROL_OPEN_DRAIN: u32 = 1 << 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+/// Output drive strength, weakest first
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DriveStrength {
+    Low = 0,
+    Medium = 1,
+    High = 2,
+    Maximum = 3,
+}
+
+/// Electrical configuration of a pad
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PadConfig {
+    /// Whether the pad's level can be read (by GPIO or a peripheral input)
+    pub input: bool,
+    pub pull: Pull,
+    pub drive: DriveStrength,
+    /// Drive low but let the line float (to its pull) instead of driving
+    /// high, for lines shared between several devices such as I2C.
+    pub open_drain: bool,
+}
+
+impl PadConfig {
+    /// Readable, not pulled, weakest push-pull drive.
+    pub const DEFAULT: PadConfig = PadConfig {
+        input: true,
+        pull: Pull::None,
+        drive: DriveStrength::Low,
+        open_drain: false,
+    };
+
+    fn to_control(&self) -> u32 {
+        let mut control = self.drive as u32;
+        if self.input {
+            control |= CONTROL_INPUT_ENABLE;
+        }
+        match self.pull {
+            Pull::None => {}
+            Pull::Up => control |= CONTROL_PULL_UP,
+            Pull::Down => control |= CONTROL_PULL_DOWN,
+        }
+        if self.open_drain {
+            control |= CONTROL_OPEN_DRAIN;
+        }
+        control
+    }
+
+    fn from_control(control: u32) -> PadConfig {
+        PadConfig {
+            input: control & CONTROL_INPUT_ENABLE != 0,
+            pull: if control & CONTROL_PULL_UP != 0 {
+                Pull::Up
+            } else if control & CONTROL_PULL_DOWN != 0 {
+                Pull::Down
+            } else {
+                Pull::None
+            },
+            drive: match control & CONTROL_DRIVE_MASK {
+                0 => DriveStrength::Low,
+                1 => DriveStrength::Medium,
+                2 => DriveStrength::High,
+                _ => DriveStrength::Maximum,
+            },
+            open_drain: control & CONTROL_OPEN_DRAIN != 0,
+        }
+    }
+}
+
+/// Pads driven by an output, as a bitmask indexed by `SelectablePin`
+static mut OUTPUT_PADS: u32 = 0;
+/// Pads some peripheral input listens to, indexed the same way
+static mut INPUT_PADS: u32 = 0;
+
+/// Every pad a board can route, in `SelectablePin` order
+const ROUTABLE_PADS: [SelectablePin; 28] = [
+    SelectablePin::Diob7, SelectablePin::Diob6, SelectablePin::Diob5, SelectablePin::Diob4,
+    SelectablePin::Diob3, SelectablePin::Diob2, SelectablePin::Diob1, SelectablePin::Diob0,
+    SelectablePin::Dioa14, SelectablePin::Dioa13, SelectablePin::Dioa12, SelectablePin::Dioa11,
+    SelectablePin::Dioa10, SelectablePin::Dioa9, SelectablePin::Dioa8, SelectablePin::Dioa7,
+    SelectablePin::Dioa6, SelectablePin::Dioa5, SelectablePin::Dioa4, SelectablePin::Dioa3,
+    SelectablePin::Dioa2, SelectablePin::Dioa1, SelectablePin::Dioa0, SelectablePin::Diom4,
+    SelectablePin::Diom3, SelectablePin::Diom2, SelectablePin::Diom1, SelectablePin::Diom0,
+];
+
+fn registers() -> &'static mut Registers {
+    unsafe { &mut *PINMUX }
+}
+
+/// The registers of `pad`.
+pub fn pad(pad: SelectablePin) -> &'static Pin {
+    let regs = registers();
+    match pad {
+        SelectablePin::Vio1 => &regs.vio1,
+        SelectablePin::Vio0 => &regs.vio0,
+        SelectablePin::Diob7 => &regs.diob7,
+        SelectablePin::Diob6 => &regs.diob6,
+        SelectablePin::Diob5 => &regs.diob5,
+        SelectablePin::Diob4 => &regs.diob4,
+        SelectablePin::Diob3 => &regs.diob3,
+        SelectablePin::Diob2 => &regs.diob2,
+        SelectablePin::Diob1 => &regs.diob1,
+        SelectablePin::Diob0 => &regs.diob0,
+        SelectablePin::Dioa14 => &regs.dioa14,
+        SelectablePin::Dioa13 => &regs.dioa13,
+        SelectablePin::Dioa12 => &regs.dioa12,
+        SelectablePin::Dioa11 => &regs.dioa11,
+        SelectablePin::Dioa10 => &regs.dioa10,
+        SelectablePin::Dioa9 => &regs.dioa9,
+        SelectablePin::Dioa8 => &regs.dioa8,
+        SelectablePin::Dioa7 => &regs.dioa7,
+        SelectablePin::Dioa6 => &regs.dioa6,
+        SelectablePin::Dioa5 => &regs.dioa5,
+        SelectablePin::Dioa4 => &regs.dioa4,
+        SelectablePin::Dioa3 => &regs.dioa3,
+        SelectablePin::Dioa2 => &regs.dioa2,
+        SelectablePin::Dioa1 => &regs.dioa1,
+        SelectablePin::Dioa0 => &regs.dioa0,
+        SelectablePin::Diom4 => &regs.diom4,
+        SelectablePin::Diom3 => &regs.diom3,
+        SelectablePin::Diom2 => &regs.diom2,
+        SelectablePin::Diom1 => &regs.diom1,
+        SelectablePin::Diom0 => &regs.diom0,
+    }
+}
+
+/// The select register of `input`, or None for a GPIO pin out of range.
+fn input_select(input: Input) -> Option<&'static Peripheral> {
+    let regs = registers();
+    match input {
+        Input::Gpio0(n) if n < 16 => Some(unsafe { &*(&regs.gpio0_gpio0 as *const Peripheral).offset(n as isize) }),
+        Input::Gpio1(n) if n < 16 => Some(unsafe { &*(&regs.gpio1_gpio0 as *const Peripheral).offset(n as isize) }),
+        Input::Gpio0(_) | Input::Gpio1(_) => None,
+        Input::I2c0Scl => Some(&regs.i2c0_scl),
+        Input::I2c0Sda => Some(&regs.i2c0_sda),
+        Input::I2c1Scl => Some(&regs.i2c1_scl),
+        Input::I2c1Sda => Some(&regs.i2c1_sda),
+        Input::I2cs0Scl => Some(&regs.i2cs0_scl),
+        Input::I2cs0Sda => Some(&regs.i2cs0_sda),
+        Input::Spi1Clk => Some(&regs.spi1_spiclk),
+        Input::Spi1Csb => Some(&regs.spi1_spicsb),
+        Input::Spi1Miso => Some(&regs.spi1_spimiso),
+        Input::Spi1Mosi => Some(&regs.spi1_spimosi),
+        Input::Uart0Cts => Some(&regs.uart0_cts),
+        Input::Uart0Rx => Some(&regs.uart0_rx),
+        Input::Uart1Cts => Some(&regs.uart1_cts),
+        Input::Uart1Rx => Some(&regs.uart1_rx),
+        Input::Uart2Cts => Some(&regs.uart2_cts),
+        Input::Uart2Rx => Some(&regs.uart2_rx),
+    }
+}
+
+/// Sets the electrical configuration of `pad`.
+pub fn configure_pad(pad: SelectablePin, config: PadConfig) {
+    self::pad(pad).control.set(config.to_control());
+}
+
+/// The current electrical configuration of `pad`.
+pub fn pad_config(pad: SelectablePin) -> PadConfig {
+    PadConfig::from_control(self::pad(pad).control.get())
+}
+
+/// Changes only the pulls of `pad`.
+pub fn set_pull(pad: SelectablePin, pull: Pull) {
+    configure_pad(pad, PadConfig {
+        pull: pull,
+        ..pad_config(pad)
+    });
+}
+
+/// Disconnects every pad and peripheral input, replacing whatever routing
+/// the chip came out of reset (or the boot loader left) with a known
+/// empty one. Board init should call this before routing any signals.
+pub fn reset() {
+    let regs = registers();
+    for &p in ROUTABLE_PADS.iter() {
+        pad(p).select.set(Function::Default);
+    }
+    // The peripheral input selectors are laid out consecutively
+    let mut input = &regs.gpio0_gpio0 as *const Peripheral;
+    let last = &regs.xo0_testbus7 as *const Peripheral;
+    while input <= last {
+        unsafe {
+            (*(input as *const VolatileCell<u32>)).set(0);
+            input = input.offset(1);
+        }
+    }
+    unsafe {
+        OUTPUT_PADS = 0;
+        INPUT_PADS = 0;
+    }
+}
+
+/// Drives `pad` from the peripheral output `function`. Returns EBUSY if
+/// the pad is already driven or routed to an input.
+pub fn connect_output(pad: SelectablePin, function: Function) -> ReturnCode {
+    let mask = 1 << (pad as u32);
+    unsafe {
+        if (OUTPUT_PADS | INPUT_PADS) & mask != 0 {
+            return ReturnCode::EBUSY;
+        }
+        OUTPUT_PADS |= mask;
+    }
+    self::pad(pad).select.set(function);
+    ReturnCode::SUCCESS
+}
+
+/// Routes `pad` to the peripheral input `input`, replacing the input's
+/// previous routing. Returns EBUSY if the pad is driven by an output and
+/// EINVAL for a GPIO pin out of range.
+pub fn connect_input(input: Input, pad: SelectablePin) -> ReturnCode {
+    let mask = 1 << (pad as u32);
+    unsafe {
+        if OUTPUT_PADS & mask != 0 {
+            return ReturnCode::EBUSY;
+        }
+    }
+    match input_select(input) {
+        Some(select) => {
+            select.select.set(pad);
+            unsafe {
+                INPUT_PADS |= mask;
+            }
+            ReturnCode::SUCCESS
+        }
+        None => ReturnCode::EINVAL,
+    }
+}
+
+/// Connects GPIO pin `pin` of `port` to `pad`, both as an output and as
+/// an input, which is what `gpio::GPIOPin` expects: whether the pad is
+/// actually driven is controlled by the GPIO's output enable.
+pub fn connect_gpio(port: usize, pin: usize, pad: SelectablePin) -> ReturnCode {
+    let function = match Function::gpio(port, pin) {
+        Some(function) => function,
+        None => return ReturnCode::EINVAL,
+    };
+    let input = if port == 0 { Input::Gpio0(pin) } else { Input::Gpio1(pin) };
+
+    let mask = 1 << (pad as u32);
+    unsafe {
+        if (OUTPUT_PADS | INPUT_PADS) & mask != 0 {
+            return ReturnCode::EBUSY;
+        }
+    }
+    input_select(input).map(|select| select.select.set(pad));
+    self::pad(pad).select.set(function);
+    unsafe {
+        let port = if port == 0 { &gpio::PORT0 } else { &gpio::PORT1 };
+        port.pins[pin].set_pad(pad);
+    }
+    unsafe {
+        OUTPUT_PADS |= mask;
+    }
+    ReturnCode::SUCCESS
+}
+
+/// Disconnects `pad` from any output. Inputs routed to it keep listening
+/// until rerouted or `reset`.
+pub fn release(pad: SelectablePin) {
+    let mask = 1 << (pad as u32);
+    self::pad(pad).select.set(Function::Default);
+    unsafe {
+        OUTPUT_PADS &= !mask;
+    }
+}
+
+/// Condition on a pad that wakes the chip from deep sleep
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WakeMode {
+    High,
+    Low,
+    RisingEdge,
+    FallingEdge,
+}
+
+/// Arms `pad` to wake the chip from deep sleep when `mode` occurs. The pad
+/// keeps whatever routing it has.
+pub fn enable_wakeup(pad: SelectablePin, mode: WakeMode) {
+    let regs = registers();
+    let mask = 1 << (pad as u32);
+    match mode {
+        WakeMode::High | WakeMode::RisingEdge => {
+            regs.exit_invert.set(regs.exit_invert.get() & !mask);
+        }
+        WakeMode::Low | WakeMode::FallingEdge => {
+            regs.exit_invert.set(regs.exit_invert.get() | mask);
+        }
+    }
+    match mode {
+        WakeMode::High | WakeMode::Low => {
+            regs.exit_edge.set(regs.exit_edge.get() & !mask);
+        }
+        WakeMode::RisingEdge | WakeMode::FallingEdge => {
+            regs.exit_edge.set(regs.exit_edge.get() | mask);
+        }
+    }
+    regs.exit_status.set(mask);
+    regs.exit_enable.set(regs.exit_enable.get() | mask);
+    pmu::enable_wake_source(WakeSource::Pin);
+}
+
+/// Stops `pad` from waking the chip, and pins from waking it at all once
+/// no pad is armed.
+pub fn disable_wakeup(pad: SelectablePin) {
+    let regs = registers();
+    let enabled = regs.exit_enable.get() & !(1 << (pad as u32));
+    regs.exit_enable.set(enabled);
+    if enabled == 0 {
+        pmu::disable_wake_source(WakeSource::Pin);
+    }
+}
+
+/// The pad that woke the chip from deep sleep, if it was woken by a pad.
+/// If several pads triggered, the lowest-numbered is reported.
+pub fn woken_by() -> Option<SelectablePin> {
+    if !pmu::woken_by(WakeSource::Pin) {
+        return None;
+    }
+    let status = registers().exit_status.get();
+    ROUTABLE_PADS.iter()
+        .chain([SelectablePin::Vio1, SelectablePin::Vio0].iter())
+        .map(|&pad| pad)
+        .filter(|&pad| status & 1 << (pad as u32) != 0)
+        .min_by_key(|&pad| pad as u32)
+}
+
+/// Clears the record of which pads triggered a wakeup.
+pub fn clear_wakeup_status() {
+    registers().exit_status.set(!0);
+}
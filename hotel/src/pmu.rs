@@ -26,9 +26,19 @@
 //!
 //!     * Designed for 1.8-3.6V
 //!
+//! `clocks` reports the state of every peripheral clock, and `dump_clocks`
+//! prints it as a table for a board's console command. Once the board has
+//! called `init_clock_tree`, a host tool can also read the clock masks with
+//! a `REQUEST_CLOCK_TREE` vendor request.
 
+use core::cmp;
+use core::fmt::Write;
 use core::mem::transmute;
+use cortexm3::support;
+use kernel::ReturnCode;
 use kernel::common::cells::VolatileCell;
+use power_trace;
+use usb::{VendorHandler, VendorRequest, USB0};
 
 /// Registers for the Power Management Unit (PMU)
 // Non-public fields prefixed with "_" mark unused registers
@@ -92,8 +102,17 @@ pub struct PMURegisters {
     pub battery_level_ok: VolatileCell<u32>,
 
     _b_reg_dig_ctrl: VolatileCell<u32>,
-    _exitpd_mask: VolatileCell<u32>,
-    _exitpd_src: VolatileCell<u32>,
+    /// Enabled sources for exiting deep sleep
+    ///
+    /// Each bit corresponds to a `WakeSource`. Only sources whose bit is set
+    /// can wake the chip from deep sleep.
+    pub exitpd_mask: VolatileCell<u32>,
+
+    /// The source(s) that caused the last exit from deep sleep
+    ///
+    /// Same mapping as `exitpd_mask`.
+    pub exitpd_src: VolatileCell<u32>,
+
     _exitpd_mon: VolatileCell<u32>,
     _osc_ctrl: VolatileCell<u32>,
 
@@ -130,19 +149,44 @@ pub struct PMURegisters {
     pub _peripheral_clocks0_ro_mask: VolatileCell<u32>,
     pub _peripheral_clocks1_ro_mask: VolatileCell<u32>,
 
-    pub _gate_on_sleep_set0: VolatileCell<u32>,
-    pub _gate_on_sleep_clr0: VolatileCell<u32>,
-
-    pub _gate_on_sleep_set1: VolatileCell<u32>,
-    pub _gate_on_sleep_clr1: VolatileCell<u32>,
+    /// Select bank 0 clocks to gate while the processor sleeps, if
+    /// `nap_enable` is set.
+    ///
+    /// Same bit mapping as the bank 0 peripheral clocks.
+    pub gate_on_sleep_set0: VolatileCell<u32>,
+    /// Deselect bank 0 clocks from gating while the processor sleeps.
+    pub gate_on_sleep_clr0: VolatileCell<u32>,
+
+    /// Select bank 1 clocks to gate while the processor sleeps.
+    pub gate_on_sleep_set1: VolatileCell<u32>,
+    /// Deselect bank 1 clocks from gating while the processor sleeps.
+    pub gate_on_sleep_clr1: VolatileCell<u32>,
     
     pub _clock0: VolatileCell<u32>,
-    pub _reset0_write_enable: VolatileCell<u32>,
+
+    /// Write enable for `reset0`
+    ///
+    /// A bit of `reset0` can only be changed while the same bit is set here.
+    pub reset0_write_enable: VolatileCell<u32>,
+
+    /// Peripheral resets (bank 0)
+    ///
+    /// Same bit mapping as the bank 0 peripheral clocks. A peripheral is
+    /// held in reset while its bit is clear.
     pub reset0: VolatileCell<u32>,
 
-    pub _reset1_write_enable: VolatileCell<u32>,
-    pub _reset1: VolatileCell<u32>
-    
+    /// Write enable for `reset1`
+    pub reset1_write_enable: VolatileCell<u32>,
+
+    /// Peripheral resets (bank 1)
+    ///
+    /// Same bit mapping as the bank 1 peripheral clocks.
+    pub reset1: VolatileCell<u32>,
+
+    /// Scratch registers that keep their value across every reset except
+    /// power on.
+    pub long_life_scratch: [VolatileCell<u32>; 3],
+
 }
 
 const PMU_BASE: isize = 0x40000000;
@@ -214,44 +258,686 @@ pub enum PeripheralClock {
 
 /// Wrapper struct around `PeripheralClock` that can only be created by.
 /// trusted code.
+///
+/// Drivers should use `acquire` and `release` rather than `enable` and
+/// `disable`: the clock is reference counted, so it is only gated once every
+/// driver sharing it has released it. `enable` and `disable` bypass the
+/// count and are meant for early boot and for recovering from faults; a
+/// clock turned on with `enable` is left running by `gate_unused_clocks`
+/// until `disable` turns it off.
+///
+/// `PeripheralClock0::Temp0` is past the end of the bank 0 registers, so it
+/// can't be switched: it is always running.
 #[derive(Clone,Copy)]
 pub struct Clock {
     // It's important that this field is private!
     clock: PeripheralClock,
 }
 
+/// Number of outstanding `acquire`s for each clock, indexed like the
+/// peripheral clock registers.
+static mut CLOCK0_USERS: [u8; 33] = [0; 33];
+static mut CLOCK1_USERS: [u8; 16] = [0; 16];
+
+/// Clocks with work in flight, as masks over the peripheral clock registers
+static mut CLOCK0_BUSY: u32 = 0;
+static mut CLOCK1_BUSY: u32 = 0;
+
+/// Clocks turned on with `Clock::enable`, which `gate_unused_clocks` leaves
+/// running
+static mut CLOCK0_PINNED: u32 = 0;
+static mut CLOCK1_PINNED: u32 = 0;
+
+/// The bank and mask of a clock's bit in the peripheral clock and reset
+/// registers, or None for `Temp0`, which has no bit.
+fn register_bit(clock: PeripheralClock) -> Option<(u8, u32)> {
+    match clock {
+        PeripheralClock::Bank0(clock) if (clock as u32) < 32 => Some((0, 1 << (clock as u32))),
+        PeripheralClock::Bank0(_) => None,
+        PeripheralClock::Bank1(clock) => Some((1, 1 << (clock as u32))),
+    }
+}
+
 impl Clock {
     pub const unsafe fn new(clock: PeripheralClock) -> Clock {
         Clock { clock: clock }
     }
 
+    /// Turns the clock on whatever its count, and keeps `gate_unused_clocks`
+    /// from gating it.
     pub fn enable(&self) {
+        self.set_pinned(true);
+        self.switch(true);
+    }
+
+    /// Turns the clock off whatever its count.
+    pub fn disable(&self) {
+        self.set_pinned(false);
+        self.switch(false);
+    }
+
+    fn set_pinned(&self, pinned: bool) {
+        let (mask, bit) = unsafe {
+            match register_bit(self.clock) {
+                Some((0, bit)) => (&mut CLOCK0_PINNED, bit),
+                Some((_, bit)) => (&mut CLOCK1_PINNED, bit),
+                None => return,
+            }
+        };
+        if pinned {
+            *mask |= bit;
+        } else {
+            *mask &= !bit;
+        }
+    }
+
+    fn switch(&self, on: bool) {
         let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+        unsafe {
+            match (register_bit(self.clock), on) {
+                (Some((0, bit)), true) => pmu.peripheral_clocks0_enable.set(bit),
+                (Some((0, bit)), false) => pmu.peripheral_clocks0_disable.set(bit),
+                (Some((_, bit)), true) => pmu.peripheral_clocks1_enable.set(bit),
+                (Some((_, bit)), false) => pmu.peripheral_clocks1_disable.set(bit),
+                (None, _) => return,
+            }
+        }
+        self.trace_change(on);
+    }
+
+    fn trace_change(&self, on: bool) {
         match self.clock {
-            PeripheralClock::Bank0(clock) => {
-                unsafe {pmu.peripheral_clocks0_enable.set(1 << (clock as u32))};
+            PeripheralClock::Bank0(clock) => power_trace::clock_changed(0, clock as u32, on),
+            PeripheralClock::Bank1(clock) => power_trace::clock_changed(1, clock as u32, on),
+        }
+    }
+
+    /// Marks the clock as in use, turning it on if it was not already.
+    pub fn acquire(&self) {
+        let users = self.users();
+        if *users == 0 {
+            self.switch(true);
+        }
+        *users = users.saturating_add(1);
+    }
+
+    /// Drops a use of the clock taken with `acquire`, gating it if this was
+    /// the last one and it wasn't turned on with `enable`.
+    pub fn release(&self) {
+        let users = self.users();
+        match *users {
+            0 => (),
+            1 => {
+                *users = 0;
+                if !self.pinned() {
+                    self.switch(false);
+                }
             }
-            PeripheralClock::Bank1(clock) => {
-                unsafe {pmu.peripheral_clocks1_enable.set(1 << (clock as u32))};
+            _ => *users -= 1,
+        }
+    }
+
+    /// Whether any driver currently holds the clock.
+    pub fn in_use(&self) -> bool {
+        *self.users() != 0
+    }
+
+    fn pinned(&self) -> bool {
+        unsafe {
+            match register_bit(self.clock) {
+                Some((0, bit)) => CLOCK0_PINNED & bit != 0,
+                Some((_, bit)) => CLOCK1_PINNED & bit != 0,
+                None => false,
             }
         }
     }
 
-    pub fn disable(&self) {
-        let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
-        match self.clock {
-            PeripheralClock::Bank0(clock) => {
-                unsafe {pmu.peripheral_clocks0_disable.set(1 << (clock as u32))};
+    /// Marks whether the driver has work in flight that needs the clock
+    /// running while the processor sleeps, e.g. a transfer under way. Of
+    /// the clocks `prepare_nap` may gate, only those not busy are.
+    pub fn set_busy(&self, busy: bool) {
+        let (mask, bit) = unsafe {
+            match register_bit(self.clock) {
+                Some((0, bit)) => (&mut CLOCK0_BUSY, bit),
+                Some((_, bit)) => (&mut CLOCK1_BUSY, bit),
+                None => return,
+            }
+        };
+        if busy {
+            *mask |= bit;
+        } else {
+            *mask &= !bit;
+        }
+    }
+
+    fn users(&self) -> &'static mut u8 {
+        unsafe {
+            match self.clock {
+                PeripheralClock::Bank0(clock) => &mut CLOCK0_USERS[clock as usize],
+                PeripheralClock::Bank1(clock) => &mut CLOCK1_USERS[clock as usize],
+            }
+        }
+    }
+}
+
+/// Bank 0 clocks that `gate_unused_clocks` may turn off: those whose drivers
+/// all go through `acquire`/`release`, plus those of peripherals nothing in
+/// the kernel uses yet.
+const GATEABLE0: u32 = 1 << (PeripheralClock0::Camo0 as u32) |
+                       1 << (PeripheralClock0::Crypto0 as u32) |
+                       1 << (PeripheralClock0::Gpio0 as u32) |
+                       1 << (PeripheralClock0::Gpio1 as u32) |
+                       1 << (PeripheralClock0::I2C0 as u32) |
+                       1 << (PeripheralClock0::I2C1 as u32) |
+                       1 << (PeripheralClock0::I2CS0 as u32) |
+                       1 << (PeripheralClock0::Spi0Hs as u32) |
+                       1 << (PeripheralClock0::Spi1Hs as u32) |
+                       1 << (PeripheralClock0::Sps0 as u32) |
+                       1 << (PeripheralClock0::Sps0TimerHs as u32);
+
+/// Bank 1 clocks that `gate_unused_clocks` may turn off.
+const GATEABLE1: u32 = 1 << (PeripheralClock1::TimeHs0Timer as u32) |
+                       1 << (PeripheralClock1::TimeHs1Timer as u32) |
+                       1 << (PeripheralClock1::TimeLs0 as u32) |
+                       1 << (PeripheralClock1::TimeUs0Timer as u32) |
+                       1 << (PeripheralClock1::Uart0Timer as u32) |
+                       1 << (PeripheralClock1::Uart1Timer as u32) |
+                       1 << (PeripheralClock1::Uart2Timer as u32) |
+                       1 << (PeripheralClock1::Usb0 as u32) |
+                       1 << (PeripheralClock1::Usb0TimerHs as u32);
+
+/// Gates every gateable peripheral clock that no driver has acquired or
+/// turned on with `Clock::enable`.
+///
+/// Boards should call this once all drivers are initialized, since the boot
+/// ROM leaves most clocks running.
+pub fn gate_unused_clocks() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    let (gateable0, gateable1) = unsafe { (GATEABLE0 & !CLOCK0_PINNED, GATEABLE1 & !CLOCK1_PINNED) };
+    let mut off0 = 0;
+    for (i, users) in unsafe { CLOCK0_USERS.iter().enumerate().take(32) } {
+        if *users == 0 && gateable0 & 1 << i != 0 {
+            off0 |= 1 << i;
+        }
+    }
+    let mut off1 = 0;
+    for (i, users) in unsafe { CLOCK1_USERS.iter().enumerate() } {
+        if *users == 0 && gateable1 & 1 << i != 0 {
+            off1 |= 1 << i;
+        }
+    }
+    unsafe {
+        pmu.peripheral_clocks0_disable.set(off0);
+        pmu.peripheral_clocks1_disable.set(off1);
+    }
+    power_trace::clocks_gated(0, off0);
+    power_trace::clocks_gated(1, off1);
+}
+
+/// Bank 0 clocks the PMU may gate while the processor sleeps: those whose
+/// drivers mark the work that needs them with `Clock::set_busy`.
+const NAP_GATEABLE0: u32 = 1 << (PeripheralClock0::Spi0Hs as u32) |
+                           1 << (PeripheralClock0::Spi1Hs as u32);
+
+/// Bank 1 clocks the PMU may gate while the processor sleeps.
+const NAP_GATEABLE1: u32 = 1 << (PeripheralClock1::Usb0 as u32) |
+                           1 << (PeripheralClock1::Usb0TimerHs as u32);
+
+// The clocks gated on sleep by the last `prepare_nap`
+static mut NAP_GATED0: u32 = 0;
+static mut NAP_GATED1: u32 = 0;
+
+/// Has the PMU gate, while the processor sleeps, the nap-gateable clocks
+/// no driver has busy. Returns whether it gates any: if not, sleeping is a
+/// plain `wfi` with every clock left running.
+pub fn prepare_nap() -> bool {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let gate0 = NAP_GATEABLE0 & !CLOCK0_BUSY;
+        let gate1 = NAP_GATEABLE1 & !CLOCK1_BUSY;
+        if gate0 != NAP_GATED0 || gate1 != NAP_GATED1 {
+            pmu.gate_on_sleep_clr0.set(NAP_GATEABLE0 & !gate0);
+            pmu.gate_on_sleep_set0.set(gate0);
+            pmu.gate_on_sleep_clr1.set(NAP_GATEABLE1 & !gate1);
+            pmu.gate_on_sleep_set1.set(gate1);
+            pmu.nap_enable.set(if gate0 | gate1 != 0 { 1 } else { 0 });
+            NAP_GATED0 = gate0;
+            NAP_GATED1 = gate1;
+        }
+        gate0 | gate1 != 0
+    }
+}
+
+/// Bank 0 clocks that don't hold the chip out of deep sleep: the GPIO
+/// blocks, which boards acquire for good, and whose pins wake the chip
+/// through the PMU rather than through their own clock.
+const DEEP_SLEEP_IGNORED0: u32 = 1 << (PeripheralClock0::Gpio0 as u32) |
+                                 1 << (PeripheralClock0::Gpio1 as u32);
+
+/// Bank 1 clocks that don't hold the chip out of deep sleep: the low-speed
+/// timer, which keeps running and is what wakes the chip, and the
+/// microsecond timer, which is acquired for good and is instead checked for
+/// armed alarms by the chip before it sleeps deep.
+const DEEP_SLEEP_IGNORED1: u32 = 1 << (PeripheralClock1::TimeLs0 as u32) |
+                                 1 << (PeripheralClock1::TimeUs0Timer as u32);
+
+/// Whether every gateable clock that deep sleep stops is idle: held by no
+/// driver, or nap-gateable and not busy. The clocks in
+/// `DEEP_SLEEP_IGNORED0` and `DEEP_SLEEP_IGNORED1` are left out. Call after
+/// `prepare_nap`.
+pub fn gateable_clocks_idle() -> bool {
+    let idle = |users: &[u8], gateable: u32, nap_gateable: u32, busy: u32| {
+        users.iter().take(32).enumerate().all(|(i, users)| {
+            let bit = 1 << i;
+            gateable & bit == 0 || *users == 0 || (nap_gateable & bit != 0 && busy & bit == 0)
+        })
+    };
+    unsafe {
+        idle(&CLOCK0_USERS, GATEABLE0 & !DEEP_SLEEP_IGNORED0, NAP_GATEABLE0, CLOCK0_BUSY) &&
+            idle(&CLOCK1_USERS, GATEABLE1 & !DEEP_SLEEP_IGNORED1, NAP_GATEABLE1, CLOCK1_BUSY)
+    }
+}
+
+/// Answers with the clock tree in the data stage: the running and the
+/// acquired clocks, as masks over bits of the peripheral clock registers.
+/// It doesn't print anything, since it is answered from the USB interrupt;
+/// use `dump_clocks` from the console for the table.
+///
+/// | Offset | Size | Contents                      |
+/// | ------ | ---- | :---------------------------- |
+/// | 0      | 4    | Running bank 0 clocks (LE)    |
+/// | 4      | 4    | Running bank 1 clocks (LE)    |
+/// | 8      | 4    | Acquired bank 0 clocks (LE)   |
+/// | 12     | 4    | Acquired bank 1 clocks (LE)   |
+pub const REQUEST_CLOCK_TREE: u8 = 0x1a;
+
+/// Frequency of the core clock, which most peripheral clocks are
+pub const CORE_HZ: u32 = 24_000_000;
+/// Nominal frequency of the always-on low-speed oscillator
+pub const LOW_SPEED_HZ: u32 = 256_000;
+
+static CLOCK0_NAMES: [&'static str; 33] = [
+    "Camo0", "Crypto0", "Dma0", "Flash0", "Fuse0", "GlobalSec", "GlobalSecTimer", "GlobalSecHs",
+    "Gpio0", "Gpio1", "I2C0", "I2C1", "I2CS0", "KeyMgr0", "PeriAPB0", "PeriAPB1", "PeriAPB2",
+    "PeriAPB2Timer", "PeriAPB3", "PeriAPB3Timer", "PeriAPB3HS", "PinMux", "Pmu", "RBox0", "Rdd0",
+    "Rtc0", "Rtc0Timer", "Spi0Hs", "Spi1Hs", "Sps0", "Sps0TimerHs", "Swdp0", "Temp0",
+];
+
+static CLOCK1_NAMES: [&'static str; 16] = [
+    "TimeHs0Timer", "TimeHs1Timer", "TimeLs0", "TimeUs0Timer", "Trng0", "Uart0Timer", "Uart1Timer",
+    "Uart2Timer", "Usb0", "Usb0TimerHs", "Volt0", "Watchdog0", "Xo0", "Xo0Timer",
+    "PeripheralMasterMatrix", "PeripheralMatrix",
+];
+
+/// Where a peripheral clock comes from
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ClockSource {
+    Core,
+    LowSpeed,
+}
+
+impl ClockSource {
+    pub fn hz(&self) -> u32 {
+        match *self {
+            ClockSource::Core => CORE_HZ,
+            ClockSource::LowSpeed => LOW_SPEED_HZ,
+        }
+    }
+}
+
+/// A peripheral clock as it is now
+#[derive(Clone,Copy,Debug)]
+pub struct ClockState {
+    pub name: &'static str,
+    /// Register bank and bit
+    pub bank: u8,
+    pub bit: u8,
+    /// Whether the PMU has it running
+    pub enabled: bool,
+    /// Outstanding `acquire`s
+    pub users: u8,
+    /// Whether `gate_unused_clocks` turns it off when nobody holds it
+    pub gateable: bool,
+    pub source: ClockSource,
+    /// The frequency it runs at, 0 while it is gated
+    pub hz: u32,
+}
+
+/// Iterator over every peripheral clock, bank 0 first, in register order
+pub struct Clocks {
+    index: usize,
+    enabled0: u32,
+    enabled1: u32,
+}
+
+/// The state of every peripheral clock. The enable registers read back as
+/// the clocks that are running, whichever way they were turned on.
+pub fn clocks() -> Clocks {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    Clocks {
+        index: 0,
+        enabled0: pmu.peripheral_clocks0_enable.get(),
+        enabled1: pmu.peripheral_clocks1_enable.get(),
+    }
+}
+
+impl Iterator for Clocks {
+    type Item = ClockState;
+
+    fn next(&mut self) -> Option<ClockState> {
+        let index = self.index;
+        let (bank, bit, name, enabled, users, gateable) = if index < CLOCK0_NAMES.len() {
+            // Temp0 is past the end of the register, so never reads as running.
+            (0, index, CLOCK0_NAMES[index], index < 32 && self.enabled0 & 1 << index != 0,
+             unsafe { CLOCK0_USERS[index] },
+             index < 32 && unsafe { GATEABLE0 & !CLOCK0_PINNED } & 1 << index != 0)
+        } else if index < CLOCK0_NAMES.len() + CLOCK1_NAMES.len() {
+            let bit = index - CLOCK0_NAMES.len();
+            (1, bit, CLOCK1_NAMES[bit], self.enabled1 & 1 << bit != 0,
+             unsafe { CLOCK1_USERS[bit] }, unsafe { GATEABLE1 & !CLOCK1_PINNED } & 1 << bit != 0)
+        } else {
+            return None;
+        };
+        self.index += 1;
+        let source = if bank == 1 && bit == PeripheralClock1::TimeLs0 as usize {
+            ClockSource::LowSpeed
+        } else {
+            ClockSource::Core
+        };
+        Some(ClockState {
+            name: name,
+            bank: bank,
+            bit: bit as u8,
+            enabled: enabled,
+            users: users,
+            gateable: gateable,
+            source: source,
+            hz: if enabled { source.hz() } else { 0 },
+        })
+    }
+}
+
+/// Prints a line per peripheral clock: whether it runs and at what rate,
+/// how many drivers hold it, and whether it is gated when they don't.
+pub fn dump_clocks(writer: &mut Write) {
+    let _ = writer.write_fmt(format_args!("\r\nClock                  bank bit state users       Hz\r\n"));
+    let mut running = 0;
+    for clock in clocks() {
+        if clock.enabled {
+            running += 1;
+        }
+        let _ = writer.write_fmt(format_args!("{:<22} {:>4} {:>3} {:<5} {:>5} {:>8}{}\r\n",
+                                              clock.name,
+                                              clock.bank,
+                                              clock.bit,
+                                              if clock.enabled { "on" } else { "off" },
+                                              clock.users,
+                                              clock.hz,
+                                              if clock.gateable { "" } else { " (always on)" }));
+    }
+    let _ = writer.write_fmt(format_args!("{} of {} clocks running\r\n",
+                                          running, CLOCK0_NAMES.len() + CLOCK1_NAMES.len()));
+}
+
+/// Answers `REQUEST_CLOCK_TREE`.
+pub fn init_clock_tree() -> ReturnCode {
+    unsafe { USB0.add_vendor_handler(REQUEST_CLOCK_TREE, &CLOCK_TREE) }
+}
+
+/// Serves `REQUEST_CLOCK_TREE`
+pub struct ClockTree;
+
+pub static CLOCK_TREE: ClockTree = ClockTree;
+
+impl VendorHandler for ClockTree {
+    fn vendor_request(&self, _request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        let mut words: [u32; 4] = [0; 4];
+        for clock in clocks() {
+            let bank = clock.bank as usize;
+            if clock.enabled {
+                words[bank] |= 1 << clock.bit;
             }
-            PeripheralClock::Bank1(clock) => {
-                unsafe {pmu.peripheral_clocks1_disable.set(1 << (clock as u32))};
+            if clock.users != 0 && clock.bit < 32 {
+                words[2 + bank] |= 1 << clock.bit;
             }
         }
+        let len = cmp::min(16, data.len());
+        for (i, byte) in data[..len].iter_mut().enumerate() {
+            *byte = (words[i / 4] >> ((i % 4) * 8)) as u8;
+        }
+        Ok(len)
+    }
+}
+
+/// Why the chip last came out of reset
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ResetCause {
+    PowerOn,
+    LowPowerExit,
+    Watchdog,
+    Lockup,
+    SysReset,
+    Software,
+    FastBurnout,
+    SecurityBreach,
+    /// The voltage monitor warned of a brownout before the reset
+    Brownout,
+    /// Software reset the chip after detecting a glitch (see `fault`)
+    FaultDetected,
+    Unknown,
+}
+
+/// Flags kept in `long_life_scratch[0]`
+const SCRATCH0_BROWNOUT: u32 = 1 << 0;
+const SCRATCH0_BOOTLOADER: u32 = 1 << 1;
+const SCRATCH0_FAULT: u32 = 1 << 2;
+
+/// Returns the cause of the last reset.
+///
+/// A brownout warning recorded with `record_brownout` or a fault recorded
+/// with `record_fault` takes precedence, since the reset it led to is
+/// usually reported as a plain power on or software reset.
+pub fn reset_cause() -> ResetCause {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    let (source, scratch) = unsafe {
+        (pmu.reset_source.get(), pmu.long_life_scratch[0].get())
+    };
+    if scratch & SCRATCH0_BROWNOUT != 0 {
+        return ResetCause::Brownout;
+    }
+    if scratch & SCRATCH0_FAULT != 0 {
+        return ResetCause::FaultDetected;
+    }
+    match source.trailing_zeros() {
+        0 => ResetCause::PowerOn,
+        1 => ResetCause::LowPowerExit,
+        2 => ResetCause::Watchdog,
+        3 => ResetCause::Lockup,
+        4 => ResetCause::SysReset,
+        5 => ResetCause::Software,
+        6 => ResetCause::FastBurnout,
+        7 => ResetCause::SecurityBreach,
+        _ => ResetCause::Unknown,
+    }
+}
+
+/// Records that a brownout is imminent, so that the next boot reports it
+/// from `reset_cause`.
+pub fn record_brownout() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let scratch = &pmu.long_life_scratch[0];
+        scratch.set(scratch.get() | SCRATCH0_BROWNOUT);
+    }
+}
+
+/// Forgets a brownout recorded with `record_brownout`, once the supply has
+/// recovered without a reset.
+pub fn clear_brownout() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let scratch = &pmu.long_life_scratch[0];
+        scratch.set(scratch.get() & !SCRATCH0_BROWNOUT);
+    }
+}
+
+/// Records that a fault was detected, so that the next boot reports it
+/// from `reset_cause`.
+pub fn record_fault() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let scratch = &pmu.long_life_scratch[0];
+        scratch.set(scratch.get() | SCRATCH0_FAULT);
+    }
+}
+
+/// Asks the next boot to start in update mode; see
+/// `take_bootloader_request`.
+pub fn request_bootloader() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let scratch = &pmu.long_life_scratch[0];
+        scratch.set(scratch.get() | SCRATCH0_BOOTLOADER);
+    }
+}
+
+/// Whether `request_bootloader` was called before the last reset. The
+/// request is cleared, so it only applies to one boot.
+pub fn take_bootloader_request() -> bool {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        let scratch = &pmu.long_life_scratch[0];
+        let requested = scratch.get() & SCRATCH0_BOOTLOADER != 0;
+        scratch.set(scratch.get() & !SCRATCH0_BOOTLOADER);
+        requested
+    }
+}
+
+/// Clears the reset cause so that it describes only the next reset.
+pub fn clear_reset_cause() {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {
+        pmu.clear_reset.set(!0);
+        let scratch = &pmu.long_life_scratch[0];
+        scratch.set(scratch.get() & !(SCRATCH0_BROWNOUT | SCRATCH0_FAULT));
     }
 }
-// This should be refactored to be a general reset
-pub fn reset_dcrypto() {
+
+/// Long-life scratch words free for drivers; word 0 holds the flags above.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum ScratchWord {
+    Scratch1 = 1,
+    Scratch2 = 2,
+}
+
+/// Reads a scratch word, which survives every reset except power on.
+pub fn scratch(word: ScratchWord) -> u32 {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {pmu.long_life_scratch[word as usize].get()}
+}
+
+pub fn set_scratch(word: ScratchWord, value: u32) {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {pmu.long_life_scratch[word as usize].set(value)};
+}
+
+/// Sources that can wake the chip from deep sleep
+///
+/// These keep running while the high-speed clocks are gated, so they are the
+/// only way back out of deep sleep short of a reset.
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub enum WakeSource {
+    Pin = 0,
+    UsbSuspend = 1,
+    TimeLs0Timer0 = 2,
+    TimeLs0Timer1 = 3,
+    Rdd = 4,
+    RBox = 5,
+}
+
+/// Allows `source` to wake the chip from deep sleep.
+pub fn enable_wake_source(source: WakeSource) {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {pmu.exitpd_mask.set(pmu.exitpd_mask.get() | 1 << (source as u32))};
+}
+
+/// Stops `source` from waking the chip from deep sleep.
+pub fn disable_wake_source(source: WakeSource) {
     let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
-    // Clear the DCRYPTO bit, which is 0x2
-    unsafe {pmu.reset.set(pmu.reset0.get() & !(0x2));}
+    unsafe {pmu.exitpd_mask.set(pmu.exitpd_mask.get() & !(1 << (source as u32)))};
+}
+
+/// Returns whether `source` caused the last exit from deep sleep.
+pub fn woken_by(source: WakeSource) -> bool {
+    let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+    unsafe {pmu.exitpd_src.get() & 1 << (source as u32) != 0}
+}
+
+/// Reset line of a single peripheral.
+///
+/// Reset lines are numbered like the peripheral clocks, so they are named by
+/// the `PeripheralClock` of the peripheral they reset. Like `Clock`, these
+/// can only be created by trusted code. Drivers use them to recover a wedged
+/// peripheral (e.g. after an AHB error or a dcrypto fault) without
+/// rebooting the whole chip. `Temp0` has no reset line, so its reset does
+/// nothing.
+#[derive(Clone,Copy)]
+pub struct PeripheralReset {
+    // It's important that this field is private!
+    peripheral: PeripheralClock,
+}
+
+impl PeripheralReset {
+    pub const unsafe fn new(peripheral: PeripheralClock) -> PeripheralReset {
+        PeripheralReset { peripheral: peripheral }
+    }
+
+    /// Holds the peripheral in reset until `deassert` is called.
+    pub fn assert(&self) {
+        self.write(false);
+    }
+
+    /// Releases the peripheral from reset.
+    pub fn deassert(&self) {
+        self.write(true);
+    }
+
+    /// Resets the peripheral, leaving it out of reset.
+    pub fn pulse(&self) {
+        self.assert();
+        // Give the reset a few cycles to propagate through the peripheral
+        for _ in 0..10 {
+            support::nop();
+        }
+        self.deassert();
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+        unsafe {
+            match register_bit(self.peripheral) {
+                Some((0, bit)) => pmu.reset0.get() & bit == 0,
+                Some((_, bit)) => pmu.reset1.get() & bit == 0,
+                None => false,
+            }
+        }
+    }
+
+    fn write(&self, out_of_reset: bool) {
+        let pmu: &mut PMURegisters = unsafe { transmute(PMU) };
+        unsafe {
+            let (write_enable, reset, bit) = match register_bit(self.peripheral) {
+                Some((0, bit)) => (&pmu.reset0_write_enable, &pmu.reset0, bit),
+                Some((_, bit)) => (&pmu.reset1_write_enable, &pmu.reset1, bit),
+                None => return,
+            };
+            write_enable.set(bit);
+            if out_of_reset {
+                reset.set(reset.get() | bit);
+            } else {
+                reset.set(reset.get() & !bit);
+            }
+            write_enable.set(0);
+        }
+    }
 }
@@ -0,0 +1,97 @@
+//! Power-state tracing
+//!
+//! Once `init` is called, the chip records in the event trace (see `trace`)
+//! every change between running, the idle states and deep sleep, and every
+//! peripheral clock the PMU turns on or off, so a trace dump shows what
+//! kept the chip awake or a clock running without a current probe:
+//!
+//! | Event                   | Argument                                       |
+//! | ----------------------- | :--------------------------------------------- |
+//! | "power run"             | Microseconds spent in the state before         |
+//! | "power idle"            | Microseconds spent in the state before         |
+//! | "power nap"             | Microseconds spent in the state before         |
+//! | "power suspend"         | Microseconds spent in the state before         |
+//! | "power deep sleep"      | Microseconds spent in the state before         |
+//! | "power deep sleep exit" | 0; the chip was reset out of deep sleep        |
+//! | "clock on"              | The clock: bank << 8 \| index in its bank      |
+//! | "clock off"             | The clock: bank << 8 \| index in its bank      |
+//! | "clocks gated"          | bank << 31 \| the bank's clocks turned off     |
+//!
+//! Idling records two events per pass of the kernel's main loop, which
+//! soon pushes everything else out of the ring, so boards only call
+//! `init` when looking into power use.
+
+use pmu::{self, ResetCause};
+use timestamp::TIMESTAMP;
+use trace;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    Run,
+    /// Waiting for an interrupt with the clocks running
+    Idle,
+    /// Waiting for an interrupt with the PMU gating idle drivers' clocks
+    Nap,
+    /// Napping with the USB bus suspended
+    Suspend,
+    /// Sleeping deep with the USB bus suspended and every gateable clock
+    /// idle
+    DeepSleep,
+}
+
+static mut ENABLED: bool = false;
+static mut STATE: PowerState = PowerState::Run;
+// When the chip entered `STATE`, in microseconds
+static mut SINCE: u64 = 0;
+
+/// Starts recording; the timestamp counter must be running.
+pub fn init() {
+    unsafe {
+        ENABLED = true;
+        STATE = PowerState::Run;
+        SINCE = TIMESTAMP.now();
+    }
+    if pmu::reset_cause() == ResetCause::LowPowerExit {
+        trace::record("power deep sleep exit", 0);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// Records that the chip is about to enter, or has just entered, `state`.
+pub fn transition(state: PowerState) {
+    unsafe {
+        if !ENABLED || state == STATE {
+            return;
+        }
+        let now = TIMESTAMP.now();
+        let event = match state {
+            PowerState::Run => "power run",
+            PowerState::Idle => "power idle",
+            PowerState::Nap => "power nap",
+            PowerState::Suspend => "power suspend",
+            PowerState::DeepSleep => "power deep sleep",
+        };
+        trace::record(event, now.wrapping_sub(SINCE) as u32);
+        STATE = state;
+        SINCE = now;
+    }
+}
+
+/// Records that clock `index` of `bank` was turned on or off.
+pub fn clock_changed(bank: u32, index: u32, on: bool) {
+    if !is_enabled() {
+        return;
+    }
+    trace::record(if on { "clock on" } else { "clock off" }, bank << 8 | index);
+}
+
+/// Records that the clocks in `mask` of `bank` were turned off together.
+pub fn clocks_gated(bank: u32, mask: u32) {
+    if !is_enabled() || mask == 0 {
+        return;
+    }
+    trace::record("clocks gated", bank << 31 | mask);
+}
@@ -0,0 +1,139 @@
+//! Cycle-accurate profiling using the Cortex-M3 DWT cycle counter
+//!
+//! A `Region` accumulates the number of times it was entered and the
+//! minimum, maximum and total number of CPU cycles spent in it. Regions are
+//! declared as statics next to the code they measure and timed with a
+//! `Scope`, which records the elapsed cycles when it is dropped, or given
+//! measurements with `record` when the start and end are in different
+//! functions. `usb::INTERRUPT` times the USB interrupt handler this way,
+//! and `crypto::dcrypto::PROGRAM` each DCRYPTO program from its call to its
+//! completion interrupt.
+//!
+//! `profile::enable()` must be called once at boot, before which all scopes
+//! measure zero cycles. The counter is 32 bits wide, so a single scope must
+//! be shorter than 2^32 cycles (a little under three minutes at 24Mhz).
+
+use core::cell::Cell;
+use core::fmt::Write;
+use kernel::common::cells::VolatileCell;
+
+#[repr(C)]
+struct DwtRegisters {
+    control: VolatileCell<u32>,
+    cycle_count: VolatileCell<u32>,
+}
+
+const DWT_BASE: *const DwtRegisters = 0xE0001000 as *const DwtRegisters;
+
+/// Debug Exception and Monitor Control Register
+const DEMCR: *const VolatileCell<u32> = 0xE000EDFC as *const VolatileCell<u32>;
+
+/// DEMCR: enables the DWT and ITM units
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+/// DWT_CTRL: enables the cycle counter
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+
+/// Starts the cycle counter from zero.
+pub fn enable() {
+    unsafe {
+        let demcr = &*DEMCR;
+        demcr.set(demcr.get() | DEMCR_TRCENA);
+        let dwt = &*DWT_BASE;
+        dwt.cycle_count.set(0);
+        dwt.control.set(dwt.control.get() | DWT_CTRL_CYCCNTENA);
+    }
+}
+
+/// The current value of the cycle counter.
+#[inline(always)]
+pub fn cycles() -> u32 {
+    unsafe { (&*DWT_BASE).cycle_count.get() }
+}
+
+pub struct Region {
+    name: &'static str,
+    count: Cell<u32>,
+    total: Cell<u64>,
+    min: Cell<u32>,
+    max: Cell<u32>,
+}
+
+impl Region {
+    pub const fn new(name: &'static str) -> Region {
+        Region {
+            name: name,
+            count: Cell::new(0),
+            total: Cell::new(0),
+            min: Cell::new(!0),
+            max: Cell::new(0),
+        }
+    }
+
+    /// Starts timing the region until the returned `Scope` is dropped.
+    pub fn scope(&'static self) -> Scope {
+        Scope {
+            region: self,
+            start: cycles(),
+        }
+    }
+
+    /// Adds a single measurement of `cycles` to the region.
+    pub fn record(&self, cycles: u32) {
+        self.count.set(self.count.get().wrapping_add(1));
+        self.total.set(self.total.get().wrapping_add(cycles as u64));
+        if cycles < self.min.get() {
+            self.min.set(cycles);
+        }
+        if cycles > self.max.get() {
+            self.max.set(cycles);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.count.set(0);
+        self.total.set(0);
+        self.min.set(!0);
+        self.max.set(0);
+    }
+
+    pub fn average(&self) -> u32 {
+        match self.count.get() {
+            0 => 0,
+            count => (self.total.get() / count as u64) as u32,
+        }
+    }
+
+    /// Writes the region's statistics to `writer`.
+    pub fn dump(&self, writer: &mut Write) {
+        if self.count.get() == 0 {
+            let _ = writer.write_fmt(format_args!("{}: never entered\r\n", self.name));
+        } else {
+            let _ = writer.write_fmt(format_args!("{}: count {} min {} max {} avg {} cycles\r\n",
+                                                  self.name,
+                                                  self.count.get(),
+                                                  self.min.get(),
+                                                  self.max.get(),
+                                                  self.average()));
+        }
+    }
+}
+
+/// Writes the statistics of every region in `regions` to `writer`.
+pub fn dump(writer: &mut Write, regions: &[&Region]) {
+    for region in regions.iter() {
+        region.dump(writer);
+    }
+}
+
+/// A timed section of a `Region`, recorded when dropped.
+pub struct Scope {
+    region: &'static Region,
+    start: u32,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        self.region.record(cycles().wrapping_sub(self.start));
+    }
+}
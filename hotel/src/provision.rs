@@ -0,0 +1,206 @@
+//! Provisioning the device's personality over USB
+//!
+//! A factory tool writes the attestation key and certificate, and
+//! optionally the firmware key (see `personality`), with vendor requests on
+//! endpoint 0:
+//!
+//! | bRequest                   | Direction | wValue | wIndex | Data                              |
+//! | -------------------------- | :-------- | :----- | :----- | :-------------------------------- |
+//! | `REQUEST_PROVISION_STATUS` | IN        |        |        | `State` (1 byte), capacity (LE16) |
+//! | `REQUEST_PROVISION_WRITE`  | OUT       | Offset |        | Bytes of the keys and certificate |
+//! | `REQUEST_PROVISION_COMMIT` | OUT       | K      | C      | SHA-256 of them, then F (LE16)    |
+//!
+//! Writes and the commit are only accepted while the device is
+//! unprovisioned. The key, certificate and firmware key are written one
+//! after the other; F can be left out of the commit when there is no
+//! firmware key. Offsets must be multiples of 4 and each byte can only be
+//! written once; a write at offset 0 starts over by erasing the page. The
+//! commit checks the digest against what reached flash and then writes the
+//! header with the device's MAC, which ends provisioning: once the page
+//! holds a personality (or anything else), `init` protects it for the rest
+//! of every boot and the requests that change it are refused. A commit that
+//! arrives while an app is using the SHA engine fails with EBUSY before
+//! anything is written, and can be retried.
+//!
+//! The page alone isn't one-way, since erasing the flash through the debug
+//! port makes it blank again. The firmware can't make it one-way either:
+//! the fuses are only readable from the chip, and there is no flash that
+//! the debug port can't erase. So the factory tool finishes by blowing the
+//! provisioning-closed fuse (see `fuse::provisioning_closed`) at the test
+//! station, and from then on writes and commits are refused whatever the
+//! page holds.
+
+use core::cmp;
+use crypto::sha::ShaEngine;
+use fault;
+use flash::Flash;
+use fuse;
+use hil::digest::{DigestEngine, DigestMode};
+use kernel::ReturnCode;
+use personality::{self, Personality, State, FIELDS_LEN, FIRMWARE_KEY_LEN, HEADER_LEN, MAC_LEN,
+                  MAX_KEY_LEN, PERSONALITY_MAGIC};
+use trace;
+use usb::{VendorHandler, VendorRequest, USB0};
+
+pub const REQUEST_PROVISION_STATUS: u8 = 0x17;
+pub const REQUEST_PROVISION_WRITE: u8 = 0x18;
+pub const REQUEST_PROVISION_COMMIT: u8 = 0x19;
+
+const DIGEST_LEN: usize = 32;
+
+pub struct Provisioning<'a> {
+    personality: &'a Personality,
+    flash: &'a Flash,
+    sha: &'a ShaEngine,
+}
+
+impl<'a> Provisioning<'a> {
+    /// `personality` must have been initialized.
+    pub fn new(personality: &'a Personality, flash: &'a Flash, sha: &'a ShaEngine) -> Provisioning<'a> {
+        Provisioning {
+            personality: personality,
+            flash: flash,
+            sha: sha,
+        }
+    }
+
+    /// Registers the vendor requests with the USB driver, and protects the
+    /// personality page if provisioning is already over.
+    pub fn init(&'static self) -> ReturnCode {
+        if !self.unprovisioned() {
+            self.lock();
+        }
+        unsafe {
+            for &request in [REQUEST_PROVISION_STATUS,
+                             REQUEST_PROVISION_WRITE,
+                             REQUEST_PROVISION_COMMIT].iter() {
+                let result = USB0.add_vendor_handler(request, self);
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether the personality can still be written: the fuse isn't blown
+    /// and the page is blank, checked so that a glitch can't make a
+    /// provisioned device writable.
+    fn unprovisioned(&self) -> bool {
+        fault::check(|| {
+            !fuse::provisioning_closed() && self.personality.state() == State::Unprovisioned
+        })
+    }
+
+    fn lock(&self) {
+        let (start, len) = self.personality.region();
+        self.flash.protect(start, len);
+    }
+
+    fn status(&self, response: &mut [u8]) -> Result<usize, ReturnCode> {
+        let capacity = self.personality.capacity();
+        let status = [self.personality.state() as u8, capacity as u8, (capacity >> 8) as u8];
+        let len = cmp::min(response.len(), status.len());
+        response[..len].copy_from_slice(&status[..len]);
+        Ok(len)
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) -> Result<usize, ReturnCode> {
+        if !self.unprovisioned() {
+            return Err(ReturnCode::EALREADY);
+        }
+        if offset % 4 != 0 || offset + data.len() > self.personality.capacity() {
+            return Err(ReturnCode::EINVAL);
+        }
+        let (start, _) = self.personality.region();
+        if offset == 0 {
+            let result = self.flash.erase(start);
+            if result != ReturnCode::SUCCESS {
+                return Err(result);
+            }
+        }
+        let address = start + HEADER_LEN + offset;
+        if !self.flash.is_erased(address, data.len()) {
+            return Err(ReturnCode::EALREADY);
+        }
+        match self.flash.program_bytes(address, data) {
+            ReturnCode::SUCCESS => Ok(0),
+            error => Err(error),
+        }
+    }
+
+    fn commit(&self, key_len: usize, certificate_len: usize, data: &[u8]) -> Result<usize, ReturnCode> {
+        if !self.unprovisioned() {
+            return Err(ReturnCode::EALREADY);
+        }
+        let firmware_key_len = match data.len() {
+            DIGEST_LEN => 0,
+            len if len == DIGEST_LEN + 2 => data[DIGEST_LEN] as usize | (data[DIGEST_LEN + 1] as usize) << 8,
+            _ => return Err(ReturnCode::EINVAL),
+        };
+        let digest = &data[..DIGEST_LEN];
+        let body_len = key_len + certificate_len + firmware_key_len;
+        if key_len > MAX_KEY_LEN || (firmware_key_len != 0 && firmware_key_len != FIRMWARE_KEY_LEN) ||
+            body_len > self.personality.capacity() {
+            return Err(ReturnCode::EINVAL);
+        }
+        let page = self.personality.bytes();
+        let body = &page[HEADER_LEN..HEADER_LEN + body_len];
+
+        // Check everything arrived before committing to it. If an app is
+        // part way through a digest, refuse before anything is written so
+        // the tool can retry.
+        let mut expected = [0; DIGEST_LEN];
+        self.sha.initialize(DigestMode::Sha256)?;
+        let computed = self.sha.update(body).is_ok() &&
+            self.sha.finalize(&mut expected).is_ok();
+        if !computed {
+            self.sha.cancel();
+        }
+        if !computed || !fault::check(|| expected.iter().zip(digest.iter()).all(|(a, b)| a == b)) {
+            trace::record("provision bad digest", 0);
+            return Err(ReturnCode::FAIL);
+        }
+
+        let mut header = [0; HEADER_LEN];
+        for i in 0..4 {
+            header[i] = (PERSONALITY_MAGIC >> (i * 8)) as u8;
+        }
+        header[4] = key_len as u8;
+        header[5] = (key_len >> 8) as u8;
+        header[6] = certificate_len as u8;
+        header[7] = (certificate_len >> 8) as u8;
+        header[8] = firmware_key_len as u8;
+        header[9] = (firmware_key_len >> 8) as u8;
+        header[10] = 0xff;
+        header[11] = 0xff;
+        let mut mac = [0; MAC_LEN];
+        personality::compute_mac(self.sha, &header[..FIELDS_LEN], body, &mut mac)?;
+        header[FIELDS_LEN..FIELDS_LEN + MAC_LEN].copy_from_slice(&mac);
+
+        let (start, _) = self.personality.region();
+        let result = self.flash.program_bytes(start, &header);
+        // Stop here even if programming failed: a header written part way
+        // can't be written again. Nothing else can have taken the SHA engine
+        // since the digest above, so the check isn't refused.
+        let state = self.personality.verify(self.sha).unwrap_or(State::Invalid);
+        self.lock();
+        trace::record("provisioned", state as u32);
+        match (result, state) {
+            (ReturnCode::SUCCESS, State::Provisioned) => Ok(0),
+            (ReturnCode::SUCCESS, _) => Err(ReturnCode::FAIL),
+            (error, _) => Err(error),
+        }
+    }
+}
+
+impl<'a> VendorHandler for Provisioning<'a> {
+    fn vendor_request(&self, request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        match request.request {
+            REQUEST_PROVISION_STATUS => self.status(data),
+            REQUEST_PROVISION_WRITE => self.write(request.value as usize, data),
+            REQUEST_PROVISION_COMMIT => self.commit(request.value as usize, request.index as usize, data),
+            _ => Err(ReturnCode::ENOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! PWM outputs on the microsecond timer
+//!
+//! TIMEUS counters 2 and 3 are used as PWM channels `PWM0` and `PWM1`. A
+//! channel's counter runs in wrapping mode at the full 24Mhz, with
+//! `max_value` set to the period and `programmed_value` to the point within
+//! it where the output turns off. The counters have no output pins of their
+//! own, so the channel drives a GPIO pin from the two compare interrupts:
+//! on at the wrap, off at the programmed value. Interrupt latency makes the
+//! edges jitter by a few microseconds, which is fine for LED brightness and
+//! buzzer tones but not for anything timing-critical, and the frequency is
+//! capped so the interrupts don't swamp the CPU. A duty cycle of 0% or 100%
+//! stops the counter and leaves the pin static.
+//!
+//! ```ignore
+//! use kernel::hil::pwm::PwmPin;
+//! hotel::pwm::PWM0.set_pin(&hotel::gpio::PORT0.pins[0], true);
+//! hotel::pwm::PWM0.start(1000, hotel::pwm::MAX_DUTY_CYCLE / 4);
+//! ```
+
+use core::cell::Cell;
+use gpio::GPIOPin;
+use kernel::ReturnCode;
+use kernel::hil;
+use kernel::hil::gpio::Pin;
+use timeus::{Counter, Enable, Registers, BASE_REGISTERS};
+
+/// Counter ticks per second with a divider of 1
+const TICK_HZ: usize = 24_000_000;
+
+/// The highest supported frequency, so a pair of interrupts takes at most
+/// a few percent of the CPU.
+pub const MAX_FREQUENCY_HZ: usize = 20_000;
+
+/// The duty cycle that keeps the output on all the time
+pub const MAX_DUTY_CYCLE: usize = 0xffff;
+
+pub static mut PWM0: PwmChannel = PwmChannel::new(2);
+pub static mut PWM1: PwmChannel = PwmChannel::new(3);
+
+/// The two channels as a `hil::pwm::Pwm`, for capsules that take a
+/// controller and a pin.
+pub static mut PWM: Pwm = Pwm;
+
+pub struct PwmChannel {
+    regs: *const Registers,
+    idx: usize,
+    pin: Cell<Option<&'static GPIOPin>>,
+    active_low: Cell<bool>,
+}
+
+impl PwmChannel {
+    const fn new(idx: usize) -> PwmChannel {
+        PwmChannel {
+            regs: BASE_REGISTERS,
+            idx: idx,
+            pin: Cell::new(None),
+            active_low: Cell::new(false),
+        }
+    }
+
+    /// Sets the pin the channel drives, e.g. an LED that is lit when the
+    /// pin is low if `active_low`. The pin must already be connected to a
+    /// pad with `pinmux::connect_gpio`.
+    pub fn set_pin(&self, pin: &'static GPIOPin, active_low: bool) {
+        pin.make_output();
+        self.pin.set(Some(pin));
+        self.active_low.set(active_low);
+        self.drive(false);
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    fn counter(&self) -> &Counter {
+        &self.registers().counters[self.idx]
+    }
+
+    fn programmed_bit(&self) -> u32 {
+        1 << (self.idx * 2)
+    }
+
+    fn max_bit(&self) -> u32 {
+        1 << (self.idx * 2 + 1)
+    }
+
+    fn drive(&self, on: bool) {
+        self.pin.get().map(|pin| if on != self.active_low.get() {
+            pin.set();
+        } else {
+            pin.clear();
+        });
+    }
+
+    fn stop_counter(&self) {
+        let regs = self.registers();
+        let bits = self.programmed_bit() | self.max_bit();
+        regs.interrupt_enable.set(regs.interrupt_enable.get() & !bits);
+        unsafe { self.counter().wrapping.set(Enable::Disabled) };
+        regs.interrupt_clear.set(bits);
+    }
+
+    /// Handles the counter reaching its programmed value, which ends the
+    /// on part of the period.
+    pub fn handle_off_interrupt(&self) {
+        self.registers().interrupt_clear.set(self.programmed_bit());
+        self.drive(false);
+    }
+
+    /// Handles the counter wrapping, which starts a new period.
+    pub fn handle_on_interrupt(&self) {
+        self.registers().interrupt_clear.set(self.max_bit());
+        self.drive(true);
+    }
+}
+
+impl hil::pwm::PwmPin for PwmChannel {
+    fn start(&self, frequency_hz: usize, duty_cycle: usize) -> ReturnCode {
+        if self.pin.get().is_none() {
+            return ReturnCode::EOFF;
+        }
+        if frequency_hz == 0 || frequency_hz > MAX_FREQUENCY_HZ || duty_cycle > MAX_DUTY_CYCLE {
+            return ReturnCode::EINVAL;
+        }
+        self.stop_counter();
+        if duty_cycle == 0 || duty_cycle == MAX_DUTY_CYCLE {
+            self.drive(duty_cycle != 0);
+            return ReturnCode::SUCCESS;
+        }
+
+        let period = (TICK_HZ / frequency_hz) as u32;
+        let on_ticks = (period as u64 * duty_cycle as u64 / MAX_DUTY_CYCLE as u64) as u32;
+        let regs = self.registers();
+        let counter = self.counter();
+        unsafe {
+            counter.divider.set(1);
+            counter.max_value.set(period);
+            // A compare of 0 would never be passed on the way up
+            counter.programmed_value.set(on_ticks.max(1));
+        }
+        regs.interrupt_enable.set(regs.interrupt_enable.get() | self.programmed_bit() |
+                                  self.max_bit());
+        self.drive(true);
+        unsafe { counter.wrapping.set(Enable::Enabled) };
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.stop_counter();
+        self.drive(false);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        MAX_FREQUENCY_HZ
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        MAX_DUTY_CYCLE
+    }
+}
+
+pub struct Pwm;
+
+impl hil::pwm::Pwm for Pwm {
+    type Pin = PwmChannel;
+
+    fn start(&self, pin: &PwmChannel, frequency_hz: usize, duty_cycle: usize) -> ReturnCode {
+        hil::pwm::PwmPin::start(pin, frequency_hz, duty_cycle)
+    }
+
+    fn stop(&self, pin: &PwmChannel) -> ReturnCode {
+        hil::pwm::PwmPin::stop(pin)
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        MAX_FREQUENCY_HZ
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        MAX_DUTY_CYCLE
+    }
+}
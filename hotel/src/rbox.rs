@@ -0,0 +1,261 @@
+//! Reset and power box (RBOX)
+//!
+//! The RBOX watches the power button and the two key inputs on behalf of
+//! the system, debounces them, and drives the reset lines of the embedded
+//! controller (EC) and application processor (AP). Clients are told when
+//! an input is pressed or released, and when one of the combo detectors
+//! fires because a set of inputs has been held together long enough. A
+//! combo can also assert a reset line by itself, so e.g. a
+//! power-plus-refresh recovery combo works even if the kernel is wedged.
+//!
+//! ```ignore
+//! use hotel::rbox::{self, Input, ResetLine};
+//! rbox::RBOX0.init();
+//! rbox::RBOX0.set_client(client);
+//! rbox::RBOX0.set_combo(0, &[Input::PowerButton, Input::Key0], 10_000, Some(ResetLine::Ec));
+//! ```
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use pmu::{self, Clock, PeripheralClock, PeripheralClock0};
+
+#[repr(C)]
+struct ComboRegisters {
+    /// Combo configuration
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0-2  | Inputs that must all be held, by `Input` number      |
+    /// | 8    | Assert the EC reset line when detected               |
+    /// | 9    | Assert the AP reset line when detected               |
+    control: VolatileCell<u32>,
+
+    /// How long the inputs must be held, in milliseconds
+    hold_time: VolatileCell<u32>,
+}
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Bit 0 enables the block
+    control: VolatileCell<u32>,
+
+    /// Debounced input levels, bit n for `Input` n; set while pressed
+    input_status: VolatileCell<u32>,
+
+    /// Bit n inverts input n; the inputs are active low out of reset
+    input_invert: VolatileCell<u32>,
+
+    /// Debounce time in milliseconds
+    debounce: VolatileCell<u32>,
+
+    /// Interrupt enables, with the same layout as `interrupt_state`
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Power button pressed                                 |
+    /// | 1    | Power button released                                |
+    /// | 2    | Key 0 pressed                                        |
+    /// | 3    | Key 0 released                                       |
+    /// | 4    | Key 1 pressed                                        |
+    /// | 5    | Key 1 released                                       |
+    /// | 6-9  | Combo 0-3 detected                                   |
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Pending interrupts; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+
+    /// Reset outputs
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Assert the EC reset line (EC_RST_L low)              |
+    /// | 1    | Assert the AP reset line (SYS_RST_L low)             |
+    reset_control: VolatileCell<u32>,
+
+    combos: [ComboRegisters; NUM_COMBOS],
+}
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+
+const INTERRUPT_COMBO_SHIFT: u32 = 6;
+const INTERRUPT_ALL: u32 = 0x3ff;
+
+const COMBO_RESET_EC: u32 = 1 << 8;
+const COMBO_RESET_AP: u32 = 1 << 9;
+
+/// Combo detectors
+pub const NUM_COMBOS: usize = 4;
+const NUM_INPUTS: usize = 3;
+
+const DEFAULT_DEBOUNCE_MS: u32 = 20;
+
+const RBOX0_BASE: *const Registers = 0x40550000 as *const Registers;
+
+pub static mut RBOX0: Rbox = unsafe { Rbox::new(RBOX0_BASE) };
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    PowerButton = 0,
+    Key0 = 1,
+    Key1 = 2,
+}
+
+const INPUTS: [Input; NUM_INPUTS] = [Input::PowerButton, Input::Key0, Input::Key1];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetLine {
+    /// The embedded controller
+    Ec,
+    /// The application processor
+    Ap,
+}
+
+impl ResetLine {
+    fn bit(self) -> u32 {
+        match self {
+            ResetLine::Ec => 1 << 0,
+            ResetLine::Ap => 1 << 1,
+        }
+    }
+
+    fn combo_bit(self) -> u32 {
+        match self {
+            ResetLine::Ec => COMBO_RESET_EC,
+            ResetLine::Ap => COMBO_RESET_AP,
+        }
+    }
+}
+
+pub trait RboxClient {
+    /// `input` was pressed or released.
+    fn input_changed(&self, input: Input, pressed: bool);
+
+    /// Combo `combo` has been held for its hold time. Any reset line the
+    /// combo was set up to assert is already asserted.
+    fn combo_detected(&self, combo: usize);
+}
+
+pub struct Rbox {
+    regs: *const Registers,
+    clock: Clock,
+    client: Cell<Option<&'static RboxClient>>,
+}
+
+impl Rbox {
+    const unsafe fn new(regs: *const Registers) -> Rbox {
+        Rbox {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank0(PeripheralClock0::RBox0)),
+            client: Cell::new(None),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    pub fn set_client(&self, client: &'static RboxClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Enables the block with active-low inputs, and lets the power button
+    /// wake the chip from deep sleep.
+    pub fn init(&self) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE == 0 {
+            self.clock.acquire();
+        }
+        regs.input_invert.set(0);
+        regs.debounce.set(DEFAULT_DEBOUNCE_MS);
+        regs.control.set(CONTROL_ENABLE);
+        regs.interrupt_state.set(INTERRUPT_ALL);
+        regs.interrupt_enable.set(INTERRUPT_ALL);
+        pmu::enable_wake_source(pmu::WakeSource::RBox);
+    }
+
+    pub fn set_debounce(&self, milliseconds: u32) {
+        self.registers().debounce.set(milliseconds);
+    }
+
+    /// Treats `input` as active high rather than active low.
+    pub fn set_active_high(&self, input: Input, active_high: bool) {
+        let regs = self.registers();
+        let bit = 1 << (input as u32);
+        if active_high {
+            regs.input_invert.set(regs.input_invert.get() | bit);
+        } else {
+            regs.input_invert.set(regs.input_invert.get() & !bit);
+        }
+    }
+
+    pub fn is_pressed(&self, input: Input) -> bool {
+        self.registers().input_status.get() & 1 << (input as u32) != 0
+    }
+
+    /// Sets up combo detector `combo` to fire when all of `inputs` have
+    /// been held for `hold_ms`, asserting `reset` if given. Returns EINVAL
+    /// if `combo` is out of range or `inputs` is empty.
+    pub fn set_combo(&self,
+                     combo: usize,
+                     inputs: &[Input],
+                     hold_ms: u32,
+                     reset: Option<ResetLine>)
+                     -> ReturnCode {
+        if combo >= NUM_COMBOS || inputs.is_empty() {
+            return ReturnCode::EINVAL;
+        }
+        let mut control = inputs.iter().fold(0, |mask, &input| mask | 1 << (input as u32));
+        control |= reset.map_or(0, |line| line.combo_bit());
+        let regs = &self.registers().combos[combo];
+        regs.control.set(0);
+        regs.hold_time.set(hold_ms);
+        regs.control.set(control);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn clear_combo(&self, combo: usize) {
+        if combo < NUM_COMBOS {
+            self.registers().combos[combo].control.set(0);
+        }
+    }
+
+    pub fn assert_reset(&self, line: ResetLine) {
+        let regs = self.registers();
+        regs.reset_control.set(regs.reset_control.get() | line.bit());
+    }
+
+    /// Releases `line`, including after a combo asserted it.
+    pub fn deassert_reset(&self, line: ResetLine) {
+        let regs = self.registers();
+        regs.reset_control.set(regs.reset_control.get() & !line.bit());
+    }
+
+    pub fn is_reset_asserted(&self, line: ResetLine) -> bool {
+        self.registers().reset_control.get() & line.bit() != 0
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        let state = regs.interrupt_state.get() & regs.interrupt_enable.get();
+        regs.interrupt_state.set(state);
+
+        self.client.get().map(|client| {
+            for (n, &input) in INPUTS.iter().enumerate() {
+                if state & 1 << (2 * n) != 0 {
+                    client.input_changed(input, true);
+                }
+                if state & 1 << (2 * n + 1) != 0 {
+                    client.input_changed(input, false);
+                }
+            }
+            for combo in 0..NUM_COMBOS {
+                if state & 1 << (INTERRUPT_COMBO_SHIFT as usize + combo) != 0 {
+                    client.combo_detected(combo);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,150 @@
+//! Reboot into update mode at a host's request
+//!
+//! `Reboot` serves vendor requests on endpoint 0 that let an update tool
+//! restart the device in its update mode (the one the recovery strap
+//! selects) without physical access to the strap pins:
+//!
+//! | bRequest                    | Direction | Data                             |
+//! | --------------------------- | :-------- | :------------------------------- |
+//! | `REQUEST_REBOOT_CHALLENGE`  | IN        | `CHALLENGE_LEN` random bytes     |
+//! | `REQUEST_REBOOT_BOOTLOADER` | OUT       | The challenge's MAC, if required |
+//!
+//! If the board asks for authentication, the OUT data stage must be the
+//! HMAC-SHA256 of the last challenge, keyed with the key ladder's output,
+//! so only a host that shares the device's key can reboot it. A challenge
+//! can only be answered once, right or wrong, but an answer that arrives
+//! while the SHA engine is busy with another digest fails with EBUSY and
+//! leaves the challenge outstanding for a retry. An accepted request sets a
+//! flag that survives the reset (see `pmu::take_bootloader_request`) and
+//! resets the chip `REBOOT_DELAY_MS` later, after the host has seen the
+//! request complete.
+
+use core::cell::Cell;
+use core::cmp;
+use crypto::sha::ShaEngine;
+use fault;
+use hil::digest::{DigestEngine, DigestError};
+use kernel::ReturnCode;
+use kernel::hil::time::{self, Alarm, Frequency};
+use panic;
+use pmu;
+use trace;
+use trng::Trng;
+use usb::{VendorHandler, VendorRequest, USB0};
+
+pub const REQUEST_REBOOT_CHALLENGE: u8 = 0x13;
+pub const REQUEST_REBOOT_BOOTLOADER: u8 = 0x14;
+
+/// Length of a challenge and of its HMAC-SHA256
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Time between accepting a request and resetting
+const REBOOT_DELAY_MS: u32 = 100;
+
+pub struct Reboot<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    trng: &'a Trng<'a>,
+    sha: &'a ShaEngine,
+    authenticate: bool,
+    // The challenge the host has to answer
+    challenge: Cell<Option<[u8; CHALLENGE_LEN]>>,
+}
+
+impl<'a, A: Alarm + 'a> Reboot<'a, A> {
+    /// Without `authenticate`, any host can reboot the device with an empty
+    /// `REQUEST_REBOOT_BOOTLOADER`. `trng` must have been started.
+    pub fn new(alarm: &'a A, trng: &'a Trng<'a>, sha: &'a ShaEngine, authenticate: bool) -> Reboot<'a, A> {
+        Reboot {
+            alarm: alarm,
+            trng: trng,
+            sha: sha,
+            authenticate: authenticate,
+            challenge: Cell::new(None),
+        }
+    }
+
+    /// Registers the vendor requests with the USB driver.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe {
+            for &request in [REQUEST_REBOOT_CHALLENGE, REQUEST_REBOOT_BOOTLOADER].iter() {
+                let result = USB0.add_vendor_handler(request, self);
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn new_challenge(&self, response: &mut [u8]) -> Result<usize, ReturnCode> {
+        let mut challenge = [0; CHALLENGE_LEN];
+        for word in challenge.chunks_mut(4) {
+            // The host retries if the TRNG can't keep up.
+            let random = match self.trng.try_read() {
+                Some(random) => random,
+                None => return Err(ReturnCode::EBUSY),
+            };
+            for (i, byte) in word.iter_mut().enumerate() {
+                *byte = (random >> (i * 8)) as u8;
+            }
+        }
+        self.challenge.set(Some(challenge));
+        let len = cmp::min(response.len(), CHALLENGE_LEN);
+        response[..len].copy_from_slice(&challenge[..len]);
+        Ok(len)
+    }
+
+    /// Whether `mac` answers the outstanding challenge, which is used up.
+    /// Fails with EBUSY, keeping the challenge for the host's retry, if the
+    /// SHA engine is in use.
+    fn verify(&self, mac: &[u8]) -> Result<bool, ReturnCode> {
+        let challenge = match self.challenge.take() {
+            Some(challenge) => challenge,
+            None => return Ok(false),
+        };
+        if mac.len() != CHALLENGE_LEN {
+            return Ok(false);
+        }
+        let mut expected = [0; CHALLENGE_LEN];
+        if let Err(DigestError::Busy) = self.sha.initialize_hidden_key_hmac() {
+            self.challenge.set(Some(challenge));
+            return Err(ReturnCode::EBUSY);
+        }
+        let computed = self.sha.update(&challenge).is_ok() &&
+            self.sha.finalize(&mut expected).is_ok();
+        if !computed {
+            self.sha.cancel();
+        }
+        // Compare every byte, so the time taken doesn't say where the first
+        // difference is.
+        Ok(computed && fault::check(|| expected.iter().zip(mac.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0))
+    }
+
+    fn reboot(&self, mac: &[u8]) -> Result<usize, ReturnCode> {
+        if self.authenticate && !self.verify(mac)? {
+            trace::record("reboot refused", mac.len() as u32);
+            return Err(ReturnCode::FAIL);
+        }
+        trace::record("reboot to bootloader", 0);
+        pmu::request_bootloader();
+        let delay = REBOOT_DELAY_MS * <A::Frequency>::frequency() / 1000;
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(delay));
+        Ok(0)
+    }
+}
+
+impl<'a, A: Alarm + 'a> VendorHandler for Reboot<'a, A> {
+    fn vendor_request(&self, request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode> {
+        match request.request {
+            REQUEST_REBOOT_CHALLENGE => self.new_challenge(data),
+            REQUEST_REBOOT_BOOTLOADER => self.reboot(data),
+            _ => Err(ReturnCode::ENOSUPPORT),
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for Reboot<'a, A> {
+    fn fired(&self) {
+        unsafe { panic::reset() };
+    }
+}
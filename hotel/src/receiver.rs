@@ -0,0 +1,118 @@
+//! Receive-side buffering shared by the `hil::uart::UART` drivers
+//!
+//! The UARTs and the USB console both fill a client's receive buffer a
+//! byte at a time as input arrives, and keep input that arrives while no
+//! receive is outstanding in a small ring (`RING_SIZE` bytes, beyond which
+//! it is dropped) to start the next receive with. `Receiver` is that
+//! bookkeeping; the driver only moves bytes into it and calls the client
+//! back when it says a receive is complete.
+//!
+//! ```ignore
+//! match self.rx.start(rx_buffer, rx_len) {
+//!     Err(buffer) => client.receive_complete(buffer, 0, Error::RepeatCallError),
+//!     Ok(true) => self.receive_complete(),
+//!     Ok(false) => self.enable_rx(),
+//! }
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, TakeCell};
+
+/// Bytes buffered while no receive is outstanding.
+pub const RING_SIZE: usize = 64;
+
+pub struct Receiver {
+    buffer: TakeCell<'static, [u8]>,
+    limit: Cell<usize>,
+    cursor: Cell<usize>,
+    ring: MapCell<[u8; RING_SIZE]>,
+    ring_head: Cell<usize>,
+    ring_len: Cell<usize>,
+}
+
+impl Receiver {
+    pub const fn new() -> Receiver {
+        Receiver {
+            buffer: TakeCell::empty(),
+            limit: Cell::new(0),
+            cursor: Cell::new(0),
+            ring: MapCell::new([0; RING_SIZE]),
+            ring_head: Cell::new(0),
+            ring_len: Cell::new(0),
+        }
+    }
+
+    /// Whether a receive is outstanding.
+    pub fn is_receiving(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Bytes waiting in the ring for the next receive.
+    pub fn buffered(&self) -> usize {
+        self.ring_len.get()
+    }
+
+    /// Starts receiving `len` bytes into `buffer`, first from the ring.
+    /// Returns whether that was already enough, or gives `buffer` back if
+    /// a receive is already outstanding.
+    pub fn start(&self, buffer: &'static mut [u8], len: usize) -> Result<bool, &'static mut [u8]> {
+        if self.buffer.is_some() {
+            return Err(buffer);
+        }
+        let limit = cmp::min(len, buffer.len());
+        let mut cursor = 0;
+        while cursor < limit {
+            match self.pop() {
+                Some(b) => buffer[cursor] = b,
+                None => break,
+            }
+            cursor += 1;
+        }
+        self.buffer.replace(buffer);
+        self.cursor.set(cursor);
+        self.limit.set(limit);
+        Ok(cursor == limit)
+    }
+
+    /// Takes a received byte: into the outstanding receive if there is
+    /// one, otherwise into the ring (or dropped if it is full). Returns
+    /// whether the receive is now complete.
+    pub fn byte_received(&self, b: u8) -> bool {
+        let cursor = self.cursor.get();
+        if self.buffer.map(|buffer| buffer[cursor] = b).is_none() {
+            self.push(b);
+            return false;
+        }
+        self.cursor.set(cursor + 1);
+        cursor + 1 == self.limit.get()
+    }
+
+    /// Ends the outstanding receive, giving back its buffer and the
+    /// number of bytes received into it.
+    pub fn finish(&self) -> Option<(&'static mut [u8], usize)> {
+        let cursor = self.cursor.get();
+        self.buffer.take().map(|buffer| (buffer, cursor))
+    }
+
+    fn push(&self, b: u8) {
+        let len = self.ring_len.get();
+        if len == RING_SIZE {
+            return;
+        }
+        let index = (self.ring_head.get() + len) % RING_SIZE;
+        self.ring.map(|ring| ring[index] = b);
+        self.ring_len.set(len + 1);
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let len = self.ring_len.get();
+        if len == 0 {
+            return None;
+        }
+        let head = self.ring_head.get();
+        self.ring_head.set((head + 1) % RING_SIZE);
+        self.ring_len.set(len - 1);
+        self.ring.map(|ring| ring[head])
+    }
+}
@@ -0,0 +1,380 @@
+//! SPI host (master) controllers
+//!
+//! The chip has two SPI hosts. SPI0 is wired to dedicated pads; SPI1's
+//! signals are routed through the pinmux (`Function::Spi1Spiclk` etc.).
+//! A hardware transaction shifts out up to `BUFFER_SIZE` bytes from the
+//! controller's buffer while shifting the received bytes into it, then
+//! raises an interrupt. Longer transfers are split into several
+//! transactions with chip select kept asserted between them. There is no
+//! DMA; each transaction is refilled from the interrupt handler.
+//!
+//! Chip select is either the controller's own CSB line or any GPIO pin,
+//! selected with `hil::spi::SpiMaster::specify_chip_select`:
+//!
+//! ```ignore
+//! let spi = &hotel::spi::SPI1;
+//! spi.init();
+//! spi.specify_chip_select(hotel::spi::ChipSelect::Gpio(&hotel::gpio::PORT0.pins[4]));
+//! spi.set_rate(1_000_000);
+//! spi.read_write_bytes(write_buffer, Some(read_buffer), 16);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use gpio::GPIOPin;
+use kernel::ReturnCode;
+use kernel::common::cells::{TakeCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::gpio::Pin;
+use kernel::hil::spi::{ClockPhase, ClockPolarity};
+use pmu::{Clock, PeripheralClock, PeripheralClock0};
+
+/// Bytes moved by a single hardware transaction
+const BUFFER_SIZE: usize = 64;
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Clock polarity: idle high if set                     |
+    /// | 1    | Clock phase: sample on trailing edge if set          |
+    /// | 2    | Enable                                               |
+    control: VolatileCell<u32>,
+
+    /// SCLK is 24MHz / (2 * (clock_divider + 1))
+    clock_divider: VolatileCell<u32>,
+
+    /// Transaction
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Start (self-clearing)                                |
+    /// | 1-6  | Number of bytes minus one                            |
+    /// | 7    | Keep CSB asserted after the transaction              |
+    transaction: VolatileCell<u32>,
+
+    /// Bit 0 enables the transaction-done interrupt
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Bit 0 is set when a transaction completes; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+
+    /// Bit 0 is set while a transaction is in progress
+    status: VolatileCell<u32>,
+
+    _reserved: [u32; 9],
+
+    /// Bytes to send, replaced by the bytes received as they are shifted
+    /// in. Little-endian: byte 0 is the low byte of word 0.
+    buffer: [VolatileCell<u32>; BUFFER_SIZE / 4],
+}
+
+const CONTROL_CPOL: u32 = 1 << 0;
+const CONTROL_CPHA: u32 = 1 << 1;
+const CONTROL_ENABLE: u32 = 1 << 2;
+
+const TRANSACTION_START: u32 = 1 << 0;
+const TRANSACTION_SIZE_SHIFT: u32 = 1;
+const TRANSACTION_HOLD_CS: u32 = 1 << 7;
+
+const PCLK_HZ: u32 = 24_000_000;
+const MAX_DIVIDER: u32 = 0xffff;
+
+const SPI0_BASE: *const Registers = 0x40700000 as *const Registers;
+const SPI1_BASE: *const Registers = 0x40710000 as *const Registers;
+
+pub static mut SPI0: SpiHost = unsafe { SpiHost::new(SPI0_BASE, PeripheralClock0::Spi0Hs) };
+pub static mut SPI1: SpiHost = unsafe { SpiHost::new(SPI1_BASE, PeripheralClock0::Spi1Hs) };
+
+/// Which line selects the device on the bus
+#[derive(Clone, Copy)]
+pub enum ChipSelect {
+    /// The controller's CSB output
+    Hardware,
+    /// An active-low GPIO pin
+    Gpio(&'static GPIOPin),
+}
+
+pub struct SpiHost {
+    regs: *const Registers,
+    clock: Clock,
+    client: Cell<Option<&'static hil::spi::SpiMasterClient>>,
+    chip_select: Cell<ChipSelect>,
+    // Keep chip select asserted after the current transfer (`hold_low`)
+    hold_cs: Cell<bool>,
+    busy: Cell<bool>,
+    write_buffer: TakeCell<'static, [u8]>,
+    read_buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    // Bytes of the transfer done before the current transaction
+    offset: Cell<usize>,
+    transaction_len: Cell<usize>,
+}
+
+impl SpiHost {
+    const unsafe fn new(regs: *const Registers, clock: PeripheralClock0) -> SpiHost {
+        SpiHost {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank0(clock)),
+            client: Cell::new(None),
+            chip_select: Cell::new(ChipSelect::Hardware),
+            hold_cs: Cell::new(false),
+            busy: Cell::new(false),
+            write_buffer: TakeCell::empty(),
+            read_buffer: TakeCell::empty(),
+            len: Cell::new(0),
+            offset: Cell::new(0),
+            transaction_len: Cell::new(0),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    fn assert_cs(&self) {
+        if let ChipSelect::Gpio(pin) = self.chip_select.get() {
+            pin.clear();
+        }
+    }
+
+    fn deassert_cs(&self) {
+        if let ChipSelect::Gpio(pin) = self.chip_select.get() {
+            pin.set();
+        }
+    }
+
+    fn write_buffer_byte(&self, index: usize, byte: u8) {
+        let word = &self.registers().buffer[index / 4];
+        let shift = (index % 4) * 8;
+        word.set(word.get() & !(0xff << shift) | (byte as u32) << shift);
+    }
+
+    fn read_buffer_byte(&self, index: usize) -> u8 {
+        (self.registers().buffer[index / 4].get() >> ((index % 4) * 8)) as u8
+    }
+
+    /// Loads the next part of the transfer into the controller and starts
+    /// it.
+    fn start_transaction(&self) {
+        let offset = self.offset.get();
+        let len = cmp::min(self.len.get() - offset, BUFFER_SIZE);
+        self.write_buffer.map(|buffer| {
+            for i in 0..len {
+                self.write_buffer_byte(i, buffer[offset + i]);
+            }
+        });
+        self.transaction_len.set(len);
+
+        let last = offset + len == self.len.get();
+        let mut transaction = TRANSACTION_START | ((len - 1) as u32) << TRANSACTION_SIZE_SHIFT;
+        if !last || self.hold_cs.get() {
+            transaction |= TRANSACTION_HOLD_CS;
+        }
+        let regs = self.registers();
+        regs.interrupt_state.set(1);
+        regs.interrupt_enable.set(1);
+        regs.transaction.set(transaction);
+    }
+
+    /// Runs a transaction of `len` (at most `BUFFER_SIZE`) bytes already in
+    /// the controller's buffer and waits for it to complete.
+    fn transaction_sync(&self, len: usize) {
+        let regs = self.registers();
+        let mut transaction = TRANSACTION_START | ((len - 1) as u32) << TRANSACTION_SIZE_SHIFT;
+        if self.hold_cs.get() {
+            transaction |= TRANSACTION_HOLD_CS;
+        }
+        regs.interrupt_enable.set(0);
+        self.assert_cs();
+        regs.transaction.set(transaction);
+        while regs.status.get() & 1 != 0 {}
+        regs.interrupt_state.set(1);
+        if !self.hold_cs.get() {
+            self.deassert_cs();
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        regs.interrupt_state.set(1);
+        if !self.busy.get() {
+            return;
+        }
+
+        let offset = self.offset.get();
+        let len = self.transaction_len.get();
+        self.read_buffer.map(|buffer| {
+            for i in 0..len {
+                if offset + i < buffer.len() {
+                    buffer[offset + i] = self.read_buffer_byte(i);
+                }
+            }
+        });
+        self.offset.set(offset + len);
+
+        if self.offset.get() < self.len.get() {
+            self.start_transaction();
+            return;
+        }
+
+        regs.interrupt_enable.set(0);
+        if !self.hold_cs.get() {
+            self.deassert_cs();
+        }
+        self.busy.set(false);
+        self.clock.set_busy(false);
+        let len = self.len.get();
+        self.write_buffer.take().map(|write_buffer| {
+            let read_buffer = self.read_buffer.take();
+            self.client.get().map(move |client| {
+                client.read_write_done(write_buffer, read_buffer, len);
+            });
+        });
+    }
+
+    /// `read_write_bytes`, but giving the buffers back with the error if
+    /// the transfer can't be started.
+    pub fn transfer(&self,
+                    write_buffer: &'static mut [u8],
+                    read_buffer: Option<&'static mut [u8]>,
+                    len: usize)
+                    -> Result<(), (ReturnCode, &'static mut [u8], Option<&'static mut [u8]>)> {
+        if self.busy.get() {
+            return Err((ReturnCode::EBUSY, write_buffer, read_buffer));
+        }
+        if len == 0 || len > write_buffer.len() {
+            return Err((ReturnCode::EINVAL, write_buffer, read_buffer));
+        }
+        self.busy.set(true);
+        self.clock.set_busy(true);
+        self.write_buffer.replace(write_buffer);
+        read_buffer.map(|buffer| self.read_buffer.replace(buffer));
+        self.len.set(len);
+        self.offset.set(0);
+        self.assert_cs();
+        self.start_transaction();
+        Ok(())
+    }
+}
+
+impl hil::spi::SpiMaster for SpiHost {
+    type ChipSelect = ChipSelect;
+
+    fn set_client(&self, client: &'static hil::spi::SpiMasterClient) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE == 0 {
+            self.clock.acquire();
+        }
+        regs.control.set(regs.control.get() | CONTROL_ENABLE);
+    }
+
+    fn is_busy(&self) -> bool {
+        self.busy.get()
+    }
+
+    /// Sends `len` bytes of `write_buffer`, storing what is received in
+    /// `read_buffer` if given (truncated to its length). Returns EBUSY if
+    /// a transfer is in progress and EINVAL if `len` is zero or longer
+    /// than `write_buffer`.
+    fn read_write_bytes(&self,
+                        write_buffer: &'static mut [u8],
+                        read_buffer: Option<&'static mut [u8]>,
+                        len: usize) -> ReturnCode {
+        match self.transfer(write_buffer, read_buffer, len) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((error, _, _)) => error,
+        }
+    }
+
+    fn write_byte(&self, val: u8) {
+        self.read_write_byte(val);
+    }
+
+    fn read_byte(&self) -> u8 {
+        self.read_write_byte(0)
+    }
+
+    fn read_write_byte(&self, val: u8) -> u8 {
+        self.write_buffer_byte(0, val);
+        self.transaction_sync(1);
+        self.read_buffer_byte(0)
+    }
+
+    fn specify_chip_select(&self, cs: ChipSelect) {
+        if let ChipSelect::Gpio(pin) = cs {
+            pin.make_output();
+            pin.set();
+        }
+        self.chip_select.set(cs);
+    }
+
+    /// Sets the fastest rate not above `rate` Hz and returns it.
+    fn set_rate(&self, rate: u32) -> u32 {
+        let rate = cmp::max(rate, 1);
+        // Round the divider up so the rate doesn't exceed what was asked
+        let divider = cmp::min((PCLK_HZ / 2 + rate - 1) / rate, MAX_DIVIDER + 1);
+        let divider = cmp::max(divider, 1);
+        self.registers().clock_divider.set(divider - 1);
+        self.get_rate()
+    }
+
+    fn get_rate(&self) -> u32 {
+        PCLK_HZ / (2 * (self.registers().clock_divider.get() + 1))
+    }
+
+    fn set_clock(&self, polarity: ClockPolarity) {
+        let regs = self.registers();
+        match polarity {
+            ClockPolarity::IdleLow => regs.control.set(regs.control.get() & !CONTROL_CPOL),
+            ClockPolarity::IdleHigh => regs.control.set(regs.control.get() | CONTROL_CPOL),
+        }
+    }
+
+    fn get_clock(&self) -> ClockPolarity {
+        if self.registers().control.get() & CONTROL_CPOL != 0 {
+            ClockPolarity::IdleHigh
+        } else {
+            ClockPolarity::IdleLow
+        }
+    }
+
+    fn set_phase(&self, phase: ClockPhase) {
+        let regs = self.registers();
+        match phase {
+            ClockPhase::SampleLeading => regs.control.set(regs.control.get() & !CONTROL_CPHA),
+            ClockPhase::SampleTrailing => regs.control.set(regs.control.get() | CONTROL_CPHA),
+        }
+    }
+
+    fn get_phase(&self) -> ClockPhase {
+        if self.registers().control.get() & CONTROL_CPHA != 0 {
+            ClockPhase::SampleTrailing
+        } else {
+            ClockPhase::SampleLeading
+        }
+    }
+
+    /// Keeps chip select asserted after the following transfers, e.g. to
+    /// send a command and read the response as one bus transaction.
+    fn hold_low(&self) {
+        self.hold_cs.set(true);
+    }
+
+    /// Deasserts chip select at the end of the next transfer, or now for
+    /// a GPIO chip select if no transfer is in progress.
+    fn release_low(&self) {
+        self.hold_cs.set(false);
+        if !self.busy.get() {
+            self.deassert_cs();
+        }
+    }
+}
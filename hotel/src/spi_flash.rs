@@ -0,0 +1,331 @@
+//! External SPI NOR flash
+//!
+//! Implements `hil::flash::Flash` for a JEDEC-compatible serial NOR flash on
+//! one of the SPI hosts, using only the basic command set every part
+//! supports (READ, PAGE PROGRAM, SECTOR ERASE, READ STATUS and WRITE
+//! ENABLE) rather than discovering the part through SFDP. A `hil::flash`
+//! page is one 4KB erase sector; `write_page` erases it and then programs
+//! it in 256-byte program pages. The driver owns the SPI host's client, so
+//! the bus can't be shared with other devices. A request that can't be
+//! started fails with the returned error and no callback; one that fails
+//! part way completes with `hil::flash::Error::FlashError`.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use kernel::hil::spi::SpiMaster;
+use spi::{ChipSelect, SpiHost};
+
+/// Bytes in a `hil::flash` page: one erase sector
+pub const PAGE_SIZE: usize = 4096;
+
+/// Bytes written by a single PAGE PROGRAM command
+const PROGRAM_SIZE: usize = 256;
+
+/// Opcode and 24-bit address
+const COMMAND_SIZE: usize = 4;
+
+/// Size of each of the buffers passed to `init`
+pub const BUFFER_SIZE: usize = COMMAND_SIZE + PROGRAM_SIZE;
+
+// JEDEC basic commands
+const CMD_READ: u8 = 0x03;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+
+/// Status register: write (program or erase) in progress
+const STATUS_WIP: u8 = 1 << 0;
+
+pub struct SpiFlashPage(pub [u8; PAGE_SIZE]);
+
+impl Default for SpiFlashPage {
+    fn default() -> SpiFlashPage {
+        SpiFlashPage([0; PAGE_SIZE])
+    }
+}
+
+impl AsMut<[u8]> for SpiFlashPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+/// The command currently on the bus
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Read,
+    WriteEnable,
+    Program,
+    Erase,
+    Status,
+}
+
+pub struct SpiFlash {
+    spi: &'static SpiHost,
+    chip_select: ChipSelect,
+    num_pages: usize,
+    client: Cell<Option<&'static hil::flash::Client<SpiFlash>>>,
+    operation: Cell<Operation>,
+    step: Cell<Step>,
+    page_number: Cell<usize>,
+    // Program page within the flash page being read or written
+    chunk: Cell<usize>,
+    // The sector being written has been erased
+    erased: Cell<bool>,
+    page: TakeCell<'static, SpiFlashPage>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+}
+
+impl SpiFlash {
+    /// A flash of `num_pages` 4KB sectors on `spi`.
+    pub const fn new(spi: &'static SpiHost, chip_select: ChipSelect, num_pages: usize) -> SpiFlash {
+        SpiFlash {
+            spi: spi,
+            chip_select: chip_select,
+            num_pages: num_pages,
+            client: Cell::new(None),
+            operation: Cell::new(Operation::Idle),
+            step: Cell::new(Step::Status),
+            page_number: Cell::new(0),
+            chunk: Cell::new(0),
+            erased: Cell::new(false),
+            page: TakeCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            rx_buffer: TakeCell::empty(),
+        }
+    }
+
+    /// Takes over the SPI host. Both buffers must be at least
+    /// `BUFFER_SIZE` bytes.
+    pub fn init(&'static self, tx_buffer: &'static mut [u8], rx_buffer: &'static mut [u8]) {
+        self.tx_buffer.replace(tx_buffer);
+        self.rx_buffer.replace(rx_buffer);
+        self.spi.set_client(self);
+        self.spi.init();
+        self.spi.specify_chip_select(self.chip_select);
+    }
+
+    /// Checks a request can be started and resets the progress through it.
+    fn start(&self, operation: Operation, page_number: usize) -> ReturnCode {
+        if self.operation.get() != Operation::Idle || self.tx_buffer.is_none() {
+            return ReturnCode::EBUSY;
+        }
+        if page_number >= self.num_pages {
+            return ReturnCode::EINVAL;
+        }
+        self.operation.set(operation);
+        self.page_number.set(page_number);
+        self.chunk.set(0);
+        self.erased.set(false);
+        ReturnCode::SUCCESS
+    }
+
+    /// Byte address of the current program page.
+    fn address(&self) -> usize {
+        self.page_number.get() * PAGE_SIZE + self.chunk.get() * PROGRAM_SIZE
+    }
+
+    /// Sends `len` bytes of the transmit buffer, after `fill` has set
+    /// them up.
+    fn command<F>(&self, step: Step, len: usize, fill: F) -> ReturnCode
+        where F: FnOnce(&mut [u8])
+    {
+        self.step.set(step);
+        match (self.tx_buffer.take(), self.rx_buffer.take()) {
+            (Some(tx), Some(rx)) => {
+                fill(tx);
+                match self.spi.transfer(tx, Some(rx), len) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((error, tx, rx)) => {
+                        self.tx_buffer.replace(tx);
+                        rx.map(|rx| self.rx_buffer.replace(rx));
+                        error
+                    }
+                }
+            }
+            (tx, rx) => {
+                tx.map(|tx| self.tx_buffer.replace(tx));
+                rx.map(|rx| self.rx_buffer.replace(rx));
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    /// Sends a command followed by the current address.
+    fn address_command(&self, step: Step, opcode: u8, len: usize) -> ReturnCode {
+        let address = self.address();
+        self.command(step, len, |tx| {
+            tx[0] = opcode;
+            tx[1] = (address >> 16) as u8;
+            tx[2] = (address >> 8) as u8;
+            tx[3] = address as u8;
+        });
+    }
+
+    fn read_chunk(&self) -> ReturnCode {
+        self.address_command(Step::Read, CMD_READ, BUFFER_SIZE)
+    }
+
+    fn write_enable(&self) -> ReturnCode {
+        self.command(Step::WriteEnable, 1, |tx| tx[0] = CMD_WRITE_ENABLE)
+    }
+
+    fn program_chunk(&self) -> ReturnCode {
+        let offset = self.chunk.get() * PROGRAM_SIZE;
+        self.page.map(|page| {
+            self.tx_buffer.map(|tx| {
+                tx[COMMAND_SIZE..BUFFER_SIZE].copy_from_slice(&page.0[offset..offset + PROGRAM_SIZE]);
+            });
+        });
+        self.address_command(Step::Program, CMD_PAGE_PROGRAM, BUFFER_SIZE)
+    }
+
+    fn erase_sector(&self) -> ReturnCode {
+        self.address_command(Step::Erase, CMD_SECTOR_ERASE, COMMAND_SIZE)
+    }
+
+    fn read_status(&self) -> ReturnCode {
+        self.command(Step::Status, 2, |tx| tx[0] = CMD_READ_STATUS)
+    }
+
+    /// Ends the current request and reports it to the client.
+    fn complete(&self, error: hil::flash::Error) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::Idle);
+        self.client.get().map(|client| match operation {
+            Operation::Read => {
+                self.page.take().map(|page| client.read_complete(page, error));
+            }
+            Operation::Write => {
+                self.page.take().map(|page| client.write_complete(page, error));
+            }
+            Operation::Erase => client.erase_complete(error),
+            Operation::Idle => {}
+        });
+    }
+
+    /// Moves on to the next program page, or completes the request.
+    fn next_chunk(&self) -> ReturnCode {
+        let chunk = self.chunk.get() + 1;
+        if chunk == PAGE_SIZE / PROGRAM_SIZE {
+            self.complete(hil::flash::Error::CommandComplete);
+            return ReturnCode::SUCCESS;
+        }
+        self.chunk.set(chunk);
+        match self.operation.get() {
+            Operation::Read => self.read_chunk(),
+            _ => self.write_enable(),
+        }
+    }
+
+    /// Ends a request whose first command couldn't be sent.
+    fn abandon(&self, result: ReturnCode) -> ReturnCode {
+        if result != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        result
+    }
+}
+
+impl hil::spi::SpiMasterClient for SpiFlash {
+    fn read_write_done(&self,
+                       write_buffer: &'static mut [u8],
+                       read_buffer: Option<&'static mut [u8]>,
+                       _len: usize) {
+        self.tx_buffer.replace(write_buffer);
+        read_buffer.map(|buffer| self.rx_buffer.replace(buffer));
+
+        let result = match self.step.get() {
+            Step::Read => {
+                let offset = self.chunk.get() * PROGRAM_SIZE;
+                self.rx_buffer.map(|rx| {
+                    self.page.map(|page| {
+                        page.0[offset..offset + PROGRAM_SIZE].copy_from_slice(&rx[COMMAND_SIZE..BUFFER_SIZE]);
+                    });
+                });
+                self.next_chunk()
+            }
+            Step::WriteEnable => {
+                if self.erased.get() {
+                    self.program_chunk()
+                } else {
+                    self.erase_sector()
+                }
+            }
+            Step::Program | Step::Erase => self.read_status(),
+            Step::Status => {
+                let busy = self.rx_buffer.map_or(false, |rx| rx[1] & STATUS_WIP != 0);
+                if busy {
+                    self.read_status()
+                } else if self.erased.get() {
+                    self.next_chunk()
+                } else if self.operation.get() == Operation::Write {
+                    // Sector erased: program it from the start
+                    self.erased.set(true);
+                    self.write_enable()
+                } else {
+                    self.complete(hil::flash::Error::CommandComplete);
+                    ReturnCode::SUCCESS
+                }
+            }
+        };
+        if result != ReturnCode::SUCCESS {
+            self.complete(hil::flash::Error::FlashError);
+        }
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for SpiFlash {
+    fn set_client(&'static self, client: &'static C) {
+        self.client.set(Some(client));
+    }
+}
+
+impl hil::flash::Flash for SpiFlash {
+    type Page = SpiFlashPage;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        let result = self.start(Operation::Read, page_number);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.abandon(self.read_chunk());
+        if result == ReturnCode::SUCCESS {
+            self.page.replace(buf);
+        }
+        result
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        let result = self.start(Operation::Write, page_number);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.abandon(self.write_enable());
+        if result == ReturnCode::SUCCESS {
+            self.page.replace(buf);
+        }
+        result
+    }
+
+    /// Erases the 4KB sector `page_number`.
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let result = self.start(Operation::Erase, page_number);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.abandon(self.write_enable())
+    }
+}
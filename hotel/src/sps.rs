@@ -0,0 +1,291 @@
+//! SPI slave controller (SPS)
+//!
+//! Lets an application processor talk to the chip over SPI when USB isn't
+//! routed. The host drives the clock; everything happens in frames, one
+//! per assertion of chip select. While selected, the bytes the host shifts
+//! in are drained from the RX FIFO into a frame buffer, and the bytes
+//! shifted out are taken from the reply queued with `set_reply` (or the
+//! idle byte once it runs out). When the host deasserts chip select the
+//! client gets the whole frame through `SpsClient::frame_received`.
+//!
+//! A reply is sent in the next frame only, so a typical exchange is a
+//! request frame, during which the client queues the response, followed by
+//! a frame in which the host clocks it out.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::cells::{TakeCell, VolatileCell};
+use kernel::hil::spi::{ClockPhase, ClockPolarity};
+use pmu::{Clock, PeripheralClock, PeripheralClock0};
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Enable                                               |
+    /// | 1    | Clock polarity: idle high if set                     |
+    /// | 2    | Clock phase: sample on trailing edge if set          |
+    /// | 3    | Reset RX FIFO (self-clearing)                        |
+    /// | 4    | Reset TX FIFO (self-clearing)                        |
+    control: VolatileCell<u32>,
+
+    /// Bit 0 is set while chip select is asserted
+    status: VolatileCell<u32>,
+
+    /// Interrupt enables, with the same layout as `interrupt_state`
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Chip select asserted                                 |
+    /// | 1    | Chip select deasserted                               |
+    /// | 2    | RX FIFO holds at least `rx_threshold` bytes          |
+    /// | 3    | RX FIFO overflowed                                   |
+    /// | 4    | TX FIFO empty                                        |
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Pending interrupts; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+
+    rx_threshold: VolatileCell<u32>,
+
+    /// Reading pops a byte from the RX FIFO
+    rx_data: VolatileCell<u32>,
+    /// Bytes in the RX FIFO
+    rx_level: VolatileCell<u32>,
+
+    /// Writing pushes a byte into the TX FIFO
+    tx_data: VolatileCell<u32>,
+    /// Bytes in the TX FIFO
+    tx_level: VolatileCell<u32>,
+
+    /// Sent while the TX FIFO is empty
+    tx_idle_byte: VolatileCell<u32>,
+}
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_CPOL: u32 = 1 << 1;
+const CONTROL_CPHA: u32 = 1 << 2;
+const CONTROL_RESET_RX: u32 = 1 << 3;
+const CONTROL_RESET_TX: u32 = 1 << 4;
+
+const INTERRUPT_CS_ASSERT: u32 = 1 << 0;
+const INTERRUPT_CS_DEASSERT: u32 = 1 << 1;
+const INTERRUPT_RX_LEVEL: u32 = 1 << 2;
+const INTERRUPT_RX_OVERFLOW: u32 = 1 << 3;
+const INTERRUPT_TX_EMPTY: u32 = 1 << 4;
+const INTERRUPT_ALL: u32 = 0x1f;
+
+/// Depth of each hardware FIFO
+const FIFO_SIZE: u32 = 32;
+
+/// Sent after the reply (or when there is none)
+const IDLE_BYTE: u8 = 0xff;
+
+const SPS0_BASE: *const Registers = 0x40520000 as *const Registers;
+
+pub static mut SPS0: Sps = unsafe { Sps::new(SPS0_BASE) };
+
+pub trait SpsClient {
+    /// The host ended a frame after sending `frame`. `lost` is set if
+    /// bytes were dropped because the RX FIFO overflowed or the frame did
+    /// not fit in the frame buffer. The data is only valid for the
+    /// duration of the call.
+    fn frame_received(&self, frame: &[u8], lost: bool);
+
+    /// The reply passed to `set_reply` was clocked out (or at least the
+    /// frame it was queued for ended) and its buffer is returned.
+    fn reply_sent(&self, reply: &'static mut [u8]);
+}
+
+pub struct Sps {
+    regs: *const Registers,
+    clock: Clock,
+    client: Cell<Option<&'static SpsClient>>,
+    frame: TakeCell<'static, [u8]>,
+    frame_len: Cell<usize>,
+    lost: Cell<bool>,
+    reply: TakeCell<'static, [u8]>,
+    reply_len: Cell<usize>,
+    reply_cursor: Cell<usize>,
+    // The reply started going out in the current frame
+    replying: Cell<bool>,
+}
+
+impl Sps {
+    const unsafe fn new(regs: *const Registers) -> Sps {
+        Sps {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank0(PeripheralClock0::Sps0)),
+            client: Cell::new(None),
+            frame: TakeCell::empty(),
+            frame_len: Cell::new(0),
+            lost: Cell::new(false),
+            reply: TakeCell::empty(),
+            reply_len: Cell::new(0),
+            reply_cursor: Cell::new(0),
+            replying: Cell::new(false),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    pub fn set_client(&self, client: &'static SpsClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Starts answering the host, collecting frames of up to
+    /// `frame_buffer.len()` bytes.
+    pub fn init(&self, frame_buffer: &'static mut [u8]) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE == 0 {
+            self.clock.acquire();
+        }
+        self.frame.replace(frame_buffer);
+        self.frame_len.set(0);
+        regs.tx_idle_byte.set(IDLE_BYTE as u32);
+        regs.rx_threshold.set(FIFO_SIZE / 2);
+        regs.control.set(regs.control.get() | CONTROL_ENABLE | CONTROL_RESET_RX | CONTROL_RESET_TX);
+        regs.interrupt_state.set(INTERRUPT_ALL);
+        regs.interrupt_enable.set(INTERRUPT_CS_ASSERT | INTERRUPT_CS_DEASSERT |
+                                  INTERRUPT_RX_LEVEL | INTERRUPT_RX_OVERFLOW);
+    }
+
+    pub fn disable(&self) {
+        let regs = self.registers();
+        if regs.control.get() & CONTROL_ENABLE != 0 {
+            regs.interrupt_enable.set(0);
+            regs.control.set(regs.control.get() & !CONTROL_ENABLE);
+            self.clock.release();
+        }
+    }
+
+    /// Queues the first `len` bytes of `reply` to be sent in the next
+    /// frame. Returns EBUSY if a reply is already queued.
+    pub fn set_reply(&self, reply: &'static mut [u8], len: usize) -> ReturnCode {
+        if self.reply.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.reply_len.set(if len < reply.len() { len } else { reply.len() });
+        self.reply_cursor.set(0);
+        self.replying.set(false);
+        self.reply.replace(reply);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn set_clock(&self, polarity: ClockPolarity) {
+        let regs = self.registers();
+        match polarity {
+            ClockPolarity::IdleLow => regs.control.set(regs.control.get() & !CONTROL_CPOL),
+            ClockPolarity::IdleHigh => regs.control.set(regs.control.get() | CONTROL_CPOL),
+        }
+    }
+
+    pub fn set_phase(&self, phase: ClockPhase) {
+        let regs = self.registers();
+        match phase {
+            ClockPhase::SampleLeading => regs.control.set(regs.control.get() & !CONTROL_CPHA),
+            ClockPhase::SampleTrailing => regs.control.set(regs.control.get() | CONTROL_CPHA),
+        }
+    }
+
+    /// Moves bytes from the RX FIFO into the frame buffer.
+    fn drain_rx(&self) {
+        let regs = self.registers();
+        while regs.rx_level.get() > 0 {
+            let b = regs.rx_data.get() as u8;
+            let len = self.frame_len.get();
+            let stored = self.frame.map_or(false, |frame| {
+                if len < frame.len() {
+                    frame[len] = b;
+                    true
+                } else {
+                    false
+                }
+            });
+            if stored {
+                self.frame_len.set(len + 1);
+            } else {
+                self.lost.set(true);
+            }
+        }
+    }
+
+    /// Tops up the TX FIFO from the reply, and stops the TX empty
+    /// interrupt once it has all been queued.
+    fn fill_tx(&self) {
+        let regs = self.registers();
+        let cursor = self.reply_cursor.get();
+        let len = self.reply_len.get();
+        let mut sent = cursor;
+        self.reply.map(|reply| {
+            while sent < len && regs.tx_level.get() < FIFO_SIZE {
+                regs.tx_data.set(reply[sent] as u32);
+                sent += 1;
+            }
+        });
+        self.reply_cursor.set(sent);
+        if sent < len {
+            regs.interrupt_enable.set(regs.interrupt_enable.get() | INTERRUPT_TX_EMPTY);
+        } else {
+            regs.interrupt_enable.set(regs.interrupt_enable.get() & !INTERRUPT_TX_EMPTY);
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        let state = regs.interrupt_state.get() & regs.interrupt_enable.get();
+        regs.interrupt_state.set(state);
+
+        if state & INTERRUPT_CS_ASSERT != 0 {
+            self.frame_len.set(0);
+            self.lost.set(false);
+            if self.reply.is_some() {
+                self.replying.set(true);
+                self.fill_tx();
+            }
+        }
+        if state & INTERRUPT_RX_OVERFLOW != 0 {
+            self.lost.set(true);
+        }
+        if state & (INTERRUPT_RX_LEVEL | INTERRUPT_CS_DEASSERT) != 0 {
+            self.drain_rx();
+        }
+        if state & INTERRUPT_TX_EMPTY != 0 {
+            self.fill_tx();
+        }
+        if state & INTERRUPT_CS_DEASSERT != 0 {
+            self.end_frame();
+        }
+    }
+
+    /// Returns the reply sent in the frame and hands the frame to the
+    /// client.
+    fn end_frame(&self) {
+        let regs = self.registers();
+        // Whatever of the reply the host didn't clock out is discarded
+        regs.control.set(regs.control.get() | CONTROL_RESET_TX);
+        regs.interrupt_enable.set(regs.interrupt_enable.get() & !INTERRUPT_TX_EMPTY);
+
+        // Return the reply first so the client can queue the next one
+        // when it sees the frame.
+        if self.replying.get() {
+            self.replying.set(false);
+            self.reply.take().map(|reply| {
+                self.client.get().map(move |client| client.reply_sent(reply));
+            });
+        }
+
+        let len = self.frame_len.get();
+        let lost = self.lost.get();
+        self.frame_len.set(0);
+        self.frame.map(|frame| {
+            self.client.get().map(|client| client.frame_received(&frame[..len], lost));
+        });
+    }
+}
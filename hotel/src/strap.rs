@@ -0,0 +1,106 @@
+//! Boot straps
+//!
+//! Boards can let jumpers or resistors on spare pads select behavior at
+//! boot, such as entering recovery or leaving the shell off, so one
+//! firmware image serves every configuration. `sample` reads the straps
+//! a board lists, and must be called first thing in board init: the pads'
+//! levels have to be read before `pinmux::reset` and the board's own
+//! routing take them over. The result is kept for `straps`, so drivers
+//! and capsules can check it later.
+//!
+//! Each strap is pulled one way in the chip and is set when a jumper pulls
+//! it the other way:
+//!
+//! ```ignore
+//! use hotel::pinmux::{Pull, SelectablePin};
+//! use hotel::strap::{self, Strap};
+//! const STRAPS: [Strap; 1] = [Strap { pad: SelectablePin::Dioa12, pull: Pull::Up }];
+//! if strap::sample(&STRAPS).is_set(0) {
+//!     // Enter recovery
+//! }
+//! ```
+
+use gpio;
+use kernel::hil::gpio::Pin;
+use pinmux::{self, Input, PadConfig, Pull, SelectablePin};
+use pmu::{Clock, PeripheralClock, PeripheralClock0};
+
+/// The GPIO input the pads are read through while sampling
+const SAMPLE_PIN: usize = 15;
+
+/// Reads of a pad before its level is taken, to give the pull time to
+/// charge the line.
+const SETTLE_READS: usize = 100;
+
+/// Straps that can be sampled at once
+pub const MAX_STRAPS: usize = 32;
+
+/// A pad read as a strap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Strap {
+    pub pad: SelectablePin,
+    /// The pad's pull while it is sampled. The strap is set if the pad
+    /// reads the opposite level; with no pull, if it reads high.
+    pub pull: Pull,
+}
+
+/// The sampled straps, bit n for the board's strap n
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Straps(u32);
+
+impl Straps {
+    pub fn is_set(&self, index: usize) -> bool {
+        index < MAX_STRAPS && self.0 & 1 << index != 0
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+static mut STRAPS: Straps = Straps(0);
+
+/// Samples `straps` (at most `MAX_STRAPS`) and records the result. Each
+/// pad is pulled as its strap asks and read through a GPIO input, and its
+/// electrical configuration is put back afterwards. The input routing is
+/// left for `pinmux::reset` to clear.
+pub fn sample(straps: &[Strap]) -> Straps {
+    let clock = unsafe { Clock::new(PeripheralClock::Bank0(PeripheralClock0::Gpio0)) };
+    clock.acquire();
+    let pin = unsafe { &gpio::PORT0.pins[SAMPLE_PIN] };
+
+    let mut bits = 0;
+    for (index, strap) in straps.iter().take(MAX_STRAPS).enumerate() {
+        let saved = pinmux::pad_config(strap.pad);
+        pinmux::configure_pad(strap.pad, PadConfig {
+            input: true,
+            pull: strap.pull,
+            ..saved
+        });
+        pinmux::connect_input(Input::Gpio0(SAMPLE_PIN), strap.pad);
+
+        let mut high = false;
+        for _ in 0..SETTLE_READS {
+            high = pin.read();
+        }
+        let set = match strap.pull {
+            Pull::Up => !high,
+            Pull::Down | Pull::None => high,
+        };
+        if set {
+            bits |= 1 << index;
+        }
+        pinmux::configure_pad(strap.pad, saved);
+    }
+
+    clock.release();
+    unsafe {
+        STRAPS = Straps(bits);
+        STRAPS
+    }
+}
+
+/// The straps read by `sample`, all clear if it hasn't been called.
+pub fn straps() -> Straps {
+    unsafe { STRAPS }
+}
@@ -0,0 +1,152 @@
+//! On-die temperature sensor
+//!
+//! The sensor's ADC converts on request and raises an interrupt with a raw
+//! reading, which is turned into hundredths of a degree Celsius using the
+//! per-chip calibration fused at test: the raw reading at 25°C and the
+//! slope. Unfused parts fall back to nominal values, which are only good
+//! to a few degrees.
+//!
+//! The sensor's clock (`PeripheralClock0::Temp0`) is not gateable, so the
+//! driver doesn't acquire it.
+
+use core::cell::Cell;
+use fuse;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Power up the sensor and ADC                          |
+    /// | 1    | Start a conversion (self-clearing)                   |
+    /// | 2-4  | log2 of the number of samples averaged               |
+    control: VolatileCell<u32>,
+
+    /// Bit 0 is set while a conversion is in progress
+    status: VolatileCell<u32>,
+
+    /// Raw result of the last conversion, 12 bits
+    data: VolatileCell<u32>,
+
+    /// Bit 0 enables the conversion-done interrupt
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Bit 0 is set when a conversion completes; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+}
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_START: u32 = 1 << 1;
+const CONTROL_AVERAGE_SHIFT: u32 = 2;
+
+/// Average 8 samples per conversion
+const AVERAGE_LOG2: u32 = 3;
+
+const RAW_MASK: u32 = 0xfff;
+
+/// Calibration used when the fuses are blank
+const NOMINAL_RAW_25C: i32 = 0x800;
+const NOMINAL_SLOPE: i32 = 0x1b0;
+
+const TEMP0_BASE: *const Registers = 0x40690000 as *const Registers;
+
+pub static mut TEMP0: TemperatureSensor = unsafe { TemperatureSensor::new(TEMP0_BASE) };
+
+pub struct TemperatureSensor {
+    regs: *const Registers,
+    client: Cell<Option<&'static hil::sensors::TemperatureClient>>,
+    busy: Cell<bool>,
+}
+
+impl TemperatureSensor {
+    const unsafe fn new(regs: *const Registers) -> TemperatureSensor {
+        TemperatureSensor {
+            regs: regs,
+            client: Cell::new(None),
+            busy: Cell::new(false),
+        }
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
+    /// The fused raw reading at 25°C and slope, or nominal values if the
+    /// calibration fuse is blank.
+    fn calibration(&self) -> (i32, i32) {
+        match fuse::temperature_calibration() {
+            Some(calibration) => (calibration.raw_25c as i32, calibration.slope as i32),
+            None => (NOMINAL_RAW_25C, NOMINAL_SLOPE),
+        }
+    }
+
+    /// Converts a raw reading to hundredths of a degree Celsius.
+    pub fn centidegrees(&self, raw: u32) -> i32 {
+        let (raw_25c, slope) = self.calibration();
+        2500 + ((raw & RAW_MASK) as i32 - raw_25c) * slope / 256
+    }
+
+    /// Takes a reading synchronously, in hundredths of a degree Celsius.
+    /// Returns None if an asynchronous reading is in progress.
+    pub fn read_sync(&self) -> Option<i32> {
+        if self.busy.get() {
+            return None;
+        }
+        let regs = self.registers();
+        let enable_irq = regs.interrupt_enable.get();
+        regs.interrupt_enable.set(0);
+        self.start();
+        while regs.status.get() & 1 != 0 {}
+        regs.interrupt_state.set(1);
+        regs.interrupt_enable.set(enable_irq);
+        regs.control.set(0);
+        Some(self.centidegrees(regs.data.get()))
+    }
+
+    fn start(&self) {
+        let regs = self.registers();
+        let control = CONTROL_ENABLE | AVERAGE_LOG2 << CONTROL_AVERAGE_SHIFT;
+        regs.control.set(control);
+        regs.control.set(control | CONTROL_START);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        regs.interrupt_state.set(1);
+        regs.interrupt_enable.set(0);
+        if !self.busy.get() {
+            return;
+        }
+        self.busy.set(false);
+        // Power the sensor down between readings
+        regs.control.set(0);
+        let value = self.centidegrees(regs.data.get());
+        self.client.get().map(|client| client.callback(value as usize, 0, 0));
+    }
+}
+
+impl hil::sensors::TemperatureDriver for TemperatureSensor {
+    fn set_client(&self, client: &'static hil::sensors::TemperatureClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Starts a reading; the client gets the temperature in hundredths of
+    /// a degree Celsius (an `i32` cast to `usize`).
+    fn read_temperature(&self) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.busy.set(true);
+        let regs = self.registers();
+        regs.interrupt_state.set(1);
+        regs.interrupt_enable.set(1);
+        self.start();
+        ReturnCode::SUCCESS
+    }
+}
@@ -0,0 +1,158 @@
+//! Test AES hardware
+//!
+//! Runs the FIPS-197 appendix C known-answer vectors through the AES
+//! engine in ECB mode, one block each, encrypting and decrypting.
+
+use core::cell::Cell;
+use crypto::aes::AesEngine;
+use hil::aes::{AesClient, KeySize};
+use test_registry::{SelfTest, SelfTestClient};
+
+struct Vector {
+    key_size: KeySize,
+    key: &'static [u8],
+    encrypt: bool,
+    input: [u8; 16],
+    output: [u8; 16],
+}
+
+const KEY_128: [u8; 16] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+];
+const KEY_256: [u8; 32] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+    0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+];
+
+const PLAINTEXT: [u8; 16] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+    0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+];
+
+// FIPS-197 C.1 and C.3
+const CIPHERTEXT_128: [u8; 16] = [
+    0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30,
+    0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5, 0x5a,
+];
+const CIPHERTEXT_256: [u8; 16] = [
+    0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf,
+    0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49, 0x60, 0x89,
+];
+
+static VECTORS: [Vector; 4] = [
+    Vector {
+        key_size: KeySize::KeySize128,
+        key: &KEY_128,
+        encrypt: true,
+        input: PLAINTEXT,
+        output: CIPHERTEXT_128,
+    },
+    Vector {
+        key_size: KeySize::KeySize128,
+        key: &KEY_128,
+        encrypt: false,
+        input: CIPHERTEXT_128,
+        output: PLAINTEXT,
+    },
+    Vector {
+        key_size: KeySize::KeySize256,
+        key: &KEY_256,
+        encrypt: true,
+        input: PLAINTEXT,
+        output: CIPHERTEXT_256,
+    },
+    Vector {
+        key_size: KeySize::KeySize256,
+        key: &KEY_256,
+        encrypt: false,
+        input: CIPHERTEXT_256,
+        output: PLAINTEXT,
+    },
+];
+
+pub struct TestAes<'a> {
+    aes: &'a AesEngine,
+    // The vector being run
+    index: Cell<usize>,
+    failed: Cell<bool>,
+    client: Cell<Option<&'static SelfTestClient>>,
+}
+
+impl<'a> TestAes<'a> {
+    pub fn new(aes: &'a AesEngine) -> Self {
+        TestAes {
+            aes: aes,
+            index: Cell::new(0),
+            failed: Cell::new(false),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        self.failed.set(false);
+        self.start_vector(0);
+    }
+
+    fn start_vector(&self, index: usize) {
+        self.index.set(index);
+        let vector = &VECTORS[index];
+        let mut key = [0; 8];
+        for (word, bytes) in key.iter_mut().zip(vector.key.chunks(4)) {
+            *word = bytes.iter()
+                .enumerate()
+                .fold(0, |accm, (i, byte)| accm | (*byte as u32) << (i * 8));
+        }
+        self.aes.set_encrypt_mode(vector.encrypt);
+        self.aes.setup(vector.key_size, &key);
+    }
+
+    fn check_vector(&self) {
+        let index = self.index.get();
+        let vector = &VECTORS[index];
+        let mut output = [0; 16];
+        let len = self.aes.read_data(&mut output);
+        if len == output.len() && output == vector.output {
+            println!("AES pass: vector {}\r", index);
+        } else {
+            println!("AES fail: vector {} gave {:x?}\r", index, &output[..len]);
+            self.failed.set(true);
+        }
+        // Wipe the key before the next vector.
+        self.aes.finish();
+    }
+}
+
+impl SelfTest for TestAes<'static> {
+    fn name(&self) -> &'static str {
+        "aes"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        self.client.set(Some(client));
+        self.aes.set_client(self);
+        self.run();
+    }
+}
+
+impl<'a> AesClient for TestAes<'a> {
+    fn done_key_expansion(&self) {
+        self.aes.crypt(&VECTORS[self.index.get()].input);
+    }
+
+    fn done_cipher(&self) {
+        self.check_vector();
+    }
+
+    fn done_wipe_secrets(&self) {
+        let next = self.index.get() + 1;
+        if next < VECTORS.len() {
+            self.start_vector(next);
+        } else {
+            let passed = !self.failed.get();
+            self.client.take().map(|client| client.test_complete(passed));
+        }
+    }
+}
@@ -3,6 +3,7 @@
 use core::cell::Cell;
 use crypto::dcrypto::{Dcrypto, DcryptoClient, DcryptoEngine, ProgramFault};
 use kernel::ReturnCode;
+use test_registry::{SelfTest, SelfTestClient};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum TestCase {
@@ -14,6 +15,8 @@ enum TestCase {
 pub struct TestDcrypto<'a> {
     dcrypto: &'a DcryptoEngine<'a>,
     case: Cell<TestCase>,
+    failed: Cell<bool>,
+    client: Cell<Option<&'static SelfTestClient>>,
 }
 
 impl<'a> TestDcrypto<'a> {
@@ -21,10 +24,13 @@ impl<'a> TestDcrypto<'a> {
         TestDcrypto {
             dcrypto: d,
             case: Cell::new(TestCase::None),
+            failed: Cell::new(false),
+            client: Cell::new(None),
         }
     }
 
     pub fn run(&self) {
+        self.failed.set(false);
         self.start_test_exec();
     }
 
@@ -43,6 +49,7 @@ impl<'a> TestDcrypto<'a> {
             println!("DCRYPTO pass: Program completed with ReturnCode {:?}.", error);
         } else {
             println!("DCRYPTO fail: Program completed with fault {:?}.", fault);
+            self.failed.set(true);
         }
     }
 
@@ -74,10 +81,24 @@ impl<'a> TestDcrypto<'a> {
         }
         else {
             println!("DCRYPTO fail: program completed with ReturnCode {:?} and fault {:?}.", error, fault);
+            self.failed.set(true);
+            self.case.set(TestCase::None);
         }
     }
 }
 
+impl<'a> SelfTest for TestDcrypto<'a> {
+    fn name(&self) -> &'static str {
+        "dcrypto"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        self.client.set(Some(client));
+        self.dcrypto.set_client(self);
+        self.run();
+    }
+}
+
 impl<'a> DcryptoClient<'a> for TestDcrypto<'a> {
     fn execution_complete(&self, error: ReturnCode, fault: ProgramFault) {
         match self.case.get() {
@@ -93,7 +114,11 @@ impl<'a> DcryptoClient<'a> for TestDcrypto<'a> {
             }
         }
         if self.case.get() == TestCase::None {
-            println!("DCRYPTO all tests passed!");
+            let passed = !self.failed.get();
+            if passed {
+                println!("DCRYPTO all tests passed!");
+            }
+            self.client.take().map(|client| client.test_complete(passed));
         }
     }
 
@@ -0,0 +1,203 @@
+//! Test a `hil::flash` driver
+//!
+//! Erases a scratch page, then writes and reads back a sequence of patterns,
+//! timing every operation and failing any that takes longer than its
+//! limit. One of the writes leaves the second half of the page erased, as
+//! a write cut short by a reset does; the next write has to recover the
+//! page from that state. A request made while a write is in flight must
+//! be refused with EBUSY.
+//!
+//! The scratch page is overwritten, so pick one nothing else uses.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use kernel::hil::flash::{Client, Error, Flash};
+use test_registry::{SelfTest, SelfTestClient};
+use timestamp::TIMESTAMP;
+
+/// Longest an erase, write (which includes an erase) and read may take
+const ERASE_LIMIT_US: u64 = 400_000;
+const WRITE_LIMIT_US: u64 = 1_000_000;
+const READ_LIMIT_US: u64 = 100_000;
+
+#[derive(Clone, Copy, Debug)]
+enum Pattern {
+    Erased,
+    Counting,
+    // `Inverted` up to the middle of the page, erased after it
+    Interrupted,
+    Inverted,
+}
+
+impl Pattern {
+    fn byte(&self, index: usize, len: usize) -> u8 {
+        match *self {
+            Pattern::Erased => 0xff,
+            Pattern::Counting => index as u8,
+            Pattern::Interrupted if index >= len / 2 => 0xff,
+            Pattern::Interrupted | Pattern::Inverted => !(index as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    Erase,
+    Write(Pattern),
+    Verify(Pattern),
+}
+
+static OPERATIONS: [Operation; 8] = [
+    Operation::Erase,
+    Operation::Verify(Pattern::Erased),
+    Operation::Write(Pattern::Counting),
+    Operation::Verify(Pattern::Counting),
+    Operation::Write(Pattern::Interrupted),
+    Operation::Verify(Pattern::Interrupted),
+    Operation::Write(Pattern::Inverted),
+    Operation::Verify(Pattern::Inverted),
+];
+
+pub struct TestFlash<'a, F: Flash + 'static> {
+    flash: &'a F,
+    page_number: usize,
+    page: TakeCell<'static, F::Page>,
+    // Index into `OPERATIONS` of the operation in flight
+    index: Cell<usize>,
+    started_us: Cell<u64>,
+    failed: Cell<bool>,
+    client: Cell<Option<&'static SelfTestClient>>,
+}
+
+impl<'a, F: Flash + 'static> TestFlash<'a, F> {
+    pub fn new(flash: &'a F, page_number: usize, page: &'static mut F::Page) -> TestFlash<'a, F> {
+        TestFlash {
+            flash: flash,
+            page_number: page_number,
+            page: TakeCell::new(page),
+            index: Cell::new(0),
+            started_us: Cell::new(0),
+            failed: Cell::new(false),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        self.failed.set(false);
+        self.start_operation(0);
+    }
+
+    fn start_operation(&self, index: usize) {
+        self.index.set(index);
+        self.started_us.set(unsafe { TIMESTAMP.now() });
+        let operation = OPERATIONS[index];
+        let result = match operation {
+            Operation::Erase => self.flash.erase_page(self.page_number),
+            Operation::Write(pattern) => {
+                self.page.take().map_or(ReturnCode::ENOMEM, |page| {
+                    {
+                        let bytes = page.as_mut();
+                        let len = bytes.len();
+                        for (index, byte) in bytes.iter_mut().enumerate() {
+                            *byte = pattern.byte(index, len);
+                        }
+                    }
+                    self.flash.write_page(self.page_number, page)
+                })
+            }
+            Operation::Verify(_) => {
+                self.page.take().map_or(ReturnCode::ENOMEM, |page| {
+                    self.flash.read_page(self.page_number, page)
+                })
+            }
+        };
+        if result != ReturnCode::SUCCESS {
+            println!("Flash fail: {:?} returned {:?}\r", operation, result);
+            self.finish(false);
+            return;
+        }
+        if let Operation::Write(Pattern::Inverted) = operation {
+            let busy = self.flash.erase_page(self.page_number);
+            if busy != ReturnCode::EBUSY {
+                println!("Flash fail: erase during a write returned {:?}\r", busy);
+                self.failed.set(true);
+            }
+        }
+    }
+
+    fn operation_complete(&self, error: Error, contents_match: bool) {
+        let operation = OPERATIONS[self.index.get()];
+        let elapsed_us = unsafe { TIMESTAMP.now() } - self.started_us.get();
+        let limit_us = match operation {
+            Operation::Erase => ERASE_LIMIT_US,
+            Operation::Write(_) => WRITE_LIMIT_US,
+            Operation::Verify(_) => READ_LIMIT_US,
+        };
+        let completed = match error {
+            Error::CommandComplete => true,
+            _ => false,
+        };
+        if !completed {
+            println!("Flash fail: {:?} failed after {}us\r", operation, elapsed_us);
+            self.finish(false);
+            return;
+        }
+        if !contents_match {
+            println!("Flash fail: {:?} read back the wrong data\r", operation);
+            self.failed.set(true);
+        } else if elapsed_us > limit_us {
+            println!("Flash fail: {:?} took {}us, limit {}us\r", operation, elapsed_us, limit_us);
+            self.failed.set(true);
+        } else {
+            println!("Flash pass: {:?} took {}us\r", operation, elapsed_us);
+        }
+
+        let next = self.index.get() + 1;
+        if next < OPERATIONS.len() {
+            self.start_operation(next);
+        } else {
+            let passed = !self.failed.get();
+            self.finish(passed);
+        }
+    }
+
+    fn finish(&self, passed: bool) {
+        self.client.take().map(|client| client.test_complete(passed));
+    }
+}
+
+impl<'a, F: Flash + 'static> SelfTest for TestFlash<'a, F> {
+    fn name(&self) -> &'static str {
+        "flash"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        self.client.set(Some(client));
+        self.run();
+    }
+}
+
+impl<'a, F: Flash + 'static> Client<F> for TestFlash<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: Error) {
+        let contents_match = match OPERATIONS[self.index.get()] {
+            Operation::Verify(pattern) => {
+                let bytes = page.as_mut();
+                let len = bytes.len();
+                bytes.iter().enumerate().all(|(index, &byte)| byte == pattern.byte(index, len))
+            }
+            _ => false,
+        };
+        self.page.replace(page);
+        self.operation_complete(error, contents_match);
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: Error) {
+        self.page.replace(page);
+        self.operation_complete(error, true);
+    }
+
+    fn erase_complete(&self, error: Error) {
+        self.operation_complete(error, true);
+    }
+}
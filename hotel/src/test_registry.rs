@@ -0,0 +1,210 @@
+//! Registry of hardware self-tests
+//!
+//! Each driver's self-test implements `SelfTest`, and the board registers
+//! the ones it wants with `TESTS`. `TESTS.run()` runs them one at a time,
+//! starting each when the previous one reports its result, prints a line
+//! per test and ends with a summary; `TESTS.run_test` runs just one. A run
+//! can be started at boot or at any later point (see `test_runner`), as
+//! long as the previous one has finished.
+//!
+//! Tests generally take over their driver's client when they start, so the
+//! board should hand the driver back to its real client from
+//! `RegistryClient::tests_complete`.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+
+/// Most tests that can be registered
+pub const MAX_TESTS: usize = 16;
+
+pub trait SelfTest {
+    /// Short name for the test in reports.
+    fn name(&self) -> &'static str;
+
+    /// Starts the test. It reports its result with `client.test_complete`,
+    /// which may be called before `start` returns.
+    fn start(&'static self, client: &'static SelfTestClient);
+}
+
+pub trait SelfTestClient {
+    fn test_complete(&self, passed: bool);
+}
+
+/// Where a test is as of the last run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestStatus {
+    NotRun = 0,
+    Running = 1,
+    Passed = 2,
+    Failed = 3,
+}
+
+pub trait RegistryClient {
+    /// All registered tests have run.
+    fn tests_complete(&self, passed: usize, failed: usize);
+}
+
+pub static mut TESTS: TestRegistry = TestRegistry::new();
+
+pub struct TestRegistry {
+    tests: Cell<[Option<&'static SelfTest>; MAX_TESTS]>,
+    count: Cell<usize>,
+    // Index of the test that is running
+    current: Cell<Option<usize>>,
+    // Index of the last test of the run
+    last: Cell<usize>,
+    // Bit N is set if test N completed in the last run, and if it failed
+    completed: Cell<u32>,
+    failures: Cell<u32>,
+    client: Cell<Option<&'static RegistryClient>>,
+}
+
+impl TestRegistry {
+    const fn new() -> TestRegistry {
+        TestRegistry {
+            tests: Cell::new([None; MAX_TESTS]),
+            count: Cell::new(0),
+            current: Cell::new(None),
+            last: Cell::new(0),
+            completed: Cell::new(0),
+            failures: Cell::new(0),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static RegistryClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Adds `test` to the end of the run. Returns ENOMEM if `MAX_TESTS`
+    /// are already registered and EBUSY during a run.
+    pub fn register(&self, test: &'static SelfTest) -> ReturnCode {
+        if self.is_running() {
+            return ReturnCode::EBUSY;
+        }
+        let count = self.count.get();
+        if count == MAX_TESTS {
+            return ReturnCode::ENOMEM;
+        }
+        let mut tests = self.tests.get();
+        tests[count] = Some(test);
+        self.tests.set(tests);
+        self.count.set(count + 1);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.current.get().is_some()
+    }
+
+    /// Runs every registered test. Returns EBUSY if a run is in progress.
+    pub fn run(&'static self) -> ReturnCode {
+        if self.is_running() {
+            return ReturnCode::EBUSY;
+        }
+        self.completed.set(0);
+        self.failures.set(0);
+        self.last.set(self.count.get().saturating_sub(1));
+        println!("Running {} self-tests\r", self.count.get());
+        self.start_test(0);
+        ReturnCode::SUCCESS
+    }
+
+    /// Runs test `index` on its own. Returns EINVAL if there is no such
+    /// test and EBUSY if a run is in progress.
+    pub fn run_test(&'static self, index: usize) -> ReturnCode {
+        if index >= self.count.get() {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_running() {
+            return ReturnCode::EBUSY;
+        }
+        self.completed.set(0);
+        self.failures.set(0);
+        self.last.set(index);
+        self.start_test(index);
+        ReturnCode::SUCCESS
+    }
+
+    /// Number of registered tests.
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+
+    /// Name of test `index`.
+    pub fn name(&self, index: usize) -> Option<&'static str> {
+        self.test(index).map(|test| test.name())
+    }
+
+    /// Index of the test called `name`.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        (0..self.count.get()).find(|&index| self.name(index) == Some(name))
+    }
+
+    pub fn status(&self, index: usize) -> TestStatus {
+        if self.current.get() == Some(index) {
+            TestStatus::Running
+        } else if self.completed.get() & 1 << index == 0 {
+            TestStatus::NotRun
+        } else if self.failures.get() & 1 << index != 0 {
+            TestStatus::Failed
+        } else {
+            TestStatus::Passed
+        }
+    }
+
+    /// Tests that passed and failed in the last run.
+    pub fn results(&self) -> (usize, usize) {
+        let completed = self.completed.get().count_ones() as usize;
+        let failed = self.failures.get().count_ones() as usize;
+        (completed - failed, failed)
+    }
+
+    fn test(&self, index: usize) -> Option<&'static SelfTest> {
+        self.tests.get()[..self.count.get()].get(index).and_then(|&test| test)
+    }
+
+    fn start_test(&'static self, index: usize) {
+        match self.test(index).filter(|_| index <= self.last.get()) {
+            Some(test) => {
+                self.current.set(Some(index));
+                test.start(self);
+            }
+            _ => {
+                self.current.set(None);
+                self.print_summary();
+                let (passed, failed) = self.results();
+                self.client.get().map(|client| client.tests_complete(passed, failed));
+            }
+        }
+    }
+
+    fn print_summary(&self) {
+        let (passed, failed) = self.results();
+        println!("Self-tests: {} passed, {} failed\r", passed, failed);
+        for index in 0..self.count.get() {
+            if self.status(index) == TestStatus::Failed {
+                self.name(index).map(|name| println!("  FAILED: {}\r", name));
+            }
+        }
+    }
+}
+
+impl SelfTestClient for TestRegistry {
+    fn test_complete(&self, passed: bool) {
+        let index = match self.current.get() {
+            Some(index) => index,
+            None => return,
+        };
+        self.name(index).map(|name| {
+            println!("  {}: {}\r", name, if passed { "pass" } else { "FAIL" });
+        });
+        self.completed.set(self.completed.get() | 1 << index);
+        if !passed {
+            self.failures.set(self.failures.get() | 1 << index);
+        }
+        // `TESTS` is the only registry, so this is `self`.
+        let registry: &'static TestRegistry = unsafe { &TESTS };
+        registry.start_test(index + 1);
+    }
+}
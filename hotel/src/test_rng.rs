@@ -1,14 +1,23 @@
 //! Test RNG hardware
 
+use core::cell::Cell;
 use hil::rng::{Client, Continue, RNG};
+use test_registry::{SelfTest, SelfTestClient};
+
+/// Samples taken, all of which must differ from the first
+const SAMPLES: usize = 5;
 
 pub struct TestRng<'a> {
     rng: &'a RNG<'a>,
+    client: Cell<Option<&'static SelfTestClient>>,
 }
 
 impl<'a> TestRng<'a> {
     pub fn new(rng: &'a RNG<'a>) -> Self {
-        TestRng { rng: rng }
+        TestRng {
+            rng: rng,
+            client: Cell::new(None),
+        }
     }
 
     pub fn run(&self) {
@@ -16,10 +25,34 @@ impl<'a> TestRng<'a> {
     }
 }
 
+impl<'a> SelfTest for TestRng<'a> {
+    fn name(&self) -> &'static str {
+        "trng"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        self.client.set(Some(client));
+        self.rng.set_client(self);
+        self.run();
+    }
+}
+
 impl<'a> Client for TestRng<'a> {
     fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> Continue {
         print!("Randomness: \r");
-        randomness.take(5).for_each(|r| print!("  [{:x}]\r", r));
+        let mut samples = [0; SAMPLES];
+        let mut count = 0;
+        for (sample, r) in samples.iter_mut().zip(randomness) {
+            print!("  [{:x}]\r", r);
+            *sample = r;
+            count += 1;
+        }
+        if count < SAMPLES {
+            return Continue::More;
+        }
+        // A stuck source repeats itself.
+        let passed = samples[1..].iter().all(|&sample| sample != samples[0]);
+        self.client.take().map(|client| client.test_complete(passed));
         Continue::Done
     }
 }
@@ -0,0 +1,90 @@
+//! Runs self-tests at a host's request
+//!
+//! `TEST_RUNNER` serves vendor requests on endpoint 0 that let a script on
+//! the host run the tests in `test_registry::TESTS` on real hardware,
+//! without a console or debugger attached. The host looks tests up by name,
+//! starts one (or all of them) and polls for the result:
+//!
+//! | bRequest             | Direction | wValue     | Response                                   |
+//! | -------------------- | :-------- | :--------- | :----------------------------------------- |
+//! | `REQUEST_TEST_NAME`  | IN        | test index | The test's name; stalls past the last test |
+//! | `REQUEST_TEST_START` | OUT       | test index | None; `RUN_ALL` runs every test            |
+//! | `REQUEST_TEST_STATUS`| IN        | test index | Status, then tests passed and failed       |
+//!
+//! Starting a test stalls if a run is in progress or there is no such
+//! test. The status is one byte of `TestStatus` for the test, followed by
+//! the number of tests that passed and failed in the last run (one byte
+//! each).
+
+use core::cmp;
+use kernel::ReturnCode;
+use test_registry::TESTS;
+use usb::{VendorHandler, VendorRequest, USB0};
+
+pub const REQUEST_TEST_NAME: u8 = 0x10;
+pub const REQUEST_TEST_START: u8 = 0x11;
+pub const REQUEST_TEST_STATUS: u8 = 0x12;
+
+/// `wValue` of a `REQUEST_TEST_START` that runs every test
+pub const RUN_ALL: u16 = 0xffff;
+
+pub static mut TEST_RUNNER: TestRunner = TestRunner::new();
+
+pub struct TestRunner;
+
+impl TestRunner {
+    const fn new() -> TestRunner {
+        TestRunner
+    }
+
+    /// Registers the runner's vendor requests with the USB driver.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe {
+            for &request in [REQUEST_TEST_NAME, REQUEST_TEST_START, REQUEST_TEST_STATUS].iter() {
+                let result = USB0.add_vendor_handler(request, self);
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+impl VendorHandler for TestRunner {
+    fn vendor_request(&self, request: &VendorRequest, response: &mut [u8]) -> Result<usize, ReturnCode> {
+        let tests = unsafe { &TESTS };
+        let index = request.value as usize;
+        match request.request {
+            REQUEST_TEST_NAME => {
+                tests.name(index).map_or(Err(ReturnCode::EINVAL), |name| {
+                    let len = cmp::min(name.len(), response.len());
+                    response[..len].copy_from_slice(&name.as_bytes()[..len]);
+                    Ok(len)
+                })
+            }
+            REQUEST_TEST_START => {
+                let result = if request.value == RUN_ALL {
+                    tests.run()
+                } else {
+                    tests.run_test(index)
+                };
+                match result {
+                    ReturnCode::SUCCESS => Ok(0),
+                    error => Err(error),
+                }
+            }
+            REQUEST_TEST_STATUS => {
+                if response.len() < 3 {
+                    return Err(ReturnCode::ESIZE);
+                }
+                let (passed, failed) = tests.results();
+                response[0] = tests.status(index) as u8;
+                response[1] = passed as u8;
+                response[2] = failed as u8;
+                Ok(3)
+            }
+            _ => Err(ReturnCode::ENOSUPPORT),
+        }
+    }
+}
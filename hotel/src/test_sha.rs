@@ -0,0 +1,89 @@
+//! Test SHA hardware
+//!
+//! Runs the FIPS 180 example messages through the SHA engine and checks
+//! the digests. The engine is synchronous, so the test is over before
+//! `start` returns.
+
+use hil::digest::{DigestEngine, DigestMode};
+use test_registry::{SelfTest, SelfTestClient};
+
+struct Vector {
+    mode: DigestMode,
+    message: &'static [u8],
+    digest: &'static [u8],
+}
+
+static VECTORS: [Vector; 4] = [
+    Vector {
+        mode: DigestMode::Sha1,
+        message: b"abc",
+        digest: &[
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e,
+            0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+        ],
+    },
+    Vector {
+        mode: DigestMode::Sha256,
+        message: b"",
+        digest: &[
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+            0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+        ],
+    },
+    Vector {
+        mode: DigestMode::Sha256,
+        message: b"abc",
+        digest: &[
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+            0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+        ],
+    },
+    Vector {
+        mode: DigestMode::Sha256,
+        message: b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        digest: &[
+            0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60, 0x39,
+            0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb, 0x06, 0xc1,
+        ],
+    },
+];
+
+pub struct TestSha<'a, E: DigestEngine + 'a> {
+    sha: &'a E,
+}
+
+impl<'a, E: DigestEngine + 'a> TestSha<'a, E> {
+    pub fn new(sha: &'a E) -> Self {
+        TestSha { sha: sha }
+    }
+
+    /// Runs every vector, returning whether they all passed.
+    pub fn run(&self) -> bool {
+        let mut passed = true;
+        for (index, vector) in VECTORS.iter().enumerate() {
+            let mut digest = [0; 32];
+            let len = self.sha.initialize(vector.mode)
+                .and_then(|_| self.sha.update(vector.message))
+                .and_then(|_| self.sha.finalize(&mut digest))
+                .unwrap_or(0);
+            if &digest[..len] == vector.digest {
+                println!("SHA pass: vector {}\r", index);
+            } else {
+                println!("SHA fail: vector {} gave {:x?}\r", index, &digest[..len]);
+                passed = false;
+            }
+        }
+        passed
+    }
+}
+
+impl<'a, E: DigestEngine + 'a> SelfTest for TestSha<'a, E> {
+    fn name(&self) -> &'static str {
+        "sha"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        let passed = self.run();
+        client.test_complete(passed);
+    }
+}
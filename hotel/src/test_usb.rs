@@ -0,0 +1,80 @@
+//! Test USB enumeration
+//!
+//! Checks that a connected host enumerates the device: it assigns an
+//! address, selects a configuration and reads the device descriptor's
+//! strings, all within `TIMEOUT_MS`. The test can only pass with a host
+//! attached, and catches descriptors the host rejects part way through.
+
+use core::cell::Cell;
+use kernel::hil::time::{self, Alarm, Frequency};
+use test_registry::{SelfTest, SelfTestClient};
+use usb::{Enumeration, USB0};
+
+/// How long the host has to finish enumerating, from the test's start
+const TIMEOUT_MS: u32 = 5000;
+
+/// How often enumeration progress is checked
+const POLL_MS: u32 = 100;
+
+pub struct TestUsb<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    // Time left before the test fails
+    remaining_ms: Cell<u32>,
+    client: Cell<Option<&'static SelfTestClient>>,
+}
+
+impl<'a, A: Alarm + 'a> TestUsb<'a, A> {
+    pub fn new(alarm: &'a A) -> TestUsb<'a, A> {
+        TestUsb {
+            alarm: alarm,
+            remaining_ms: Cell::new(0),
+            client: Cell::new(None),
+        }
+    }
+
+    fn enumerated(&self, enumeration: Enumeration) -> bool {
+        let strings = unsafe { USB0.enumeration_strings() };
+        enumeration.address != 0 && enumeration.configuration != 0 &&
+            enumeration.strings & strings == strings
+    }
+
+    fn poll(&self) {
+        let enumeration = unsafe { USB0.enumeration() };
+        if self.enumerated(enumeration) {
+            println!("USB enumerated: address {}, configuration {}, strings {:#x}\r",
+                     enumeration.address, enumeration.configuration, enumeration.strings);
+            self.finish(true);
+        } else if self.remaining_ms.get() == 0 {
+            println!("USB not enumerated after {}ms: address {}, configuration {}, strings {:#x}\r",
+                     TIMEOUT_MS, enumeration.address, enumeration.configuration,
+                     enumeration.strings);
+            self.finish(false);
+        } else {
+            self.remaining_ms.set(self.remaining_ms.get().saturating_sub(POLL_MS));
+            let interval = POLL_MS * <A::Frequency>::frequency() / 1000;
+            self.alarm.set_alarm(self.alarm.now().wrapping_add(interval));
+        }
+    }
+
+    fn finish(&self, passed: bool) {
+        self.client.take().map(|client| client.test_complete(passed));
+    }
+}
+
+impl<'a, A: Alarm + 'a> SelfTest for TestUsb<'a, A> {
+    fn name(&self) -> &'static str {
+        "usb"
+    }
+
+    fn start(&'static self, client: &'static SelfTestClient) {
+        self.client.set(Some(client));
+        self.remaining_ms.set(TIMEOUT_MS);
+        self.poll();
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for TestUsb<'a, A> {
+    fn fired(&self) {
+        self.poll();
+    }
+}
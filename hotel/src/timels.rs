@@ -1,12 +1,28 @@
+//! Low-speed timers (TIMELS)
+//!
+//! The two low-speed timers run from the always-on 256Khz oscillator, so
+//! unlike the microsecond timers they keep counting while the high-speed
+//! clocks are gated and can wake the chip from deep sleep (see
+//! `enable_wakeup`).
+//!
+//! Each timer counts `value` down from `load` and, on reaching zero,
+//! interrupts and restarts from `reload`. Between alarms the timer is left
+//! counting down from `IDLE_RELOAD` with its interrupt disabled so that
+//! `now` keeps advancing.
+
 use core::cell::Cell;
 use kernel::common::cells::VolatileCell;
 use kernel::hil::time::{self, Alarm, Frequency};
+use pmu::{self, WakeSource};
 
 const TIMELS0_BASE: *const Registers = 0x40540000 as *const Registers;
 const TIMELS1_BASE: *const Registers = 0x40540040 as *const Registers;
 
-pub static mut TIMELS0: Timels = Timels::new(TIMELS0_BASE);
-pub static mut TIMELS1: Timels = Timels::new(TIMELS1_BASE);
+pub static mut TIMELS0: Timels = Timels::new(TIMELS0_BASE, WakeSource::TimeLs0Timer0);
+pub static mut TIMELS1: Timels = Timels::new(TIMELS1_BASE, WakeSource::TimeLs0Timer1);
+
+/// Countdown used while no alarm is set.
+const IDLE_RELOAD: u32 = !0;
 
 struct Registers {
     pub control: VolatileCell<u32>,
@@ -26,17 +42,28 @@ pub struct Timels<'a> {
     registers: *const Registers,
     client: Cell<Option<&'a time::Client>>,
     now: Cell<u32>,
+    wake_source: WakeSource,
 }
 
 impl<'a> Timels<'a> {
-    const fn new(regs: *const Registers) -> Timels<'a> {
+    const fn new(regs: *const Registers, wake_source: WakeSource) -> Timels<'a> {
         Timels {
             registers: regs,
             client: Cell::new(None),
             now: Cell::new(0),
+            wake_source: wake_source,
         }
     }
 
+    /// Lets this timer's alarm wake the chip from deep sleep.
+    pub fn enable_wakeup(&self) {
+        pmu::enable_wake_source(self.wake_source);
+    }
+
+    pub fn disable_wakeup(&self) {
+        pmu::disable_wake_source(self.wake_source);
+    }
+
     pub fn set_client(&'static self, client: &'static time::Client) {
         self.client.set(Some(client));
     }
@@ -45,9 +72,9 @@ impl<'a> Timels<'a> {
         let regs = unsafe { &*self.registers };
         regs.interrupt_ack.set(1);
         regs.interrupt_wakeup_ack.set(1);
-        regs.control.set(0);
+        regs.interrupt_enable.set(0);
         self.now.set(self.now.get().wrapping_add(regs.reload.get()));
-        regs.reload.set(0);
+        self.load(IDLE_RELOAD);
         self.client.get().map(|client| {
             client.fired();
         });
@@ -55,15 +82,23 @@ impl<'a> Timels<'a> {
 
     fn disable_alarm(&self) {
         let regs = unsafe { &*self.registers };
-        regs.control.set(0);
+        regs.interrupt_enable.set(0);
     }
 
     fn is_enabled(&self) -> bool {
         let regs = unsafe { &*self.registers };
-        regs.control.get() & 1 == 1 && regs.value.get() != 0
+        regs.control.get() & 1 == 1 && regs.interrupt_enable.get() & 1 == 1
     }
 
-    
+    /// Restarts the countdown from `tics`, first folding the time elapsed
+    /// since the last load into `now`.
+    fn load(&self, tics: u32) {
+        let regs = unsafe { &*self.registers };
+        self.now.set(Alarm::now(self));
+        regs.load.set(tics);
+        regs.reload.set(tics);
+        regs.control.set(1);
+    }
 }
 
 pub struct Freq256Khz;
@@ -90,23 +125,28 @@ impl<'a> Alarm for Timels<'a> {
 
     fn now(&self) -> u32 {
         let regs = unsafe { &*self.registers };
+        if regs.control.get() & 1 == 0 {
+            return self.now.get();
+        }
         let cur = regs.value.get();
         let reload = regs.reload.get();
-        let elapsed = reload - cur;
+        let elapsed = reload.wrapping_sub(cur);
         self.now.get().wrapping_add(elapsed)
     }
 
     fn set_alarm(&self, tics: u32) {
-        let distance = tics.wrapping_sub(self.now.get());
         let regs = unsafe { &*self.registers };
-        regs.load.set(distance);
-        regs.reload.set(distance);
+        regs.interrupt_enable.set(0);
+        let distance = tics.wrapping_sub(Alarm::now(self));
+        // A zero countdown never fires and one for an alarm in the past
+        // would take hours, so fire on the next tic instead.
+        let distance = if distance == 0 || distance >= 1 << 31 { 1 } else { distance };
+        self.load(distance);
         regs.interrupt_enable.set(1);
-        regs.control.set(1);
     }
 
     fn get_alarm(&self) -> u32 {
         let regs = unsafe { &*self.registers };
-        regs.reload.get()
+        self.now.get().wrapping_add(regs.reload.get())
     }
 }
@@ -0,0 +1,78 @@
+//! Free-running 64-bit microsecond timestamps
+//!
+//! Uses counter 0 of the Timeus controller running at 1Mhz and extends it to
+//! 64 bits in software. The 32-bit hardware counter wraps roughly every 71
+//! minutes; the extension is kept current by `now` itself (which notices
+//! the low word going backwards) and by the counter's wrap interrupt, which
+//! guarantees `now` is called at least once per wrap even if nobody else
+//! asks for the time.
+//!
+//! `now` only touches a register and two cells, so it is cheap enough to
+//! call from interrupt handlers, e.g. to timestamp trace events. It masks
+//! interrupts while it updates the extension, so a call that interrupts
+//! another can't count the same wrap twice or put back a stale low word.
+
+use core::cell::Cell;
+use timeus::TIMEUS0;
+
+pub static mut TIMESTAMP: Timestamp = Timestamp::new();
+
+pub struct Timestamp {
+    high: Cell<u32>,
+    last_low: Cell<u32>,
+}
+
+impl Timestamp {
+    const fn new() -> Timestamp {
+        Timestamp {
+            high: Cell::new(0),
+            last_low: Cell::new(0),
+        }
+    }
+
+    /// Starts the underlying counter. The `TimeUs0Timer` clock must already
+    /// be enabled.
+    pub fn start(&self) {
+        unsafe {
+            TIMEUS0.start();
+            TIMEUS0.enable_wrap_interrupt();
+        }
+    }
+
+    /// Microseconds since `start` was called.
+    pub fn now(&self) -> u64 {
+        unsafe {
+            masked(|| {
+                let low = TIMEUS0.now();
+                if low < self.last_low.get() {
+                    self.high.set(self.high.get().wrapping_add(1));
+                }
+                self.last_low.set(low);
+                (self.high.get() as u64) << 32 | low as u64
+            })
+        }
+    }
+
+    pub fn handle_wrap_interrupt(&self) {
+        unsafe { TIMEUS0.clear_wrap_interrupt() };
+        self.now();
+    }
+}
+
+/// Runs `f` with interrupts masked and then leaves them masked or not as
+/// they were. Unlike `support::atomic` this doesn't unmask interrupts when
+/// called from code that has already masked them, such as the kernel loop
+/// around `Chip::sleep`.
+unsafe fn masked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let primask: u32;
+    asm!("mrs $0, primask
+          cpsid i" : "=r"(primask) :: "memory" : "volatile");
+    let result = f();
+    if primask & 1 == 0 {
+        asm!("cpsie i" ::: "memory" : "volatile");
+    }
+    result
+}
@@ -1,5 +1,6 @@
-use core::mem;
+use core::cell::Cell;
 use kernel::common::cells::VolatileCell;
+use kernel::hil::time::{self, Alarm, Frequency};
 
 #[repr(u32)]
 #[derive(PartialEq, Eq)]
@@ -73,46 +74,148 @@ pub struct Registers {
     /// Same mapping as `interrupt_enable`
     pub interrupt_clear: VolatileCell<u32>,
 
-    _interrupt_test: VolatileCell<u32>,
+    /// Test interrupts
+    ///
+    /// Same mapping as `interrupt_enable`. Writing a bit raises the
+    /// corresponding interrupt.
+    pub interrupt_test: VolatileCell<u32>,
     _reserved: [u8; 240],
 
     /// Registers for each of the four counters
     pub counters: [Counter; 4],
 }
 
-const BASE_REGISTERS: *const Registers = 0x40670000 as *const Registers;
+pub const BASE_REGISTERS: *const Registers = 0x40670000 as *const Registers;
+
+pub static mut TIMEUS0: Timeus = Timeus::new(0);
+pub static mut TIMEUS1: Timeus = Timeus::new(1);
+// Counters 2 and 3 are the PWM channels in `pwm`.
+
+/// Divider that makes a counter tick once per microsecond.
+const DIVIDER_1MHZ: u32 = 24;
 
-pub struct Timeus {
-    regs: &'static Registers,
+pub struct Timeus<'a> {
+    regs: *const Registers,
     idx: usize,
+    client: Cell<Option<&'a time::Client>>,
 }
 
-impl Timeus {
+impl<'a> Timeus<'a> {
     /// Creates a new Timeus for a particular counter.
     ///
-    /// It is unsafe to create multiple Timeus with the same `idx`.
-    ///
     /// `idx` must betwee in the range [0, 3].
-    pub unsafe fn new(idx: usize) -> Timeus {
+    const fn new(idx: usize) -> Timeus<'a> {
         Timeus {
-            regs: mem::transmute(BASE_REGISTERS),
+            regs: BASE_REGISTERS,
             idx: idx,
+            client: Cell::new(None),
         }
     }
 
+    pub fn set_client(&'static self, client: &'static time::Client) {
+        self.client.set(Some(client));
+    }
+
     pub fn now(&self) -> u32 {
         unsafe {self.counter().current_value.get()}
     }
 
-
+    /// Starts the counter free running at 1Mhz, wrapping at `MAX_INT`.
     pub fn start(&self) {
         let counter = self.counter();
         unsafe {counter.max_value.set(!0); // MAX_INT
-                counter.divider.set(1);
+                counter.divider.set(DIVIDER_1MHZ);
                 counter.wrapping.set(Enable::Enabled)};
     }
 
+    /// Handles the counter reaching its `programmed_value`.
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers();
+        regs.interrupt_clear.set(self.programmed_bit());
+        self.disable_alarm();
+        self.client.get().map(|client| {
+            client.fired();
+        });
+    }
+
+    /// Enables the interrupt raised when the counter reaches `max_value`.
+    pub fn enable_wrap_interrupt(&self) {
+        let regs = self.registers();
+        regs.interrupt_enable.set(regs.interrupt_enable.get() | self.max_bit());
+    }
+
+    pub fn clear_wrap_interrupt(&self) {
+        self.registers().interrupt_clear.set(self.max_bit());
+    }
+
+    fn disable_alarm(&self) {
+        let regs = self.registers();
+        regs.interrupt_enable.set(regs.interrupt_enable.get() & !self.programmed_bit());
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.registers().interrupt_enable.get() & self.programmed_bit() != 0
+    }
+
+    fn programmed_bit(&self) -> u32 {
+        1 << (self.idx * 2)
+    }
+
+    fn max_bit(&self) -> u32 {
+        1 << (self.idx * 2 + 1)
+    }
+
+    fn registers(&self) -> &Registers {
+        unsafe { &*self.regs }
+    }
+
     fn counter(&self) -> &Counter {
-        &self.regs.counters[self.idx]
+        &self.registers().counters[self.idx]
+    }
+}
+
+pub struct Freq1Mhz;
+
+impl Frequency for Freq1Mhz {
+    fn frequency() -> u32 {
+        1000000
+    }
+}
+
+impl<'a> time::Time for Timeus<'a> {
+    type Frequency = Freq1Mhz;
+
+    fn disable(&self) {
+        self.disable_alarm();
+    }
+
+    fn is_armed(&self) -> bool {
+        self.is_enabled()
+    }
+}
+
+impl<'a> Alarm for Timeus<'a> {
+    fn now(&self) -> u32 {
+        unsafe {self.counter().current_value.get()}
+    }
+
+    fn set_alarm(&self, tics: u32) {
+        let regs = self.registers();
+        let counter = self.counter();
+        regs.interrupt_clear.set(self.programmed_bit());
+        unsafe {counter.programmed_value.set(tics)};
+        regs.interrupt_enable.set(regs.interrupt_enable.get() | self.programmed_bit());
+
+        // The compare only matches on the way up, so an alarm that is
+        // already in the past would otherwise not fire until the counter
+        // wraps around (over an hour later). Raise it by hand instead.
+        let now = unsafe {counter.current_value.get()};
+        if now.wrapping_sub(tics) < (1 << 31) {
+            regs.interrupt_test.set(self.programmed_bit());
+        }
+    }
+
+    fn get_alarm(&self) -> u32 {
+        unsafe {self.counter().programmed_value.get()}
     }
 }
@@ -0,0 +1,333 @@
+//! TPM-style register transport over the SPI slave
+//!
+//! Presents the register interface of the TPM TIS specification (TPM_ACCESS,
+//! TPM_STS, TPM_DATA_FIFO and TPM_DID_VID in per-locality windows at
+//! 0xD40000 + locality * 0x1000) to the host over `sps::SPS0`, so an
+//! application processor can use an existing TPM SPI driver to send
+//! commands to the chip. The transport only moves bytes: commands are
+//! handed to a `TpmClient` when the host sets tpmGo, and the client's
+//! response is returned through `Tpm::command_complete`.
+//!
+//! Every SPS frame starts with the usual 4-byte TPM SPI header (bit 7 of
+//! the first byte set for a read, bits 0-5 the transfer size minus one,
+//! then a 24-bit big-endian address). A write frame carries its data after
+//! the header. Because the SPS delivers whole frames, there is no wait
+//! state flow control: a read's data is clocked out in the frame following
+//! its header, which the host reads with a header-less frame of the
+//! requested size.
+//!
+//! Only one locality can be active at a time; it is claimed by writing
+//! requestUse to its TPM_ACCESS and released by writing activeLocality.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::TakeCell;
+use sps::{self, SpsClient};
+
+/// Base of the register windows
+const TIS_BASE: u32 = 0xD40000;
+const NUM_LOCALITIES: u32 = 5;
+
+// Register offsets within a locality's window
+const TPM_ACCESS: u32 = 0x000;
+const TPM_STS: u32 = 0x018;
+const TPM_DATA_FIFO: u32 = 0x024;
+const TPM_DID_VID: u32 = 0xF00;
+const TPM_RID: u32 = 0xF04;
+
+// TPM_ACCESS bits
+const ACCESS_VALID: u8 = 0x80;
+const ACCESS_ACTIVE_LOCALITY: u8 = 0x20;
+const ACCESS_REQUEST_USE: u8 = 0x02;
+
+// TPM_STS bits
+const STS_VALID: u32 = 0x80;
+const STS_COMMAND_READY: u32 = 0x40;
+const STS_GO: u32 = 0x20;
+const STS_DATA_AVAIL: u32 = 0x10;
+const STS_EXPECT: u32 = 0x08;
+const STS_RESPONSE_RETRY: u32 = 0x02;
+const STS_BURST_COUNT_SHIFT: u32 = 8;
+
+/// Largest burst advertised in TPM_STS, bounded by the SPI header's size
+/// field
+const MAX_BURST: usize = 64;
+
+/// Vendor and device ID reported in TPM_DID_VID
+const VENDOR_ID: u32 = 0x1ae0;
+const DEVICE_ID: u32 = 0x0028;
+
+pub static mut TPM: Tpm = Tpm::new();
+
+/// Handles the commands sent by the host.
+pub trait TpmClient {
+    /// The host sent the `len`-byte command in `buffer`. The client must
+    /// eventually write the response to `buffer` and return it with
+    /// `Tpm::command_complete`.
+    fn command_received(&self, buffer: &'static mut [u8], len: usize);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    /// No command in progress
+    Idle,
+    /// Ready for the host to write a command
+    Ready,
+    /// Receiving a command
+    Reception,
+    /// The client is executing a command
+    Execution,
+    /// The response is available to read
+    Completion,
+}
+
+pub struct Tpm {
+    client: Cell<Option<&'static TpmClient>>,
+    state: Cell<State>,
+    active_locality: Cell<Option<u32>>,
+    // Command and response buffer; taken while the client executes
+    buffer: TakeCell<'static, [u8]>,
+    // Bytes of the command received so far, or of the response read so far
+    cursor: Cell<usize>,
+    // Length of the command (from its header) or of the response
+    len: Cell<usize>,
+    // Holds read data between a read header and the frame that
+    // clocks it out
+    reply: TakeCell<'static, [u8]>,
+}
+
+impl Tpm {
+    const fn new() -> Tpm {
+        Tpm {
+            client: Cell::new(None),
+            state: Cell::new(State::Idle),
+            active_locality: Cell::new(None),
+            buffer: TakeCell::empty(),
+            cursor: Cell::new(0),
+            len: Cell::new(0),
+            reply: TakeCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static TpmClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Starts serving the register interface on SPS0. `buffer` holds
+    /// commands and responses, and bounds their size; `reply` holds read
+    /// data and must be at least `MAX_BURST` bytes; `frame` is the SPS
+    /// frame buffer and must be at least `MAX_BURST + 4` bytes.
+    pub fn init(&'static self,
+                buffer: &'static mut [u8],
+                reply: &'static mut [u8],
+                frame: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.reply.replace(reply);
+        unsafe {
+            sps::SPS0.set_client(self);
+            sps::SPS0.init(frame);
+        }
+    }
+
+    /// Returns the response to the last command, `len` bytes at the start
+    /// of `buffer`, to the host.
+    pub fn command_complete(&self, buffer: &'static mut [u8], len: usize) {
+        self.len.set(cmp::min(len, buffer.len()));
+        self.cursor.set(0);
+        self.buffer.replace(buffer);
+        self.state.set(State::Completion);
+    }
+
+    /// TPM_STS as seen by the active locality.
+    fn status(&self) -> u32 {
+        let capacity = self.buffer.map_or(0, |buffer| buffer.len());
+        let remaining = self.len.get().saturating_sub(self.cursor.get());
+        let (flags, burst) = match self.state.get() {
+            State::Idle => (STS_VALID, 0),
+            State::Ready => (STS_VALID | STS_COMMAND_READY, capacity),
+            State::Reception => {
+                // Until the size in the command header has arrived, expect
+                // at least the rest of the header.
+                let expected = if self.cursor.get() < 6 { 6 } else { self.len.get() };
+                if self.cursor.get() < expected {
+                    (STS_VALID | STS_EXPECT, cmp::min(expected, capacity) - self.cursor.get())
+                } else {
+                    (STS_VALID, 0)
+                }
+            }
+            State::Execution => (0, 0),
+            State::Completion => {
+                if remaining > 0 {
+                    (STS_VALID | STS_DATA_AVAIL, remaining)
+                } else {
+                    (STS_VALID, 0)
+                }
+            }
+        };
+        flags | (cmp::min(burst, MAX_BURST) as u32) << STS_BURST_COUNT_SHIFT
+    }
+
+    fn read_byte(&self, locality: u32, offset: u32) -> u8 {
+        let active = self.active_locality.get() == Some(locality);
+        match offset {
+            TPM_ACCESS => {
+                if active {
+                    ACCESS_VALID | ACCESS_ACTIVE_LOCALITY
+                } else {
+                    ACCESS_VALID
+                }
+            }
+            TPM_DID_VID...0xF03 => ((DEVICE_ID << 16 | VENDOR_ID) >> ((offset - TPM_DID_VID) * 8)) as u8,
+            TPM_RID => 0,
+            _ if !active => 0xff,
+            TPM_STS...0x01B => (self.status() >> ((offset - TPM_STS) * 8)) as u8,
+            TPM_DATA_FIFO => {
+                if self.state.get() != State::Completion || self.cursor.get() >= self.len.get() {
+                    return 0xff;
+                }
+                let cursor = self.cursor.get();
+                self.cursor.set(cursor + 1);
+                self.buffer.map_or(0xff, |buffer| buffer[cursor])
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_byte(&self, locality: u32, offset: u32, value: u8) {
+        if offset == TPM_ACCESS {
+            self.write_access(locality, value);
+            return;
+        }
+        if self.active_locality.get() != Some(locality) {
+            return;
+        }
+        match offset {
+            TPM_STS => self.write_status(value as u32),
+            TPM_DATA_FIFO => self.write_fifo(value),
+            _ => {}
+        }
+    }
+
+    fn write_access(&self, locality: u32, value: u8) {
+        let active = self.active_locality.get();
+        if value & ACCESS_REQUEST_USE != 0 && active.is_none() {
+            self.active_locality.set(Some(locality));
+        } else if value & ACCESS_ACTIVE_LOCALITY != 0 && active == Some(locality) {
+            self.active_locality.set(None);
+            if self.state.get() != State::Execution {
+                self.state.set(State::Idle);
+            }
+        }
+    }
+
+    fn write_status(&self, value: u32) {
+        if value & STS_COMMAND_READY != 0 {
+            // Starts a command, or aborts the current one unless the
+            // client is already executing it
+            if self.state.get() != State::Execution {
+                self.state.set(State::Ready);
+                self.cursor.set(0);
+                self.len.set(0);
+            }
+        } else if value & STS_GO != 0 {
+            if self.state.get() == State::Reception && self.cursor.get() >= 6 &&
+                self.cursor.get() == self.len.get() {
+                self.state.set(State::Execution);
+                let len = self.len.get();
+                self.buffer.take().map(|buffer| {
+                    self.client.get().map(move |client| client.command_received(buffer, len));
+                });
+            }
+        } else if value & STS_RESPONSE_RETRY != 0 {
+            if self.state.get() == State::Completion {
+                self.cursor.set(0);
+            }
+        }
+    }
+
+    fn write_fifo(&self, value: u8) {
+        match self.state.get() {
+            State::Ready => {
+                self.state.set(State::Reception);
+                self.cursor.set(0);
+            }
+            State::Reception => {}
+            _ => return,
+        }
+        let cursor = self.cursor.get();
+        let stored = self.buffer.map_or(false, |buffer| {
+            if cursor < buffer.len() && (cursor < 6 || cursor < self.len.get()) {
+                buffer[cursor] = value;
+                true
+            } else {
+                false
+            }
+        });
+        if !stored {
+            return;
+        }
+        self.cursor.set(cursor + 1);
+        if cursor + 1 == 6 {
+            // The command header holds the total size, big-endian, in
+            // bytes 2-5
+            let size = self.buffer.map_or(0, |buffer| {
+                (buffer[2] as usize) << 24 | (buffer[3] as usize) << 16 |
+                (buffer[4] as usize) << 8 | buffer[5] as usize
+            });
+            self.len.set(size);
+        }
+    }
+
+    /// Splits a TIS address into locality and offset.
+    fn decode_address(address: u32) -> Option<(u32, u32)> {
+        if address & 0xFF0000 != TIS_BASE {
+            return None;
+        }
+        let locality = (address >> 12) & 0xF;
+        if locality >= NUM_LOCALITIES {
+            return None;
+        }
+        Some((locality, address & 0xFFF))
+    }
+}
+
+impl SpsClient for Tpm {
+    fn frame_received(&self, frame: &[u8], lost: bool) {
+        if lost || frame.len() < 4 {
+            // A header-less frame clocking out read data, or garbage
+            return;
+        }
+        let read = frame[0] & 0x80 != 0;
+        let size = (frame[0] & 0x3f) as usize + 1;
+        let address = (frame[1] as u32) << 16 | (frame[2] as u32) << 8 | frame[3] as u32;
+        let (locality, offset) = match Tpm::decode_address(address) {
+            Some(decoded) => decoded,
+            None => return,
+        };
+
+        // Multi-byte accesses to the FIFO all target the FIFO; other
+        // registers are accessed byte by byte at consecutive offsets.
+        let offset_of = |i: usize| if offset == TPM_DATA_FIFO { offset } else { offset + i as u32 };
+
+        if read {
+            self.reply.take().map(|reply| {
+                let size = cmp::min(size, reply.len());
+                for i in 0..size {
+                    reply[i] = self.read_byte(locality, offset_of(i));
+                }
+                unsafe {
+                    sps::SPS0.set_reply(reply, size);
+                }
+            });
+        } else {
+            let data = &frame[4..cmp::min(frame.len(), 4 + size)];
+            for (i, &b) in data.iter().enumerate() {
+                self.write_byte(locality, offset_of(i), b);
+            }
+        }
+    }
+
+    fn reply_sent(&self, reply: &'static mut [u8]) {
+        self.reply.replace(reply);
+    }
+}
@@ -0,0 +1,67 @@
+//! Lightweight event trace
+//!
+//! Drivers record notable events (a bus reset, a setup request, a fault)
+//! with `trace::record`, which stores a timestamp, a static event name and
+//! one word of argument in a ring of the last `TRACE_ENTRIES` events.
+//! Recording is cheap enough for interrupt handlers and prints nothing; the
+//! ring is printed by `trace::dump`, typically from the panic handler, to
+//! show what led up to a failure.
+
+use core::fmt::Write;
+use timestamp::TIMESTAMP;
+
+const TRACE_ENTRIES: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Low 32 bits of the timestamp counter, in microseconds
+    time: u32,
+    event: &'static str,
+    arg: u32,
+}
+
+const EMPTY: Entry = Entry {
+    time: 0,
+    event: "",
+    arg: 0,
+};
+
+static mut ENTRIES: [Entry; TRACE_ENTRIES] = [EMPTY; TRACE_ENTRIES];
+// Total number of events recorded; the next entry to write is
+// `RECORDED % TRACE_ENTRIES`.
+static mut RECORDED: usize = 0;
+
+/// Appends an event to the trace, overwriting the oldest one if the ring
+/// is full.
+pub fn record(event: &'static str, arg: u32) {
+    unsafe {
+        ENTRIES[RECORDED % TRACE_ENTRIES] = Entry {
+            time: TIMESTAMP.now() as u32,
+            event: event,
+            arg: arg,
+        };
+        RECORDED = RECORDED.wrapping_add(1);
+    }
+}
+
+/// Prints the recorded events, oldest first.
+pub fn dump(writer: &mut Write) {
+    unsafe {
+        let count = if RECORDED < TRACE_ENTRIES { RECORDED } else { TRACE_ENTRIES };
+        let _ = writer.write_fmt(format_args!("Trace ({} of {} events):\r\n", count, RECORDED));
+        for i in (RECORDED - count)..RECORDED {
+            let entry = &ENTRIES[i % TRACE_ENTRIES];
+            let _ = writer.write_fmt(format_args!("  {:>10}us {:<16} {:#010x}\r\n",
+                                                  entry.time,
+                                                  entry.event,
+                                                  entry.arg));
+        }
+    }
+}
+
+/// Discards all recorded events.
+pub fn clear() {
+    unsafe {
+        RECORDED = 0;
+    }
+}
@@ -3,8 +3,10 @@
 //! Driver for the True Random Number Generator (TRNG).
 
 use core::cell::Cell;
+use errata::{self, Erratum};
 use hil::rng::{Continue, RNG, Client};
 use kernel::common::cells::VolatileCell;
+use pmu::{PeripheralClock, PeripheralClock1, PeripheralReset};
 
 
 #[repr(C)]
@@ -120,9 +122,14 @@ const TRNG0_BASE: *mut Registers = 0x40410000 as *mut Registers;
 
 pub static mut TRNG0: Trng<'static> = unsafe { Trng::new(TRNG0_BASE) };
 
+/// How many times a timed out TRNG is restarted before it is reset.
+const MAX_RESTARTS: u8 = 3;
+
 pub struct Trng<'a> {
     regs: *mut Registers,
     client: Cell<Option<&'a Client>>,
+    reset: PeripheralReset,
+    restarts: Cell<u8>,
 }
 
 impl<'a> Trng<'a> {
@@ -130,6 +137,8 @@ impl<'a> Trng<'a> {
         Trng {
             regs: trng,
             client: Cell::new(None),
+            reset: PeripheralReset::new(PeripheralClock::Bank1(PeripheralClock1::Trng0)),
+            restarts: Cell::new(0),
         }
     }
 
@@ -139,6 +148,7 @@ impl<'a> Trng<'a> {
         // Disable and clear the interrupt.
         regs.interrupt_enable.set(0);
         regs.interrupt_state.set(0x1);
+        self.restarts.set(0);
 
         self.client.get().map(|client| {
             if let Continue::More = client.randomness_available(&mut Iter(self)) {
@@ -148,19 +158,29 @@ impl<'a> Trng<'a> {
         });
     }
 
-    fn init(&self) {
+    /// Starts generating random numbers.
+    pub fn init(&self) {
         let regs = unsafe { &*self.regs };
 
         // Enable bit shuffling and churn mode.  Disable XOR and Von Neumann processing.
         regs.post_processing_control.set(0xa);
         regs.slice_max_upper_limit.set(1);
         regs.slice_min_lower_limit.set(0);
-        regs.timeout_counter.set(0x7ff);
+        if errata::applies(Erratum::TrngSlowStartup) {
+            regs.timeout_counter.set(0xfff);
+        } else {
+            regs.timeout_counter.set(0x7ff);
+        }
         regs.timeout_max_try_num.set(4);
         regs.power_down_b.set(1);
         regs.go_event.set(1);
     }
 
+    /// A random word if one is ready, without waiting or calling the
+    /// client.
+    pub fn try_read(&self) -> Option<u32> {
+        Iter(self).next()
+    }
 }
 
 impl<'a> RNG<'a> for Trng<'a> {
@@ -175,9 +195,17 @@ impl<'a> RNG<'a> for Trng<'a> {
         if regs.empty.get() > 0 {
             // Make sure the TRNG isn't stuck.
             if regs.fsm_state.get() & 0x8 != 0 {
-                // TRNG timed out.  Restart.
-                regs.stop_work.set(1);
-                regs.go_event.set(1);
+                if self.restarts.get() < MAX_RESTARTS {
+                    // TRNG timed out.  Restart.
+                    self.restarts.set(self.restarts.get() + 1);
+                    regs.stop_work.set(1);
+                    regs.go_event.set(1);
+                } else {
+                    // Restarting isn't helping, so reset it.
+                    self.restarts.set(0);
+                    self.reset.pulse();
+                    self.init();
+                }
             }
 
             // Enable interrupts so we know when there is random data ready.
@@ -5,6 +5,13 @@
 //! flow-control. There is no DMA for the UART, but it has a 32-character deep
 //! FIFO transmit and receive buffer.
 //!
+//! Transmissions longer than `BURST_THRESHOLD` bytes set the TX FIFO
+//! interrupt level to its lowest, so the interrupt only fires once the FIFO
+//! has nearly drained and each one refills it with up to 31 bytes, which
+//! matters for console-heavy debugging. Shorter writes fit in the FIFO, so
+//! they set the highest level and complete as soon as they are queued,
+//! letting the writer queue the next one while the FIFO drains.
+//!
 //! # Examples
 //!
 //! Before using the UART you must configure the TX and/or RX pins and set the
@@ -30,7 +37,17 @@
 //! ```
 //! you'll be notified of completion through a callback
 //!
-//! TODO
+//! The baud rate, parity, stop bits and flow control can be changed at any
+//! time no transmission is in progress through `hil::uart::UART::configure`.
+//! Overrun, framing and parity errors end the outstanding receive early
+//! with the corresponding `hil::uart::Error`.
+//!
+//! Reception is interrupt driven through `hil::uart::UART::receive`. Bytes
+//! that arrive while no receive is outstanding are kept in a small ring
+//! buffer (`receiver::RING_SIZE` bytes, in addition to the hardware FIFO)
+//! and handed to the next receive; beyond that they are dropped. A receive
+//! issued while one is outstanding is handed straight back with
+//! `RepeatCallError`, the HIL's EBUSY.
 //!
 
 use core::cell::Cell;
@@ -39,6 +56,7 @@ use kernel::common::cells::VolatileCell;
 use kernel::hil;
 use kernel::ReturnCode;
 use pmu::{Clock, PeripheralClock, PeripheralClock1};
+use receiver::Receiver;
 
 /// Registers for the UART controller
 #[allow(dead_code)]
@@ -46,14 +64,78 @@ struct Registers {
     read_data: VolatileCell<u32>,
     write_data: VolatileCell<u32>,
     nco: VolatileCell<u32>,
+
+    /// Control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | TX enable                                            |
+    /// | 1    | RX enable                                            |
+    /// | 5    | Parity enable                                        |
+    /// | 6    | Odd parity (even if clear)                           |
+    /// | 7    | Two stop bits (one if clear)                         |
+    /// | 8    | Hardware (CTS/RTS) flow control                      |
     control: VolatileCell<u32>,
+
+    /// Interrupt enables, with the same layout as `interrupt_state`
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | TX FIFO level                                        |
+    /// | 1    | RX FIFO level                                        |
+    /// | 3    | RX overrun: a byte arrived with the FIFO full        |
+    /// | 4    | RX framing error: missing stop bit                   |
+    /// | 5    | RX parity error                                      |
     interrupt_control: VolatileCell<u32>,
     state: VolatileCell<u32>,
     clear_state: VolatileCell<u32>,
     interrupt_state: VolatileCell<u32>,
     clear_interrupt_state: VolatileCell<u32>,
+
+    /// FIFO control
+    ///
+    /// | bits | Description                                          |
+    /// | ---- | :--------------------------------------------------- |
+    /// | 0    | Reset RX FIFO                                        |
+    /// | 1    | Reset TX FIFO                                        |
+    /// | 2-4  | RX interrupt level (interrupt at >= 2^n bytes)       |
+    /// | 5-6  | TX interrupt level (interrupt at <= `TxLevel` bytes) |
+    fifo: VolatileCell<u32>,
+}
+
+/// Levels the TX FIFO must drain to before a TX interrupt fires.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum TxLevel {
+    One = 0,
+    Four = 1,
+    Eight = 2,
+    Sixteen = 3,
 }
 
+const CONTROL_PARITY_ENABLE: u32 = 1 << 5;
+const CONTROL_PARITY_ODD: u32 = 1 << 6;
+const CONTROL_TWO_STOP_BITS: u32 = 1 << 7;
+const CONTROL_FLOW_CONTROL: u32 = 1 << 8;
+const CONTROL_FRAMING_MASK: u32 = CONTROL_PARITY_ENABLE | CONTROL_PARITY_ODD |
+                                  CONTROL_TWO_STOP_BITS | CONTROL_FLOW_CONTROL;
+
+const INTERRUPT_RX_OVERRUN: u32 = 1 << 3;
+const INTERRUPT_RX_FRAMING: u32 = 1 << 4;
+const INTERRUPT_RX_PARITY: u32 = 1 << 5;
+const INTERRUPT_RX_ERRORS: u32 = INTERRUPT_RX_OVERRUN | INTERRUPT_RX_FRAMING |
+                                 INTERRUPT_RX_PARITY;
+
+/// The NCO register is 16 bits wide, which limits the baud rate to just
+/// under 1.5Mbaud.
+const NCO_MAX: u32 = 0xffff;
+
+const FIFO_TX_LEVEL_SHIFT: u32 = 5;
+const FIFO_TX_LEVEL_MASK: u32 = 0b11 << FIFO_TX_LEVEL_SHIFT;
+
+/// Transmissions longer than this are sent in bursts.
+const BURST_THRESHOLD: usize = 16;
+
 const UART0_BASE: *mut Registers = 0x40600000 as *mut Registers;
 const UART1_BASE: *mut Registers = 0x40610000 as *mut Registers;
 const UART2_BASE: *mut Registers = 0x40620000 as *mut Registers;
@@ -80,6 +162,10 @@ pub struct UART {
     tx_buffer: TakeCell<'static, [u8]>,
     tx_limit: Cell<usize>,
     tx_cursor: Cell<usize>,
+    rx: Receiver,
+    // A receive error that happened while no receive was outstanding,
+    // reported to the next one.
+    rx_error: Cell<Option<hil::uart::Error>>,
     client: Cell<Option<&'static hil::uart::Client>>,
 }
 
@@ -91,17 +177,21 @@ impl UART {
             tx_buffer: TakeCell::empty(),
             tx_limit: Cell::new(0),
             tx_cursor: Cell::new(0),
+            rx: Receiver::new(),
+            rx_error: Cell::new(None),
             client: Cell::new(None),
         }
     }
 
     /// Enables transmission on the UART
     ///
-    /// Side-effect: ensures the clock is on.
+    /// Side-effect: acquires the clock if RX is not already enabled.
     pub fn enable_tx(&self) {
         let regs = unsafe { &*self.regs };
 
-        self.clock.enable();
+        if regs.control.get() & 0b11 == 0 {
+            self.clock.acquire();
+        }
 
         let ctrl = regs.control.get() | 0b1;
         regs.control.set(ctrl);
@@ -109,67 +199,96 @@ impl UART {
 
     /// Disable transmission on the UART
     ///
-    /// Side-effect: turns the clock off if RX is also disabled.
+    /// Side-effect: releases the clock if RX is also disabled.
     pub fn disable_tx(&self) {
         let regs = unsafe { &*self.regs };
 
-        let ctrl = regs.control.get() & !(0b1);
+        let old = regs.control.get();
+        let ctrl = old & !(0b1);
         regs.control.set(ctrl);
 
-        if ctrl & 0b11 == 0 {
+        if old & 0b11 != 0 && ctrl & 0b11 == 0 {
             // Neither TX nor RX enabled anymore
-            self.clock.disable();
+            self.clock.release();
         }
     }
 
     /// Enables reception on the UART
     ///
-    /// Side-effect: ensures the clock is on.
+    /// Side-effect: acquires the clock if TX is not already enabled.
     pub fn enable_rx(&self) {
         let regs = unsafe { &*self.regs };
 
-        self.clock.enable();
+        if regs.control.get() & 0b11 == 0 {
+            self.clock.acquire();
+        }
 
         let ctrl = regs.control.get() | 0b10;
         regs.control.set(ctrl);
-        regs.interrupt_control.set(regs.interrupt_control.get() | 2);
+        regs.interrupt_control.set(regs.interrupt_control.get() | 2 | INTERRUPT_RX_ERRORS);
     }
 
     /// Disable reception on the UART
     ///
-    /// Side-effect: turns the clock off if TX is also disabled.
+    /// Side-effect: releases the clock if TX is also disabled.
     pub fn disable_rx(&self) {
         let regs = unsafe { &*self.regs };
 
-        let ctrl = regs.control.get() & !(0b10);
+        let old = regs.control.get();
+        let ctrl = old & !(0b10);
         regs.control.set(ctrl);
+        regs.interrupt_control.set(regs.interrupt_control.get() & !(2 | INTERRUPT_RX_ERRORS));
 
-        if ctrl & 0b11 == 0 {
+        if old & 0b11 != 0 && ctrl & 0b11 == 0 {
             // Neither TX nor RX enabled anymore
-            self.clock.disable();
+            self.clock.release();
         }
-        regs.interrupt_control.set(regs.interrupt_control.get() & !2);
     }
 
 
     /// Prepare the UART for operation
     ///
-    /// `baudrate` is specified in Hz (e.g. 9600, 115200).
-    // TODO: Allow specification of other parameters like hardware flow control,
-    // parity, etc.
+    /// `baudrate` is specified in Hz (e.g. 9600, 115200). The framing is
+    /// left as it was (8N1 after reset); see `set_framing`.
     pub fn config(&self, baudrate: u32) {
         let regs = unsafe { &*self.regs };
 
-        // NCO is 2**20 * f_baud / f_pclk
-        // f_pclk is 24_000_000 (24Mhz)
-        // To avoid overflow, we use 2**14 * f_baud / (24Mhz / 2**6)
-        let nco = (1 << 14) * baudrate / 375000;
-        regs.nco.set(nco);
+        regs.nco.set(Self::nco(baudrate));
 
         regs.clear_interrupt_state.set(!0);
         regs.state.set(!0);
     }
 
+    /// The NCO setting for `baudrate`.
+    fn nco(baudrate: u32) -> u32 {
+        // NCO is 2**20 * f_baud / f_pclk, with f_pclk 24MHz. The product
+        // overflows 32 bits from 4096 baud, but the quotient always fits.
+        (((baudrate as u64) << 20) / 24_000_000) as u32
+    }
+
+    /// Sets parity, the number of stop bits and whether CTS/RTS flow
+    /// control is used.
+    pub fn set_framing(&self,
+                       parity: hil::uart::Parity,
+                       stop_bits: hil::uart::StopBits,
+                       hw_flow_control: bool) {
+        let regs = unsafe { &*self.regs };
+        let mut ctrl = regs.control.get() & !CONTROL_FRAMING_MASK;
+        match parity {
+            hil::uart::Parity::None => {}
+            hil::uart::Parity::Odd => ctrl |= CONTROL_PARITY_ENABLE | CONTROL_PARITY_ODD,
+            hil::uart::Parity::Even => ctrl |= CONTROL_PARITY_ENABLE,
+        }
+        match stop_bits {
+            hil::uart::StopBits::One => {}
+            hil::uart::StopBits::Two => ctrl |= CONTROL_TWO_STOP_BITS,
+        }
+        if hw_flow_control {
+            ctrl |= CONTROL_FLOW_CONTROL;
+        }
+        regs.control.set(ctrl);
+    }
+
     /// Send an array of bytes synchronously over the UART
     ///
     /// # Safety
@@ -264,10 +383,9 @@ impl UART {
 
     /// Called by the chip following a RX interrupt.
     ///
-    /// This will clear the NVIC pending bit to mark that we've handled the
-    /// interrupt. Then, if there are bytes left in the buffer to send, write
-    /// another batch to the TX FIFO. Otherwise, return the buffer back to the
-    /// client (TODO: no client yet, so not yet implemented).
+    /// Drains the RX FIFO into the outstanding receive buffer, or into the
+    /// ring buffer if there is none, and completes the receive once the
+    /// requested number of bytes has arrived.
     ///
     /// # Invariants
     ///
@@ -277,14 +395,65 @@ impl UART {
     ///
     pub fn handle_rx_interrupt(&self) {
         let regs = unsafe { &*self.regs };
-        // Currently discards bytes: need to read into buffer. -pal 4/11/18
         regs.clear_interrupt_state.set(2);
-        self.client.get().map(|_client| {
-            while regs.state.get() & 1 << 7 == 0 {
-                // While RX FIFO not empty
-                let _b = regs.read_data.get() as u8;
-//                client.receive_complete(b, hil::uart::Error::CommandComplete);
+
+        while regs.state.get() & 1 << 7 == 0 {
+            // While RX FIFO not empty
+            let b = regs.read_data.get() as u8;
+            if self.rx.byte_received(b) {
+                self.receive_complete();
             }
+        }
+    }
+
+    /// Called by the chip following an RX overrun, framing or parity error
+    /// interrupt.
+    ///
+    /// Completes the outstanding receive early with the bytes received so
+    /// far and the error; if there is none, the error is reported by the
+    /// next receive. Overruns are reported in preference to the others
+    /// since they mean bytes were lost.
+    pub fn handle_error_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        let errors = regs.interrupt_state.get() & INTERRUPT_RX_ERRORS;
+        regs.clear_interrupt_state.set(errors);
+
+        let error = if errors & INTERRUPT_RX_OVERRUN != 0 {
+            hil::uart::Error::OverrunError
+        } else if errors & INTERRUPT_RX_FRAMING != 0 {
+            hil::uart::Error::FramingError
+        } else if errors & INTERRUPT_RX_PARITY != 0 {
+            hil::uart::Error::ParityError
+        } else {
+            return;
+        };
+
+        // Keep whatever good bytes are still in the FIFO
+        self.handle_rx_interrupt();
+        if self.rx.is_receiving() {
+            self.receive_complete_with(error);
+        } else {
+            self.rx_error.set(Some(error));
+        }
+    }
+
+    fn set_tx_level(&self, level: TxLevel) {
+        let regs = unsafe { &*self.regs };
+        let fifo = regs.fifo.get() & !FIFO_TX_LEVEL_MASK;
+        regs.fifo.set(fifo | (level as u32) << FIFO_TX_LEVEL_SHIFT);
+    }
+
+    /// Returns the receive buffer to the client with whatever has been
+    /// received so far.
+    fn receive_complete(&self) {
+        self.receive_complete_with(hil::uart::Error::CommandComplete);
+    }
+
+    fn receive_complete_with(&self, error: hil::uart::Error) {
+        self.rx.finish().map(|(buffer, len)| {
+            self.client.get().map(move |client| {
+                client.receive_complete(buffer, len, error);
+            });
         });
     }
 }
@@ -295,22 +464,59 @@ impl hil::uart::UART for UART {
     }
     
     fn transmit(&self, tx_buffer: &'static mut [u8], tx_len: usize) {
+        // Each TX interrupt refills the FIFO, so the fewer bytes left in it
+        // when the interrupt fires, the fewer interrupts a long transmission
+        // takes.
+        if tx_len > BURST_THRESHOLD {
+            self.set_tx_level(TxLevel::One);
+        } else {
+            self.set_tx_level(TxLevel::Sixteen);
+        }
         self.tx_buffer.replace(tx_buffer);
         self.tx_cursor.set(0);
         self.tx_limit.set(tx_len);
         self.send_remaining_bytes();
     }
 
-    fn receive(&self, _rx_buffer: &'static mut[u8], _rx_len: usize) {
-        unimplemented!();
+    /// Receives `rx_len` bytes into `rx_buffer`, starting with any bytes
+    /// buffered since the last receive. If the buffered bytes are enough,
+    /// `receive_complete` is called before this returns. If a receive is
+    /// already outstanding, `rx_buffer` is returned at once with
+    /// `RepeatCallError` and the outstanding one carries on.
+    fn receive(&self, rx_buffer: &'static mut[u8], rx_len: usize) {
+        match self.rx.start(rx_buffer, rx_len) {
+            Err(buffer) => {
+                self.client.get().map(move |client| {
+                    client.receive_complete(buffer, 0, hil::uart::Error::RepeatCallError);
+                });
+            }
+            Ok(complete) => match self.rx_error.take() {
+                Some(error) => self.receive_complete_with(error),
+                None if complete => self.receive_complete(),
+                None => self.enable_rx(),
+            },
+        }
     }
 
+    /// Ends the outstanding receive early, returning the bytes received
+    /// so far through `receive_complete`.
     fn abort_receive(&self) {
-        unimplemented!();
+        self.receive_complete();
     }
 
+    /// Changes the baud rate and framing. Returns EINVAL if the baud rate
+    /// is out of range and EBUSY if a transmission is in progress; a
+    /// receive in progress continues with the new settings.
     fn configure(&self, params: hil::uart::UARTParameters) -> ReturnCode {
+        let nco = Self::nco(params.baud_rate);
+        if nco == 0 || nco > NCO_MAX {
+            return ReturnCode::EINVAL;
+        }
+        if self.tx_buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
         self.config(params.baud_rate);
+        self.set_framing(params.parity, params.stop_bits, params.hw_flow_control);
         ReturnCode::SUCCESS
     }
 }
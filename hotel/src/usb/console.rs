@@ -0,0 +1,204 @@
+//! Console transport over the USB shell interface
+//!
+//! `USB_CONSOLE` implements `hil::uart::UART` on top of the vendor-specific
+//! bulk "shell" interface (endpoint 2), for boards that offer
+//! `SHELL_INTERFACE`, so a board can route the console and debug output
+//! over the same cable as U2F instead of needing UART pins. Writes are split into
+//! 64-byte packets; reads are filled from whatever packets the host sends.
+//!
+//! There is no flow control with the host: a write while the device is not
+//! configured (no host, or before enumeration) is handed back, after
+//! `transmit` returns, with `ResetError` (the UART HIL's EOFF) rather than
+//! stalling the console, and input that arrives while no receive is
+//! outstanding is kept in a small ring buffer (`receiver::RING_SIZE` bytes)
+//! and beyond that dropped, as with the UART.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use deferred_call::{DeferredCall, Task};
+use kernel::ReturnCode;
+use memory::BufferUsage;
+use receiver::{Receiver, RING_SIZE};
+
+use super::dwc_otg::{EndpointClient, EndpointType, Interface, EP2_BUFFERS, MAX_PACKET_SIZE};
+use super::{STRING_INTERFACE1, USB0};
+
+/// The shell interface's bulk endpoint
+const SHELL_ENDPOINT: usize = 2;
+
+/// The vendor-specific shell interface
+pub const SHELL_INTERFACE: Interface = Interface {
+    class: 0xFF,
+    sub_class: 80,
+    protocol: 1,
+    string: STRING_INTERFACE1,
+    endpoint: SHELL_ENDPOINT,
+    transfer: EndpointType::Bulk,
+    interval: 0,
+    // Output spans packets
+    tx_fifo_packets: 2,
+    report_descriptor: None,
+};
+
+pub static mut USB_CONSOLE: UsbConsole = UsbConsole::new();
+
+// Bytes waiting in the receive ring, including any dropped
+static mut RX_RING_USAGE: BufferUsage = BufferUsage::new("usb console rx ring");
+
+// Hands back a transmission refused because the device isn't configured
+static DEFERRED_CALL: DeferredCall = DeferredCall::new(Task::UsbConsole);
+
+pub struct UsbConsole {
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_limit: Cell<usize>,
+    tx_cursor: Cell<usize>,
+    // Length of the packet currently queued on the endpoint
+    tx_packet_len: Cell<usize>,
+    rx: Receiver,
+    client: Cell<Option<&'static hil::uart::Client>>,
+    // Set once a synchronous write times out, so later ones don't wait too
+    sync_failed: Cell<bool>,
+}
+
+impl UsbConsole {
+    const fn new() -> UsbConsole {
+        UsbConsole {
+            tx_buffer: TakeCell::empty(),
+            tx_limit: Cell::new(0),
+            tx_cursor: Cell::new(0),
+            tx_packet_len: Cell::new(0),
+            rx: Receiver::new(),
+            client: Cell::new(None),
+            sync_failed: Cell::new(false),
+        }
+    }
+
+    /// Binds the console to the shell endpoint. Must be called before
+    /// `USB0.init` so the endpoint is activated when the host configures
+    /// the device.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe {
+            RX_RING_USAGE.register(RING_SIZE);
+            USB0.setup_endpoint(SHELL_ENDPOINT, EndpointType::Bulk, &mut EP2_BUFFERS, self)
+        }
+    }
+
+    /// Writes `bytes` synchronously, bypassing any outstanding
+    /// transmission, for the panic handler. Does nothing if no host is
+    /// collecting output.
+    pub unsafe fn write_sync(&self, bytes: &[u8]) {
+        if !self.sync_failed.get() && !USB0.transmit_sync(SHELL_ENDPOINT, bytes) {
+            self.sync_failed.set(true);
+        }
+    }
+
+    /// Hands back a transmission refused by `transmit`.
+    pub fn handle_deferred_call(&self) {
+        self.transmit_complete(hil::uart::Error::ResetError);
+    }
+
+    /// Queues the next packet of the current transmission, completing it
+    /// once everything has been sent or if the host stops listening.
+    fn send_next_packet(&self) {
+        let cursor = self.tx_cursor.get();
+        let limit = self.tx_limit.get();
+        if cursor == limit {
+            self.transmit_complete(hil::uart::Error::CommandComplete);
+            return;
+        }
+        let len = cmp::min(limit - cursor, MAX_PACKET_SIZE as usize);
+        let result = self.tx_buffer.map_or(ReturnCode::FAIL, |buffer| unsafe {
+            USB0.transmit_packet(SHELL_ENDPOINT, &buffer[cursor..cursor + len])
+        });
+        if result == ReturnCode::SUCCESS {
+            self.tx_packet_len.set(len);
+        } else {
+            self.transmit_complete(hil::uart::Error::ResetError);
+        }
+    }
+
+    fn transmit_complete(&self, error: hil::uart::Error) {
+        self.tx_buffer.take().map(|buffer| {
+            self.client.get().map(move |client| {
+                client.transmit_complete(buffer, error);
+            });
+        });
+    }
+
+    /// Returns the receive buffer to the client with whatever has been
+    /// received so far.
+    fn receive_complete(&self) {
+        self.rx.finish().map(|(buffer, len)| {
+            self.client.get().map(move |client| {
+                client.receive_complete(buffer, len, hil::uart::Error::CommandComplete);
+            });
+        });
+    }
+}
+
+impl EndpointClient for UsbConsole {
+    fn packet_received(&self, _endpoint: usize, packet: &[u8]) {
+        for &b in packet.iter() {
+            if !self.rx.is_receiving() {
+                unsafe { RX_RING_USAGE.record(self.rx.buffered() + 1) };
+            }
+            if self.rx.byte_received(b) {
+                self.receive_complete();
+            }
+        }
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {
+        self.tx_cursor.set(self.tx_cursor.get() + self.tx_packet_len.get());
+        self.tx_packet_len.set(0);
+        self.send_next_packet();
+    }
+}
+
+impl hil::uart::UART for UsbConsole {
+    fn set_client(&self, client: &'static hil::uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn transmit(&self, tx_buffer: &'static mut [u8], tx_len: usize) {
+        let limit = cmp::min(tx_len, tx_buffer.len());
+        self.tx_buffer.replace(tx_buffer);
+        self.tx_cursor.set(0);
+        self.tx_limit.set(limit);
+        if unsafe { USB0.is_configured() } {
+            self.send_next_packet();
+        } else {
+            DEFERRED_CALL.set();
+        }
+    }
+
+    /// Receives `rx_len` bytes into `rx_buffer`, starting with any bytes
+    /// buffered since the last receive. If the buffered bytes are enough,
+    /// `receive_complete` is called before this returns. If a receive is
+    /// already outstanding, `rx_buffer` is returned at once with
+    /// `RepeatCallError`.
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        match self.rx.start(rx_buffer, rx_len) {
+            Err(buffer) => {
+                self.client.get().map(move |client| {
+                    client.receive_complete(buffer, 0, hil::uart::Error::RepeatCallError);
+                });
+            }
+            Ok(true) => self.receive_complete(),
+            Ok(false) => {}
+        }
+    }
+
+    /// Ends the outstanding receive early, returning the bytes received
+    /// so far through `receive_complete`.
+    fn abort_receive(&self) {
+        self.receive_complete();
+    }
+
+    /// The baud rate and framing have no meaning over USB.
+    fn configure(&self, _params: hil::uart::UARTParameters) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+}
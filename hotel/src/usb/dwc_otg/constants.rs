@@ -1,15 +1,10 @@
-#![allow(dead_code)]
-
-
-// The USB stack currently expects 7 strings, at these indices.
-pub const STRING_LANG: u8       = 0;
+// The device and configuration descriptors name themselves with the
+// strings at these indices, and report the serial number if the board
+// provides it. Indices 4 to 6 are left for the interfaces' strings.
 pub const STRING_VENDOR: u8     = 1;
 pub const STRING_BOARD: u8      = 2;
 pub const STRING_PLATFORM: u8   = 3;
-pub const STRING_INTERFACE1: u8 = 4;  // Shell
-pub const STRING_BLAH: u8       = 5;  // Garbage?
-pub const STRING_INTERFACE2: u8 = 6;  // Haven_U2F
-
+pub const STRING_SERIAL: u8     = 7;
 
 pub const SOF: u32           = 1 << 3;
 pub const EARLY_SUSPEND: u32 = 1 << 10;
@@ -20,16 +15,15 @@ pub const IEPINT: u32        = 1 << 18;
 pub const OEPINT: u32        = 1 << 19;
 pub const GOUTNAKEFF: u32    = 1 << 7;
 pub const GINNAKEFF: u32     = 1 << 6;
+pub const RESUME_WAKEUP: u32 = 1 << 31;
+
+// Power and clock gating control: stop the PHY clock
+pub const PCGCCTL_STOP_PCLK: u32 = 1 << 0;
 
-const MAX_CONTROL_ENDPOINTS: u16 = 3;
-const MAX_NORMAL_ENDPOINTS: u16 = 16;
 pub const MAX_PACKET_SIZE: u16 = 64;
 
-// Ask Amit 
-pub const RX_FIFO_SIZE: u16 = (4 * MAX_CONTROL_ENDPOINTS + 6) +
-                              (2 * (MAX_PACKET_SIZE / 4 + 1)) +
-                              (2 * MAX_NORMAL_ENDPOINTS) + 1;
-pub const TX_FIFO_SIZE: u16 = 2 * MAX_PACKET_SIZE / 4;
+/// Bytes reserved for the serialized configuration descriptor
+pub const CONFIGURATION_BUFFER_SIZE: usize = 128;
 
 #[derive(PartialEq)]
 pub enum Interrupt {
@@ -189,33 +183,8 @@ impl Descriptor {
     }
 }
 
-#[allow(dead_code)]
 pub const GET_DESCRIPTOR_DEVICE: u32           = 1;
 pub const GET_DESCRIPTOR_CONFIGURATION: u32    = 2;
 pub const GET_DESCRIPTOR_STRING: u32           = 3;
 pub const GET_DESCRIPTOR_INTERFACE: u32        = 4;
-pub const GET_DESCRIPTOR_ENDPOINT: u32         = 5;
 pub const GET_DESCRIPTOR_DEVICE_QUALIFIER: u32 = 6;
-pub const GET_DESCRIPTOR_DEBUG: u32            = 10;
-
-// Copied from Cr52 usb_hidu2f.c - pal
-pub const U2F_REPORT_DESCRIPTOR: [u8; 34] = [
-    0x06, 0xD0, 0xF1, /* Usage Page (FIDO Alliance), FIDO_USAGE_PAGE */
-    0x09, 0x01,       /* Usage (U2F HID Authenticator Device),
-                         FIDO_USAGE_U2FHID */
-    0xA1, 0x01,       /* Collection (Application), HID_APPLICATION */
-    0x09, 0x20,       /*   Usage (Input Report Data), FIDO_USAGE_DATA_IN */
-    0x15, 0x00,       /*   Logical Minimum (0) */
-    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
-    0x75, 0x08,       /*   Report Size (8) */
-    0x95, 0x40,       /*   Report Count (64), HID_INPUT_REPORT_BYTES */
-    0x81, 0x02,       /*   Input (Data, Var, Abs), Usage */
-    0x09, 0x21,       /*   Usage (Output Report Data), FIDO_USAGE_DATA_OUT */
-    0x15, 0x00,       /*   Logical Minimum (0) */
-    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
-    0x75, 0x08,       /*   Report Size (8) */
-    0x95, 0x40,       /*   Report Count (64), HID_OUTPUT_REPORT_BYTES */
-    0x91, 0x02,       /*   Output (Data, Var, Abs), Usage */
-    0xC0              /* End Collection */
-];
-
@@ -0,0 +1,331 @@
+//! Data (non-control) endpoints
+//!
+//! Endpoints 1 through `NUM_DATA_ENDPOINTS` can each be bound to a client
+//! with `USB::setup_endpoint`. Every data endpoint has one IN and one OUT
+//! descriptor pointing at a single 64-byte buffer each, so it moves at most
+//! one maximum-size packet per transfer in each direction: a client sends
+//! a packet with `USB::transmit_packet` and is told when it has gone out,
+//! and is handed every packet the host sends to its OUT endpoint.
+//!
+//! Endpoints are activated when the host selects a configuration and are
+//! deactivated by a bus reset, so a client should not assume the host is
+//! listening until it has received a packet or a transmission completed.
+//!
+//! The interrupt handler only notes which endpoints finished a transfer;
+//! clients are called back later from a deferred call, so they can queue
+//! the next packet without re-entering the handler. An OUT endpoint stays
+//! NAKing the host until its packet has been handed to the client.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+
+use deferred_call::{DeferredCall, Task};
+
+use super::USB;
+use super::constants::MAX_PACKET_SIZE;
+use super::interface::ClassHandler;
+use super::registers::{DMADescriptor, DescFlag, EpCtl};
+
+/// Number of data endpoints the driver supports (endpoints 1-3).
+pub const NUM_DATA_ENDPOINTS: usize = 3;
+
+/// Descriptors and packet buffers for one data endpoint.
+pub struct EndpointBuffers {
+    in_descriptor: DMADescriptor,
+    in_buffer: [u32; 16],
+    out_descriptor: DMADescriptor,
+    out_buffer: [u32; 16],
+}
+
+impl EndpointBuffers {
+    pub const fn new() -> EndpointBuffers {
+        EndpointBuffers {
+            in_descriptor: DMADescriptor {
+                flags: DescFlag::HOST_BUSY,
+                addr: 0,
+            },
+            in_buffer: [0; 16],
+            out_descriptor: DMADescriptor {
+                flags: DescFlag::HOST_BUSY,
+                addr: 0,
+            },
+            out_buffer: [0; 16],
+        }
+    }
+}
+
+// Statically allocated buffers for the data endpoints
+pub static mut EP1_BUFFERS: EndpointBuffers = EndpointBuffers::new();
+pub static mut EP2_BUFFERS: EndpointBuffers = EndpointBuffers::new();
+pub static mut EP3_BUFFERS: EndpointBuffers = EndpointBuffers::new();
+
+/// Transfer types supported on data endpoints
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EndpointType {
+    Bulk = 0b10,
+    Interrupt = 0b11,
+}
+
+/// Callbacks for traffic on a data endpoint.
+pub trait EndpointClient {
+    /// The host sent `packet` to the endpoint's OUT direction. The data is
+    /// only valid for the duration of the call.
+    fn packet_received(&self, endpoint: usize, packet: &[u8]);
+
+    /// The packet passed to `transmit_packet` was collected by the host.
+    fn packet_transmitted(&self, endpoint: usize);
+}
+
+/// Per-endpoint driver state
+pub struct EndpointState {
+    buffers: TakeCell<'static, EndpointBuffers>,
+    endpoint_type: Cell<EndpointType>,
+    client: Cell<Option<&'static EndpointClient>>,
+    // Serves class requests to the interface on the endpoint
+    pub(super) class_handler: Cell<Option<&'static ClassHandler>>,
+    in_busy: Cell<bool>,
+    // Transfers that completed since the last deferred call
+    received: Cell<bool>,
+    transmitted: Cell<bool>,
+}
+
+impl EndpointState {
+    pub const fn new() -> EndpointState {
+        EndpointState {
+            buffers: TakeCell::empty(),
+            endpoint_type: Cell::new(EndpointType::Bulk),
+            client: Cell::new(None),
+            class_handler: Cell::new(None),
+            in_busy: Cell::new(false),
+            received: Cell::new(false),
+            transmitted: Cell::new(false),
+        }
+    }
+
+    /// Copies of the endpoint's IN and OUT descriptors, unless they are
+    /// borrowed or the endpoint was never set up.
+    pub fn descriptors(&self) -> Option<(DMADescriptor, DMADescriptor)> {
+        self.buffers.map(|buffers| (buffers.in_descriptor, buffers.out_descriptor))
+    }
+}
+
+/// Endpoint control: endpoint is active in the current configuration
+const EPCTL_USB_ACTIVE: u32 = 1 << 15;
+/// Endpoint control: set DATA0 PID
+const EPCTL_SET_D0_PID: u32 = 1 << 28;
+
+/// Polls of the interrupt register before `transmit_sync` gives up on a
+/// packet (a few hundred milliseconds).
+const SYNC_SPIN_LIMIT: u32 = 1_000_000;
+
+static DEFERRED_CALL: DeferredCall = DeferredCall::new(Task::Usb);
+
+fn as_bytes(words: &mut [u32; 16]) -> &mut [u8; 64] {
+    // A [u32; 16] has the same size as and stricter alignment than a
+    // [u8; 64]; the controller's DMA engine is little-endian like the core.
+    unsafe { &mut *(words as *mut [u32; 16] as *mut [u8; 64]) }
+}
+
+impl USB {
+    /// Binds data endpoint `endpoint` (its IN and OUT directions) to
+    /// `client`. The endpoint becomes usable once the host sets a
+    /// configuration.
+    pub fn setup_endpoint(&self,
+                          endpoint: usize,
+                          endpoint_type: EndpointType,
+                          buffers: &'static mut EndpointBuffers,
+                          client: &'static EndpointClient) -> ReturnCode {
+        if endpoint < 1 || endpoint > NUM_DATA_ENDPOINTS {
+            return ReturnCode::EINVAL;
+        }
+        let state = &self.endpoints[endpoint - 1];
+        if state.buffers.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        state.buffers.replace(buffers);
+        state.endpoint_type.set(endpoint_type);
+        state.client.set(Some(client));
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether the host has configured the device, i.e., data endpoints are
+    /// active.
+    pub fn is_configured(&self) -> bool {
+        self.configuration_current_value.get() != 0
+    }
+
+    /// Queues `packet` (at most 64 bytes) to be sent to the host on
+    /// `endpoint`. Returns EBUSY if the previous packet has not been sent
+    /// yet and EOFF if the host has not configured the device.
+    pub fn transmit_packet(&self, endpoint: usize, packet: &[u8]) -> ReturnCode {
+        if endpoint < 1 || endpoint > NUM_DATA_ENDPOINTS {
+            return ReturnCode::EINVAL;
+        }
+        if !self.is_configured() {
+            return ReturnCode::EOFF;
+        }
+        let state = &self.endpoints[endpoint - 1];
+        if state.in_busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let len = cmp::min(packet.len(), MAX_PACKET_SIZE as usize);
+        state.buffers.map_or(ReturnCode::ENOSUPPORT, |bufs| {
+            as_bytes(&mut bufs.in_buffer)[..len].copy_from_slice(&packet[..len]);
+            bufs.in_descriptor.addr = bufs.in_buffer.as_ptr() as usize;
+            bufs.in_descriptor.flags = (DescFlag::HOST_READY |
+                                        DescFlag::LAST |
+                                        DescFlag::SHORT |
+                                        DescFlag::IOC).bytes(len as u16);
+            state.in_busy.set(true);
+            let ep = &self.registers.in_endpoints[endpoint];
+            ep.dma_address.set(&bufs.in_descriptor);
+            ep.control.set(ep.control.get() | EpCtl::ENABLE | EpCtl::CNAK);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Whether a packet queued on `endpoint` is waiting for the host.
+    pub fn is_transmitting(&self, endpoint: usize) -> bool {
+        endpoint >= 1 && endpoint <= NUM_DATA_ENDPOINTS &&
+            self.endpoints[endpoint - 1].in_busy.get()
+    }
+
+    /// Sends `bytes` on `endpoint` by polling the controller rather than
+    /// waiting for interrupts, for use when they are unavailable (e.g. in
+    /// the panic handler). Returns false, possibly after sending part of
+    /// `bytes`, if the host is not configured or stops collecting packets.
+    pub unsafe fn transmit_sync(&self, endpoint: usize, bytes: &[u8]) -> bool {
+        if endpoint < 1 || endpoint > NUM_DATA_ENDPOINTS {
+            return false;
+        }
+        if self.endpoints[endpoint - 1].in_busy.get() && !self.wait_in_complete(endpoint) {
+            return false;
+        }
+        for packet in bytes.chunks(MAX_PACKET_SIZE as usize) {
+            if self.transmit_packet(endpoint, packet) != ReturnCode::SUCCESS ||
+                !self.wait_in_complete(endpoint) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Busy-waits for the packet queued on `endpoint` to be sent.
+    fn wait_in_complete(&self, endpoint: usize) -> bool {
+        let ep = &self.registers.in_endpoints[endpoint];
+        for _ in 0..SYNC_SPIN_LIMIT {
+            if ep.interrupt.get() & 1 != 0 {
+                // XferCompl
+                ep.interrupt.set(1);
+                self.endpoints[endpoint - 1].in_busy.set(false);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Activates every bound data endpoint; called when the host sets a
+    /// configuration.
+    pub(super) fn activate_endpoints(&self) {
+        for (i, state) in self.endpoints.iter().enumerate() {
+            let endpoint = i + 1;
+            state.in_busy.set(false);
+            state.buffers.map(|bufs| {
+                let common = EPCTL_USB_ACTIVE |
+                    EPCTL_SET_D0_PID |
+                    (state.endpoint_type.get() as u32) << 18 |
+                    MAX_PACKET_SIZE as u32;
+
+                // IN endpoint n uses TX FIFO n
+                self.registers.in_endpoints[endpoint].control.set(
+                    EpCtl(common | (endpoint as u32) << 22));
+                self.registers.out_endpoints[endpoint].control.set(EpCtl(common));
+                self.arm_out_endpoint(endpoint, bufs);
+
+                self.registers.device_all_ep_interrupt_mask.set(
+                    self.registers.device_all_ep_interrupt_mask.get() |
+                    1 << endpoint | 1 << (16 + endpoint));
+            });
+        }
+    }
+
+    /// Deactivates all data endpoints, e.g. on a bus reset.
+    pub(super) fn deactivate_endpoints(&self) {
+        for (i, state) in self.endpoints.iter().enumerate() {
+            let endpoint = i + 1;
+            state.in_busy.set(false);
+            state.received.set(false);
+            state.transmitted.set(false);
+            self.registers.in_endpoints[endpoint].control.set(EpCtl(0));
+            self.registers.out_endpoints[endpoint].control.set(EpCtl(0));
+        }
+        self.registers.device_all_ep_interrupt_mask.set(
+            self.registers.device_all_ep_interrupt_mask.get() & 0x00010001);
+    }
+
+    /// Prepares the OUT descriptor of `endpoint` to receive a packet.
+    fn arm_out_endpoint(&self, endpoint: usize, bufs: &'static mut EndpointBuffers) {
+        bufs.out_descriptor.addr = bufs.out_buffer.as_ptr() as usize;
+        bufs.out_descriptor.flags = (DescFlag::HOST_READY |
+                                     DescFlag::LAST |
+                                     DescFlag::IOC).bytes(MAX_PACKET_SIZE);
+        let ep = &self.registers.out_endpoints[endpoint];
+        ep.dma_address.set(&bufs.out_descriptor);
+        ep.control.set(ep.control.get() | EpCtl::ENABLE | EpCtl::CNAK);
+    }
+
+    /// Handles IN/OUT events on data endpoint `endpoint`, leaving the
+    /// client callbacks to `handle_deferred_call`.
+    pub(super) fn handle_data_endpoint_events(&self, endpoint: usize, inter_out: bool, inter_in: bool) {
+        let state = &self.endpoints[endpoint - 1];
+
+        if inter_in {
+            let ep = &self.registers.in_endpoints[endpoint];
+            let interrupts = ep.interrupt.get();
+            ep.interrupt.set(interrupts);
+            if interrupts & 1 != 0 && state.in_busy.get() {
+                // XferCompl
+                state.in_busy.set(false);
+                state.transmitted.set(true);
+                DEFERRED_CALL.set();
+            }
+        }
+
+        if inter_out {
+            let ep = &self.registers.out_endpoints[endpoint];
+            let interrupts = ep.interrupt.get();
+            ep.interrupt.set(interrupts);
+            if interrupts & 1 != 0 {
+                // XferCompl
+                state.received.set(true);
+                DEFERRED_CALL.set();
+            }
+        }
+    }
+
+    /// Hands completed transfers to the endpoints' clients and re-arms the
+    /// OUT endpoints that received a packet.
+    pub fn handle_deferred_call(&self) {
+        for (i, state) in self.endpoints.iter().enumerate() {
+            let endpoint = i + 1;
+            if state.received.replace(false) {
+                // The descriptor's byte count now holds how many of the 64
+                // bytes were not filled.
+                let mut packet = [0u8; 64];
+                let len = state.buffers.map_or(0, |bufs| {
+                    let remaining = (bufs.out_descriptor.flags.0 & 0xffff) as usize;
+                    let len = (MAX_PACKET_SIZE as usize).saturating_sub(remaining);
+                    packet[..len].copy_from_slice(&as_bytes(&mut bufs.out_buffer)[..len]);
+                    self.arm_out_endpoint(endpoint, bufs);
+                    len
+                });
+                state.client.get().map(|client| client.packet_received(endpoint, &packet[..len]));
+            }
+            if state.transmitted.replace(false) {
+                state.client.get().map(|client| client.packet_transmitted(endpoint));
+            }
+        }
+    }
+}
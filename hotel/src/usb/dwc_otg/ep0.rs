@@ -0,0 +1,536 @@
+//! The endpoint 0 control transfer state machine
+//!
+//! A control transfer is a SETUP stage, an optional data stage in either
+//! direction and a status stage in the other. Each stage is a type that
+//! owns the endpoint 0 descriptors and buffers, and only offers what may be
+//! done with them in that stage: only `Setup` reads the SETUP packet and
+//! fills the IN buffer, since nothing is being sent while it waits, and only
+//! `DataOut` reads a data stage, for the `ControlWrite` it carries. Moving
+//! to the next stage consumes the current one, so nothing is left that
+//! could touch a buffer the DMA engine has been given.
+//!
+//! `Stage` holds whichever stage the transfer has got to. `step` gives,
+//! for every stage and every `Event` endpoint 0's interrupts can report,
+//! what the driver does next, and hands it the stage to do it with. None of
+//! them panic: an event the stage doesn't expect is either ignored or
+//! answered by re-arming for the next SETUP, since the host will retry.
+//!
+//! | Stage   | Setup       | SetupDone   | Data(A)     | Data(C)     | Data(E)     | Other       |
+//! | ------- | ----------- | ----------- | ----------- | ----------- | ----------- | ----------- |
+//! | Setup   | HandleSetup | Stall       | ExpectSetup | ExpectSetup | Wait        | Wait        |
+//! | DataIn  | HandleSetup | ClearNaks   | ExpectSetup | ExpectSetup | Wait        | Wait        |
+//! | DataOut | HandleSetup | ClearOutNak | Received    | Wait        | Received    | Wait        |
+//! | Status  | HandleSetup | ClearNaks   | ExpectSetup | ExpectSetup | ExpectSetup | ExpectSetup |
+
+use core::fmt;
+use trace;
+
+use super::constants::{AllEndpointInterruptMask, Reset, MAX_PACKET_SIZE};
+use super::registers::{DescFlag, DMADescriptor, EpCtl, Registers};
+use super::types::SetupRequest;
+use super::vendor::VendorRequest;
+use super::{copy_from_words, TableCase};
+
+/// How many times to poll for the transmit FIFO flush to finish, as
+/// `soft_reset` does, rather than hang if the core never finishes it
+const FLUSH_TIMEOUT: usize = 10000;
+
+/// Where the data stage of a host-to-device control transfer goes once
+/// it has been received
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlWrite {
+    /// SET_REPORT of a report of type `report_type` and ID `report_id` to
+    /// interface `interface`, for its class handler
+    Report { interface: u16, report_type: u8, report_id: u8 },
+    Vendor(VendorRequest),
+}
+
+/// How the driver answers a SETUP packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// Send the first `len` bytes of the IN buffer in the data stage
+    Send(usize),
+    /// Receive the data stage, for the `ControlWrite`
+    Receive(ControlWrite),
+    /// There is no data stage: go straight to the status stage
+    Acknowledge,
+    /// Refuse the request
+    Stall,
+}
+
+/// Endpoint 0's two OUT descriptors and the buffers they receive into.
+/// They take turns, so a packet can arrive while the last is read.
+pub struct OutQueue {
+    descriptors: &'static mut [DMADescriptor; 2],
+    buffers: &'static [[u32; 16]; 2],
+    // The descriptor armed for the next packet, and the one that received
+    // the most recent
+    next: usize,
+    last: usize,
+}
+
+impl OutQueue {
+    /// Points the descriptors at their buffers, both idle, and the DMA
+    /// engine at the first.
+    fn reset(&mut self, registers: &Registers) {
+        for (desc, buf) in self.descriptors.iter_mut().zip(self.buffers.iter()) {
+            desc.flags = DescFlag::HOST_BUSY;
+            desc.addr = buf.as_ptr() as usize;
+        }
+        self.next = 0;
+        self.last = 0;
+        registers.out_endpoints[0].dma_address.set(dma_address(&self.descriptors[0]));
+    }
+
+    /// Sets the descriptor for the next packet to receive up to 64 bytes
+    /// with `flags`.
+    fn arm(&mut self, flags: DescFlag) {
+        self.descriptors[self.next].flags = flags.bytes(64);
+    }
+
+    /// A packet arrived in the next descriptor, so it becomes the last one
+    /// and the other is given to the DMA engine for the packet after.
+    pub fn swap(&mut self, registers: &Registers) {
+        self.last = self.next;
+        self.next = (self.next + 1) % self.descriptors.len();
+        registers.out_endpoints[0].dma_address.set(dma_address(&self.descriptors[self.next]));
+    }
+
+    /// The flags the DMA engine left in the descriptor of the most recent
+    /// packet.
+    pub fn last_flags(&self) -> DescFlag {
+        self.descriptors[self.last].flags
+    }
+
+    /// The index of the descriptor armed for the next packet and of the
+    /// one that received the most recent, for the panic dump.
+    pub fn indices(&self) -> (usize, usize) {
+        (self.next, self.last)
+    }
+
+    pub fn descriptors(&self) -> &[DMADescriptor; 2] {
+        self.descriptors
+    }
+
+    fn last_packet(&self) -> &[u32; 16] {
+        &self.buffers[self.last]
+    }
+}
+
+/// Endpoint 0's four IN descriptors and the buffer they send from, which is
+/// one large buffer so a response can be copied into it as one blob.
+pub struct InQueue {
+    descriptors: &'static mut [DMADescriptor; 4],
+    buffer: &'static mut [u32; 16 * 4],
+}
+
+impl InQueue {
+    /// Points the descriptors at successive packets of the buffer, all
+    /// idle, and the DMA engine at the first.
+    fn reset(&mut self, registers: &Registers) {
+        let base = self.buffer.as_ptr() as usize;
+        for (i, desc) in self.descriptors.iter_mut().enumerate() {
+            desc.flags = DescFlag::HOST_BUSY;
+            desc.addr = base + i * MAX_PACKET_SIZE as usize;
+        }
+        registers.in_endpoints[0].dma_address.set(dma_address(&self.descriptors[0]));
+    }
+
+    /// Sends the first `len` bytes of the buffer, in case `case`.
+    fn send(&mut self, registers: &Registers, case: TableCase, len: usize) {
+        self.descriptors[0].addr = self.buffer.as_ptr() as usize;
+        self.descriptors[0].flags =
+            (DescFlag::HOST_READY | DescFlag::LAST | DescFlag::SHORT | DescFlag::IOC).bytes(len as u16);
+        flush_in_fifo(registers);
+        registers.in_endpoints[0].dma_address.set(dma_address(&self.descriptors[0]));
+        // If we clear the NAK (write CNAK) then this responds to a
+        // non-setup packet, leading to failure as the code needs to first
+        // respond to a setup packet.
+        registers.in_endpoints[0].control.set(enable(case));
+    }
+
+    pub fn descriptors(&self) -> &[DMADescriptor; 4] {
+        self.descriptors
+    }
+}
+
+/// Waiting for a SETUP packet. Nothing is being sent, so the IN buffer is
+/// free for the response.
+pub struct Setup {
+    out: OutQueue,
+    response: InQueue,
+}
+
+impl Setup {
+    /// The SETUP packet the last OUT descriptor received.
+    pub fn request(&self) -> SetupRequest {
+        SetupRequest::new(self.out.last_packet())
+    }
+
+    /// The IN buffer, to write a response into before `respond`.
+    pub fn response(&mut self) -> &mut [u32; 16 * 4] {
+        self.response.buffer
+    }
+
+    /// Answers the SETUP packet, which arrived in table case `case`.
+    pub fn respond(self, registers: &Registers, case: TableCase, response: Response) -> Stage {
+        match response {
+            Response::Send(len) => Stage::DataIn(self.send(registers, case, len)),
+            Response::Receive(target) => Stage::DataOut(self.receive(registers, case, target)),
+            Response::Acknowledge => Stage::Status(status(self.out, self.response, registers, case)),
+            Response::Stall => Stage::Setup(Stage::Setup(self).stall(registers)),
+        }
+    }
+
+    /// Arms for the next SETUP packet.
+    pub fn expect_setup(mut self, registers: &Registers) -> Setup {
+        self.out.arm(DescFlag::HOST_READY | DescFlag::LAST | DescFlag::IOC);
+        set_interrupts(registers, false);
+        // Clearing the NAK bit tells host that device is ready to receive.
+        registers.out_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::CNAK);
+        self
+    }
+
+    fn send(mut self, registers: &Registers, case: TableCase, len: usize) -> DataIn {
+        self.response.send(registers, case, len);
+        // The host's zero-length status packet comes next
+        self.out.arm(DescFlag::HOST_READY | DescFlag::LAST | DescFlag::IOC);
+        registers.out_endpoints[0].control.set(enable(case));
+        set_interrupts(registers, true);
+        DataIn {
+            out: self.out,
+            sending: self.response,
+        }
+    }
+
+    fn receive(mut self, registers: &Registers, case: TableCase, target: ControlWrite) -> DataOut {
+        self.out.arm(DescFlag::HOST_READY | DescFlag::LAST | DescFlag::IOC);
+        registers.out_endpoints[0].control.set(enable(case));
+        // The IN status stage follows the data, so only OUT interrupts are
+        // needed until then.
+        set_interrupts(registers, false);
+        DataOut {
+            out: self.out,
+            idle: self.response,
+            target: target,
+        }
+    }
+}
+
+/// Sending the response in the IN buffer, which belongs to the DMA engine
+/// until the host has it
+pub struct DataIn {
+    out: OutQueue,
+    sending: InQueue,
+}
+
+/// Receiving the data of a command from the host, for the `ControlWrite`
+pub struct DataOut {
+    out: OutQueue,
+    idle: InQueue,
+    target: ControlWrite,
+}
+
+impl DataOut {
+    /// Where the data goes.
+    pub fn target(&self) -> ControlWrite {
+        self.target
+    }
+
+    /// Copies the data stage the last OUT descriptor received into `data`,
+    /// and returns its length.
+    pub fn data(&self, data: &mut [u8; MAX_PACKET_SIZE as usize]) -> usize {
+        // The descriptor's byte count now holds how many of the 64 bytes
+        // were not filled.
+        let remaining = (self.out.last_flags().0 & 0xffff) as usize;
+        let len = data.len().saturating_sub(remaining);
+        copy_from_words(self.out.last_packet(), &mut data[..len]);
+        len
+    }
+
+    /// The SETUP stage is over: lets the data come in.
+    pub fn clear_nak(self, registers: &Registers) -> DataOut {
+        registers.out_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::CNAK);
+        self
+    }
+
+    /// The data was taken: acknowledges it in the status stage, straight
+    /// away as for case C since the data stage is over.
+    pub fn acknowledge(self, registers: &Registers) -> Status {
+        status(self.out, self.idle, registers, TableCase::C)
+    }
+}
+
+/// Sending the zero-length status packet, e.g. in response to a set
+/// command
+pub struct Status {
+    out: OutQueue,
+    sending: InQueue,
+}
+
+/// How far the control transfer on endpoint 0 has got
+pub enum Stage {
+    Setup(Setup),
+    DataIn(DataIn),
+    DataOut(DataOut),
+    Status(Status),
+}
+
+impl Stage {
+    /// Takes endpoint 0's descriptors and buffers, which stay with the
+    /// driver from then on. Waits for a SETUP once the bus is `reset`.
+    pub fn new(out_descriptors: &'static mut [DMADescriptor; 2],
+               out_buffers: &'static [[u32; 16]; 2],
+               in_descriptors: &'static mut [DMADescriptor; 4],
+               in_buffer: &'static mut [u32; 16 * 4]) -> Stage {
+        Stage::Setup(Setup {
+            out: OutQueue {
+                descriptors: out_descriptors,
+                buffers: out_buffers,
+                next: 0,
+                last: 0,
+            },
+            response: InQueue {
+                descriptors: in_descriptors,
+                buffer: in_buffer,
+            },
+        })
+    }
+
+    /// A number for the event trace
+    pub fn code(&self) -> u32 {
+        match *self {
+            Stage::Setup(_) => 0,
+            Stage::DataIn(_) => 1,
+            Stage::DataOut(_) => 2,
+            Stage::Status(_) => 3,
+        }
+    }
+
+    pub fn out_queue(&self) -> &OutQueue {
+        match *self {
+            Stage::Setup(ref stage) => &stage.out,
+            Stage::DataIn(ref stage) => &stage.out,
+            Stage::DataOut(ref stage) => &stage.out,
+            Stage::Status(ref stage) => &stage.out,
+        }
+    }
+
+    pub fn out_queue_mut(&mut self) -> &mut OutQueue {
+        match *self {
+            Stage::Setup(ref mut stage) => &mut stage.out,
+            Stage::DataIn(ref mut stage) => &mut stage.out,
+            Stage::DataOut(ref mut stage) => &mut stage.out,
+            Stage::Status(ref mut stage) => &mut stage.out,
+        }
+    }
+
+    pub fn in_queue(&self) -> &InQueue {
+        match *self {
+            Stage::Setup(ref stage) => &stage.response,
+            Stage::DataIn(ref stage) => &stage.sending,
+            Stage::DataOut(ref stage) => &stage.idle,
+            Stage::Status(ref stage) => &stage.sending,
+        }
+    }
+
+    /// Gives up on the transfer, without touching the controller: after a
+    /// bus reset or a new SETUP, the host has given up on it too.
+    pub fn abandon(self) -> Setup {
+        match self {
+            Stage::Setup(stage) => stage,
+            Stage::DataIn(DataIn { out, sending }) |
+            Stage::Status(Status { out, sending }) => Setup { out: out, response: sending },
+            Stage::DataOut(DataOut { out, idle, .. }) => Setup { out: out, response: idle },
+        }
+    }
+
+    /// Resets endpoint 0's descriptors after a USB reset, and waits for the
+    /// first SETUP packet of the enumeration.
+    pub fn reset(self, registers: &Registers) -> Setup {
+        let mut setup = self.abandon();
+        setup.out.reset(registers);
+        setup.response.reset(registers);
+        setup.expect_setup(registers)
+    }
+
+    /// The transfer is over: waits for the next SETUP packet.
+    pub fn expect_setup(self, registers: &Registers) -> Setup {
+        self.abandon().expect_setup(registers)
+    }
+
+    /// Stalls both the IN and OUT endpoints for endpoint 0, and waits for
+    /// the next SETUP.
+    //
+    // A STALL condition indicates that an endpoint is unable to transmit or
+    // receive data. STALLing when waiting for a SETUP message forces the
+    // host to send a new SETUP. This can be used to indicate the request
+    // wasn't understood or needs to be resent.
+    pub fn stall(self, registers: &Registers) -> Setup {
+        trace::record("usb stall", self.code());
+        let mut setup = self.abandon();
+        setup.out.arm(DescFlag::LAST | DescFlag::IOC);
+        set_interrupts(registers, false);
+        registers.out_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::STALL);
+        flush_in_fifo(registers);
+        registers.in_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::STALL);
+        setup
+    }
+
+    /// The SETUP stage is over: lets the IN and OUT stages after it
+    /// proceed.
+    pub fn clear_naks(self, registers: &Registers) -> Stage {
+        registers.in_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::CNAK);
+        registers.out_endpoints[0].control.set(EpCtl::ENABLE | EpCtl::CNAK);
+        self
+    }
+
+    /// An IN packet was sent. Only the IN stages send anything, and the
+    /// controller disables the endpoint once it has been sent.
+    pub fn sent(&self, registers: &Registers) {
+        match *self {
+            Stage::DataIn(_) | Stage::Status(_) => {
+                registers.in_endpoints[0].control.set(EpCtl::ENABLE);
+            }
+            Stage::Setup(_) | Stage::DataOut(_) => {}
+        }
+    }
+}
+
+impl fmt::Debug for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stage::Setup(_) => f.write_str("Setup"),
+            Stage::DataIn(_) => f.write_str("DataIn"),
+            Stage::DataOut(ref stage) => write!(f, "DataOut({:?})", stage.target),
+            Stage::Status(_) => f.write_str("Status"),
+        }
+    }
+}
+
+/// What an endpoint 0 OUT interrupt reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A SETUP packet is in the OUT buffer
+    Setup,
+    /// The SETUP stage is over (case B)
+    SetupDone,
+    /// Another OUT descriptor completed, in table case A, C or E
+    Data(TableCase),
+    /// Any other interrupt
+    Other,
+}
+
+impl Event {
+    /// The event for an OUT interrupt of table case `case`, whose
+    /// descriptor's SETUP_READY flag was `setup_ready`.
+    pub fn decode(case: TableCase, setup_ready: bool) -> Event {
+        match case {
+            TableCase::B => Event::SetupDone,
+            _ if setup_ready => Event::Setup,
+            TableCase::A | TableCase::C | TableCase::E => Event::Data(case),
+            _ => Event::Other,
+        }
+    }
+}
+
+/// What the driver does on an event, and the stage it does it with
+pub enum Step {
+    /// Decode and answer the SETUP packet, which starts a new transfer
+    HandleSetup(Setup),
+    /// Let the IN and OUT stages after the SETUP proceed
+    ClearNaks(Stage),
+    /// Let the OUT data stage after the SETUP proceed
+    ClearOutNak(DataOut),
+    /// The data stage arrived
+    Received(DataOut),
+    /// The transfer is over; receive the next SETUP
+    ExpectSetup(Stage),
+    /// Refuse the transfer
+    Stall(Stage),
+    /// Nothing to do until the next event
+    Wait(Stage),
+}
+
+/// What to do on `event` in `stage`.
+pub fn step(stage: Stage, event: Event) -> Step {
+    match (stage, event) {
+        (stage, Event::Setup) => Step::HandleSetup(stage.abandon()),
+
+        // Only happens when we're stalling, so keep waiting for a SETUP
+        (stage @ Stage::Setup(_), Event::SetupDone) => Step::Stall(stage),
+        (stage @ Stage::Setup(_), Event::Data(TableCase::A)) |
+        (stage @ Stage::Setup(_), Event::Data(TableCase::C)) => Step::ExpectSetup(stage),
+        (stage @ Stage::Setup(_), Event::Data(_)) |
+        (stage @ Stage::Setup(_), Event::Other) => Step::Wait(stage),
+
+        (stage @ Stage::DataIn(_), Event::SetupDone) => Step::ClearNaks(stage),
+        // The host's zero-length status packet
+        (stage @ Stage::DataIn(_), Event::Data(TableCase::A)) |
+        (stage @ Stage::DataIn(_), Event::Data(TableCase::C)) => Step::ExpectSetup(stage),
+        (stage @ Stage::DataIn(_), Event::Data(_)) |
+        (stage @ Stage::DataIn(_), Event::Other) => Step::Wait(stage),
+
+        (Stage::DataOut(stage), Event::SetupDone) => Step::ClearOutNak(stage),
+        (Stage::DataOut(stage), Event::Data(TableCase::A)) |
+        (Stage::DataOut(stage), Event::Data(TableCase::E)) => Step::Received(stage),
+        (stage @ Stage::DataOut(_), Event::Data(_)) |
+        (stage @ Stage::DataOut(_), Event::Other) => Step::Wait(stage),
+
+        (stage @ Stage::Status(_), Event::SetupDone) => Step::ClearNaks(stage),
+        (stage @ Stage::Status(_), Event::Data(_)) |
+        (stage @ Stage::Status(_), Event::Other) => Step::ExpectSetup(stage),
+    }
+}
+
+/// Sends the zero-length status packet, in case `case`, and arms for the
+/// host's next packet.
+fn status(mut out: OutQueue, mut sending: InQueue, registers: &Registers, case: TableCase) -> Status {
+    sending.send(registers, case, 0);
+    out.arm(DescFlag::HOST_READY | DescFlag::LAST | DescFlag::IOC);
+    registers.out_endpoints[0].control.set(enable(case));
+    set_interrupts(registers, true);
+    Status {
+        out: out,
+        sending: sending,
+    }
+}
+
+/// Enables an endpoint 0 direction for a stage after a SETUP that arrived
+/// in case `case`: only clears the NAK once the SETUP stage is done.
+fn enable(case: TableCase) -> EpCtl {
+    if case == TableCase::C {
+        EpCtl::ENABLE | EpCtl::CNAK
+    } else {
+        EpCtl::ENABLE
+    }
+}
+
+/// Unmasks endpoint 0's OUT interrupts, and its IN interrupts if `in0`.
+fn set_interrupts(registers: &Registers, in0: bool) {
+    let mut interrupts = registers.device_all_ep_interrupt_mask.get();
+    interrupts |= AllEndpointInterruptMask::OUT0 as u32;
+    if in0 {
+        interrupts |= AllEndpointInterruptMask::IN0 as u32;
+    } else {
+        interrupts &= !(AllEndpointInterruptMask::IN0 as u32);
+    }
+    registers.device_all_ep_interrupt_mask.set(interrupts);
+}
+
+/// Flushes endpoint 0's transmit FIFO, so a response doesn't follow what
+/// an abandoned one left there.
+fn flush_in_fifo(registers: &Registers) {
+    registers.reset.set(Reset::TxFFlsh as u32 | Reset::FlushFifo0 as u32);
+    let mut timeout = FLUSH_TIMEOUT;
+    while registers.reset.get() & (Reset::TxFFlsh as u32) != 0 && timeout > 0 {
+        timeout -= 1;
+    }
+}
+
+/// The descriptor as the DMA address register holds it. The descriptors
+/// are `'static`, and the stage that owns them only changes one the engine
+/// isn't using.
+fn dma_address(descriptor: &DMADescriptor) -> &'static DMADescriptor {
+    unsafe { &*(descriptor as *const DMADescriptor) }
+}
@@ -0,0 +1,94 @@
+//! Data FIFO RAM layout
+//!
+//! The core's FIFO RAM holds one receive FIFO, shared by every OUT
+//! endpoint, followed by a transmit FIFO per IN endpoint. Nothing checks
+//! the layout in hardware: a FIFO set larger than the RAM (or than its
+//! size field) reads back as size 0 and silently transfers nothing. So
+//! the sizes are checked when compiling instead, against the smallest RAM
+//! of any chip revision: the receive FIFO and every transmit FIFO at its
+//! largest must fit, and the core must have a transmit FIFO for every IN
+//! endpoint.
+//!
+//! Within that, each data endpoint's transmit FIFO holds one or two
+//! packets, set by the descriptor builder for the interface using it
+//! (see `Interface::tx_fifo_packets`); endpoint 0's always holds
+//! `MAX_TX_FIFO_PACKETS`. The chip can cap all of them lower (see
+//! `Integration::tx_fifo_packets`).
+
+use super::constants::MAX_PACKET_SIZE;
+use super::endpoint::NUM_DATA_ENDPOINTS;
+
+/// Words in a packet of `MAX_PACKET_SIZE` bytes
+pub const PACKET_WORDS: u16 = MAX_PACKET_SIZE / 4;
+
+/// Words of FIFO RAM every revision has: the early silicon, with half the
+/// RAM (see `Erratum::UsbSmallFifoRam`), ran with an 85-word receive FIFO
+/// and 16 transmit FIFOs of one packet.
+pub const FIFO_RAM_WORDS: u16 = 85 + 16 * PACKET_WORDS;
+
+/// IN endpoints the core has a transmit FIFO for, including endpoint 0
+pub const MAX_IN_FIFOS: usize = 16;
+
+/// IN endpoints the driver uses, including endpoint 0
+pub const NUM_IN_FIFOS: usize = NUM_DATA_ENDPOINTS + 1;
+
+const MAX_CONTROL_ENDPOINTS: u16 = 3;
+const MAX_NORMAL_ENDPOINTS: u16 = 16;
+
+/// Receive FIFO words, as the databook sizes it: 4 per control endpoint
+/// and 6 more for SETUP packets, two packets with a status word each, 2
+/// per endpoint for transfer-complete status, and 1 for global OUT NAK.
+pub const RX_FIFO_WORDS: u16 = (4 * MAX_CONTROL_ENDPOINTS + 6) +
+                               (2 * (PACKET_WORDS + 1)) +
+                               (2 * MAX_NORMAL_ENDPOINTS) + 1;
+
+/// Most packets a transmit FIFO holds
+pub const MAX_TX_FIFO_PACKETS: u16 = 2;
+
+/// FIFO RAM used when every transmit FIFO is at its largest
+pub const MAX_FIFO_WORDS: u16 = RX_FIFO_WORDS + NUM_IN_FIFOS as u16 * MAX_TX_FIFO_PACKETS * PACKET_WORDS;
+
+// Checked when compiling: the array's length underflows if the condition
+// is false.
+#[allow(dead_code)]
+const FIFOS_FIT_RAM: [(); 0 - !(MAX_FIFO_WORDS <= FIFO_RAM_WORDS) as usize] = [];
+#[allow(dead_code)]
+const FIFO_PER_IN_ENDPOINT: [(); 0 - !(NUM_IN_FIFOS <= MAX_IN_FIFOS) as usize] = [];
+
+/// Packets each transmit FIFO holds, endpoint 0 first
+#[derive(Clone, Copy, Debug)]
+pub struct FifoLayout {
+    tx_packets: [u16; NUM_IN_FIFOS],
+}
+
+impl FifoLayout {
+    pub const fn new() -> FifoLayout {
+        FifoLayout { tx_packets: [MAX_TX_FIFO_PACKETS; NUM_IN_FIFOS] }
+    }
+
+    /// Sets data endpoint `endpoint`'s transmit FIFO to hold `packets`,
+    /// from 1 to `MAX_TX_FIFO_PACKETS`.
+    pub fn set_tx_packets(&mut self, endpoint: usize, packets: u16) {
+        if endpoint >= 1 && endpoint < NUM_IN_FIFOS {
+            self.tx_packets[endpoint] = if packets < 1 {
+                1
+            } else if packets > MAX_TX_FIFO_PACKETS {
+                MAX_TX_FIFO_PACKETS
+            } else {
+                packets
+            };
+        }
+    }
+
+    /// The start and size in words of IN endpoint `endpoint`'s transmit
+    /// FIFO, with each holding at most `max_packets`. Endpoints the driver
+    /// doesn't use get an empty FIFO at the end.
+    pub fn tx_fifo(&self, endpoint: usize, max_packets: u16) -> (u16, u16) {
+        let words = |packets: u16| ::core::cmp::min(packets, max_packets) * PACKET_WORDS;
+        let start = self.tx_packets
+            .iter()
+            .take(endpoint)
+            .fold(RX_FIFO_WORDS, |start, &packets| start + words(packets));
+        (start, self.tx_packets.get(endpoint).map_or(0, |&packets| words(packets)))
+    }
+}
@@ -0,0 +1,600 @@
+//! Host mode
+//!
+//! The controller is an OTG core, so instead of `USB::init` a board can
+//! call `USB::init_host` to make the chip the host of a device plugged
+//! into its port, e.g. to provision a peripheral or exercise one in a
+//! production test. Host mode is minimal: it powers the port, resets a
+//! device when it is attached and runs control transfers to it on channel
+//! 0, one at a time, so a client can enumerate the device and talk to it
+//! with standard, class and vendor requests. Only full and low speed
+//! devices are supported (the PHY is full speed), and there are no hubs,
+//! split transactions or data channels.
+//!
+//! Host mode needs a 1MHz alarm, whose client the board sets to the driver:
+//! the core takes a while to switch to host mode, and the port is held in
+//! reset for `RESET_MS` as soon as a device is attached, both timed by the
+//! alarm rather than spinning. The client is told once the port is enabled
+//! after the reset. Channel 0 uses buffer DMA into the buffer given to
+//! `init_host`, so a data stage is at most `MAX_HOST_DATA` bytes. A
+//! transaction error is retried up to `MAX_RETRIES` times before the
+//! transfer fails.
+//!
+//! ```ignore
+//! host_alarm.set_client(&hotel::usb::USB0);
+//! hotel::usb::USB0.init_host(&mut hotel::usb::IN_BUFFERS, &PROVISIONER, host_alarm);
+//! ...
+//! // From PROVISIONER's device_attached, read the device descriptor
+//! let request = ControlRequest::get_descriptor(DEVICE_DESCRIPTOR, 0, 18);
+//! USB0.control_transfer(0, 8, request, buffer);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use kernel::hil::time::{self, Alarm, Frequency};
+use timeus::Freq1Mhz;
+use trace;
+
+use super::fifo::RX_FIFO_WORDS;
+use super::{copy_from_words, copy_to_words, USB};
+
+/// Longest data stage of a control transfer: the size of the DMA buffer
+pub const MAX_HOST_DATA: usize = 4 * HOST_BUFFER_WORDS;
+
+/// Words in the DMA buffer given to `init_host`
+pub const HOST_BUFFER_WORDS: usize = 16 * 4;
+
+/// Attempts at a transaction that failed on the bus before the transfer
+/// fails
+pub const MAX_RETRIES: u8 = 3;
+
+/// Whether the controller is a device or a host
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Device,
+    Host,
+}
+
+/// Speed of the device attached to the port
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    Full,
+    Low,
+}
+
+/// The fields of a SETUP packet the host sends
+#[derive(Clone, Copy, Debug)]
+pub struct ControlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl ControlRequest {
+    /// A standard GET_DESCRIPTOR of the device's descriptor `kind` number
+    /// `index`, of up to `length` bytes
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> ControlRequest {
+        ControlRequest {
+            request_type: 0x80,
+            request: 6,
+            value: (kind as u16) << 8 | index as u16,
+            index: 0,
+            length: length,
+        }
+    }
+
+    /// A standard SET_ADDRESS, after which the device answers at `address`
+    pub fn set_address(address: u8) -> ControlRequest {
+        ControlRequest {
+            request_type: 0x00,
+            request: 5,
+            value: address as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// A standard SET_CONFIGURATION
+    pub fn set_configuration(configuration: u8) -> ControlRequest {
+        ControlRequest {
+            request_type: 0x00,
+            request: 9,
+            value: configuration as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    fn device_to_host(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+}
+
+/// Callbacks for the device on the port.
+pub trait HostClient {
+    /// A device was attached and the port has been reset and enabled, so
+    /// it answers control transfers at address 0.
+    fn device_attached(&self, speed: Speed);
+
+    /// The device was detached. A transfer underway fails first.
+    fn device_detached(&self);
+
+    /// The control transfer started with `control_transfer` finished:
+    /// with the number of bytes of its data stage that were moved (the
+    /// received bytes are at the start of `data`), or an error. A stall
+    /// is `ENOSUPPORT`; a bus error or detached device is `FAIL`.
+    fn control_done(&self, data: &'static mut [u8], result: Result<usize, ReturnCode>);
+}
+
+/// What the port is waiting for the alarm to end
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Port {
+    Off,
+    /// The core switching to host mode
+    ForcingMode,
+    /// Powered, with nothing to wait for
+    Powered,
+    /// A device held in reset
+    Resetting,
+}
+
+/// How far the control transfer on channel 0 has got
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    Idle,
+    Setup,
+    DataIn,
+    DataOut,
+    Status,
+}
+
+/// Host mode driver state
+pub struct HostState {
+    client: Cell<Option<&'static HostClient>>,
+    alarm: Cell<Option<&'static Alarm<Frequency = Freq1Mhz>>>,
+    port: Cell<Port>,
+    buffer: TakeCell<'static, [u32; HOST_BUFFER_WORDS]>,
+    // The client's buffer, while a transfer is underway
+    data: TakeCell<'static, [u8]>,
+    speed: Cell<Option<Speed>>,
+    stage: Cell<Stage>,
+    request: Cell<ControlRequest>,
+    address: Cell<u8>,
+    max_packet: Cell<u16>,
+    // Bytes the stage underway moves
+    stage_len: Cell<usize>,
+    // Bytes of the data stage moved
+    moved: Cell<usize>,
+    retries: Cell<u8>,
+}
+
+impl HostState {
+    pub const fn new() -> HostState {
+        HostState {
+            client: Cell::new(None),
+            alarm: Cell::new(None),
+            port: Cell::new(Port::Off),
+            buffer: TakeCell::empty(),
+            data: TakeCell::empty(),
+            speed: Cell::new(None),
+            stage: Cell::new(Stage::Idle),
+            request: Cell::new(ControlRequest {
+                request_type: 0,
+                request: 0,
+                value: 0,
+                index: 0,
+                length: 0,
+            }),
+            address: Cell::new(0),
+            max_packet: Cell::new(8),
+            stage_len: Cell::new(0),
+            moved: Cell::new(0),
+            retries: Cell::new(0),
+        }
+    }
+}
+
+// Core interrupt status: the controller is in host mode
+const CURRENT_MODE_HOST: u32 = 1 << 0;
+// Core interrupts used in host mode
+const PORT_INT: u32 = 1 << 24;
+const CHANNEL_INT: u32 = 1 << 25;
+const DISCONNECT: u32 = 1 << 29;
+
+// Core USB configuration: force host mode
+const GUSBCFG_FORCE_HOST: u32 = 1 << 29;
+
+// Host configuration: the PHY clock is 48MHz (full speed) or 6MHz (low
+// speed), and only full and low speed are supported
+const HCFG_FS_LS_CLOCK_48MHZ: u32 = 1;
+const HCFG_FS_LS_CLOCK_6MHZ: u32 = 2;
+const HCFG_FS_LS_ONLY: u32 = 1 << 2;
+
+// Host port bits
+const HPRT_CONNECTED: u32 = 1 << 0;
+const HPRT_CONNECT_DETECTED: u32 = 1 << 1;
+const HPRT_ENABLED: u32 = 1 << 2;
+const HPRT_ENABLE_CHANGED: u32 = 1 << 3;
+const HPRT_OVERCURRENT_CHANGED: u32 = 1 << 5;
+const HPRT_RESET: u32 = 1 << 8;
+const HPRT_POWER: u32 = 1 << 12;
+const HPRT_SPEED_SHIFT: u32 = 17;
+const HPRT_SPEED_LOW: u32 = 2;
+/// Bits of `host_port` that are cleared by writing 1
+pub const HPRT_W1C: u32 = HPRT_CONNECT_DETECTED | HPRT_ENABLED | HPRT_ENABLE_CHANGED |
+                          HPRT_OVERCURRENT_CHANGED;
+
+// Channel characteristics
+const HCCHAR_ENABLE: u32 = 1 << 31;
+const HCCHAR_DISABLE: u32 = 1 << 30;
+const HCCHAR_ADDRESS_SHIFT: u32 = 22;
+const HCCHAR_ONE_PER_FRAME: u32 = 1 << 20;
+const HCCHAR_LOW_SPEED: u32 = 1 << 17;
+const HCCHAR_IN: u32 = 1 << 15;
+
+// Channel transfer size: packet count and data PID
+const HCTSIZ_PACKETS_SHIFT: u32 = 19;
+const HCTSIZ_PID_SHIFT: u32 = 29;
+const PID_DATA1: u32 = 2;
+const PID_SETUP: u32 = 3;
+
+// Channel interrupts
+const HCINT_XFER_COMPLETE: u32 = 1 << 0;
+const HCINT_HALTED: u32 = 1 << 1;
+const HCINT_AHB_ERROR: u32 = 1 << 2;
+const HCINT_STALL: u32 = 1 << 3;
+const HCINT_XACT_ERROR: u32 = 1 << 7;
+const HCINT_BABBLE: u32 = 1 << 8;
+const HCINT_TOGGLE_ERROR: u32 = 1 << 10;
+
+/// How long the port is held in reset
+pub const RESET_MS: u32 = 10;
+
+/// How long the core takes to switch to host mode once forced
+const FORCE_MODE_MS: u32 = 25;
+
+impl USB {
+    /// Initialize the driver in host mode, so it powers the port and can
+    /// talk to a device attached to it. `buffer` is the channel's DMA
+    /// buffer (device mode's `IN_BUFFERS` can be reused, since a driver is
+    /// only ever in one mode). `alarm`'s client must be the driver.
+    pub fn init_host(&self,
+                     buffer: &'static mut [u32; HOST_BUFFER_WORDS],
+                     client: &'static HostClient,
+                     alarm: &'static Alarm<Frequency = Freq1Mhz>) {
+        self.mode.set(Mode::Host);
+        self.host.buffer.replace(buffer);
+        self.host.client.set(Some(client));
+        self.host.alarm.set(Some(alarm));
+
+        self.integration.enable_clocks();
+        self.set_suspended(false);
+        self.initialize_host();
+    }
+
+    /// Which mode `init` or `init_host` put the driver in.
+    pub fn mode(&self) -> Mode {
+        self.mode.get()
+    }
+
+    /// The speed of the device on the port, once it has been reset and
+    /// can be talked to.
+    pub fn attached_device(&self) -> Option<Speed> {
+        self.host.speed.get()
+    }
+
+    /// Starts a control transfer of `request` to endpoint 0 of the device
+    /// at `address`, whose endpoint 0 takes packets of up to `max_packet`
+    /// bytes (8 until its device descriptor has been read). The data stage
+    /// is sent from or received into `data`, which is returned to
+    /// `HostClient::control_done`.
+    pub fn control_transfer(&self,
+                            address: u8,
+                            max_packet: u16,
+                            request: ControlRequest,
+                            data: &'static mut [u8])
+                            -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.mode.get() != Mode::Host || self.host.speed.get().is_none() {
+            return Err((ReturnCode::EOFF, data));
+        }
+        if self.host.stage.get() != Stage::Idle {
+            return Err((ReturnCode::EBUSY, data));
+        }
+        match max_packet {
+            8 | 16 | 32 | 64 => {}
+            _ => return Err((ReturnCode::EINVAL, data)),
+        }
+        if request.length as usize > MAX_HOST_DATA ||
+            (!request.device_to_host() && request.length as usize > data.len()) {
+            return Err((ReturnCode::ESIZE, data));
+        }
+        trace::record("usb host control",
+                      (request.request_type as u32) << 24 | (request.request as u32) << 16 |
+                      request.value as u32);
+
+        self.host.data.replace(data);
+        self.host.request.set(request);
+        self.host.address.set(address);
+        self.host.max_packet.set(max_packet);
+        self.host.moved.set(0);
+        self.host_stage(Stage::Setup);
+        Ok(())
+    }
+
+    /// Starts the controller core switching to host mode; the alarm
+    /// finishes bringing it up.
+    fn initialize_host(&self) {
+        self.registers.interrupt_mask.set(0);
+
+        // Select and power the PHY
+        self.registers.gpio.set((self.integration.phy_config() as u32) << 16);
+
+        let configuration = 1 << 6 | // USB 1.1 Full Speed
+            14 << 10 | // USB Turnaround time
+            7; // Timeout calibration
+        self.registers.configuration.set(configuration);
+        self.soft_reset();
+        self.registers.configuration.set(configuration | GUSBCFG_FORCE_HOST);
+        self.host_wait(Port::ForcingMode, FORCE_MODE_MS);
+    }
+
+    /// Once the core is in host mode, sets it up and powers the port.
+    fn host_mode_entered(&self) {
+        if self.registers.interrupt_status.get() & CURRENT_MODE_HOST == 0 {
+            trace::record("usb host mode late", self.registers.interrupt_status.get());
+        }
+
+        // Global interrupt unmask and DMA enable, as in device mode
+        self.registers.ahb_config.set(1 | 1 << 5 | 1 << 7);
+
+        self.registers.host_config.set(HCFG_FS_LS_CLOCK_48MHZ | HCFG_FS_LS_ONLY);
+        self.registers.host_frame_interval.set(48_000);
+        self.setup_host_fifos();
+
+        // Channel 0 only reports that it halted; why is in its interrupt
+        // register.
+        let channel = &self.registers.host_channels[0];
+        channel.interrupt.set(!0);
+        channel.interrupt_mask.set(HCINT_HALTED);
+        self.registers.host_all_channel_interrupt_mask.set(1 << 0);
+
+        self.registers.interrupt_status.set(!0);
+        self.registers.interrupt_mask.set(PORT_INT | CHANNEL_INT | DISCONNECT);
+
+        self.host.port.set(Port::Powered);
+        let port = self.registers.host_port.get() & !HPRT_W1C;
+        self.registers.host_port.set(port | HPRT_POWER);
+    }
+
+    /// The receive FIFO and the non-periodic transmit FIFO take the same
+    /// room as in device mode: EP0's transmit FIFO comes after the receive
+    /// FIFO.
+    fn setup_host_fifos(&self) {
+        let (start, words) = self.fifo_layout.get().tx_fifo(0, self.integration.tx_fifo_packets());
+        self.registers.receive_fifo_size.set(RX_FIFO_WORDS as u32);
+        self.registers.transmit_fifo_size.set((words as u32) << 16 | start as u32);
+        self.flush_tx_fifo(0x10);
+        self.flush_rx_fifo();
+    }
+
+    /// Turns off the port's power, failing any transfer underway.
+    pub(super) fn stop_host(&self) {
+        self.host.alarm.get().map(|alarm| alarm.disable());
+        self.host.port.set(Port::Off);
+        let port = self.registers.host_port.get() & !HPRT_W1C;
+        self.registers.host_port.set(port & !HPRT_POWER);
+        self.device_gone();
+    }
+
+    /// Waits `ms` in `state` for the alarm.
+    fn host_wait(&self, state: Port, ms: u32) {
+        self.host.port.set(state);
+        self.host.alarm.get().map(|alarm| {
+            let delay = ms * <Freq1Mhz>::frequency() / 1000;
+            alarm.set_alarm(alarm.now().wrapping_add(delay));
+        });
+    }
+
+    /// The host mode part of `handle_interrupt`.
+    pub(super) fn handle_host_interrupt(&self, status: u32) {
+        if status & PORT_INT != 0 {
+            self.handle_port_events();
+        }
+        if status & DISCONNECT != 0 {
+            trace::record("usb host detach", status);
+            self.device_gone();
+        }
+        if status & CHANNEL_INT != 0 && self.registers.host_all_channel_interrupt.get() & 1 != 0 {
+            self.handle_channel_events();
+        }
+    }
+
+    fn handle_port_events(&self) {
+        let port = self.registers.host_port.get();
+        // Acknowledge the changes
+        self.registers.host_port.set(port & !HPRT_ENABLED);
+        trace::record("usb host port", port);
+
+        if port & HPRT_CONNECT_DETECTED != 0 && port & HPRT_CONNECTED != 0 {
+            self.reset_port();
+        }
+        if port & HPRT_ENABLE_CHANGED != 0 && port & HPRT_ENABLED != 0 {
+            let speed = if (port >> HPRT_SPEED_SHIFT) & 0b11 == HPRT_SPEED_LOW {
+                Speed::Low
+            } else {
+                Speed::Full
+            };
+            // A low speed device needs the PHY clock at 6MHz, which only
+            // takes effect after another reset.
+            let clock = if speed == Speed::Low {
+                HCFG_FS_LS_CLOCK_6MHZ
+            } else {
+                HCFG_FS_LS_CLOCK_48MHZ
+            };
+            let config = self.registers.host_config.get();
+            if config & 0b11 != clock {
+                self.registers.host_config.set(config & !0b11 | clock);
+                self.registers.host_frame_interval.set(if speed == Speed::Low { 6_000 } else { 48_000 });
+                self.reset_port();
+                return;
+            }
+            self.host.speed.set(Some(speed));
+            self.host.client.get().map(|client| client.device_attached(speed));
+        }
+    }
+
+    /// Holds the port in reset until the alarm releases it; the port is
+    /// enabled once it is released.
+    fn reset_port(&self) {
+        let port = self.registers.host_port.get() & !HPRT_W1C;
+        self.registers.host_port.set(port | HPRT_RESET);
+        self.host_wait(Port::Resetting, RESET_MS);
+    }
+
+    /// The device went away: fails the transfer underway and tells the
+    /// client.
+    fn device_gone(&self) {
+        if self.host.stage.get() != Stage::Idle {
+            let channel = &self.registers.host_channels[0];
+            channel.characteristics.set(channel.characteristics.get() | HCCHAR_ENABLE | HCCHAR_DISABLE);
+            self.host_transfer_done(Err(ReturnCode::FAIL));
+        }
+        if self.host.speed.take().is_some() {
+            self.host.client.get().map(|client| client.device_detached());
+        }
+    }
+
+    fn handle_channel_events(&self) {
+        let channel = &self.registers.host_channels[0];
+        let interrupts = channel.interrupt.get();
+        channel.interrupt.set(interrupts);
+        if interrupts & HCINT_HALTED == 0 || self.host.stage.get() == Stage::Idle {
+            return;
+        }
+
+        if interrupts & HCINT_XFER_COMPLETE != 0 {
+            self.host.retries.set(0);
+            self.host_stage_complete(channel.transfer_size.get() & 0x7ffff);
+        } else if interrupts & HCINT_STALL != 0 {
+            trace::record("usb host stall", interrupts);
+            self.host_transfer_done(Err(ReturnCode::ENOSUPPORT));
+        } else if interrupts & (HCINT_XACT_ERROR | HCINT_TOGGLE_ERROR) != 0 &&
+            self.host.retries.get() < MAX_RETRIES {
+            self.host.retries.set(self.host.retries.get() + 1);
+            self.host_stage(self.host.stage.get());
+        } else {
+            // A babble or AHB error, or too many transaction errors
+            trace::record("usb host error", interrupts & (HCINT_AHB_ERROR | HCINT_BABBLE | HCINT_XACT_ERROR));
+            self.host_transfer_done(Err(ReturnCode::FAIL));
+        }
+    }
+
+    /// The stage underway finished with `remaining` of its bytes not
+    /// moved; moves on to the next.
+    fn host_stage_complete(&self, remaining: u32) {
+        let request = self.host.request.get();
+        match self.host.stage.get() {
+            Stage::Setup if request.length == 0 => self.host_stage(Stage::Status),
+            Stage::Setup if request.device_to_host() => self.host_stage(Stage::DataIn),
+            Stage::Setup => self.host_stage(Stage::DataOut),
+            Stage::DataIn => {
+                let received = self.host.stage_len.get().saturating_sub(remaining as usize);
+                let received = cmp::min(received, request.length as usize);
+                let copied = self.host.buffer.map_or(0, |buffer| {
+                    self.host.data.map_or(0, |data| {
+                        let len = cmp::min(received, data.len());
+                        copy_from_words(buffer, &mut data[..len]);
+                        len
+                    })
+                });
+                self.host.moved.set(copied);
+                self.host_stage(Stage::Status);
+            }
+            Stage::DataOut => {
+                self.host.moved.set(request.length as usize);
+                self.host_stage(Stage::Status);
+            }
+            Stage::Status => {
+                let moved = self.host.moved.get();
+                self.host_transfer_done(Ok(moved));
+            }
+            Stage::Idle => {}
+        }
+    }
+
+    /// Starts `stage` of the transfer on channel 0.
+    fn host_stage(&self, stage: Stage) {
+        let request = self.host.request.get();
+        let max_packet = self.host.max_packet.get() as usize;
+        // The status stage goes the other way from the data stage, or in
+        // if there is none.
+        let (pid, device_to_host, len) = match stage {
+            Stage::Setup => (PID_SETUP, false, 8),
+            Stage::DataIn => {
+                // IN transfers are whole packets
+                let packets = (request.length as usize + max_packet - 1) / max_packet;
+                (PID_DATA1, true, packets * max_packet)
+            }
+            Stage::DataOut => (PID_DATA1, false, request.length as usize),
+            Stage::Status => (PID_DATA1, request.length == 0 || !request.device_to_host(), 0),
+            Stage::Idle => return,
+        };
+        self.host.stage.set(stage);
+        self.host.stage_len.set(len);
+
+        let address = self.host.buffer.map_or(0, |buffer| {
+            match stage {
+                Stage::Setup => {
+                    buffer[0] = request.request_type as u32 | (request.request as u32) << 8 |
+                        (request.value as u32) << 16;
+                    buffer[1] = request.index as u32 | (request.length as u32) << 16;
+                }
+                Stage::DataOut => {
+                    self.host.data.map(|data| copy_to_words(&data[..len], buffer));
+                }
+                _ => {}
+            }
+            buffer.as_ptr() as u32
+        });
+
+        let packets = cmp::max(1, (len + max_packet - 1) / max_packet) as u32;
+        let mut characteristics = HCCHAR_ENABLE | HCCHAR_ONE_PER_FRAME |
+            (self.host.address.get() as u32) << HCCHAR_ADDRESS_SHIFT | max_packet as u32;
+        if device_to_host {
+            characteristics |= HCCHAR_IN;
+        }
+        if self.host.speed.get() == Some(Speed::Low) {
+            characteristics |= HCCHAR_LOW_SPEED;
+        }
+        let channel = &self.registers.host_channels[0];
+        channel.interrupt.set(!0);
+        channel.transfer_size.set(pid << HCTSIZ_PID_SHIFT | packets << HCTSIZ_PACKETS_SHIFT | len as u32);
+        channel.dma_address.set(address);
+        channel.characteristics.set(characteristics);
+    }
+
+    fn host_transfer_done(&self, result: Result<usize, ReturnCode>) {
+        self.host.stage.set(Stage::Idle);
+        self.host.retries.set(0);
+        self.host.data.take().map(|data| {
+            self.host.client.get().map(move |client| client.control_done(data, result));
+        });
+    }
+}
+
+impl time::Client for USB {
+    fn fired(&self) {
+        match self.host.port.get() {
+            Port::ForcingMode => self.host_mode_entered(),
+            Port::Resetting => {
+                self.host.port.set(Port::Powered);
+                let port = self.registers.host_port.get() & !HPRT_W1C;
+                self.registers.host_port.set(port & !HPRT_RESET);
+            }
+            Port::Off | Port::Powered => {}
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Interfaces the device offers
+//!
+//! The core doesn't know what any interface is for. A chip or board
+//! describes each one with an `Interface` (the class codes and string of
+//! its interface descriptor, its data endpoint, and its report descriptor
+//! if it is a HID interface) and lists them in `USB::init`, which numbers
+//! them by their position in the list.
+//!
+//! Class-specific requests to an interface on endpoint 0 (GET_REPORT and
+//! SET_REPORT) go to the `ClassHandler` registered for the interface's
+//! endpoint with `USB::set_class_handler`, and are stalled if there is
+//! none.
+//!
+//! ```ignore
+//! const SHELL: Interface = Interface {
+//!     class: 0xFF,
+//!     sub_class: 80,
+//!     protocol: 1,
+//!     string: 4,
+//!     endpoint: 2,
+//!     transfer: EndpointType::Bulk,
+//!     interval: 0,
+//!     tx_fifo_packets: 2,
+//!     report_descriptor: None,
+//! };
+//! ```
+
+use kernel::ReturnCode;
+
+use super::endpoint::{EndpointType, NUM_DATA_ENDPOINTS};
+use super::USB;
+
+/// An interface and how the core describes it to the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interface {
+    /// bInterfaceClass, e.g. 3 for HID or 0xFF for vendor-specific
+    pub class: u8,
+    /// bInterfaceSubClass
+    pub sub_class: u8,
+    /// bInterfaceProtocol
+    pub protocol: u8,
+    /// Index of the string descriptor naming the interface, 0 for none
+    pub string: u8,
+    /// The data endpoint (1..=`NUM_DATA_ENDPOINTS`) whose IN and OUT
+    /// directions the interface uses
+    pub endpoint: usize,
+    /// The transfer type of both directions
+    pub transfer: EndpointType,
+    /// bInterval of both directions' endpoint descriptors
+    pub interval: u8,
+    /// Packets of `MAX_PACKET_SIZE` the IN endpoint's transmit FIFO holds:
+    /// two lets the next be queued while one is sent
+    pub tx_fifo_packets: u16,
+    /// The report descriptor of a HID interface, which is then also given
+    /// a HID descriptor
+    pub report_descriptor: Option<&'static [u8]>,
+}
+
+/// Class-specific requests to an interface
+pub trait ClassHandler {
+    /// The host asked for the report of type `report_type` (the high byte
+    /// of wValue) and ID `report_id` with GET_REPORT. Writes it to `report`
+    /// and returns its length, or None to stall.
+    fn get_report(&self, report_type: u8, report_id: u8, report: &mut [u8]) -> Option<usize>;
+
+    /// The host is about to write a report of `length` bytes with
+    /// SET_REPORT. Returns whether to receive it; if not, the request is
+    /// stalled before its data stage.
+    fn accepts_report(&self, report_type: u8, report_id: u8, length: u16) -> bool;
+
+    /// The data stage of an accepted SET_REPORT arrived. It is only valid
+    /// for the duration of the call.
+    fn set_report(&self, report_type: u8, report_id: u8, report: &[u8]);
+}
+
+impl USB {
+    /// Sends the class-specific requests to interfaces on data endpoint
+    /// `endpoint` to `handler`.
+    pub fn set_class_handler(&self, endpoint: usize, handler: &'static ClassHandler) -> ReturnCode {
+        if endpoint < 1 || endpoint > NUM_DATA_ENDPOINTS {
+            return ReturnCode::EINVAL;
+        }
+        self.endpoints[endpoint - 1].class_handler.set(Some(handler));
+        ReturnCode::SUCCESS
+    }
+
+    /// The interface numbered `number`, if the board offers one.
+    pub fn interface(&self, number: u16) -> Option<Interface> {
+        self.interfaces.get().get(number as usize).cloned()
+    }
+
+    /// The class handler of interface `number`, if it has one.
+    pub(super) fn class_handler(&self, number: u16) -> Option<&'static ClassHandler> {
+        self.interface(number).and_then(|interface| self.endpoint_class_handler(interface.endpoint))
+    }
+
+    /// The class handler registered for data endpoint `endpoint`.
+    pub(super) fn endpoint_class_handler(&self, endpoint: usize) -> Option<&'static ClassHandler> {
+        if endpoint < 1 || endpoint > NUM_DATA_ENDPOINTS {
+            return None;
+        }
+        self.endpoints[endpoint - 1].class_handler.get()
+    }
+}
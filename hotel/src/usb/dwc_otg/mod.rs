@@ -0,0 +1,1417 @@
+//! Device and host mode core for the Synopsys DWC-OTG controller
+//!
+//! `USB` drives the controller core and is chip-agnostic: everything
+//! outside the core (clocks, reset, PHY and what the host's frames are
+//! used for) goes through an `Integration`. A chip supplies one and the
+//! `USB` singleton built on it; hotel's are in `usb::integration`.
+
+mod constants;
+mod endpoint;
+mod ep0;
+mod fifo;
+mod host;
+mod interface;
+mod registers;
+mod serialize;
+mod types;
+mod validate;
+mod vendor;
+
+use cortexm3::support;
+use profile::Region;
+
+pub use self::constants::{Descriptor, MAX_PACKET_SIZE, STRING_PLATFORM, STRING_SERIAL};
+pub use self::endpoint::{EndpointBuffers, EndpointClient, EndpointType, NUM_DATA_ENDPOINTS};
+pub use self::endpoint::{EP1_BUFFERS, EP2_BUFFERS, EP3_BUFFERS};
+pub use self::host::{ControlRequest, HostClient, Mode, Speed, HOST_BUFFER_WORDS, MAX_HOST_DATA};
+pub use self::interface::{ClassHandler, Interface};
+pub use self::registers::{DescFlag, DMADescriptor, Registers};
+pub use self::types::StringDescriptor;
+pub use self::validate::DescriptorError;
+pub use self::vendor::{VendorHandler, VendorRequest, MAX_VENDOR_HANDLERS, MAX_VENDOR_DATA};
+
+use core::cell::Cell;
+use core::fmt::Write;
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use memory::BufferUsage;
+use trace;
+
+use self::constants::*;
+use self::endpoint::EndpointState;
+use self::ep0::{ControlWrite, Event, Response, Stage, Step};
+use self::fifo::{FifoLayout, RX_FIFO_WORDS};
+use self::host::HostState;
+use self::types::{StaticRef};
+use self::types::{SetupRequest, SetupRequestType};
+use self::types::{SetupDirection, SetupRequestClass, SetupRecipient};
+use self::types::{DeviceDescriptor, ConfigurationDescriptor};
+use self::types::{InterfaceDescriptor, EndpointDescriptor, HidDeviceDescriptor};
+use self::types::{EndpointAttributes, EndpointUsageType, EndpointTransferType};
+use self::types::{EndpointSynchronizationType};
+
+// Simple macro for USB debugging output: default definitions do nothing,
+// but you can uncomment print defintions to get detailed output on the
+// messages sent and received.
+macro_rules! usb_debug {
+//    () => ({print!();});
+//    ($fmt:expr) => ({print!($fmt);});
+//    ($fmt:expr, $($arg:tt)+) => ({print!($fmt, $($arg)+);});
+    () => ({});
+    ($fmt:expr) => ({});
+    ($fmt:expr, $($arg:tt)+) => ({});
+}
+
+
+/// How far the host has got enumerating the device since the last bus
+/// reset.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Enumeration {
+    /// Address assigned with SET_ADDRESS, 0 if none
+    pub address: u8,
+    /// Configuration selected with SET_CONFIGURATION, 0 if none
+    pub configuration: u8,
+    /// Bit N is set once string descriptor N has been sent
+    pub strings: u32,
+}
+
+const NOT_ENUMERATED: Enumeration = Enumeration {
+    address: 0,
+    configuration: 0,
+    strings: 0,
+};
+
+/// What a chip provides around the controller core: its clocks, reset and
+/// PHY, and what it does with the host's frame timing. `USB` drives the
+/// core itself and calls these for everything outside it.
+pub trait Integration {
+    /// Turns on the clocks the controller needs.
+    fn enable_clocks(&self);
+
+    /// Turns them off again, once the controller has stopped.
+    fn disable_clocks(&self);
+
+    /// Whether the controller needs its clocks while the processor
+    /// sleeps; it doesn't while the bus is suspended.
+    fn set_busy(&self, busy: bool);
+
+    /// Resets the core from outside, e.g. after an AHB error.
+    fn reset_core(&self);
+
+    /// The GP_OUT value (see `Registers::gpio`) that selects and powers
+    /// the PHY.
+    fn phy_config(&self) -> u16;
+
+    /// Packets of `MAX_PACKET_SIZE` each IN endpoint's transmit FIFO has
+    /// room for.
+    fn tx_fifo_packets(&self) -> u16;
+
+    /// A start-of-frame arrived with frame number `frame`. Returns whether
+    /// more are wanted; if not, SOF is masked until the next bus reset,
+    /// resume or `USB::watch_frames`.
+    fn start_of_frame(&self, frame: u32) -> bool;
+
+    /// Frames stopped arriving (the bus was reset or suspended, or SOF
+    /// was masked), so the interval between the last one and the next is
+    /// meaningless.
+    fn frames_stopped(&self);
+}
+
+/// Driver for the Synopsys DesignWare Cores USB 2.0 Hi-Speed
+/// On-The-Go (OTG) controller.
+///
+/// Page/figure references are for the Synopsys DesignWare Cores USB
+/// 2.0 Hi-Speed On-The-Go (OTG) Programmer's Guide.
+///
+/// The driver can enumerate (appear as a device to a host OS) and
+/// exchange packets on data endpoints (see `endpoint`), or instead be the
+/// host of a device on its port (see `host`). The driver operates as
+/// a device in Scatter-Gather DMA mode (Figure 1-1) and performs the
+/// initial handshakes with the host on endpoint 0. It appears as an
+/// "Unknown counterfeit flash drive" (ID 0011:7788) under Linux; this
+/// was chosen as it won't collide with other valid devices and Linux
+/// doesn't expect anything.
+///
+/// Scatter-gather mode operates using lists of descriptors. Each
+/// descriptor points to a 64 byte memory buffer. A transfer larger
+/// than 64 bytes uses multiple descriptors in sequence. An IN
+/// descriptor is for sending to the host (the data goes IN to the
+/// host), while an OUT descriptor is for receiving from the host (the
+/// data goes OUT of the host).
+///
+/// For endpoint 0, the driver configures 2 OUT descriptors and 4 IN
+/// descriptors. Four IN descriptors allows responses up to 256 bytes
+/// (64 * 4), which is important for sending the device configuration
+/// descriptor as one big blob.  The driver never expects to receive
+/// OUT packets larger than 64 bytes (the maximum each descriptor can
+/// handle), so host-to-device requests with a data stage carry at
+/// most 64 bytes. It uses two OUT descriptors so it can receive a packet
+/// while processing the previous one.
+///
+/// The USB stack currently assumes the presence of 7
+/// StringDescriptors, which are provided by the boot sequence. The
+/// meaning of each StringDescriptor is defined by its index, in
+/// `constants`. An eighth, if provided, is reported as the serial
+/// number (see `fuse::serial_number`).
+
+pub struct USB {
+    registers: StaticRef<Registers>,
+    integration: &'static Integration,
+    mode: Cell<Mode>,
+
+    // How far endpoint 0's control transfer has got, holding endpoint 0's
+    // descriptors and buffers (see `ep0`). Empty until `init`, and while
+    // an endpoint 0 event is handled.
+    ep0: Cell<Option<Stage>>,
+
+    device_class: Cell<u8>,
+    vendor_id: Cell<u16>,
+    product_id: Cell<u16>,
+
+    // `configuration_descriptor` stores the bytes of the full
+    // ConfigurationDescriptor, whose length is stored in
+    // `configuration_total_length`.  The field is populated by
+    // serializing all of the descriptors into it.
+    configuration_descriptor: TakeCell<'static, [u8; CONFIGURATION_BUFFER_SIZE]>,
+    configuration_total_length: Cell<u16>,
+    // Which configuration is currently being used.
+    configuration_current_value: Cell<u8>,
+    strings: TakeCell<'static, [StringDescriptor]>,
+    // The interfaces the board offers, in interface number order
+    interfaces: Cell<&'static [Interface]>,
+    // Transmit FIFO sizes, set for the interfaces' endpoints
+    fifo_layout: Cell<FifoLayout>,
+    enumeration: Cell<Enumeration>,
+    // Whether the host has suspended the bus, and the PHY clock is stopped
+    suspended: Cell<bool>,
+    // Vendor request codes and their handlers
+    vendor_handlers: Cell<[Option<(u8, &'static VendorHandler)>; MAX_VENDOR_HANDLERS]>,
+
+    // State of data endpoints 1..=NUM_DATA_ENDPOINTS, indexed by
+    // endpoint number - 1.
+    endpoints: [EndpointState; NUM_DATA_ENDPOINTS],
+    // The port and the transfer on channel 0, in host mode
+    host: HostState,
+}
+
+/// Cycles spent in `USB::handle_interrupt`
+pub static mut INTERRUPT: Region = Region::new("usb interrupt");
+
+// Statically allocated buffers for initializing USB stack
+pub static mut OUT_DESCRIPTORS: [DMADescriptor; 2] = [DMADescriptor {
+    flags: DescFlag::HOST_BUSY,
+    addr: 0,
+}; 2];
+pub static mut OUT_BUFFERS: [[u32; 16]; 2] = [[0; 16]; 2];
+pub static mut IN_DESCRIPTORS: [DMADescriptor; 4] = [DMADescriptor {
+    flags: DescFlag::HOST_BUSY,
+    addr: 0,
+}; 4];
+pub static mut IN_BUFFERS: [u32; 16 * 4] = [0; 16 * 4];
+pub static mut CONFIGURATION_BUFFER: [u8; CONFIGURATION_BUFFER_SIZE] = [0; CONFIGURATION_BUFFER_SIZE];
+
+// The longest control transfer response and configuration descriptor
+static mut EP0_IN_USAGE: BufferUsage = BufferUsage::new("usb ep0 in");
+static mut CONFIGURATION_USAGE: BufferUsage = BufferUsage::new("usb configuration");
+
+impl USB {
+    /// Creates a new value referencing the single USB driver.
+    ///
+    /// ## Safety
+    ///
+    /// Callers must ensure this is only called once for every program
+    /// execution. Creating multiple instances will result in conflicting
+    /// handling of events and can lead to undefined behavior.
+    pub const unsafe fn new(base: *const Registers, integration: &'static Integration) -> USB {
+        USB {
+            registers: StaticRef::new(base),
+            integration: integration,
+            mode: Cell::new(Mode::Device),
+            ep0: Cell::new(None),
+            configuration_descriptor: TakeCell::empty(),
+            device_class: Cell::new(0x00),
+            vendor_id: Cell::new(0x0011),    // Unknown
+            product_id: Cell::new(0x5026),   // unknown counterfeit flash drive
+            configuration_current_value: Cell::new(0),
+            configuration_total_length: Cell::new(0),
+            strings: TakeCell::empty(),
+            interfaces: Cell::new(&[]),
+            fifo_layout: Cell::new(FifoLayout::new()),
+            enumeration: Cell::new(NOT_ENUMERATED),
+            suspended: Cell::new(true),
+            vendor_handlers: Cell::new([None; MAX_VENDOR_HANDLERS]),
+            endpoints: [EndpointState::new(), EndpointState::new(), EndpointState::new()],
+            host: HostState::new(),
+        }
+    }
+
+    /// Initialize the USB driver in device mode, so it can be begin
+    /// communicating with a connected host. The configuration offers
+    /// `interfaces`, numbered in order; the board must also initialize the
+    /// driver serving each of them.
+    pub fn init(&self,
+                out_descriptors: &'static mut [DMADescriptor; 2],
+                out_buffers: &'static mut [[u32; 16]; 2],
+                in_descriptors: &'static mut [DMADescriptor; 4],
+                in_buffers: &'static mut [u32; 16 * 4],
+                configuration_buffer: &'static mut [u8; CONFIGURATION_BUFFER_SIZE],
+                device_class: Option<u8>,
+                vendor_id: Option<u16>,
+                product_id: Option<u16>,
+                strings: &'static mut [StringDescriptor],
+                interfaces: &'static [Interface]) {
+        self.mode.set(Mode::Device);
+        unsafe {
+            EP0_IN_USAGE.register(in_buffers.len() * 4);
+            CONFIGURATION_USAGE.register(configuration_buffer.len());
+        }
+        self.ep0.set(Some(Stage::new(out_descriptors, out_buffers, in_descriptors, in_buffers)));
+        self.configuration_descriptor.replace(configuration_buffer);
+        self.strings.replace(strings);
+        self.interfaces.set(interfaces);
+        
+        if let Some(dclass) = device_class {
+            self.device_class.set(dclass);
+        }
+
+        if let Some(vid) = vendor_id {
+            self.vendor_id.set(vid);
+        }
+
+        if let Some(pid) = product_id {
+            self.product_id.set(pid);
+        }
+
+        // Fail here rather than as an enumeration error on the host.
+        let device = self.generate_device_descriptor();
+        let strings_valid = self.strings.map_or(Ok(()), |strings| {
+            validate::validate_strings(strings).and_then(|_| validate::validate_device(&device, strings))
+        });
+        if let Err(error) = strings_valid.and_then(|_| self.generate_full_configuration_descriptor()) {
+            panic!("USB descriptors invalid: {:?}", error);
+        }
+
+        self.integration.enable_clocks();
+        self.set_suspended(false);
+        self.initialize_core();
+    }
+
+    /// Replaces string descriptor `index`, e.g. to report a state found
+    /// after `init`. The host sees the new string the next time it asks.
+    pub fn set_string(&self, index: u8, string: StringDescriptor) -> ReturnCode {
+        self.strings.map_or(ReturnCode::EOFF, |strings| match strings.get_mut(index as usize) {
+            Some(slot) => {
+                *slot = string;
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        })
+    }
+
+    /// Recover from a controller fault (e.g., an AHB error during DMA)
+    /// by resetting the core and re-running initialization. The host
+    /// sees a disconnect followed by a fresh enumeration.
+    pub fn recover(&self) {
+        trace::record("usb recover", self.registers.interrupt_status.get());
+        self.integration.reset_core();
+        self.map_ep0(|stage| Stage::Setup(stage.abandon()));
+        self.configuration_current_value.set(0);
+        self.initialize_core();
+    }
+
+    /// Bring up the controller core in device mode and connect to the
+    /// host. Assumes the clocks are on and `init` has provided buffers.
+    fn initialize_core(&self) {
+        self.registers.interrupt_mask.set(0);
+        self.registers.device_all_ep_interrupt_mask.set(0);
+        self.registers.device_in_ep_interrupt_mask.set(0);
+        self.registers.device_out_ep_interrupt_mask.set(0);
+
+        // Select and power the PHY
+        self.registers.gpio.set((self.integration.phy_config() as u32) << 16);
+
+        // Configure the chip
+        self.registers.configuration.set(1 << 6 | // USB 1.1 Full Speed
+            0 << 5 | // 6-pin unidirectional
+            14 << 10 | // USB Turnaround time to 14 -- what does this mean though??
+            7); // Timeout calibration to 7 -- what does this mean though??
+
+
+        // Soft reset
+        self.soft_reset();
+
+        // Configure the chip
+        self.registers.configuration.set(1 << 6 | // USB 1.1 Full Speed
+            0 << 5 | // 6-pin unidirectional
+            14 << 10 | // USB Turnaround time to 14 -- what does this mean though??
+            7); // Timeout calibration to 7 -- what does this mean though??
+
+        // === Begin Core Initialization ==//
+
+        // We should be reading `user_hw_config` registers to find out about the
+        // hardware configuration (which endpoints are in/out, OTG capable,
+        // etc). Skip that for now and just make whatever assumption CR50 is
+        // making.
+
+        // Set the following parameters:
+        //   * Enable DMA Mode
+        //   * Global unmask interrupts
+        //   * Interrupt on Non-Periodic TxFIFO completely empty
+        // _Don't_ set:
+        //   * Periodic TxFIFO interrupt on empty (only valid in slave mode)
+        //   * AHB Burst length (defaults to 1 word)
+        self.registers.ahb_config.set(1 |      // Global Interrupt unmask
+                                      1 << 5 | // DMA Enable
+                                      1 << 7); // Non_periodic TxFIFO
+
+        // Set Soft Disconnect bit to make sure we're in disconnected state
+        self.registers.device_control.set(self.registers.device_control.get() | (1 << 1));
+
+        // The datasheet says to unmask OTG and Mode Mismatch interrupts, but
+        // we don't support anything but device mode for now, so let's skip
+        // handling that
+        //
+        // If we're right, then
+        // `self.registers.interrupt_status.get() & 1 == 0`
+        //
+
+        // === Done with core initialization ==//
+
+        // ===  Begin Device Initialization  ==//
+
+        self.registers.device_config.set(self.registers.device_config.get() |
+            0b11       | // Device Speed: USB 1.1 Full speed (48Mhz)
+            0 << 2     | // Non-zero-length Status: send packet to application
+            0b00 << 11 | // Periodic frame interval: 80%
+            1 << 23);   // Enable Scatter/gather
+
+        // We would set the device threshold control register here, but I don't
+        // think we enable thresholding.
+
+        self.setup_data_fifos();
+
+        // Clear any pending interrupts
+        for endpoint in self.registers.out_endpoints.iter() {
+            endpoint.interrupt.set(!0);
+        }
+        for endpoint in self.registers.in_endpoints.iter() {
+            endpoint.interrupt.set(!0);
+        }
+        self.registers.interrupt_status.set(!0);
+
+        // Unmask some endpoint interrupts
+        //    Device OUT SETUP & XferCompl
+        self.registers.device_out_ep_interrupt_mask.set(1 << 0 | // XferCompl
+            1 << 1 | // Disabled
+            1 << 2 | // AHB error
+            1 << 3); // SETUP
+        //    Device IN XferCompl & TimeOut
+        self.registers.device_in_ep_interrupt_mask.set(1 << 0 | // XferCompl
+            1 << 1 | // Disabled
+            1 << 2); // AHB error
+
+        // To set ourselves up for processing the state machine through interrupts,
+        // unmask:
+        //
+        //   * USB Reset
+        //   * Enumeration Done
+        //   * Early Suspend
+        //   * USB Suspend
+        //   * Resume/Wakeup
+        //   * SOF
+        //
+        self.registers
+            .interrupt_mask
+            .set(GOUTNAKEFF | GINNAKEFF | USB_RESET | ENUM_DONE | OEPINT | IEPINT |
+                 EARLY_SUSPEND | USB_SUSPEND | RESUME_WAKEUP | SOF);
+
+        // Power on programming done
+        self.registers.device_control.set(self.registers.device_control.get() | 1 << 11);
+        for _ in 0..10000 {
+            support::nop();
+        }
+        self.registers.device_control.set(self.registers.device_control.get() & !(1 << 11));
+
+        // Clear global NAKs
+        self.registers.device_control.set(self.registers.device_control.get() |
+            1 << 10 | // Clear global OUT NAK
+            1 << 8);  // Clear Global Non-periodic IN NAK
+
+        // Reconnect:
+        //  Clear the Soft Disconnect bit to allow the core to issue a connect.
+        self.registers.device_control.set(self.registers.device_control.get() & !(1 << 1));
+
+    }
+
+
+    
+    /// Disconnect from the host (or power off the port, in host mode) and
+    /// release the controller's clocks.
+    ///
+    /// `init` or `init_host` must be called again before the controller
+    /// is used.
+    pub fn stop(&self) {
+        if self.mode.get() == Mode::Host {
+            self.stop_host();
+        } else {
+            // Soft disconnect so the host sees us go away
+            self.registers.device_control.set(self.registers.device_control.get() | (1 << 1));
+        }
+        self.registers.interrupt_mask.set(0);
+        self.registers.interrupt_status.set(!0);
+        self.map_ep0(|stage| Stage::Setup(stage.abandon()));
+
+        self.set_suspended(true);
+        self.integration.disable_clocks();
+    }
+
+    /// Whether the host has suspended the bus (or the controller is
+    /// stopped), so it draws only suspend current.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.get()
+    }
+
+    /// Stops the PHY clock while the bus is suspended, and lets the chip
+    /// gate the controller's clocks while the processor sleeps; the core
+    /// still raises the resume and reset interrupts, which wake it.
+    fn set_suspended(&self, suspended: bool) {
+        self.suspended.set(suspended);
+        let gating = self.registers.power_clock_gating_control.get() & !PCGCCTL_STOP_PCLK;
+        if suspended {
+            self.registers.power_clock_gating_control.set(gating | PCGCCTL_STOP_PCLK);
+        } else {
+            self.registers.power_clock_gating_control.set(gating);
+        }
+        self.integration.set_busy(!suspended);
+    }
+
+    /// Restarts the integration's measurements against the host's frame
+    /// timing, e.g. to retrim an oscillator after the temperature changed,
+    /// unmasking SOF until they are done.
+    pub fn watch_frames(&self) {
+        if self.mode.get() == Mode::Host {
+            return;
+        }
+        self.integration.frames_stopped();
+        self.set_sof_unmasked(true);
+    }
+
+    fn set_sof_unmasked(&self, unmasked: bool) {
+        let mask = self.registers.interrupt_mask.get();
+        if unmasked {
+            self.registers.interrupt_mask.set(mask | SOF);
+        } else {
+            self.registers.interrupt_mask.set(mask & !SOF);
+        }
+    }
+
+    /// Whether the host has selected a configuration, so the data
+    /// endpoints are active.
+    pub fn is_configured(&self) -> bool {
+        self.configuration_current_value.get() != 0
+    }
+
+    /// What the host has done to enumerate the device so far.
+    pub fn enumeration(&self) -> Enumeration {
+        self.enumeration.get()
+    }
+
+    /// The strings a host reads while enumerating, as an
+    /// `Enumeration::strings` mask: the device descriptor's manufacturer,
+    /// product and (if there is one) serial number strings.
+    pub fn enumeration_strings(&self) -> u32 {
+        let descriptor = self.generate_device_descriptor();
+        [descriptor.i_manufacturer, descriptor.i_product, descriptor.i_serial_number]
+            .iter()
+            .filter(|&&index| index != 0)
+            .fold(0, |strings, &index| strings | 1 << index)
+    }
+
+    /// Calls `f` with a copy of each DMA descriptor: EP0's two OUT and four
+    /// IN descriptors, then the IN and OUT descriptor of each data
+    /// endpoint. Descriptors that are borrowed are `None`.
+    pub fn each_dma_descriptor<F: FnMut(Option<DMADescriptor>)>(&self, mut f: F) {
+        let ep0_descs = self.peek_ep0(|stage| {
+            (*stage.out_queue().descriptors(), *stage.in_queue().descriptors())
+        });
+        for i in 0..2 {
+            f(ep0_descs.map(|(out_descs, _)| out_descs[i]));
+        }
+        for i in 0..4 {
+            f(ep0_descs.map(|(_, in_descs)| in_descs[i]));
+        }
+        for endpoint in self.endpoints.iter() {
+            match endpoint.descriptors() {
+                Some((in_desc, out_desc)) => {
+                    f(Some(in_desc));
+                    f(Some(out_desc));
+                }
+                None => {
+                    f(None);
+                    f(None);
+                }
+            }
+        }
+    }
+
+    /// Prints the driver and controller state, for the panic handler.
+    /// Descriptors that are borrowed (e.g. because the panic happened while
+    /// they were being updated) are reported as such.
+    pub fn dump_state(&self, writer: &mut Write) {
+        let _ = writer.write_fmt(format_args!(
+            "USB: configuration {}, interrupt status {:#010x}, device status {:#010x}\r\n",
+            self.configuration_current_value.get(),
+            self.registers.interrupt_status.get(),
+            self.registers.device_status.get()));
+        let dumped = self.peek_ep0(|stage| {
+            let out_descs = stage.out_queue().descriptors();
+            let (next, last) = stage.out_queue().indices();
+            let in_descs = stage.in_queue().descriptors();
+            let _ = writer.write_fmt(format_args!(
+                "  EP0 {:?}, OUT descriptors {:#010x} {:#010x} (next {}, last {})\r\n",
+                stage, out_descs[0].flags.0, out_descs[1].flags.0, next, last));
+            let _ = writer.write_fmt(format_args!(
+                "  EP0 IN descriptors {:#010x} {:#010x} {:#010x} {:#010x}\r\n",
+                in_descs[0].flags.0, in_descs[1].flags.0, in_descs[2].flags.0, in_descs[3].flags.0));
+        });
+        if dumped.is_none() {
+            let _ = writer.write_str("  EP0 descriptors in use\r\n");
+        }
+        for endpoint in 0..(NUM_DATA_ENDPOINTS + 1) {
+            let _ = writer.write_fmt(format_args!(
+                "  EP{} IN ctl {:#010x} int {:#06x}  OUT ctl {:#010x} int {:#06x}\r\n",
+                endpoint,
+                self.registers.in_endpoints[endpoint].control.get().0,
+                self.registers.in_endpoints[endpoint].interrupt.get(),
+                self.registers.out_endpoints[endpoint].control.get().0,
+                self.registers.out_endpoints[endpoint].interrupt.get()));
+        }
+    }
+
+    /// Initialize descriptors for endpoint 0 IN and OUT, resetting
+    /// the endpoint 0 descriptors to a clean state and putting the
+    /// stack into the state of waiting for a SETUP packet from the
+    /// host (since this is the first message in an enumeration
+    /// exchange).
+    fn init_descriptors(&self) {
+        self.map_ep0(|stage| Stage::Setup(stage.reset(&self.registers)));
+    }
+
+    /// Replaces endpoint 0's stage with what `f` makes of it, once `init`
+    /// has provided its descriptors and buffers.
+    fn map_ep0<F: FnOnce(Stage) -> Stage>(&self, f: F) {
+        if let Some(stage) = self.ep0.take() {
+            self.ep0.set(Some(f(stage)));
+        }
+    }
+
+    /// Calls `f` with endpoint 0's stage, unless it is being handled.
+    fn peek_ep0<F: FnOnce(&Stage) -> R, R>(&self, f: F) -> Option<R> {
+        self.ep0.take().map(|stage| {
+            let result = f(&stage);
+            self.ep0.set(Some(stage));
+            result
+        })
+    }
+
+    /// Reset the device in response to a USB RESET.
+    fn reset(&self) {
+        usb_debug!("USB: WaitingForSetupPacket in reset.\n");
+        trace::record("usb reset", 0);
+        if self.suspended.get() {
+            self.set_suspended(false);
+        }
+        self.integration.frames_stopped();
+        self.set_sof_unmasked(true);
+        self.configuration_current_value.set(0);
+        self.enumeration.set(NOT_ENUMERATED);
+        self.deactivate_endpoints();
+        // Reset device address field (bits 10:4) of device config
+        //self.registers.device_config.set(self.registers.device_config.get() & !(0b1111111 << 4));
+
+        self.init_descriptors();
+    }
+
+    /// Perform a soft reset on the USB core; timeout if the reset
+    /// takes too long.
+    fn soft_reset(&self) {
+        // Reset
+        self.registers.reset.set(Reset::CSftRst as u32);
+
+        let mut timeout = 10000;
+        // Wait until reset flag is cleared or timeout
+        while self.registers.reset.get() & (Reset::CSftRst as u32) == 1 &&
+            timeout > 0 {
+            timeout -= 1;
+        }
+        if timeout == 0 {
+            return;
+        }
+
+        // Wait until Idle flag is set or timeout
+        let mut timeout = 10000;
+        while self.registers.reset.get() & (Reset::AHBIdle as u32) == 0 &&
+            timeout > 0 {
+            timeout -= 1;
+        }
+        if timeout == 0 {
+            return;
+        }
+
+    }
+    
+    /// The chip should call this interrupt bottom half from its
+    /// `service_pending_interrupts` routine when an interrupt is
+    /// received on the USB nvic line. T
+    ///
+    /// Directly handles events related to device initialization, connection and
+    /// disconnection, as well as control transfers on endpoint 0. Other events
+    /// are passed to clients delegated for particular endpoints or interfaces.
+    ///
+    /// TODO(alevy): implement what this comment promises
+    pub fn handle_interrupt(&self) {
+        let _scope = unsafe { INTERRUPT.scope() };
+
+        // Save current interrupt status snapshot to correctly clear at end
+        let status = self.registers.interrupt_status.get();
+        //print_usb_interrupt_status(status);
+
+        if self.mode.get() == Mode::Host {
+            self.handle_host_interrupt(status);
+            self.registers.interrupt_status.set(status);
+            return;
+        }
+ 
+        if status & ENUM_DONE != 0 {
+            trace::record("usb enum done", self.registers.device_status.get());
+            // MPS default set to 0 == 64 bytes
+            // "Application must read the DSTS register to obtain the
+            //  enumerated speed."
+        }
+
+        if status & RESUME_WAKEUP != 0 && self.suspended.get() {
+            trace::record("usb resume", status);
+            self.set_suspended(false);
+            self.set_sof_unmasked(true);
+        }
+
+        if status & EARLY_SUSPEND != 0  || status & USB_SUSPEND != 0 {
+            // Frames stop arriving
+            trace::record("usb suspend", status);
+            self.integration.frames_stopped();
+            if status & USB_SUSPEND != 0 {
+                self.set_suspended(true);
+            }
+        }
+
+        if status & SOF != 0 {
+            // The chip may use the host's frame timing as a reference
+            // (e.g. to trim an oscillator), but once it has no more use for
+            // it there's no point taking an interrupt every millisecond.
+            let frame = (self.registers.device_status.get() >> 8) & 0x3fff;
+            if !self.integration.start_of_frame(frame) {
+                self.set_sof_unmasked(false);
+            }
+        }
+
+        if status & GOUTNAKEFF != 0 { // Clear Global OUT NAK
+            self.registers.device_control.set(self.registers.device_control.get() | 1 << 10);
+        }
+
+        if status & GINNAKEFF != 0 { // Clear Global Non-periodic IN NAK
+            self.registers.device_control.set(self.registers.device_control.get() | 1 << 8);
+        }
+
+        if status & (OEPINT | IEPINT) != 0 { // Interrupt pending
+            usb_debug!(" - handling endpoint interrupts\n");
+            let daint = self.registers.device_all_ep_interrupt.get();
+            let inter_ep0_out = daint & 1 << 16 != 0;
+            let inter_ep0_in = daint & 1 != 0;
+            if inter_ep0_out || inter_ep0_in {
+                self.handle_endpoint0_events(inter_ep0_out, inter_ep0_in);
+            }
+            for endpoint in 1..(NUM_DATA_ENDPOINTS + 1) {
+                let inter_out = daint & 1 << (16 + endpoint) != 0;
+                let inter_in = daint & 1 << endpoint != 0;
+                if inter_out || inter_in {
+                    self.handle_data_endpoint_events(endpoint, inter_out, inter_in);
+                }
+            }
+        }
+
+        if status & USB_RESET != 0 {
+            self.reset();
+        }
+        
+        self.registers.interrupt_status.set(status);
+    }
+
+    /// Handle all endpoint 0 IN/OUT events; clear pending interrupt
+    /// flags, swap buffers if needed, then do what `ep0::step` says for
+    /// the OUT event in the current stage.
+    fn handle_endpoint0_events(&self, inter_out: bool, inter_in: bool) {
+        let ep_out = &self.registers.out_endpoints[0];
+        let ep_out_interrupts = ep_out.interrupt.get();
+        if inter_out {
+            ep_out.interrupt.set(ep_out_interrupts);
+        }
+
+        let ep_in = &self.registers.in_endpoints[0];
+        let ep_in_interrupts = ep_in.interrupt.get();
+        if inter_in {
+            ep_in.interrupt.set(ep_in_interrupts);
+        }
+
+        // The DMA engine hit a bus error: the endpoint state can't be
+        // trusted any more, so start over.
+        if (inter_out && ep_out_interrupts & (OutInterruptMask::AHBErrMsk as u32) != 0) ||
+            (inter_in && ep_in_interrupts & (InInterruptMask::AHBErrMsk as u32) != 0) {
+            trace::record("usb ahb error", ep_out_interrupts << 16 | ep_in_interrupts & 0xffff);
+            self.recover();
+            return;
+        }
+
+        let mut stage = match self.ep0.take() {
+            Some(stage) => stage,
+            None => return,
+        };
+
+        // If the transfer is compelte (XferCompl), swap which EP0
+        // OUT descriptor to use so stack can immediately receive again.
+        if inter_out && ep_out_interrupts & (OutInterruptMask::XferComplMsk as u32) != 0 {
+            stage.out_queue_mut().swap(&self.registers);
+        }
+
+        let transfer_type = TableCase::decode_interrupt(ep_out_interrupts);
+        usb_debug!("USB: handle endpoint 0, transfer type: {:?}\n", transfer_type);
+        let flags = stage.out_queue().last_flags();
+        let setup_ready = flags & DescFlag::SETUP_READY == DescFlag::SETUP_READY;
+
+        if inter_in && ep_in_interrupts & (InInterruptMask::XferComplMsk as u32) != 0 {
+            stage.sent(&self.registers);
+        }
+        if !inter_out {
+            self.ep0.set(Some(stage));
+            return;
+        }
+
+        let event = Event::decode(transfer_type, setup_ready);
+        let next = match ep0::step(stage, event) {
+            Step::HandleSetup(setup) => self.handle_setup(setup, transfer_type),
+            // IN detected
+            Step::ClearNaks(stage) => stage.clear_naks(&self.registers),
+            Step::ClearOutNak(data) => Stage::DataOut(data.clear_nak(&self.registers)),
+            Step::Received(data) => self.handle_data_stage_out(data),
+            Step::ExpectSetup(stage) => {
+                if let Stage::Setup(_) = stage {
+                    trace::record("usb unexpected out", ep_out_interrupts);
+                }
+                Stage::Setup(stage.expect_setup(&self.registers))
+            }
+            Step::Stall(stage) => Stage::Setup(stage.stall(&self.registers)),
+            Step::Wait(stage) => stage,
+        };
+        self.ep0.set(Some(next));
+    }
+
+    /// Handle a SETUP packet to endpoint 0 OUT, dispatching to a
+    /// helper function depending on what kind of a request it is;
+    /// currently supports Standard requests to Device and Interface,
+    /// Class requests to Interface, and Vendor requests (see `vendor`).
+    ///
+    /// `transfer_type` is the `TableCase` found by inspecting
+    /// endpoint-0's interrupt register. The helpers write any response
+    /// into the IN buffer and say how to answer; requests that aren't
+    /// supported are stalled. Returns the stage the answer leads to.
+    fn handle_setup(&self, mut setup: ep0::Setup, transfer_type: TableCase) -> Stage {
+        usb_debug!("Handle setup, case {:?}\n", transfer_type);
+        let request = setup.request();
+        trace::record("usb setup",
+                      (request.bm_request_type as u32) << 24 |
+                      (request.b_request as u32) << 16 |
+                      request.w_value as u32);
+        usb_debug!("  - type={:?} recip={:?} dir={:?} request={:?}\n", request.req_type(), request.recipient(), request.data_direction(), request.request());
+
+        let response = {
+            let buf = setup.response();
+            if request.req_type() == SetupRequestClass::Standard {
+                if request.recipient() == SetupRecipient::Device {
+                    usb_debug!("Standard request on device.\n");
+                    if request.data_direction() == SetupDirection::DeviceToHost {
+                        self.handle_standard_device_to_host(&request, buf)
+                    } else if request.w_length > 0 { // Data requested
+                        self.handle_standard_host_to_device(&request)
+                    } else { // No data requested
+                        self.handle_standard_no_data_phase(&request)
+                    }
+                } else if request.recipient() == SetupRecipient::Interface {
+                    usb_debug!("Standard request on interface.\n");
+                    if request.data_direction() == SetupDirection::DeviceToHost {
+                        self.handle_standard_interface_to_host(&request, buf)
+                    } else {
+                        self.handle_standard_host_to_interface(&request)
+                    }
+                } else {
+                    Response::Stall
+                }
+            } else if request.req_type() == SetupRequestClass::Class && request.recipient() == SetupRecipient::Interface {
+                if request.data_direction() == SetupDirection::DeviceToHost {
+                    self.handle_class_interface_to_host(&request, buf)
+                } else {
+                    self.handle_class_host_to_interface(&request)
+                }
+            } else if request.req_type() == SetupRequestClass::Vendor {
+                self.handle_vendor(&request, buf)
+            } else {
+                usb_debug!("  - unknown case.\n");
+                Response::Stall
+            }
+        };
+        if let Response::Send(len) = response {
+            unsafe { EP0_IN_USAGE.record(len) };
+        }
+        setup.respond(&self.registers, transfer_type, response)
+    }
+
+    fn handle_standard_host_to_device(&self, _request: &SetupRequest) -> Response {
+        // TODO(alevy): don't support any of these yet...
+        Response::Stall
+    }
+
+
+    fn handle_standard_device_to_host(&self, request: &SetupRequest, buf: &mut [u32; 64]) -> Response {
+        use self::types::SetupRequestType::*;
+        use self::serialize::Serialize;
+        match request.request() {
+            GetDescriptor => {
+                let descriptor_type: u32 = (request.w_value >> 8) as u32;
+                match descriptor_type {
+                    GET_DESCRIPTOR_DEVICE => {
+                        let len = self.generate_device_descriptor().serialize(buf);
+                        usb_debug!("Trying to send device descriptor.\n");
+                        Response::Send(::core::cmp::min(len, request.w_length as usize))
+                    },
+                    GET_DESCRIPTOR_CONFIGURATION => {
+                        let mut len = 0;
+                        self.configuration_descriptor.map(|desc| {
+                            len = self.get_configuration_total_length();
+                            for i in 0..CONFIGURATION_BUFFER_SIZE / 4 {
+                                buf[i] = desc[4 * i + 0] as u32 |
+                                         (desc[4 * i + 1] as u32) << 8 |
+                                         (desc[4 * i + 2] as u32) << 16 |
+                                         (desc[4 * i + 3] as u32) << 24; 
+                            }
+                        });
+                        usb_debug!("USB: Trying to send configuration descriptor, len {}\n  ", len);
+                        Response::Send(::core::cmp::min(len, request.w_length) as usize)
+                    },
+                    GET_DESCRIPTOR_INTERFACE => {
+                        let number = (request.w_value & 0xff) as u8;
+                        match self.interface(number as u16) {
+                            Some(interface) => {
+                                let len = interface_descriptors(interface, number).0.into_u32_buf(buf);
+                                Response::Send(::core::cmp::min(len, request.w_length as usize))
+                            }
+                            None => Response::Stall,
+                        }
+                    },
+                    GET_DESCRIPTOR_DEVICE_QUALIFIER => {
+                        usb_debug!("Trying to send device qualifier: stall both fifos.\n");
+                        Response::Stall
+                    }
+                    GET_DESCRIPTOR_STRING => {
+                        let index = (request.w_value & 0xff) as usize;
+                        if index < 32 {
+                            let mut enumeration = self.enumeration.get();
+                            enumeration.strings |= 1 << index;
+                            self.enumeration.set(enumeration);
+                        }
+                        self.strings.map_or(Response::Stall, |strs| match strs.get(index) {
+                            Some(str) => {
+                                let len = ::core::cmp::min(str.into_u32_buf(buf), request.w_length as usize);
+                                usb_debug!("USB: requesting string descriptor {}, len: {}: {:?}", index, len, str);
+                                Response::Send(len)
+                            }
+                            // Not a string the board provides
+                            None => Response::Stall,
+                        })
+                    }
+                    _ => {
+                        // The specification says that a not-understood request should send an
+                        // error response. Cr52 just stalls, this seems to work. -pal
+                        usb_debug!("USB: unhandled setup descriptor type: {}", descriptor_type);
+                        Response::Stall
+                    }
+                }
+            }
+            GetConfiguration => {
+                let len = self.configuration_current_value.get().serialize(buf);
+                Response::Send(::core::cmp::min(len, request.w_length as usize))
+            }
+            GetStatus => {
+                // Bus powered, no remote wakeup
+                buf[0] = 0x0;
+                Response::Send(::core::cmp::min(2, request.w_length as usize))
+            }
+            _ => {
+                usb_debug!("USB: unhandled device-to-host setup request code: {}", request.b_request as u8);
+                Response::Stall
+            }
+        }
+    }
+
+
+
+    /// Responds to a SETUP message destined to an interface. Currently
+    /// only handles GetDescriptor requests for Report descriptors, otherwise
+    /// stalls.
+    fn handle_standard_interface_to_host(&self, request: &SetupRequest, buf: &mut [u32; 64]) -> Response {
+        usb_debug!("Handle setup interface, device to host.\n");
+        let request_type = request.request();
+        match request_type {
+            SetupRequestType::GetDescriptor => {
+                let value      = request.value();
+                let descriptor = Descriptor::from_u8((value >> 8) as u8);
+                let _index      = (value & 0xff) as u8;
+                let len        = request.length() as usize;
+                usb_debug!("  - Descriptor: {:?}, index: {}, length: {}\n", descriptor, _index, len);
+                match descriptor {
+                    Descriptor::Report => {
+                        match self.interface(request.index()).and_then(|i| i.report_descriptor) {
+                            Some(report) => {
+                                let len = ::core::cmp::min(len, report.len());
+                                copy_to_words(&report[..len], buf);
+                                Response::Send(len)
+                            }
+                            None => Response::Stall,
+                        }
+                    },
+                    _ => Response::Stall,
+                }
+            },
+            _ => {
+                usb_debug!("Interface device to host, unhandled request: {:?}", request_type);
+                Response::Stall
+            }
+        }
+    }
+
+    /// Handles a setup message to an interface, host-to-device
+    /// communication.  Currently not supported: stalls.
+    fn handle_standard_host_to_interface(&self, _request: &SetupRequest) -> Response {
+        usb_debug!("Unhandled setup: interface, host to device!");
+        Response::Stall
+    }
+
+    /// Handles a setup message to a class, device-to-host
+    /// communication.  Currently supports only GetReport, answered by
+    /// the interface's class handler, otherwise stalls.
+    fn handle_class_interface_to_host(&self, request: &SetupRequest, buf: &mut [u32; 64]) -> Response {
+        use self::types::SetupClassRequestType;
+        usb_debug!("Handle setup class, device to host.\n");
+        let report_type = (request.value() >> 8) as u8;
+        let report_id = (request.value() & 0xff) as u8;
+        let handler = self.class_handler(request.index());
+        match (request.class_request(), handler) {
+            (SetupClassRequestType::GetReport, Some(handler)) => {
+                let mut report = [0; MAX_PACKET_SIZE as usize];
+                match handler.get_report(report_type, report_id, &mut report) {
+                    Some(len) => {
+                        let len = ::core::cmp::min(::core::cmp::min(len, report.len()), request.length() as usize);
+                        copy_to_words(&report[..len], buf);
+                        Response::Send(len)
+                    }
+                    None => Response::Stall,
+                }
+            }
+            _ => {
+                usb_debug!("Unhandled class request {:?}, stall fifos.", request.class_request());
+                Response::Stall
+            }
+        }
+    }
+    
+    /// Handles a setup message to a class, host-to-device
+    /// communication.  Currently supports only SetIdle commands and
+    /// SetReport of a report the interface's class handler accepts,
+    /// otherwise stalls.
+    fn handle_class_host_to_interface(&self, request: &SetupRequest) -> Response {
+        use self::types::SetupClassRequestType;
+        usb_debug!("Handle setup class, host to device.\n");
+        let report_type = (request.value() >> 8) as u8;
+        let report_id = (request.value() & 0xff) as u8;
+        match request.class_request() {
+            SetupClassRequestType::SetIdle => {
+                let val = request.value();
+                let _interval: u8 = (val & 0xff) as u8;
+                let _id: u8 = (val >> 8) as u8;
+                usb_debug!("SetIdle: {} to {}, stall fifos.", _id, _interval);
+                Response::Stall
+            },
+            SetupClassRequestType::SetReport if request.length() <= MAX_PACKET_SIZE &&
+                self.class_handler(request.index()).map_or(false, |handler| {
+                    handler.accepts_report(report_type, report_id, request.length())
+                }) => {
+                usb_debug!("SetReport: accepted, expect data stage.");
+                Response::Receive(ControlWrite::Report {
+                    interface: request.index(),
+                    report_type: report_type,
+                    report_id: report_id,
+                })
+            },
+            SetupClassRequestType::SetReport => {
+                usb_debug!("SetReport: unhandled report, stall fifos.");
+                Response::Stall
+            },
+            _ => {
+                usb_debug!("Unknown handle setup case: {:?}.\n", request.class_request());
+                Response::Stall
+            }
+        }
+    }
+
+    fn handle_standard_no_data_phase(&self, request: &SetupRequest) -> Response {
+        use self::types::SetupRequestType::*;
+        usb_debug!(" - setup (no data): {:?}\n", request.request());
+        match request.request() {
+            GetStatus => {
+                usb_debug!("USB: GET_STATUS no data setup packet.");
+                Response::Stall
+            }
+            SetAddress => {
+                usb_debug!("Setting address: {:#x}.\n", request.w_value & 0x7f);
+                // Even though USB wants the address to be set after the
+                // IN packet handshake, the hardware knows to wait, so
+                // we should just set it now.
+                let mut dcfg = self.registers.device_config.get();
+                dcfg &= !(0x7f << 4); // Strip address from config
+                dcfg |= ((request.w_value & 0x7f) as u32) << 4; // Put in addr
+                self.registers
+                    .device_config
+                    .set(dcfg);
+                let mut enumeration = self.enumeration.get();
+                enumeration.address = (request.w_value & 0x7f) as u8;
+                self.enumeration.set(enumeration);
+                Response::Acknowledge
+            }
+            SetConfiguration => {
+                usb_debug!("SetConfiguration: {:?}\n", request.w_value);
+                self.configuration_current_value.set(request.w_value as u8);
+                let mut enumeration = self.enumeration.get();
+                enumeration.configuration = request.w_value as u8;
+                self.enumeration.set(enumeration);
+                // Configuration 0 returns the device to the Address state
+                if request.w_value == 0 {
+                    self.deactivate_endpoints();
+                } else {
+                    self.activate_endpoints();
+                }
+                Response::Acknowledge
+            }
+            _ => {
+                usb_debug!("USB: unhandled no data setup packet {}", request.b_request as u8);
+                Response::Stall
+            }
+        }
+    }
+
+    /// Passes a received data stage to the request it belongs to, and
+    /// then acknowledges it in the status stage, or stalls if the request
+    /// is refused.
+    fn handle_data_stage_out(&self, stage: ep0::DataOut) -> Stage {
+        let mut data = [0; MAX_PACKET_SIZE as usize];
+        let len = stage.data(&mut data);
+
+        let accepted = match stage.target() {
+            ControlWrite::Report { interface, report_type, report_id } => {
+                self.class_handler(interface).map_or(false, |handler| {
+                    handler.set_report(report_type, report_id, &data[..len]);
+                    true
+                })
+            }
+            ControlWrite::Vendor(request) => {
+                let len = ::core::cmp::min(len, request.length as usize);
+                self.handle_vendor_data(&request, &mut data[..len])
+            }
+        };
+        if accepted {
+            Stage::Status(stage.acknowledge(&self.registers))
+        } else {
+            Stage::Setup(Stage::DataOut(stage).stall(&self.registers))
+        }
+    }
+
+    /// Flush endpoint 0's RX FIFO
+    ///
+    /// # Safety
+    ///
+    /// Only call this when  transaction is not underway and data from this FIFO
+    /// is not being copied.
+    fn flush_rx_fifo(&self) {
+        self.registers.reset.set(Reset::TxFFlsh as u32); // TxFFlsh
+
+        // Wait for TxFFlsh to clear
+        while self.registers.reset.get() & (Reset::TxFFlsh as u32) != 0 {}
+    }
+
+    /// Flush endpoint 0's TX FIFO
+    ///
+    /// `fifo_num` is 0x0-0xF for a particular fifo, or 0x10 for all fifos
+    ///
+    /// # Safety
+    ///
+    /// Only call this when  transaction is not underway and data from this FIFO
+    /// is not being copied.
+    fn flush_tx_fifo(&self, fifo_num: u8) {
+        let reset_val = (Reset::TxFFlsh as u32) |
+        (match fifo_num {
+            0  => Reset::FlushFifo0,
+            1  => Reset::FlushFifo1,
+            2  => Reset::FlushFifo2,
+            3  => Reset::FlushFifo3,
+            4  => Reset::FlushFifo4,
+            5  => Reset::FlushFifo5,
+            6  => Reset::FlushFifo6,
+            7  => Reset::FlushFifo7,
+            8  => Reset::FlushFifo8,
+            9  => Reset::FlushFifo9,
+            10 => Reset::FlushFifo10,
+            11 => Reset::FlushFifo11,
+            12 => Reset::FlushFifo12,
+            13 => Reset::FlushFifo13,
+            14 => Reset::FlushFifo14,
+            15 => Reset::FlushFifo15,
+            16 => Reset::FlushFifoAll,
+            _  => Reset::FlushFifoAll, // Should Panic, or make param typed
+        } as u32);
+        self.registers.reset.set(reset_val);
+
+        // Wait for TxFFlsh to clear
+        while self.registers.reset.get() & (Reset::TxFFlsh as u32) != 0 {}
+    }
+
+    /// Lays out the data FIFOs (see `fifo`) and flushes them.
+    fn setup_data_fifos(&self) {
+        let max_packets = self.integration.tx_fifo_packets();
+        let layout = self.fifo_layout.get();
+        self.registers.receive_fifo_size.set(RX_FIFO_WORDS as u32);
+        let (start, words) = layout.tx_fifo(0, max_packets);
+        self.registers.transmit_fifo_size.set((words as u32) << 16 | start as u32);
+        // Entry n - 1 is IN endpoint n's
+        for (i, d) in self.registers.device_in_ep_tx_fifo_size.iter().enumerate() {
+            let (start, words) = layout.tx_fifo(i + 1, max_packets);
+            d.set((words as u32) << 16 | start as u32);
+        }
+
+        self.flush_tx_fifo(0x10);
+        self.flush_rx_fifo();
+    }
+
+    /// Writes the configuration descriptor and the descriptors of the
+    /// board's interfaces into the configuration buffer, and checks them.
+    fn generate_full_configuration_descriptor(&self) -> Result<(), DescriptorError> {
+        self.configuration_descriptor.map_or(Ok(()), |desc| {
+            let interfaces = self.interfaces.get();
+            let mut config = ConfigurationDescriptor::new(interfaces.len() as u8, STRING_PLATFORM, 50);
+
+            let needed = interfaces.iter().enumerate().fold(config.length(), |needed, (number, &interface)| {
+                let (descriptor, hid, endpoints) = interface_descriptors(interface, number as u8);
+                needed + descriptor.length() + hid.map_or(0, |hid| hid.length()) +
+                    endpoints[0].length() + endpoints[1].length()
+            });
+            if needed > desc.len() {
+                return Err(DescriptorError::BufferTooSmall { needed: needed, capacity: desc.len() });
+            }
+
+            let mut layout = FifoLayout::new();
+            let mut size: usize = config.length();
+            for (number, &interface) in interfaces.iter().enumerate() {
+                layout.set_tx_packets(interface.endpoint, interface.tx_fifo_packets);
+                let (descriptor, hid, endpoints) = interface_descriptors(interface, number as u8);
+                size += descriptor.into_u8_buf(&mut desc[size..size + descriptor.length()]);
+                if let Some(hid) = hid {
+                    size += hid.into_u8_buf(&mut desc[size..size + hid.length()]);
+                }
+                for endpoint in endpoints.iter() {
+                    size += endpoint.into_u8_buf(&mut desc[size..size + endpoint.length()]);
+                }
+            }
+            
+            self.fifo_layout.set(layout);
+            unsafe { CONFIGURATION_USAGE.record(size) };
+            config.set_total_length(size as u16);
+            config.into_u8_buf(&mut desc[0..config.length()]);
+            self.set_configuration_total_length(size as u16);
+            self.strings.map_or(Ok(()), |strings| validate::validate_configuration(&desc[..size], strings))
+        })
+    }
+
+    pub fn set_configuration_total_length(&self, length: u16) {
+        self.configuration_total_length.set(length);
+    }
+
+    pub fn get_configuration_total_length(&self) -> u16 {
+        self.configuration_total_length.get()
+    }
+    
+    fn generate_device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            b_length: 18,
+            b_descriptor_type: 1,
+            bcd_usb: 0x0200,
+            b_device_class: self.device_class.get(),
+            b_device_sub_class: 0x00,
+            b_device_protocol: 0x00,
+            b_max_packet_size0: MAX_PACKET_SIZE as u8,
+            id_vendor: self.vendor_id.get(),
+            id_product: self.product_id.get(),
+            bcd_device: 0x0100,
+            i_manufacturer: STRING_VENDOR,
+            i_product: STRING_BOARD,
+            i_serial_number: if self.strings.map_or(false, |strs| strs.len() > STRING_SERIAL as usize) {
+                STRING_SERIAL
+            } else {
+                0
+            },
+            b_num_configurations: 1,
+        }
+    }
+}
+
+/// Combinations of OUT endpoint interrupts for control transfers denote
+/// different transfer cases.
+///
+/// TableCase encodes the cases from Table 10.7 in the OTG Programming
+/// Guide (pages 279-230).
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum TableCase {
+    /// Case A
+    ///
+    /// * StsPhseRcvd: 0
+    /// * SetUp: 0
+    /// * XferCompl: 1
+    A,   // OUT descriptor updated; check the SR bit to see if Setup or OUT
+    /// Case B
+    ///
+    /// * StsPhseRcvd: 0
+    /// * SetUp: 1
+    /// * XferCompl: 0
+    B,   // Setup Phase Done for previously decoded Setup packet
+    /// Case C
+    ///
+    /// * StsPhseRcvd: 0
+    /// * SetUp: 1
+    /// * XferCompl: 1
+    C,   // OUT descriptor updated for a Setup packet, Setup complete
+    /// Case D
+    ///
+    /// * StsPhseRcvd: 1
+    /// * SetUp: 0
+    /// * XferCompl: 0
+    D,   // Status phase of Control OUT transfer
+    /// Case E
+    ///
+    /// * StsPhseRcvd: 1
+    /// * SetUp: 0
+    /// * XferCompl: 1
+    E,   // OUT descriptor updated; check SR bit to see if Setup or Out.
+         // Plus, host is now in Control Write Status phase
+}
+
+impl TableCase {
+    /// Decodes a value from the OUT endpoint interrupt register.
+    ///
+    /// Only properly decodes values with the combinations shown in the
+    /// programming guide.
+    pub fn decode_interrupt(device_out_int: u32) -> TableCase {
+        if device_out_int & (OutInterruptMask::XferComplMsk as u32) != 0 {
+            if device_out_int & (OutInterruptMask::SetUPMsk as u32) != 0 {
+                TableCase::C
+            } else if device_out_int & (OutInterruptMask::StsPhseRcvdMsk as u32) != 0 {
+                TableCase::E
+            } else {
+                TableCase::A
+            }
+        } else {
+            if device_out_int & (OutInterruptMask::SetUPMsk as u32) != 0 {
+                TableCase::B
+            } else {
+                TableCase::D
+            }
+        }
+    }
+}
+
+/// Packs `bytes` into `words` in the little-endian order the DMA engine
+/// uses.
+fn copy_to_words(bytes: &[u8], words: &mut [u32]) {
+    for (i, &b) in bytes.iter().enumerate() {
+        if i % 4 == 0 {
+            words[i / 4] = 0;
+        }
+        words[i / 4] |= (b as u32) << ((i % 4) * 8);
+    }
+}
+
+/// Unpacks `words` into `bytes`, the reverse of `copy_to_words`.
+fn copy_from_words(words: &[u32], bytes: &mut [u8]) {
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (words[i / 4] >> ((i % 4) * 8)) as u8;
+    }
+}
+
+fn data_endpoint(address: u8, transfer: EndpointType, interval: u8) -> EndpointDescriptor {
+    let attributes = EndpointAttributes {
+        transfer: match transfer {
+            EndpointType::Bulk => EndpointTransferType::Bulk,
+            EndpointType::Interrupt => EndpointTransferType::Interrupt,
+        },
+        synchronization: EndpointSynchronizationType::None,
+        usage: EndpointUsageType::Data,
+    };
+    EndpointDescriptor::new(address, attributes, interval)
+}
+
+/// The descriptors of `interface` as interface `number`: the interface,
+/// its HID descriptor if it is a HID interface, and its OUT and IN
+/// endpoints.
+fn interface_descriptors(interface: Interface, number: u8)
+                         -> (InterfaceDescriptor, Option<HidDeviceDescriptor>, [EndpointDescriptor; 2]) {
+    let descriptor = InterfaceDescriptor::new(interface.string, number, interface.class,
+                                              interface.sub_class, interface.protocol);
+    let hid = interface.report_descriptor.map(|report| HidDeviceDescriptor::new(report.len() as u16));
+    let address = interface.endpoint as u8;
+    let endpoints = [data_endpoint(address, interface.transfer, interface.interval),
+                     data_endpoint(0x80 | address, interface.transfer, interface.interval)];
+    (descriptor, hid, endpoints)
+}
+
+// Uncomment the call in `handle_interrupt`, with `usb_debug`'s print
+// definitions, to see each interrupt's causes.
+#[allow(dead_code)]
+fn print_usb_interrupt_status(status: u32) {
+    usb_debug!("USB interrupt, status: {:08x}\n", status);
+    if (status & Interrupt::HostMode as u32) != 0           {usb_debug!("  +Host mode\n");}
+    if (status & Interrupt::Mismatch as u32) != 0           {usb_debug!("  +Mismatch\n");}
+    if (status & Interrupt::OTG as u32) != 0                {usb_debug!("  +OTG\n");}
+    if (status & Interrupt::SOF as u32) != 0                {usb_debug!("  +SOF\n");}
+    if (status & Interrupt::RxFIFO as u32) != 0             {usb_debug!("  +RxFIFO\n");}
+    if (status & Interrupt::GlobalInNak as u32) != 0        {usb_debug!("  +GlobalInNak\n");}
+    if (status & Interrupt::OutNak as u32) != 0             {usb_debug!("  +OutNak\n");}
+    if (status & Interrupt::EarlySuspend as u32) != 0       {usb_debug!("  +EarlySuspend\n");}
+    if (status & Interrupt::Suspend as u32) != 0            {usb_debug!("  +Suspend\n");}
+    if (status & Interrupt::Reset as u32) != 0              {usb_debug!("  +USB reset\n");}
+    if (status & Interrupt::EnumDone as u32) != 0           {usb_debug!("  +Speed enum done\n");}
+    if (status & Interrupt::OutISOCDrop as u32) != 0        {usb_debug!("  +Out ISOC drop\n");}
+    if (status & Interrupt::EOPF as u32) != 0               {usb_debug!("  +EOPF\n");}
+    if (status & Interrupt::EndpointMismatch as u32) != 0   {usb_debug!("  +Endpoint mismatch\n");}
+    if (status & Interrupt::InEndpoints as u32) != 0        {usb_debug!("  +IN endpoints\n");}
+    if (status & Interrupt::OutEndpoints as u32) != 0       {usb_debug!("  +OUT endpoints\n");}
+    if (status & Interrupt::InISOCIncomplete as u32) != 0   {usb_debug!("  +IN ISOC incomplete\n");}
+    if (status & Interrupt::IncompletePeriodic as u32) != 0 {usb_debug!("  +Incomp periodic\n");}
+    if (status & Interrupt::FetchSuspend as u32) != 0       {usb_debug!("  +Fetch suspend\n");}
+    if (status & Interrupt::ResetDetected as u32) != 0      {usb_debug!("  +Reset detected\n");}
+    if (status & Interrupt::ConnectIDChange as u32) != 0    {usb_debug!("  +Connect ID change\n");}
+    if (status & Interrupt::SessionRequest as u32) != 0     {usb_debug!("  +Session request\n");}
+    if (status & Interrupt::ResumeWakeup as u32) != 0       {usb_debug!("  +Resume/wakeup\n");}
+}
@@ -42,8 +42,29 @@ pub struct Registers {
 
     pub device_in_ep_tx_fifo_size: [VolatileCell<u32>; 15],
 
-    _reserved2: [u32; 432],
-
+    _reserved2: [u32; 176],
+    // 0x400
+    pub host_config: VolatileCell<u32>,
+    pub host_frame_interval: VolatileCell<u32>,
+    pub host_frame_number: VolatileCell<u32>,
+    _reserved_host0: u32,
+    pub _hptxsts: VolatileCell<u32>,
+    pub host_all_channel_interrupt: VolatileCell<u32>,
+    pub host_all_channel_interrupt_mask: VolatileCell<u32>,
+
+    _reserved_host1: [u32; 9],
+    // 0x440
+    /// Port control and status. Bits 1, 2, 3 and 5 are cleared by writing
+    /// 1, so writes must mask them out of the value read (`HPRT_W1C`).
+    pub host_port: VolatileCell<u32>,
+
+    _reserved_host2: [u32; 47],
+    // 0x500
+    pub host_channels: [HostChannel; 16],
+    // 0x700
+    _reserved_host3: [u32; 64],
+
+    // 0x800
     pub device_config: VolatileCell<u32>,
     pub device_control: VolatileCell<u32>,
     pub device_status: VolatileCell<u32>,
@@ -97,6 +118,21 @@ pub struct OutEndpoint {
     pub buffer_address: VolatileCell<u32>,
 }
 
+/// A host mode channel. The driver uses buffer DMA in host mode, so a
+/// transfer is set up with `transfer_size` and `dma_address` rather than
+/// descriptors.
+#[repr(C)]
+pub struct HostChannel {
+    pub characteristics: VolatileCell<u32>,
+    pub _split_control: VolatileCell<u32>,
+    pub interrupt: VolatileCell<u32>,
+    pub interrupt_mask: VolatileCell<u32>,
+    pub transfer_size: VolatileCell<u32>,
+    pub dma_address: VolatileCell<u32>,
+    _reserved0: u32,
+    pub buffer_address: VolatileCell<u32>,
+}
+
 /// In/Out Endpoint Control flags
 #[repr(C)]
 #[derive(Clone, Copy)]
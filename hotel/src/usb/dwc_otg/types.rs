@@ -2,8 +2,8 @@
 
 use core::ops::Deref;
 use super::serialize::Serialize;
-use usb::constants::Descriptor;
-use usb::constants::MAX_PACKET_SIZE;
+use super::constants::Descriptor;
+use super::constants::MAX_PACKET_SIZE;
 
 /// A StaticRef is a pointer to statically allocated mutable data such
 /// as memory mapped I/O registers.
@@ -382,7 +382,9 @@ pub struct HidDeviceDescriptor {
 }
 
 impl HidDeviceDescriptor {
-    pub fn new() -> HidDeviceDescriptor {
+    /// A HID descriptor for an interface with one report descriptor of
+    /// `report_length` bytes.
+    pub fn new(report_length: u16) -> HidDeviceDescriptor {
         HidDeviceDescriptor {
             b_length: 9,
             b_descriptor_type: Descriptor::HidDevice as u8,
@@ -390,7 +392,7 @@ impl HidDeviceDescriptor {
             b_country: 0,
             b_descriptors: 1,
             b_sub_descriptor_type: 34, // Report
-            w_sub_descriptor_length: 34
+            w_sub_descriptor_length: report_length
         }
     }
 
@@ -438,6 +440,8 @@ pub enum SetupRequestType {
 #[repr(u8)]
 pub enum SetupClassRequestType {
     Undefined = 0,
+    GetReport = 1,
+    SetReport = 9,
     SetIdle = 10,
 }
 
@@ -536,6 +540,8 @@ impl SetupRequest {
 
     pub fn class_request(&self) -> SetupClassRequestType {
         match self.b_request {
+            1  => SetupClassRequestType::GetReport,
+            9  => SetupClassRequestType::SetReport,
             10 => SetupClassRequestType::SetIdle,
             _  => SetupClassRequestType::Undefined,
         }
@@ -0,0 +1,151 @@
+//! Consistency checks for the descriptors the device reports
+//!
+//! A host that finds a descriptor set inconsistent usually just fails to
+//! enumerate the device, without saying why. `USB::init` runs these
+//! checks on the configuration descriptor it generates and on the board's
+//! strings, and panics with the first problem found, so it shows up on
+//! the console instead.
+
+use super::constants::Descriptor;
+use super::types::{DeviceDescriptor, StringDescriptor};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The descriptors don't fit in the configuration buffer
+    BufferTooSmall { needed: usize, capacity: usize },
+    /// A descriptor's bLength doesn't match its type, or runs past the end
+    BadLength { offset: usize },
+    /// wTotalLength isn't the length of the configuration
+    TotalLength { declared: usize, actual: usize },
+    /// bNumInterfaces isn't the number of interfaces
+    InterfaceCount { declared: u8, actual: u8 },
+    /// Interfaces must be numbered 0, 1, ... in order
+    InterfaceNumber { expected: u8, actual: u8 },
+    /// bNumEndpoints isn't the number of endpoints after the interface
+    EndpointCount { interface: u8, declared: u8, actual: u8 },
+    /// Two endpoint descriptors have the same address
+    DuplicateEndpoint(u8),
+    /// An endpoint is outside an interface, or is endpoint 0
+    BadEndpoint(u8),
+    /// A descriptor refers to a string the board doesn't provide
+    StringIndex(u8),
+    /// A string descriptor's bLength doesn't match its string
+    StringLength(u8),
+}
+
+/// Lengths of the descriptors of fixed size
+const CONFIGURATION_LEN: usize = 9;
+const INTERFACE_LEN: usize = 9;
+const ENDPOINT_LEN: usize = 7;
+
+/// Longest string a string descriptor's bLength can describe
+const MAX_STRING_LEN: usize = (255 - 2) / 2;
+
+fn check_string(index: u8, strings: &[StringDescriptor]) -> Result<(), DescriptorError> {
+    if index == 0 || (index as usize) < strings.len() {
+        Ok(())
+    } else {
+        Err(DescriptorError::StringIndex(index))
+    }
+}
+
+/// Checks that every string descriptor's length matches its string.
+pub fn validate_strings(strings: &[StringDescriptor]) -> Result<(), DescriptorError> {
+    for (index, string) in strings.iter().enumerate() {
+        if string.b_string.len() > MAX_STRING_LEN || string.length() != 2 + 2 * string.b_string.len() {
+            return Err(DescriptorError::StringLength(index as u8));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the strings the device descriptor refers to.
+pub fn validate_device(device: &DeviceDescriptor, strings: &[StringDescriptor]) -> Result<(), DescriptorError> {
+    for &index in [device.i_manufacturer, device.i_product, device.i_serial_number].iter() {
+        if let Err(error) = check_string(index, strings) {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Checks a serialized configuration descriptor and the descriptors that
+/// follow it, against the board's `strings`.
+pub fn validate_configuration(configuration: &[u8], strings: &[StringDescriptor])
+                              -> Result<(), DescriptorError> {
+    if configuration.len() < CONFIGURATION_LEN || configuration[0] as usize != CONFIGURATION_LEN ||
+        configuration[1] != Descriptor::Configuration as u8 {
+        return Err(DescriptorError::BadLength { offset: 0 });
+    }
+    let declared_len = configuration[2] as usize | (configuration[3] as usize) << 8;
+    if declared_len != configuration.len() {
+        return Err(DescriptorError::TotalLength { declared: declared_len, actual: configuration.len() });
+    }
+    if let Err(error) = check_string(configuration[6], strings) {
+        return Err(error);
+    }
+
+    let mut interfaces = 0;
+    // The interface being walked: its number, declared and seen endpoints
+    let mut current: Option<(u8, u8, u8)> = None;
+    // Bit n for OUT endpoint n, bit 16 + n for IN endpoint n
+    let mut endpoints: u32 = 0;
+    let mut offset = CONFIGURATION_LEN;
+    while offset < configuration.len() {
+        let len = configuration[offset] as usize;
+        if len < 2 || offset + len > configuration.len() {
+            return Err(DescriptorError::BadLength { offset: offset });
+        }
+        let descriptor = &configuration[offset..offset + len];
+        match descriptor[1] {
+            t if t == Descriptor::Interface as u8 => {
+                if len != INTERFACE_LEN {
+                    return Err(DescriptorError::BadLength { offset: offset });
+                }
+                if let Some((number, declared, actual)) = current {
+                    if declared != actual {
+                        return Err(DescriptorError::EndpointCount {
+                            interface: number, declared: declared, actual: actual });
+                    }
+                }
+                if descriptor[2] != interfaces {
+                    return Err(DescriptorError::InterfaceNumber { expected: interfaces, actual: descriptor[2] });
+                }
+                if let Err(error) = check_string(descriptor[8], strings) {
+                    return Err(error);
+                }
+                current = Some((descriptor[2], descriptor[4], 0));
+                interfaces += 1;
+            }
+            t if t == Descriptor::Endpoint as u8 => {
+                if len != ENDPOINT_LEN {
+                    return Err(DescriptorError::BadLength { offset: offset });
+                }
+                let address = descriptor[2];
+                let number = address & 0x0f;
+                let bit = if address & 0x80 != 0 { 16 + number } else { number };
+                let interface = match current {
+                    Some(interface) if number != 0 && address & 0x70 == 0 => interface,
+                    _ => return Err(DescriptorError::BadEndpoint(address)),
+                };
+                if endpoints & 1 << bit != 0 {
+                    return Err(DescriptorError::DuplicateEndpoint(address));
+                }
+                endpoints |= 1 << bit;
+                current = Some((interface.0, interface.1, interface.2 + 1));
+            }
+            // Class descriptors (e.g. HID) aren't checked.
+            _ => {}
+        }
+        offset += len;
+    }
+    if let Some((number, declared, actual)) = current {
+        if declared != actual {
+            return Err(DescriptorError::EndpointCount { interface: number, declared: declared, actual: actual });
+        }
+    }
+    if configuration[4] != interfaces {
+        return Err(DescriptorError::InterfaceCount { declared: configuration[4], actual: interfaces });
+    }
+    Ok(())
+}
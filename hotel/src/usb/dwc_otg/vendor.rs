@@ -0,0 +1,117 @@
+//! Vendor control requests on endpoint 0
+//!
+//! Vendor-type SETUP requests are dispatched by their `bRequest` to the
+//! handler registered for it with `USB::add_vendor_handler`, so kernel
+//! services can offer a host tool commands without an interface of their
+//! own. A handler answers device-to-host requests with up to
+//! `MAX_VENDOR_DATA` bytes, and is given the data stage (also at most
+//! `MAX_VENDOR_DATA` bytes) of host-to-device requests once it has been
+//! received; if there is no handler, or it returns an error, the request
+//! is stalled.
+
+use core::cmp;
+use kernel::ReturnCode;
+use trace;
+
+use super::constants::MAX_PACKET_SIZE;
+use super::ep0::{ControlWrite, Response};
+use super::types::{SetupDirection, SetupRequest};
+use super::{copy_to_words, USB};
+
+/// Most vendor request codes that can have handlers
+pub const MAX_VENDOR_HANDLERS: usize = 16;
+
+/// Longest data stage of a vendor request, in either direction: one
+/// packet
+pub const MAX_VENDOR_DATA: usize = MAX_PACKET_SIZE as usize;
+
+/// The fields of a vendor SETUP packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VendorRequest {
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+pub trait VendorHandler {
+    /// Handles `request`. For a device-to-host request, writes the
+    /// response into `data` and returns its length (it is cut to the
+    /// length the host asked for). For a host-to-device request, `data`
+    /// holds what the host sent in the data stage, if any, and the
+    /// returned length is ignored. Errors stall the request.
+    fn vendor_request(&self, request: &VendorRequest, data: &mut [u8]) -> Result<usize, ReturnCode>;
+}
+
+impl USB {
+    /// Sends vendor requests with `request_code` to `handler`. Returns
+    /// EALREADY if the code already has a handler and ENOMEM if
+    /// `MAX_VENDOR_HANDLERS` codes do.
+    pub fn add_vendor_handler(&self, request_code: u8, handler: &'static VendorHandler) -> ReturnCode {
+        let mut handlers = self.vendor_handlers.get();
+        if handlers.iter().any(|entry| entry.map_or(false, |(code, _)| code == request_code)) {
+            return ReturnCode::EALREADY;
+        }
+        match handlers.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some((request_code, handler)),
+            None => return ReturnCode::ENOMEM,
+        }
+        self.vendor_handlers.set(handlers);
+        ReturnCode::SUCCESS
+    }
+
+    fn vendor_handler(&self, request_code: u8) -> Option<&'static VendorHandler> {
+        self.vendor_handlers.get()
+            .iter()
+            .filter_map(|&entry| entry)
+            .find(|&(code, _)| code == request_code)
+            .map(|(_, handler)| handler)
+    }
+
+    /// Answers a vendor request, writing any response into `buf`.
+    pub(super) fn handle_vendor(&self, request: &SetupRequest, buf: &mut [u32; 64]) -> Response {
+        let vendor_request = VendorRequest {
+            request: request.b_request,
+            value: request.w_value,
+            index: request.w_index,
+            length: request.w_length,
+        };
+        let handler = match self.vendor_handler(request.b_request) {
+            Some(handler) => handler,
+            None => {
+                trace::record("usb vendor unhandled", request.b_request as u32);
+                return Response::Stall;
+            }
+        };
+        match request.data_direction() {
+            SetupDirection::DeviceToHost => {
+                let mut response = [0; MAX_VENDOR_DATA];
+                match handler.vendor_request(&vendor_request, &mut response) {
+                    Ok(len) => {
+                        let len = cmp::min(cmp::min(len, response.len()), request.w_length as usize);
+                        copy_to_words(&response[..len], buf);
+                        Response::Send(len)
+                    }
+                    Err(_) => Response::Stall,
+                }
+            }
+            SetupDirection::HostToDevice if request.w_length == 0 => {
+                match handler.vendor_request(&vendor_request, &mut []) {
+                    Ok(_) => Response::Acknowledge,
+                    Err(_) => Response::Stall,
+                }
+            }
+            SetupDirection::HostToDevice if request.w_length as usize <= MAX_VENDOR_DATA => {
+                Response::Receive(ControlWrite::Vendor(vendor_request))
+            }
+            SetupDirection::HostToDevice => Response::Stall,
+        }
+    }
+
+    /// Hands the data stage of a host-to-device vendor request to its
+    /// handler. Returns whether the handler accepted it.
+    pub(super) fn handle_vendor_data(&self, request: &VendorRequest, data: &mut [u8]) -> bool {
+        self.vendor_handler(request.request)
+            .map_or(false, |handler| handler.vendor_request(request, data).is_ok())
+    }
+}
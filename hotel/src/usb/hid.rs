@@ -0,0 +1,164 @@
+//! Raw reports on the vendor HID interface
+//!
+//! `RAW_HID` implements `hil::hid::HidReports` for the vendor-defined HID
+//! interface (`RAW_HID_INTERFACE`, on endpoint 3), whose report
+//! descriptor (`RAW_HID_REPORT_DESCRIPTOR`) declares one 64-byte input, output and feature report without report
+//! IDs. Unlike the U2F interface, nothing is interpreted here: tools that
+//! speak their own protocol over HID (which needs no driver on the host)
+//! exchange reports directly with the client.
+//!
+//! The host reads the feature report with GET_REPORT and writes it with
+//! SET_REPORT, both on endpoint 0, which the core passes on as the
+//! interface's `ClassHandler`; the client is told about each write.
+
+use core::cell::Cell;
+use core::cmp;
+use hil::hid::{HidClient, HidReports, REPORT_SIZE};
+use kernel::ReturnCode;
+
+use super::dwc_otg::{ClassHandler, EndpointClient, EndpointType, Interface, EP3_BUFFERS};
+use super::USB0;
+
+/// The raw HID interface's interrupt endpoint
+const RAW_HID_ENDPOINT: usize = 3;
+
+/// The report type in the high byte of a GetReport/SetReport `wValue`
+const HID_REPORT_TYPE_FEATURE: u8 = 3;
+
+/// The vendor-defined HID interface
+pub const RAW_HID_INTERFACE: Interface = Interface {
+    class: 3,
+    sub_class: 0,
+    protocol: 0,
+    string: 0,
+    endpoint: RAW_HID_ENDPOINT,
+    transfer: EndpointType::Interrupt,
+    interval: 2,
+    tx_fifo_packets: 1,
+    report_descriptor: Some(&RAW_HID_REPORT_DESCRIPTOR),
+};
+
+// A vendor-defined HID interface with 64-byte input, output and feature
+// reports and no report IDs.
+const RAW_HID_REPORT_DESCRIPTOR: [u8; 47] = [
+    0x06, 0x00, 0xFF, /* Usage Page (Vendor Defined 0xFF00) */
+    0x09, 0x01,       /* Usage (0x01) */
+    0xA1, 0x01,       /* Collection (Application) */
+    0x09, 0x02,       /*   Usage (0x02) */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64) */
+    0x81, 0x02,       /*   Input (Data, Var, Abs) */
+    0x09, 0x03,       /*   Usage (0x03) */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64) */
+    0x91, 0x02,       /*   Output (Data, Var, Abs) */
+    0x09, 0x04,       /*   Usage (0x04) */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64) */
+    0xB1, 0x02,       /*   Feature (Data, Var, Abs) */
+    0xC0              /* End Collection */
+];
+
+pub static mut RAW_HID: RawHid = RawHid::new();
+
+pub struct RawHid {
+    client: Cell<Option<&'static HidClient>>,
+    // The feature report the host reads
+    input_feature: Cell<[u8; REPORT_SIZE]>,
+    // The feature report the host last wrote, and its length
+    output_feature: Cell<[u8; REPORT_SIZE]>,
+    output_feature_len: Cell<usize>,
+}
+
+impl RawHid {
+    const fn new() -> RawHid {
+        RawHid {
+            client: Cell::new(None),
+            input_feature: Cell::new([0; REPORT_SIZE]),
+            output_feature: Cell::new([0; REPORT_SIZE]),
+            output_feature_len: Cell::new(0),
+        }
+    }
+
+    /// Binds the interface to its endpoint. Must be called before
+    /// `USB0.init` so the endpoint is activated when the host configures
+    /// the device.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe {
+            let rc = USB0.setup_endpoint(RAW_HID_ENDPOINT, EndpointType::Interrupt, &mut EP3_BUFFERS, self);
+            if rc != ReturnCode::SUCCESS {
+                return rc;
+            }
+            USB0.set_class_handler(RAW_HID_ENDPOINT, self)
+        }
+    }
+}
+
+impl ClassHandler for RawHid {
+    fn get_report(&self, report_type: u8, _report_id: u8, report: &mut [u8]) -> Option<usize> {
+        if report_type != HID_REPORT_TYPE_FEATURE {
+            return None;
+        }
+        let len = cmp::min(report.len(), REPORT_SIZE);
+        report[..len].copy_from_slice(&self.input_feature.get()[..len]);
+        Some(len)
+    }
+
+    fn accepts_report(&self, report_type: u8, _report_id: u8, length: u16) -> bool {
+        report_type == HID_REPORT_TYPE_FEATURE && length as usize <= REPORT_SIZE
+    }
+
+    fn set_report(&self, _report_type: u8, _report_id: u8, report: &[u8]) {
+        let len = cmp::min(report.len(), REPORT_SIZE);
+        let mut feature = [0; REPORT_SIZE];
+        feature[..len].copy_from_slice(&report[..len]);
+        self.output_feature.set(feature);
+        self.output_feature_len.set(len);
+        self.client.get().map(|client| client.feature_report_written());
+    }
+}
+
+impl EndpointClient for RawHid {
+    fn packet_received(&self, _endpoint: usize, packet: &[u8]) {
+        self.client.get().map(|client| client.report_received(packet));
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {
+        self.client.get().map(|client| client.report_sent());
+    }
+}
+
+impl HidReports for RawHid {
+    fn set_client(&self, client: &'static HidClient) {
+        self.client.set(Some(client));
+    }
+
+    fn send_report(&self, report: &[u8]) -> ReturnCode {
+        if report.len() > REPORT_SIZE {
+            return ReturnCode::ESIZE;
+        }
+        // Reports have a fixed size, so pad short ones out.
+        let mut packet = [0; REPORT_SIZE];
+        packet[..report.len()].copy_from_slice(report);
+        unsafe { USB0.transmit_packet(RAW_HID_ENDPOINT, &packet) }
+    }
+
+    fn set_feature_report(&self, report: &[u8]) {
+        let len = cmp::min(report.len(), REPORT_SIZE);
+        let mut feature = [0; REPORT_SIZE];
+        feature[..len].copy_from_slice(&report[..len]);
+        self.input_feature.set(feature);
+    }
+
+    fn feature_report(&self, report: &mut [u8]) -> usize {
+        let len = cmp::min(self.output_feature_len.get(), report.len());
+        report[..len].copy_from_slice(&self.output_feature.get()[..len]);
+        len
+    }
+}
@@ -0,0 +1,99 @@
+//! Hotel's integration of the DWC-OTG core
+//!
+//! The controller is at `BASE_ADDR`, clocked by the PMU's Usb0 and
+//! Usb0TimerHs clocks, and has two PHYs behind a mux; a board picks one
+//! with `select_phy` before `USB0.init`. The host's frames trim the RC
+//! oscillator and measure the calendar's drift.
+
+use calendar;
+use errata::{self, Erratum};
+use pmu::{Clock, PeripheralClock, PeripheralClock1, PeripheralReset};
+use xo;
+
+use super::dwc_otg::{Integration, Registers, USB};
+
+// Hardware base address of the singleton USB controller
+const BASE_ADDR: *const Registers = 0x40300000 as *const Registers;
+
+/// Which physical connection to use
+#[derive(Clone, Copy)]
+pub enum PHY {
+    A,
+    B,
+}
+
+// The PHY `USB0` uses, set by the board
+static mut SELECTED_PHY: PHY = PHY::A;
+
+/// Sets the PHY `USB0` uses, before `init`.
+pub fn select_phy(phy: PHY) {
+    unsafe {
+        SELECTED_PHY = phy;
+    }
+}
+
+pub struct HotelUsb {
+    core_clock: Clock,
+    timer_clock: Clock,
+    core_reset: PeripheralReset,
+}
+
+static HOTEL_USB: HotelUsb = unsafe {
+    HotelUsb {
+        core_clock: Clock::new(PeripheralClock::Bank1(PeripheralClock1::Usb0)),
+        timer_clock: Clock::new(PeripheralClock::Bank1(PeripheralClock1::Usb0TimerHs)),
+        core_reset: PeripheralReset::new(PeripheralClock::Bank1(PeripheralClock1::Usb0)),
+    }
+};
+
+pub static mut USB0: USB = unsafe { USB::new(BASE_ADDR, &HOTEL_USB) };
+
+impl Integration for HotelUsb {
+    fn enable_clocks(&self) {
+        self.core_clock.acquire();
+        self.timer_clock.acquire();
+    }
+
+    fn disable_clocks(&self) {
+        self.core_clock.release();
+        self.timer_clock.release();
+    }
+
+    fn set_busy(&self, busy: bool) {
+        self.core_clock.set_busy(busy);
+        self.timer_clock.set_busy(busy);
+    }
+
+    fn reset_core(&self) {
+        self.core_reset.pulse();
+    }
+
+    fn phy_config(&self) -> u16 {
+        let phy = match unsafe { SELECTED_PHY } {
+            PHY::A => 0b100, // USB PHY0, active
+            PHY::B => 0b101, // USB PHY1, active
+        };
+        // Write `phy` to custom register 0, CUSTOM_CFG
+        1 << 15 | phy << 4
+    }
+
+    fn tx_fifo_packets(&self) -> u16 {
+        // Early silicon only has room for one packet per IN FIFO
+        if errata::applies(Erratum::UsbSmallFifoRam) { 1 } else { 2 }
+    }
+
+    fn start_of_frame(&self, frame: u32) -> bool {
+        unsafe {
+            let trimming = xo::XO0.handle_sof(frame);
+            let measuring = calendar::CALENDAR.handle_sof(frame);
+            trimming || measuring
+        }
+    }
+
+    fn frames_stopped(&self) {
+        unsafe {
+            xo::XO0.restart();
+            calendar::CALENDAR.restart_drift_measurement();
+        }
+    }
+}
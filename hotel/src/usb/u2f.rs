@@ -0,0 +1,531 @@
+//! U2FHID transport over the U2F interface
+//!
+//! `U2F_HID` implements `hil::u2f::U2fTransport` on the interrupt endpoint
+//! (endpoint 1) of the HID interface advertised in the configuration
+//! descriptor, following the FIDO U2F HID protocol. Every 64-byte report
+//! starts with a 4-byte channel ID; a message is an initialization packet
+//! carrying the command and length followed by numbered continuation
+//! packets.
+//!
+//! The transport handles the parts of the protocol that don't need the
+//! authenticator: it allocates channels (`U2FHID_INIT`), answers
+//! `U2FHID_PING` by echoing, and reports protocol errors. `U2FHID_MSG`
+//! requests are passed to the client. One request is received at a time,
+//! and up to `MAX_REQUESTS` can be waiting for responses, on different
+//! channels; a channel is told `ERR_CHANNEL_BUSY` while its own request is
+//! waiting, and the others while the client holds the buffer or all
+//! `MAX_REQUESTS` are waiting. Responses are sent one at a time, in the
+//! order the client gives them. There is no message timeout: a host that
+//! stops part way through a request must start over with a new
+//! initialization packet.
+
+use core::cell::Cell;
+use core::cmp;
+use hil::u2f::{U2fClient, U2fTransport, MAX_REQUESTS};
+use kernel::ReturnCode;
+use kernel::common::cells::TakeCell;
+use memory::BufferUsage;
+
+use super::dwc_otg::{EndpointClient, EndpointType, Interface, EP1_BUFFERS};
+use super::{STRING_INTERFACE2, USB0};
+
+/// The U2F interface's interrupt endpoint
+const U2F_ENDPOINT: usize = 1;
+
+/// The U2FHID interface
+pub const U2F_INTERFACE: Interface = Interface {
+    class: 3,
+    sub_class: 0,
+    protocol: 0,
+    string: STRING_INTERFACE2,
+    endpoint: U2F_ENDPOINT,
+    transfer: EndpointType::Interrupt,
+    interval: 2,
+    // Messages span packets
+    tx_fifo_packets: 2,
+    report_descriptor: Some(&U2F_REPORT_DESCRIPTOR),
+};
+
+// Copied from Cr52 usb_hidu2f.c - pal
+const U2F_REPORT_DESCRIPTOR: [u8; 34] = [
+    0x06, 0xD0, 0xF1, /* Usage Page (FIDO Alliance), FIDO_USAGE_PAGE */
+    0x09, 0x01,       /* Usage (U2F HID Authenticator Device),
+                         FIDO_USAGE_U2FHID */
+    0xA1, 0x01,       /* Collection (Application), HID_APPLICATION */
+    0x09, 0x20,       /*   Usage (Input Report Data), FIDO_USAGE_DATA_IN */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64), HID_INPUT_REPORT_BYTES */
+    0x81, 0x02,       /*   Input (Data, Var, Abs), Usage */
+    0x09, 0x21,       /*   Usage (Output Report Data), FIDO_USAGE_DATA_OUT */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xFF, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64), HID_OUTPUT_REPORT_BYTES */
+    0x91, 0x02,       /*   Output (Data, Var, Abs), Usage */
+    0xC0              /* End Collection */
+];
+
+const PACKET_SIZE: usize = 64;
+/// Payload bytes in an initialization packet, after the channel ID,
+/// command and length
+const INIT_DATA_SIZE: usize = PACKET_SIZE - 7;
+/// Payload bytes in a continuation packet, after the channel ID and
+/// sequence number
+const CONT_DATA_SIZE: usize = PACKET_SIZE - 5;
+/// The largest message the framing can carry
+const MAX_MESSAGE_SIZE: usize = INIT_DATA_SIZE + 128 * CONT_DATA_SIZE;
+
+/// Initialization packets have the top bit of the command byte set.
+const TYPE_INIT: u8 = 0x80;
+
+const U2FHID_PING: u8 = 0x81;
+const U2FHID_MSG: u8 = 0x83;
+const U2FHID_INIT: u8 = 0x86;
+const U2FHID_ERROR: u8 = 0xbf;
+
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CID: u8 = 0x0b;
+const ERR_OTHER: u8 = 0x7f;
+
+const CID_BROADCAST: u32 = 0xffffffff;
+
+/// Length of the nonce in a `U2FHID_INIT` request
+const INIT_NONCE_SIZE: usize = 8;
+const PROTOCOL_VERSION: u8 = 2;
+const DEVICE_VERSION: [u8; 3] = [0, 1, 0];
+/// No optional capabilities (wink, lock) are implemented.
+const CAPABILITIES: u8 = 0;
+
+pub static mut U2F_HID: U2fHid = U2fHid::new();
+
+// The longest request or response, against the client's buffer
+static mut MESSAGE_USAGE: BufferUsage = BufferUsage::new("u2f message");
+
+pub struct U2fHid {
+    client: Cell<Option<&'static U2fClient>>,
+    next_channel: Cell<u32>,
+
+    // The request being reassembled, if any
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_channel: Cell<Option<u32>>,
+    rx_command: Cell<u8>,
+    rx_len: Cell<usize>,
+    rx_cursor: Cell<usize>,
+    rx_seq: Cell<u8>,
+
+    // The channels whose requests are waiting for responses
+    awaiting: [Cell<Option<u32>>; MAX_REQUESTS],
+
+    // The message being sent, if any
+    tx_buffer: TakeCell<'static, [u8]>,
+    // Whether `tx_buffer` is the receive buffer, echoing a ping
+    tx_echo: Cell<bool>,
+    // A response given while another message was being sent
+    queued_buffer: TakeCell<'static, [u8]>,
+    queued_channel: Cell<u32>,
+    queued_command: Cell<u8>,
+    queued_len: Cell<usize>,
+    tx_channel: Cell<u32>,
+    tx_command: Cell<u8>,
+    tx_len: Cell<usize>,
+    tx_cursor: Cell<usize>,
+    // None until the initialization packet has been queued
+    tx_seq: Cell<Option<u8>>,
+
+    // A single-packet reply (to INIT, or an error) waiting to be sent
+    pending: Cell<Option<[u8; PACKET_SIZE]>>,
+}
+
+impl U2fHid {
+    const fn new() -> U2fHid {
+        U2fHid {
+            client: Cell::new(None),
+            next_channel: Cell::new(1),
+            rx_buffer: TakeCell::empty(),
+            rx_channel: Cell::new(None),
+            rx_command: Cell::new(0),
+            rx_len: Cell::new(0),
+            rx_cursor: Cell::new(0),
+            rx_seq: Cell::new(0),
+            awaiting: [Cell::new(None), Cell::new(None), Cell::new(None), Cell::new(None)],
+            tx_buffer: TakeCell::empty(),
+            tx_echo: Cell::new(false),
+            queued_buffer: TakeCell::empty(),
+            queued_channel: Cell::new(0),
+            queued_command: Cell::new(0),
+            queued_len: Cell::new(0),
+            tx_channel: Cell::new(0),
+            tx_command: Cell::new(0),
+            tx_len: Cell::new(0),
+            tx_cursor: Cell::new(0),
+            tx_seq: Cell::new(None),
+            pending: Cell::new(None),
+        }
+    }
+
+    /// Binds the transport to the U2F endpoint. Must be called before
+    /// `USB0.init` so the endpoint is activated when the host configures
+    /// the device.
+    pub fn init(&'static self) -> ReturnCode {
+        unsafe { USB0.setup_endpoint(U2F_ENDPOINT, EndpointType::Interrupt, &mut EP1_BUFFERS, self) }
+    }
+
+    /// Whether a new transaction would be refused as busy.
+    fn is_busy(&self) -> bool {
+        self.rx_buffer.is_none() || self.rx_channel.get().is_some() ||
+            self.awaiting.iter().all(|awaiting| awaiting.get().is_some())
+    }
+
+    /// Whether the request on `channel` is waiting for a response.
+    fn is_awaiting(&self, channel: u32) -> bool {
+        self.awaiting.iter().any(|awaiting| awaiting.get() == Some(channel))
+    }
+
+    fn set_awaiting(&self, channel: u32) {
+        self.awaiting.iter().find(|awaiting| awaiting.get().is_none()).map(|free| free.set(Some(channel)));
+    }
+
+    /// Whether the response to the request on `channel` is being sent or
+    /// queued.
+    fn is_answered(&self, channel: u32) -> bool {
+        (self.tx_buffer.is_some() && !self.tx_echo.get() && self.tx_channel.get() == channel) ||
+            (self.queued_buffer.is_some() && self.queued_channel.get() == channel)
+    }
+
+    fn clear_awaiting(&self, channel: u32) {
+        self.awaiting.iter().find(|awaiting| awaiting.get() == Some(channel)).map(|entry| entry.set(None));
+    }
+
+    fn allocate_channel(&self) -> u32 {
+        let channel = self.next_channel.get();
+        let mut next = channel.wrapping_add(1);
+        if next == 0 || next == CID_BROADCAST {
+            next = 1;
+        }
+        self.next_channel.set(next);
+        channel
+    }
+
+    /// Queues a single-packet reply, replacing any that has not been sent.
+    fn send_reply(&self, channel: u32, command: u8, data: &[u8]) {
+        let mut packet = [0; PACKET_SIZE];
+        write_init_header(&mut packet, channel, command, data.len());
+        packet[7..7 + data.len()].copy_from_slice(data);
+        self.pending.set(Some(packet));
+        self.send_next();
+    }
+
+    fn send_error(&self, channel: u32, error: u8) {
+        self.send_reply(channel, U2FHID_ERROR, &[error]);
+    }
+
+    fn handle_init(&self, channel: u32, data: &[u8], len: usize) {
+        if len != INIT_NONCE_SIZE {
+            self.send_error(channel, ERR_INVALID_LEN);
+            return;
+        }
+        // INIT on an allocated channel resynchronizes it, abandoning any
+        // request being received on it.
+        if self.rx_channel.get() == Some(channel) {
+            self.rx_channel.set(None);
+        }
+        let allocated = if channel == CID_BROADCAST { self.allocate_channel() } else { channel };
+        let mut reply = [0; INIT_NONCE_SIZE + 9];
+        reply[..INIT_NONCE_SIZE].copy_from_slice(&data[..INIT_NONCE_SIZE]);
+        write_u32(&mut reply[INIT_NONCE_SIZE..], allocated);
+        reply[INIT_NONCE_SIZE + 4] = PROTOCOL_VERSION;
+        reply[INIT_NONCE_SIZE + 5..INIT_NONCE_SIZE + 8].copy_from_slice(&DEVICE_VERSION);
+        reply[INIT_NONCE_SIZE + 8] = CAPABILITIES;
+        self.send_reply(channel, U2FHID_INIT, &reply);
+    }
+
+    fn handle_init_packet(&self, channel: u32, packet: &[u8]) {
+        let command = packet[4];
+        let len = (packet[5] as usize) << 8 | packet[6] as usize;
+        let data = &packet[7..];
+
+        if command == U2FHID_INIT {
+            self.handle_init(channel, data, len);
+            return;
+        }
+        if channel == 0 || channel == CID_BROADCAST {
+            self.send_error(channel, ERR_INVALID_CID);
+            return;
+        }
+        if self.rx_channel.get() == Some(channel) {
+            self.rx_channel.set(None);
+            self.send_error(channel, ERR_INVALID_SEQ);
+            return;
+        }
+        if self.is_busy() || self.is_awaiting(channel) {
+            self.send_error(channel, ERR_CHANNEL_BUSY);
+            return;
+        }
+        if command != U2FHID_PING && command != U2FHID_MSG {
+            self.send_error(channel, ERR_INVALID_CMD);
+            return;
+        }
+        unsafe { MESSAGE_USAGE.record(len) };
+        let capacity = self.rx_buffer.map_or(0, |buffer| buffer.len());
+        if len > capacity || len > MAX_MESSAGE_SIZE {
+            self.send_error(channel, ERR_INVALID_LEN);
+            return;
+        }
+
+        let count = cmp::min(len, INIT_DATA_SIZE);
+        self.rx_buffer.map(|buffer| buffer[..count].copy_from_slice(&data[..count]));
+        self.rx_channel.set(Some(channel));
+        self.rx_command.set(command);
+        self.rx_len.set(len);
+        self.rx_cursor.set(count);
+        self.rx_seq.set(0);
+        if count == len {
+            self.request_complete();
+        }
+    }
+
+    fn handle_continuation_packet(&self, channel: u32, packet: &[u8]) {
+        // Continuations for other channels are spurious and ignored.
+        if self.rx_channel.get() != Some(channel) {
+            return;
+        }
+        if packet[4] != self.rx_seq.get() {
+            self.rx_channel.set(None);
+            self.send_error(channel, ERR_INVALID_SEQ);
+            return;
+        }
+        let cursor = self.rx_cursor.get();
+        let count = cmp::min(self.rx_len.get() - cursor, CONT_DATA_SIZE);
+        self.rx_buffer.map(|buffer| {
+            buffer[cursor..cursor + count].copy_from_slice(&packet[5..5 + count]);
+        });
+        self.rx_cursor.set(cursor + count);
+        self.rx_seq.set(self.rx_seq.get() + 1);
+        if cursor + count == self.rx_len.get() {
+            self.request_complete();
+        }
+    }
+
+    /// Answers a ping or hands a message to the client.
+    fn request_complete(&self) {
+        let channel = match self.rx_channel.get() {
+            Some(channel) => channel,
+            None => return,
+        };
+        self.rx_channel.set(None);
+        let command = self.rx_command.get();
+        let len = self.rx_len.get();
+
+        if command == U2FHID_PING {
+            self.rx_buffer.take().map(|buffer| {
+                self.tx_echo.set(true);
+                self.start_transmission(channel, command, buffer, len);
+            });
+            return;
+        }
+        match self.client.get() {
+            Some(client) => {
+                self.rx_buffer.take().map(|buffer| {
+                    self.set_awaiting(channel);
+                    client.request_received(channel, command, buffer, len);
+                });
+            }
+            None => self.send_error(channel, ERR_OTHER),
+        }
+    }
+
+    fn start_transmission(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize) {
+        unsafe { MESSAGE_USAGE.record(len) };
+        self.tx_channel.set(channel);
+        self.tx_command.set(command);
+        self.tx_len.set(cmp::min(cmp::min(len, buffer.len()), MAX_MESSAGE_SIZE));
+        self.tx_cursor.set(0);
+        self.tx_seq.set(None);
+        self.tx_buffer.replace(buffer);
+        self.send_next();
+    }
+
+    /// Whether every packet of the current message has been queued.
+    fn transmission_queued(&self) -> bool {
+        self.tx_seq.get().is_some() && self.tx_cursor.get() >= self.tx_len.get()
+    }
+
+    /// Builds the next packet of the current message, if there is one
+    /// left to queue.
+    fn next_message_packet(&self, packet: &mut [u8; PACKET_SIZE]) -> Option<(usize, Option<u8>)> {
+        if self.tx_buffer.is_none() || self.transmission_queued() {
+            return None;
+        }
+        let cursor = self.tx_cursor.get();
+        let len = self.tx_len.get();
+        self.tx_buffer.map(|buffer| match self.tx_seq.get() {
+            None => {
+                let count = cmp::min(len, INIT_DATA_SIZE);
+                write_init_header(packet, self.tx_channel.get(), self.tx_command.get(), len);
+                packet[7..7 + count].copy_from_slice(&buffer[..count]);
+                (cursor + count, Some(0))
+            }
+            Some(seq) => {
+                let count = cmp::min(len - cursor, CONT_DATA_SIZE);
+                write_u32(packet, self.tx_channel.get());
+                packet[4] = seq;
+                packet[5..5 + count].copy_from_slice(&buffer[cursor..cursor + count]);
+                (cursor + count, Some(seq + 1))
+            }
+        })
+    }
+
+    /// Queues the next packet to send, replies first, if the endpoint is
+    /// free.
+    fn send_next(&self) {
+        if unsafe { USB0.is_transmitting(U2F_ENDPOINT) } {
+            return;
+        }
+        let mut packet = [0; PACKET_SIZE];
+        let advance = match self.pending.get() {
+            Some(reply) => {
+                packet = reply;
+                None
+            }
+            None => match self.next_message_packet(&mut packet) {
+                Some(advance) => Some(advance),
+                None => return,
+            },
+        };
+        match unsafe { USB0.transmit_packet(U2F_ENDPOINT, &packet) } {
+            ReturnCode::SUCCESS => {
+                match advance {
+                    None => self.pending.set(None),
+                    Some((cursor, seq)) => {
+                        self.tx_cursor.set(cursor);
+                        self.tx_seq.set(seq);
+                    }
+                }
+            }
+            ReturnCode::EBUSY => {}
+            _ => {
+                // The host is gone; drop everything.
+                self.pending.set(None);
+                if self.tx_buffer.is_some() {
+                    self.transmission_complete();
+                }
+            }
+        }
+    }
+
+    fn transmission_complete(&self) {
+        self.tx_buffer.take().map(|buffer| if self.tx_echo.get() {
+            self.tx_echo.set(false);
+            self.rx_buffer.replace(buffer);
+        } else {
+            self.clear_awaiting(self.tx_channel.get());
+            self.client.get().map(move |client| client.response_sent(buffer));
+        });
+        // The client may have started another response already.
+        if self.tx_buffer.is_none() {
+            self.queued_buffer.take().map(|buffer| {
+                self.start_transmission(self.queued_channel.get(), self.queued_command.get(), buffer,
+                                        self.queued_len.get());
+            });
+        }
+    }
+}
+
+impl EndpointClient for U2fHid {
+    fn packet_received(&self, _endpoint: usize, packet: &[u8]) {
+        // A bus reset drops the packet in flight without a completion, so
+        // pick up a stalled transmission when the host talks again.
+        if self.tx_buffer.is_some() && unsafe { !USB0.is_transmitting(U2F_ENDPOINT) } {
+            if self.transmission_queued() {
+                self.transmission_complete();
+            } else {
+                self.send_next();
+            }
+        }
+
+        if packet.len() < 7 {
+            return;
+        }
+        let channel = read_u32(packet);
+        if packet[4] & TYPE_INIT != 0 {
+            self.handle_init_packet(channel, packet);
+        } else {
+            self.handle_continuation_packet(channel, packet);
+        }
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {
+        if self.tx_buffer.is_some() && self.transmission_queued() {
+            self.transmission_complete();
+        }
+        self.send_next();
+    }
+}
+
+impl U2fTransport for U2fHid {
+    fn set_client(&self, client: &'static U2fClient) {
+        self.client.set(Some(client));
+    }
+
+    fn receive(&self, buffer: &'static mut [u8]) -> ReturnCode {
+        if self.rx_buffer.is_some() || self.tx_echo.get() {
+            return ReturnCode::EALREADY;
+        }
+        unsafe { MESSAGE_USAGE.register(buffer.len()) };
+        self.rx_buffer.replace(buffer);
+        ReturnCode::SUCCESS
+    }
+
+    fn respond(&self, channel: u32, command: u8, buffer: &'static mut [u8], len: usize)
+               -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.is_awaiting(channel) || self.is_answered(channel) {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if self.tx_buffer.is_none() {
+            self.start_transmission(channel, command, buffer, len);
+            return Ok(());
+        }
+        if self.queued_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        self.queued_channel.set(channel);
+        self.queued_command.set(command);
+        self.queued_len.set(len);
+        self.queued_buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn cancel(&self, channel: u32) -> ReturnCode {
+        if !self.is_awaiting(channel) {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_answered(channel) {
+            return ReturnCode::EALREADY;
+        }
+        self.clear_awaiting(channel);
+        ReturnCode::SUCCESS
+    }
+}
+
+fn write_init_header(packet: &mut [u8], channel: u32, command: u8, len: usize) {
+    write_u32(packet, channel);
+    packet[4] = command;
+    packet[5] = (len >> 8) as u8;
+    packet[6] = len as u8;
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+fn write_u32(bytes: &mut [u8], value: u32) {
+    bytes[0] = (value >> 24) as u8;
+    bytes[1] = (value >> 16) as u8;
+    bytes[2] = (value >> 8) as u8;
+    bytes[3] = value as u8;
+}
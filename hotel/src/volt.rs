@@ -0,0 +1,165 @@
+//! Voltage monitor (VOLT)
+//!
+//! Watches the main supply and raises an interrupt when it drops below a
+//! programmable threshold, which on a falling supply gives a short warning
+//! before the chip browns out. Drivers that hold state which must not be
+//! lost half-written (flash) or must not survive the brownout (secrets in
+//! peripheral memories) register as `BrownoutClient`s and are told when the
+//! warning fires. The event is also recorded in the PMU so that
+//! `pmu::reset_cause` reports it after the resulting reset; if the supply
+//! recovers instead, a `RecoveryMonitor` clears the record again so a
+//! later, unrelated reset isn't blamed on it.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use kernel::hil::time::{self, Alarm, Frequency};
+use pmu::{self, Clock, PeripheralClock, PeripheralClock1};
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Bit 0 enables the monitor
+    control: VolatileCell<u32>,
+
+    /// Warning threshold, in 100mV steps above `MIN_THRESHOLD_MV`
+    threshold: VolatileCell<u32>,
+
+    /// Bit 0 enables the brownout warning interrupt
+    interrupt_enable: VolatileCell<u32>,
+
+    /// Bit 0 is set while the warning interrupt is pending; write 1 to clear
+    interrupt_state: VolatileCell<u32>,
+
+    /// Bit 0 is set while the supply is below the threshold
+    status: VolatileCell<u32>,
+}
+
+const VOLT0_BASE: *const Registers = 0x40560000 as *const Registers;
+
+pub static mut VOLT0: VoltageMonitor = unsafe { VoltageMonitor::new(VOLT0_BASE) };
+
+pub const MIN_THRESHOLD_MV: u32 = 1600;
+pub const MAX_THRESHOLD_MV: u32 = 3100;
+
+const MAX_CLIENTS: usize = 4;
+
+/// How often `RecoveryMonitor` looks at the supply after a warning
+const RECOVERY_CHECK_MS: u32 = 100;
+
+/// Notified when the supply drops below the warning threshold.
+///
+/// Callbacks run in the bottom half and should do as little as possible:
+/// there may only be a few milliseconds of power left.
+pub trait BrownoutClient {
+    fn brownout_warning(&self);
+}
+
+pub struct VoltageMonitor {
+    regs: *const Registers,
+    clock: Clock,
+    clients: [Cell<Option<&'static BrownoutClient>>; MAX_CLIENTS],
+}
+
+impl VoltageMonitor {
+    const unsafe fn new(regs: *const Registers) -> VoltageMonitor {
+        VoltageMonitor {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank1(PeripheralClock1::Volt0)),
+            clients: [Cell::new(None), Cell::new(None), Cell::new(None), Cell::new(None)],
+        }
+    }
+
+    /// Adds a client to be told about brownout warnings. Returns ENOMEM if
+    /// `MAX_CLIENTS` are already registered.
+    pub fn add_client(&self, client: &'static BrownoutClient) -> ReturnCode {
+        for slot in self.clients.iter() {
+            if slot.get().is_none() {
+                slot.set(Some(client));
+                return ReturnCode::SUCCESS;
+            }
+        }
+        ReturnCode::ENOMEM
+    }
+
+    /// Starts monitoring with a warning at `millivolts`, which must be a
+    /// multiple of 100 between `MIN_THRESHOLD_MV` and `MAX_THRESHOLD_MV`.
+    pub fn enable(&self, millivolts: u32) -> ReturnCode {
+        if millivolts < MIN_THRESHOLD_MV || millivolts > MAX_THRESHOLD_MV ||
+            millivolts % 100 != 0 {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        if regs.control.get() & 1 == 0 {
+            self.clock.acquire();
+        }
+        regs.threshold.set((millivolts - MIN_THRESHOLD_MV) / 100);
+        regs.interrupt_state.set(1);
+        regs.interrupt_enable.set(1);
+        regs.control.set(1);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn disable(&self) {
+        let regs = unsafe { &*self.regs };
+        if regs.control.get() & 1 != 0 {
+            regs.interrupt_enable.set(0);
+            regs.control.set(0);
+            self.clock.release();
+        }
+    }
+
+    /// Whether the supply is currently below the warning threshold.
+    pub fn is_low(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.status.get() & 1 != 0
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.interrupt_state.set(1);
+
+        pmu::record_brownout();
+        for slot in self.clients.iter() {
+            slot.get().map(|client| client.brownout_warning());
+        }
+    }
+}
+
+/// Clears the brownout recorded by a warning once the supply is back above
+/// the threshold, checking every `RECOVERY_CHECK_MS` until it is.
+pub struct RecoveryMonitor<'a, A: Alarm + 'a> {
+    monitor: &'a VoltageMonitor,
+    alarm: &'a A,
+}
+
+impl<'a, A: Alarm + 'a> RecoveryMonitor<'a, A> {
+    pub fn new(monitor: &'a VoltageMonitor, alarm: &'a A) -> RecoveryMonitor<'a, A> {
+        RecoveryMonitor {
+            monitor: monitor,
+            alarm: alarm,
+        }
+    }
+
+    fn check_later(&self) {
+        let delay = RECOVERY_CHECK_MS * <A::Frequency>::frequency() / 1000;
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(delay));
+    }
+}
+
+impl<'a, A: Alarm + 'a> BrownoutClient for RecoveryMonitor<'a, A> {
+    fn brownout_warning(&self) {
+        self.check_later();
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for RecoveryMonitor<'a, A> {
+    fn fired(&self) {
+        if self.monitor.is_low() {
+            self.check_later();
+        } else {
+            pmu::clear_brownout();
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Watchdog (WATCHDOG0)
+//!
+//! Once started, the watchdog has to be fed at least once a period. The
+//! chip feeds it from `service_pending_interrupts`, which the kernel calls
+//! once per pass of its main loop, so it is only fed while the scheduler is
+//! making progress; a driver or interrupt handler that never returns stops
+//! the feeding. The first period without a feed raises the early-warning
+//! ("bark") interrupt, which panics naming the component the chip was
+//! running; the panic handler prints the usual state and, as nothing feeds
+//! the watchdog after that, it resets the chip at the end of the second
+//! period. A period spent asleep waiting for an interrupt is not a hang:
+//! the bark is acknowledged and the chip goes back to sleep.
+//!
+//! Every panic after `start` ends in a watchdog reset, even if the panic
+//! handler would otherwise halt.
+
+use core::cell::Cell;
+use deferred_call::Task;
+use kernel::ReturnCode;
+use kernel::common::cells::VolatileCell;
+use pmu::{Clock, PeripheralClock, PeripheralClock1};
+
+#[repr(C)]
+struct Registers {
+    /// Counter reload value, in cycles of the 24MHz peripheral clock
+    load: VolatileCell<u32>,
+
+    /// Current counter value; it counts down from `load`
+    value: VolatileCell<u32>,
+
+    /// | bits | Description                                     |
+    /// | ---- | :---------------------------------------------- |
+    /// | 0    | INTEN: bark at zero and restart the counter     |
+    /// | 1    | RESEN: reset the chip if a bark is left pending |
+    control: VolatileCell<u32>,
+
+    /// Any write clears the bark and reloads the counter
+    interrupt_clear: VolatileCell<u32>,
+
+    /// Bit 0 is set while a bark is pending
+    raw_interrupt_status: VolatileCell<u32>,
+
+    _masked_interrupt_status: VolatileCell<u32>,
+
+    _reserved: [u32; 762],
+
+    /// Writing `LOCK_KEY` unlocks the other registers; anything else locks
+    /// them
+    lock: VolatileCell<u32>,
+}
+
+const WATCHDOG0_BASE: *const Registers = 0x40500000 as *const Registers;
+
+/// The watchdog's NVIC line, which has its own vector (see `IRQS`)
+pub const WATCHDOG0_IRQ: usize = 197;
+
+pub static mut WATCHDOG0: Watchdog = unsafe { Watchdog::new(WATCHDOG0_BASE) };
+
+const LOCK_KEY: u32 = 0x1acce551;
+
+const CONTROL_INTEN: u32 = 1 << 0;
+const CONTROL_RESEN: u32 = 1 << 1;
+
+const CYCLES_PER_MS: u32 = 24_000;
+
+/// What the chip is doing, reported if the watchdog barks
+#[derive(Clone, Copy, Debug)]
+pub enum Component {
+    /// The kernel's main loop, a system call or an app
+    Kernel,
+    /// The handler for an NVIC line
+    Interrupt(u32),
+    Deferred(Task),
+    /// Waiting for an interrupt
+    Sleep,
+}
+
+pub struct Watchdog {
+    regs: *const Registers,
+    clock: Clock,
+    running: Cell<bool>,
+    component: Cell<Component>,
+}
+
+impl Watchdog {
+    const unsafe fn new(regs: *const Registers) -> Watchdog {
+        Watchdog {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank1(PeripheralClock1::Watchdog0)),
+            running: Cell::new(false),
+            component: Cell::new(Component::Kernel),
+        }
+    }
+
+    /// Starts the watchdog with a period of `period_ms`, which must be
+    /// between 1ms and about 178 seconds.
+    pub fn start(&self, period_ms: u32) -> ReturnCode {
+        if period_ms == 0 || period_ms > !0 / CYCLES_PER_MS {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        if !self.running.get() {
+            self.clock.acquire();
+        }
+        regs.lock.set(LOCK_KEY);
+        regs.load.set(period_ms * CYCLES_PER_MS);
+        regs.interrupt_clear.set(1);
+        regs.control.set(CONTROL_INTEN | CONTROL_RESEN);
+        regs.lock.set(0);
+        self.running.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn stop(&self) {
+        if !self.running.get() {
+            return;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.lock.set(LOCK_KEY);
+        regs.control.set(0);
+        regs.interrupt_clear.set(1);
+        regs.lock.set(0);
+        self.clock.release();
+        self.running.set(false);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.get()
+    }
+
+    /// Restarts the period.
+    pub fn feed(&self) {
+        if !self.running.get() {
+            return;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.lock.set(LOCK_KEY);
+        regs.interrupt_clear.set(1);
+        regs.lock.set(0);
+    }
+
+    /// Milliseconds left before the watchdog barks.
+    pub fn remaining_ms(&self) -> u32 {
+        if !self.running.get() {
+            return 0;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.value.get() / CYCLES_PER_MS
+    }
+
+    /// Notes what the chip is about to run, for the bark to report.
+    pub fn set_component(&self, component: Component) {
+        self.component.set(component);
+    }
+
+    pub fn component(&self) -> Component {
+        self.component.get()
+    }
+
+    fn handle_bark(&self) {
+        let regs = unsafe { &*self.regs };
+        if regs.raw_interrupt_status.get() & 1 == 0 {
+            return;
+        }
+        match self.component.get() {
+            Component::Sleep => self.feed(),
+            // The bark is left pending, so the watchdog resets the chip one
+            // period from now.
+            component => panic!("Watchdog bark: hung in {:?}", component),
+        }
+    }
+}
+
+/// Vector for `WATCHDOG0_IRQ`. The bark is handled in the interrupt itself
+/// rather than left to `service_pending_interrupts`, which a hang may never
+/// reach.
+pub unsafe extern "C" fn bark_handler() {
+    WATCHDOG0.handle_bark();
+}
@@ -0,0 +1,168 @@
+//! Crystal/RC oscillator controller (XO)
+//!
+//! Without a crystal the chip's 24Mhz clock comes from a trimmed RC
+//! oscillator, which drifts with temperature by more than full-speed USB
+//! tolerates (+/-2500 ppm). While connected, the host sends a Start of
+//! Frame (SOF) every millisecond with far better accuracy than that, so we
+//! use it as a reference: the USB driver reports each SOF with its frame
+//! number, we count how many microseconds the local clock measured over a
+//! window of `WINDOW_FRAMES` frames, and nudge the RC trim towards the
+//! host's clock whenever the error exceeds `TOLERANCE_PPM`.
+//!
+//! The fine trim is adjusted one step per window; when it saturates the
+//! coarse trim is moved one step and the fine trim recentered. Once a
+//! window's error is within tolerance the trim has converged and
+//! `handle_sof` asks for no more frames, so the USB driver can mask SOF;
+//! `restart` (on a bus reset or suspend, or through `USB::watch_frames`)
+//! starts retrimming.
+
+use core::cell::Cell;
+use kernel::common::cells::VolatileCell;
+use pmu::{Clock, PeripheralClock, PeripheralClock1};
+use timestamp::TIMESTAMP;
+
+#[repr(C)]
+struct Registers {
+    _version: VolatileCell<u32>,
+
+    /// Coarse trim of the RC oscillator. Larger values run faster.
+    rc_trim_coarse: VolatileCell<u32>,
+
+    /// Fine trim of the RC oscillator. Larger values run faster.
+    rc_trim_fine: VolatileCell<u32>,
+}
+
+const XO0_BASE: *const Registers = 0x40460000 as *const Registers;
+
+pub static mut XO0: Xo = unsafe { Xo::new(XO0_BASE) };
+
+const COARSE_TRIM_MAX: u32 = 0x3f;
+const FINE_TRIM_MAX: u32 = 0xff;
+
+/// Frames per measurement window (~1 second).
+const WINDOW_FRAMES: u32 = 1024;
+
+/// Largest error left uncorrected. A quarter of the USB full-speed budget,
+/// leaving room for drift between windows.
+const TOLERANCE_PPM: i32 = 600;
+
+/// Frame numbers are 11 bits on a full-speed bus.
+const FRAME_MASK: u32 = 0x7ff;
+
+pub struct Xo {
+    regs: *const Registers,
+    clock: Clock,
+    calibrating: Cell<bool>,
+    // Frame number and local time of the start of the current window
+    window_start: Cell<Option<(u32, u64)>>,
+    last_frame: Cell<u32>,
+    frames: Cell<u32>,
+    last_error_ppm: Cell<i32>,
+    converged: Cell<bool>,
+}
+
+impl Xo {
+    const unsafe fn new(regs: *const Registers) -> Xo {
+        Xo {
+            regs: regs,
+            clock: Clock::new(PeripheralClock::Bank1(PeripheralClock1::Xo0)),
+            calibrating: Cell::new(false),
+            window_start: Cell::new(None),
+            last_frame: Cell::new(0),
+            frames: Cell::new(0),
+            last_error_ppm: Cell::new(0),
+            converged: Cell::new(false),
+        }
+    }
+
+    /// Starts trimming the RC oscillator against SOF timing. Requires the
+    /// timestamp counter to be running.
+    pub fn start_calibration(&self) {
+        self.clock.acquire();
+        self.calibrating.set(true);
+        self.restart();
+    }
+
+    pub fn stop_calibration(&self) {
+        if self.calibrating.get() {
+            self.calibrating.set(false);
+            self.clock.release();
+        }
+    }
+
+    /// Discards the current measurement window and retrims, e.g. because
+    /// the bus was reset or suspended and frames were missed.
+    pub fn restart(&self) {
+        self.window_start.set(None);
+        self.frames.set(0);
+        self.converged.set(false);
+    }
+
+    /// The error measured over the last complete window, in parts per
+    /// million. Positive means the local clock runs fast.
+    pub fn last_error_ppm(&self) -> i32 {
+        self.last_error_ppm.get()
+    }
+
+    /// Called by the USB driver on every Start of Frame with the frame
+    /// number from the device status register. Returns whether the trim
+    /// still needs frames.
+    pub fn handle_sof(&self, frame: u32) -> bool {
+        if !self.calibrating.get() {
+            return false;
+        }
+        let now = TIMESTAMP.now();
+        let frame = frame & FRAME_MASK;
+
+        match self.window_start.get() {
+            None => {
+                self.window_start.set(Some((frame, now)));
+                self.last_frame.set(frame);
+                self.frames.set(0);
+            }
+            Some((_, start)) => {
+                let elapsed = frame.wrapping_sub(self.last_frame.get()) & FRAME_MASK;
+                self.last_frame.set(frame);
+                self.frames.set(self.frames.get() + elapsed);
+                if self.frames.get() >= WINDOW_FRAMES {
+                    let expected = self.frames.get() as i64 * 1000;
+                    let measured = (now - start) as i64;
+                    let error = ((measured - expected) * 1_000_000 / expected) as i32;
+                    self.last_error_ppm.set(error);
+                    if error > TOLERANCE_PPM {
+                        self.step(false);
+                    } else if error < -TOLERANCE_PPM {
+                        self.step(true);
+                    } else {
+                        self.converged.set(true);
+                    }
+                    self.window_start.set(Some((frame, now)));
+                    self.frames.set(0);
+                }
+            }
+        }
+        !self.converged.get()
+    }
+
+    /// Moves the oscillator one trim step faster or slower.
+    fn step(&self, faster: bool) {
+        let regs = unsafe { &*self.regs };
+        let fine = regs.rc_trim_fine.get() & FINE_TRIM_MAX;
+        let coarse = regs.rc_trim_coarse.get() & COARSE_TRIM_MAX;
+        if faster {
+            if fine < FINE_TRIM_MAX {
+                regs.rc_trim_fine.set(fine + 1);
+            } else if coarse < COARSE_TRIM_MAX {
+                regs.rc_trim_coarse.set(coarse + 1);
+                regs.rc_trim_fine.set(FINE_TRIM_MAX / 2);
+            }
+        } else {
+            if fine > 0 {
+                regs.rc_trim_fine.set(fine - 1);
+            } else if coarse > 0 {
+                regs.rc_trim_coarse.set(coarse - 1);
+                regs.rc_trim_fine.set(FINE_TRIM_MAX / 2);
+            }
+        }
+    }
+}